@@ -1,13 +1,13 @@
 #![no_std]
 #![no_main]
 #![feature(impl_trait_in_assoc_type)]
-use bme280::i2c::AsyncBME280;
 use defmt::info;
 use embassy_executor::Spawner;
 use embassy_time::{Delay, Duration, Timer};
 use esp_hal::i2c::master::Config;
 use esp_hal::{clock::CpuClock, i2c::master::I2c};
 use esp_println::println;
+use weather_station::sensors::bme280::{Bme280, Config as Bme280Config, PRIMARY_ADDRESS};
 use {esp_backtrace as _, esp_println as _};
 use esp_hal::interrupt::software::SoftwareInterruptControl;
 use esp_alloc as _;
@@ -33,9 +33,9 @@ async fn main(_spawner: Spawner) {
         .with_sda(peripherals.GPIO8)
     .with_scl(peripherals.GPIO9)
         .into_async();
-    let mut bme280 = AsyncBME280::new_primary(i2c_bus);
     let mut delay = Delay;
-    bme280.init(&mut delay).await.unwrap();
+    let mut bme280 = Bme280::new(i2c_bus, PRIMARY_ADDRESS, Bme280Config::default(), &mut delay).await.unwrap();
+    info!("Detected {}", bme280.chip());
     loop {
         info!("Hello world!");
         let measurments = bme280.measure(&mut delay).await;
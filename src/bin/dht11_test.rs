@@ -7,6 +7,10 @@ use embassy_time::{Delay, Duration, Timer};
 use esp_hal::clock::CpuClock;
 use esp_hal::gpio::{Flex, InputConfig, OutputConfig, Pull};
 use esp_hal::interrupt::software::SoftwareInterruptControl;
+use esp_hal::rmt::Rmt;
+use esp_hal::time::Rate;
+use weather_station::sensors::dht::EmbassyClock;
+use weather_station::sensors::dht::rmt::{RMT_FREQUENCY_MHZ, RmtCapture};
 use weather_station::sensors::dht11::Dht11;
 use {esp_backtrace as _, esp_println as _};
 
@@ -35,17 +39,16 @@ async fn main(_spawner: Spawner) {
     dht11_pin.set_output_enable(true);
     dht11_pin.set_input_enable(true);
 
-    let mut dht11 = Dht11::new(dht11_pin);
+    let rmt = Rmt::new(peripherals.RMT, Rate::from_mhz(RMT_FREQUENCY_MHZ))
+        .unwrap()
+        .into_async();
+    let capture = RmtCapture::new(rmt.channel2, &dht11_pin).unwrap();
+    let mut dht11 = Dht11::new(dht11_pin, capture, EmbassyClock);
     let mut delay = Delay;
     loop {
-        info!("Unchecked");
-        let measurments = critical_section::with(|_| dht11.read(&mut delay)).await;
+        let measurments = dht11.read(&mut delay).await;
         println!("{:?}", measurments);
         Timer::after(Duration::from_millis(1000)).await; // >=1s interval between measturments is suitable
-        info!("CRC");
-        let measurments = critical_section::with(|_| dht11.read_with_crc_check(&mut delay)).await;
-        println!("{:?}", measurments);
-        Timer::after(Duration::from_millis(1000)).await;
     }
 
     // for inspiration have a look at the examples at https://github.com/esp-rs/esp-hal/tree/esp-hal-v1.0.0-beta.0/examples/src/bin
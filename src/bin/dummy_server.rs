@@ -12,6 +12,7 @@
 #![no_std]
 #![no_main]
 #![feature(impl_trait_in_assoc_type)]
+use core::cell::RefCell;
 use core::{net::Ipv4Addr, str::FromStr};
 
 use embassy_executor::Spawner;
@@ -24,8 +25,12 @@ use esp_hal::interrupt::software::SoftwareInterruptControl;
 use esp_hal::{clock::CpuClock, rng::Rng, timer::timg::TimerGroup};
 use esp_println::println;
 use picoserve::{AppBuilder, AppRouter, routing::get};
+use weather_station::config::AuthMethod;
 use weather_station::make_static;
-use weather_station::network::dhcp::run_dhcp;
+use weather_station::config::Config;
+use weather_station::network::dhcp::leases::{Leases, Pool};
+use weather_station::network::dhcp::{TheLeases, run_dhcp};
+use weather_station::network::dns::run_dns;
 use weather_station::network::network_tasks::connection;
 use weather_station::network::network_tasks::net_task;
 
@@ -82,9 +87,19 @@ async fn main(spawner: Spawner) {
         seed,
     );
 
-    spawner.spawn(connection(controller, SSID)).ok();
+    spawner
+        .spawn(connection(controller, SSID, AuthMethod::Open, ""))
+        .ok();
     spawner.spawn(net_task(runner)).ok();
-    spawner.spawn(run_dhcp(stack, gw_ip_addr_str)).ok();
+    let leases = make_static!(
+        TheLeases,
+        TheLeases::new(RefCell::new(Leases::new(Pool::from_config(&Config {
+            address: gw_ip_addr,
+            ..Config::default()
+        }))))
+    );
+    spawner.spawn(run_dhcp(stack, gw_ip_addr, leases)).ok();
+    spawner.spawn(run_dns(stack, gw_ip_addr)).ok();
 
     loop {
         if stack.is_link_up() {
@@ -0,0 +1,1101 @@
+// Settings that can change without a rebuild, persisted in the `config` partition
+use core::cell::RefCell;
+use core::net::Ipv4Addr;
+
+use defmt::warn;
+use embassy_sync::blocking_mutex::{Mutex, raw::NoopRawMutex};
+use embedded_storage::nor_flash::NorFlash;
+use esp_storage::FlashStorageError;
+use heapless::String;
+use serde::{Deserialize, Serialize};
+
+use crate::sensors::bme280::{self, Filter, Mode, Oversampling};
+use crate::storage::kv::{self, FlashKvStore, KvStore, MAX_VALUE_LEN};
+
+/// Version of the stored settings, bump it when the meaning of a value changes
+/// and handle the older one in [`migrate`].
+pub const VERSION: u16 = 1;
+
+/// Bounds of [`Config::measurement_interval_ms`].
+pub const MEASUREMENT_INTERVAL_MS: core::ops::RangeInclusive<u32> = 10..=3_600_000;
+
+/// Bounds of [`Config::humidity_interval_ms`], the DHT sensors can't be read
+/// more often than once a second.
+pub const HUMIDITY_INTERVAL_MS: core::ops::RangeInclusive<u32> = 1_000..=3_600_000;
+
+/// Bounds of [`Config::events_interval_ms`].
+pub const EVENTS_INTERVAL_MS: core::ops::RangeInclusive<u32> = 100..=3_600_000;
+
+/// Bounds of [`Config::dhcp_lease_secs`], a minute to a week.
+pub const DHCP_LEASE_SECS: core::ops::RangeInclusive<u32> = 60..=604_800;
+
+/// Bounds of [`Config::dhcp_max_leases`].
+pub const DHCP_MAX_LEASES: core::ops::RangeInclusive<u8> = 1..=32;
+
+/// Passphrase of the access point set at build time, it's open without one.
+const DEFAULT_PASSPHRASE: Option<&str> = option_env!("WEATHER_STATION_PASSPHRASE");
+
+/// Network to join at boot, the station runs its own access point without one.
+const DEFAULT_STATION_SSID: Option<&str> = option_env!("WEATHER_STATION_STA_SSID");
+const DEFAULT_STATION_PASSPHRASE: Option<&str> = option_env!("WEATHER_STATION_STA_PASSPHRASE");
+
+/// Whether the station runs its own network or joins one.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, Serialize, defmt::Format)]
+#[serde(rename_all = "kebab-case")]
+pub enum WifiMode {
+    AccessPoint,
+    Station,
+}
+
+impl WifiMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            WifiMode::AccessPoint => "access-point",
+            WifiMode::Station => "station",
+        }
+    }
+
+    fn from_u8(value: u8) -> Option<Self> {
+        [Self::AccessPoint, Self::Station]
+            .get(value as usize)
+            .copied()
+    }
+}
+
+/// How measurements are pushed to InfluxDB.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, Serialize, defmt::Format)]
+#[serde(rename_all = "kebab-case")]
+pub enum InfluxTransport {
+    /// `POST /api/v2/write` of InfluxDB 2.
+    Http,
+    /// The UDP listener of InfluxDB 1.
+    Udp,
+}
+
+impl InfluxTransport {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            InfluxTransport::Http => "http",
+            InfluxTransport::Udp => "udp",
+        }
+    }
+
+    fn from_u8(value: u8) -> Option<Self> {
+        [Self::Http, Self::Udp].get(value as usize).copied()
+    }
+}
+
+/// How clients authenticate to the access point.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, Serialize, defmt::Format)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuthMethod {
+    Open,
+    Wpa2,
+    Wpa3,
+    Wpa2Wpa3,
+}
+
+impl AuthMethod {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AuthMethod::Open => "open",
+            AuthMethod::Wpa2 => "wpa2",
+            AuthMethod::Wpa3 => "wpa3",
+            AuthMethod::Wpa2Wpa3 => "wpa2-wpa3",
+        }
+    }
+
+    fn from_u8(value: u8) -> Option<Self> {
+        [Self::Open, Self::Wpa2, Self::Wpa3, Self::Wpa2Wpa3]
+            .get(value as usize)
+            .copied()
+    }
+}
+
+/// Checks `passphrase` can be used with `auth`.
+///
+/// WPA passphrases are 8 to 63 printable ASCII characters, WPA2 also accepts
+/// a pre-shared key of 64 hex digits. Open networks don't use one.
+pub fn validate_passphrase(auth: AuthMethod, passphrase: &str) -> Result<(), ConfigError> {
+    let is_psk = passphrase.len() == 64 && passphrase.bytes().all(|b| b.is_ascii_hexdigit());
+    match auth {
+        AuthMethod::Open => Ok(()),
+        AuthMethod::Wpa2 if is_psk => Ok(()),
+        _ if !(8..=63).contains(&passphrase.len()) => Err(ConfigError::PassphraseLength),
+        _ if !passphrase.bytes().all(|b| (b' '..=b'~').contains(&b)) => {
+            Err(ConfigError::PassphraseCharacters)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Keys of the settings in the store.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, defmt::Format)]
+#[repr(u16)]
+pub enum Key {
+    Version = 0,
+    Ssid = 1,
+    Passphrase = 2,
+    Address = 3,
+    MeasurementInterval = 4,
+    HumidityInterval = 5,
+    Auth = 6,
+    Mode = 7,
+    StationSsid = 8,
+    StationPassphrase = 9,
+    /// Set when the station couldn't join its network, see
+    /// [`ConfigStore::request_provisioning`].
+    Provisioning = 10,
+    Hostname = 11,
+    DhcpFirstHost = 12,
+    DhcpLastHost = 13,
+    DhcpLease = 14,
+    DhcpMaxLeases = 15,
+    MqttBroker = 16,
+    MqttPort = 17,
+    MqttUsername = 18,
+    MqttPassword = 19,
+    MqttTopic = 20,
+    InfluxServer = 21,
+    InfluxPort = 22,
+    InfluxTransport = 23,
+    InfluxOrg = 24,
+    InfluxBucket = 25,
+    InfluxToken = 26,
+    EventsInterval = 27,
+    Bme280 = 28,
+}
+
+impl Key {
+    /// Every key but [`Key::Version`] and [`Key::Provisioning`].
+    pub const SETTINGS: [Key; 27] = [
+        Key::Ssid,
+        Key::Auth,
+        Key::Passphrase,
+        Key::Address,
+        Key::MeasurementInterval,
+        Key::HumidityInterval,
+        Key::Mode,
+        Key::StationSsid,
+        Key::StationPassphrase,
+        Key::Hostname,
+        Key::DhcpFirstHost,
+        Key::DhcpLastHost,
+        Key::DhcpLease,
+        Key::DhcpMaxLeases,
+        Key::MqttBroker,
+        Key::MqttPort,
+        Key::MqttUsername,
+        Key::MqttPassword,
+        Key::MqttTopic,
+        Key::InfluxServer,
+        Key::InfluxPort,
+        Key::InfluxTransport,
+        Key::InfluxOrg,
+        Key::InfluxBucket,
+        Key::InfluxToken,
+        Key::EventsInterval,
+        Key::Bme280,
+    ];
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    /// Name of the access point.
+    pub ssid: String<32>,
+    pub auth: AuthMethod,
+    /// Ignored for an open network.
+    pub passphrase: String<64>,
+    /// Address of the station, DHCP clients get it as their gateway.
+    pub address: Ipv4Addr,
+    pub mode: WifiMode,
+    /// Network joined in [`WifiMode::Station`], the station is provisioned
+    /// without one.
+    pub station_ssid: String<32>,
+    /// Empty for an open network.
+    pub station_passphrase: String<64>,
+    /// Name advertised over mDNS, the station is `<hostname>.local`.
+    pub hostname: String<32>,
+    /// Last byte of the first address leased to the access point clients, in
+    /// the /24 subnet of [`Config::address`].
+    pub dhcp_first_host: u8,
+    /// Last byte of the last address leased.
+    pub dhcp_last_host: u8,
+    pub dhcp_lease_secs: u32,
+    /// How many clients can have an address at the same time.
+    pub dhcp_max_leases: u8,
+    /// MQTT broker measurements are published to, unspecified to disable it.
+    pub mqtt_broker: Ipv4Addr,
+    pub mqtt_port: u16,
+    /// Empty to connect anonymously.
+    pub mqtt_username: String<32>,
+    /// Empty without a username.
+    pub mqtt_password: String<64>,
+    /// Measurements are published to `<topic>/<quantity>`, the availability
+    /// to `<topic>/status`.
+    pub mqtt_topic: String<64>,
+    /// InfluxDB server measurements are pushed to, unspecified to disable it.
+    pub influx_server: Ipv4Addr,
+    pub influx_port: u16,
+    pub influx_transport: InfluxTransport,
+    /// Organization and bucket written to over HTTP.
+    pub influx_org: String<32>,
+    pub influx_bucket: String<32>,
+    /// API token of the HTTP transport, empty without authentication.
+    pub influx_token: String<96>,
+    /// How often the sensors are read.
+    pub measurement_interval_ms: u32,
+    /// How often the DHT sensor is read.
+    pub humidity_interval_ms: u32,
+    /// Shortest time between two events of an `/events` stream.
+    pub events_interval_ms: u32,
+    /// Oversampling, filter and mode of the BME280 (or BMP280) applied at
+    /// boot, a standby time the chip doesn't support leaves it on the defaults.
+    pub bme280: bme280::Config,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            // Fits
+            ssid: String::try_from("WeatherStation").unwrap(),
+            auth: match DEFAULT_PASSPHRASE {
+                Some(_) => AuthMethod::Wpa2,
+                None => AuthMethod::Open,
+            },
+            // Checked by `validate` like a stored one
+            passphrase: DEFAULT_PASSPHRASE
+                .and_then(|passphrase| String::try_from(passphrase).ok())
+                .unwrap_or_default(),
+            address: Ipv4Addr::new(192, 168, 1, 1),
+            mode: match DEFAULT_STATION_SSID {
+                Some(_) => WifiMode::Station,
+                None => WifiMode::AccessPoint,
+            },
+            station_ssid: DEFAULT_STATION_SSID
+                .and_then(|ssid| String::try_from(ssid).ok())
+                .unwrap_or_default(),
+            station_passphrase: DEFAULT_STATION_PASSPHRASE
+                .and_then(|passphrase| String::try_from(passphrase).ok())
+                .unwrap_or_default(),
+            // Fits
+            hostname: String::try_from("weather-station").unwrap(),
+            dhcp_first_host: 50,
+            dhcp_last_host: 200,
+            dhcp_lease_secs: 7200,
+            dhcp_max_leases: 16,
+            mqtt_broker: Ipv4Addr::UNSPECIFIED,
+            mqtt_port: 1883,
+            mqtt_username: String::new(),
+            mqtt_password: String::new(),
+            // Fits
+            mqtt_topic: String::try_from("weather-station").unwrap(),
+            influx_server: Ipv4Addr::UNSPECIFIED,
+            influx_port: 8086,
+            influx_transport: InfluxTransport::Http,
+            influx_org: String::new(),
+            // Fits
+            influx_bucket: String::try_from("weather").unwrap(),
+            influx_token: String::new(),
+            measurement_interval_ms: 100,
+            humidity_interval_ms: 1250,
+            events_interval_ms: 1000,
+            bme280: bme280::Config::default(),
+        }
+    }
+}
+
+/// The settings as served by `/config`, the secrets are only told to be set.
+#[derive(Serialize)]
+pub struct ConfigView<'a> {
+    version: u16,
+    ssid: &'a str,
+    auth: AuthMethod,
+    passphrase_set: bool,
+    address: Ipv4Addr,
+    mode: WifiMode,
+    station_ssid: &'a str,
+    station_passphrase_set: bool,
+    hostname: &'a str,
+    dhcp_first_host: u8,
+    dhcp_last_host: u8,
+    dhcp_lease_secs: u32,
+    dhcp_max_leases: u8,
+    mqtt_broker: Ipv4Addr,
+    mqtt_port: u16,
+    mqtt_username: &'a str,
+    mqtt_password_set: bool,
+    mqtt_topic: &'a str,
+    influx_server: Ipv4Addr,
+    influx_port: u16,
+    influx_transport: InfluxTransport,
+    influx_org: &'a str,
+    influx_bucket: &'a str,
+    influx_token_set: bool,
+    measurement_interval_ms: u32,
+    humidity_interval_ms: u32,
+    events_interval_ms: u32,
+    bme280_temperature: Oversampling,
+    bme280_pressure: Oversampling,
+    bme280_humidity: Oversampling,
+    bme280_filter: Filter,
+    bme280_mode: Mode,
+}
+
+impl<'a> ConfigView<'a> {
+    pub fn new(config: &'a Config) -> Self {
+        Self {
+            version: VERSION,
+            ssid: &config.ssid,
+            auth: config.auth,
+            passphrase_set: !config.passphrase.is_empty(),
+            address: config.address,
+            mode: config.mode,
+            station_ssid: &config.station_ssid,
+            station_passphrase_set: !config.station_passphrase.is_empty(),
+            hostname: &config.hostname,
+            dhcp_first_host: config.dhcp_first_host,
+            dhcp_last_host: config.dhcp_last_host,
+            dhcp_lease_secs: config.dhcp_lease_secs,
+            dhcp_max_leases: config.dhcp_max_leases,
+            mqtt_broker: config.mqtt_broker,
+            mqtt_port: config.mqtt_port,
+            mqtt_username: &config.mqtt_username,
+            mqtt_password_set: !config.mqtt_password.is_empty(),
+            mqtt_topic: &config.mqtt_topic,
+            influx_server: config.influx_server,
+            influx_port: config.influx_port,
+            influx_transport: config.influx_transport,
+            influx_org: &config.influx_org,
+            influx_bucket: &config.influx_bucket,
+            influx_token_set: !config.influx_token.is_empty(),
+            measurement_interval_ms: config.measurement_interval_ms,
+            humidity_interval_ms: config.humidity_interval_ms,
+            events_interval_ms: config.events_interval_ms,
+            bme280_temperature: config.bme280.temperature,
+            bme280_pressure: config.bme280.pressure,
+            bme280_humidity: config.bme280.humidity,
+            bme280_filter: config.bme280.filter,
+            bme280_mode: config.bme280.mode,
+        }
+    }
+}
+
+/// Why a configuration can't be used.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, defmt::Format)]
+pub enum ConfigError {
+    EmptySsid,
+    /// Not 8 to 63 characters, or a 64 hex digit key for WPA2.
+    PassphraseLength,
+    /// Not printable ASCII.
+    PassphraseCharacters,
+    /// No network to join.
+    EmptyStationSsid,
+    /// Not empty nor a valid WPA passphrase.
+    InvalidStationPassphrase,
+    /// Not a DNS label of letters, digits and hyphens.
+    InvalidHostname,
+    /// Empty, outside of the subnet or holding the station's address.
+    InvalidDhcpRange,
+    DhcpLeaseOutOfRange,
+    DhcpMaxLeasesOutOfRange,
+    InvalidMqttPort,
+    /// MQTT 3.1.1 only sends a password along with a username.
+    MqttPasswordWithoutUsername,
+    /// Empty, with a wildcard or an empty level.
+    InvalidMqttTopic,
+    InvalidInfluxPort,
+    /// Empty with the HTTP transport.
+    EmptyInfluxBucket,
+    /// Not printable ASCII.
+    InvalidInfluxToken,
+    MeasurementIntervalOutOfRange,
+    HumidityIntervalOutOfRange,
+    EventsIntervalOutOfRange,
+}
+
+impl ConfigError {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ConfigError::EmptySsid => "the SSID is empty",
+            ConfigError::PassphraseLength => "the passphrase must have 8 to 63 characters",
+            ConfigError::PassphraseCharacters => {
+                "the passphrase must only contain printable ASCII characters"
+            }
+            ConfigError::EmptyStationSsid => "the station SSID is empty",
+            ConfigError::InvalidStationPassphrase => {
+                "the station passphrase must be empty or have 8 to 63 printable ASCII characters"
+            }
+            ConfigError::InvalidHostname => {
+                "the hostname must only contain letters, digits and inner hyphens"
+            }
+            ConfigError::InvalidDhcpRange => {
+                "the DHCP range must be in 1 to 254 and not hold the station's address"
+            }
+            ConfigError::DhcpLeaseOutOfRange => "the DHCP lease duration is out of range",
+            ConfigError::DhcpMaxLeasesOutOfRange => "the maximum number of DHCP leases is out of range",
+            ConfigError::InvalidMqttPort => "the MQTT port can't be 0",
+            ConfigError::MqttPasswordWithoutUsername => {
+                "the MQTT password can't be set without a username"
+            }
+            ConfigError::InvalidMqttTopic => {
+                "the MQTT topic must be levels of letters, digits, hyphens and underscores"
+            }
+            ConfigError::InvalidInfluxPort => "the InfluxDB port can't be 0",
+            ConfigError::EmptyInfluxBucket => "the InfluxDB bucket is empty",
+            ConfigError::InvalidInfluxToken => {
+                "the InfluxDB token must only contain printable ASCII characters"
+            }
+            ConfigError::MeasurementIntervalOutOfRange => {
+                "the measurement interval is out of range"
+            }
+            ConfigError::HumidityIntervalOutOfRange => "the humidity interval is out of range",
+            ConfigError::EventsIntervalOutOfRange => "the events interval is out of range",
+        }
+    }
+
+    /// The settings that make the configuration invalid.
+    pub fn keys(self) -> &'static [Key] {
+        match self {
+            ConfigError::EmptySsid => &[Key::Ssid],
+            ConfigError::PassphraseLength | ConfigError::PassphraseCharacters => {
+                &[Key::Auth, Key::Passphrase]
+            }
+            ConfigError::EmptyStationSsid => &[Key::StationSsid],
+            ConfigError::InvalidStationPassphrase => &[Key::StationPassphrase],
+            ConfigError::InvalidHostname => &[Key::Hostname],
+            ConfigError::InvalidDhcpRange => &[Key::Address, Key::DhcpFirstHost, Key::DhcpLastHost],
+            ConfigError::DhcpLeaseOutOfRange => &[Key::DhcpLease],
+            ConfigError::DhcpMaxLeasesOutOfRange => &[Key::DhcpMaxLeases],
+            ConfigError::InvalidMqttPort => &[Key::MqttPort],
+            ConfigError::MqttPasswordWithoutUsername => &[Key::MqttPassword],
+            ConfigError::InvalidMqttTopic => &[Key::MqttTopic],
+            ConfigError::InvalidInfluxPort => &[Key::InfluxPort],
+            ConfigError::EmptyInfluxBucket => &[Key::InfluxTransport, Key::InfluxBucket],
+            ConfigError::InvalidInfluxToken => &[Key::InfluxToken],
+            ConfigError::MeasurementIntervalOutOfRange => &[Key::MeasurementInterval],
+            ConfigError::HumidityIntervalOutOfRange => &[Key::HumidityInterval],
+            ConfigError::EventsIntervalOutOfRange => &[Key::EventsInterval],
+        }
+    }
+}
+
+/// A stored value that can't be decoded.
+#[derive(Copy, Clone, Debug, defmt::Format)]
+pub struct InvalidValue(pub Key);
+
+impl Config {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.ssid.is_empty() {
+            return Err(ConfigError::EmptySsid);
+        }
+        validate_passphrase(self.auth, &self.passphrase)?;
+        if !self.station_passphrase.is_empty()
+            && validate_passphrase(AuthMethod::Wpa2, &self.station_passphrase).is_err()
+        {
+            return Err(ConfigError::InvalidStationPassphrase);
+        }
+        if !is_hostname(&self.hostname) {
+            return Err(ConfigError::InvalidHostname);
+        }
+        let hosts = self.dhcp_first_host..=self.dhcp_last_host;
+        if hosts.is_empty()
+            || *hosts.start() == 0
+            || *hosts.end() == 255
+            || hosts.contains(&self.address.octets()[3])
+        {
+            return Err(ConfigError::InvalidDhcpRange);
+        }
+        if !DHCP_LEASE_SECS.contains(&self.dhcp_lease_secs) {
+            return Err(ConfigError::DhcpLeaseOutOfRange);
+        }
+        if !DHCP_MAX_LEASES.contains(&self.dhcp_max_leases) {
+            return Err(ConfigError::DhcpMaxLeasesOutOfRange);
+        }
+        if self.mqtt_port == 0 {
+            return Err(ConfigError::InvalidMqttPort);
+        }
+        if self.mqtt_username.is_empty() && !self.mqtt_password.is_empty() {
+            return Err(ConfigError::MqttPasswordWithoutUsername);
+        }
+        if !is_topic(&self.mqtt_topic) {
+            return Err(ConfigError::InvalidMqttTopic);
+        }
+        if self.influx_port == 0 {
+            return Err(ConfigError::InvalidInfluxPort);
+        }
+        if self.influx_transport == InfluxTransport::Http && self.influx_bucket.is_empty() {
+            return Err(ConfigError::EmptyInfluxBucket);
+        }
+        // Sent in a header
+        if !self.influx_token.bytes().all(|b| (b' '..=b'~').contains(&b)) {
+            return Err(ConfigError::InvalidInfluxToken);
+        }
+        if !MEASUREMENT_INTERVAL_MS.contains(&self.measurement_interval_ms) {
+            return Err(ConfigError::MeasurementIntervalOutOfRange);
+        }
+        if !HUMIDITY_INTERVAL_MS.contains(&self.humidity_interval_ms) {
+            return Err(ConfigError::HumidityIntervalOutOfRange);
+        }
+        if !EVENTS_INTERVAL_MS.contains(&self.events_interval_ms) {
+            return Err(ConfigError::EventsIntervalOutOfRange);
+        }
+        Ok(())
+    }
+
+    /// Whether the station has no network to join and has to be provisioned.
+    pub fn needs_provisioning(&self) -> bool {
+        self.mode == WifiMode::Station && self.station_ssid.is_empty()
+    }
+
+    /// Switches to [`WifiMode::Station`] joining `ssid`, an empty `passphrase`
+    /// is an open network.
+    pub fn join(&mut self, ssid: String<32>, passphrase: String<64>) -> Result<(), ConfigError> {
+        if ssid.is_empty() {
+            return Err(ConfigError::EmptyStationSsid);
+        }
+        if !passphrase.is_empty() && validate_passphrase(AuthMethod::Wpa2, &passphrase).is_err() {
+            return Err(ConfigError::InvalidStationPassphrase);
+        }
+        self.mode = WifiMode::Station;
+        self.station_ssid = ssid;
+        self.station_passphrase = passphrase;
+        Ok(())
+    }
+
+    /// Encodes the value of a setting into `buffer`.
+    ///
+    /// Strings are stored as UTF-8, numbers and addresses as little-endian
+    /// bytes. [`Key::Version`] is the current [`VERSION`], [`Key::Provisioning`]
+    /// isn't a setting and is empty.
+    pub fn encode<'b>(&self, key: Key, buffer: &'b mut [u8; MAX_VALUE_LEN]) -> &'b [u8] {
+        let value: &[u8] = match key {
+            Key::Version => &VERSION.to_le_bytes(),
+            Key::Provisioning => &[],
+            Key::Ssid => self.ssid.as_bytes(),
+            Key::Auth => &[self.auth as u8],
+            Key::Passphrase => self.passphrase.as_bytes(),
+            Key::Address => &self.address.octets(),
+            Key::Mode => &[self.mode as u8],
+            Key::StationSsid => self.station_ssid.as_bytes(),
+            Key::StationPassphrase => self.station_passphrase.as_bytes(),
+            Key::Hostname => self.hostname.as_bytes(),
+            Key::DhcpFirstHost => &[self.dhcp_first_host],
+            Key::DhcpLastHost => &[self.dhcp_last_host],
+            Key::DhcpLease => &self.dhcp_lease_secs.to_le_bytes(),
+            Key::DhcpMaxLeases => &[self.dhcp_max_leases],
+            Key::MqttBroker => &self.mqtt_broker.octets(),
+            Key::MqttPort => &self.mqtt_port.to_le_bytes(),
+            Key::MqttUsername => self.mqtt_username.as_bytes(),
+            Key::MqttPassword => self.mqtt_password.as_bytes(),
+            Key::MqttTopic => self.mqtt_topic.as_bytes(),
+            Key::InfluxServer => &self.influx_server.octets(),
+            Key::InfluxPort => &self.influx_port.to_le_bytes(),
+            Key::InfluxTransport => &[self.influx_transport as u8],
+            Key::InfluxOrg => self.influx_org.as_bytes(),
+            Key::InfluxBucket => self.influx_bucket.as_bytes(),
+            Key::InfluxToken => self.influx_token.as_bytes(),
+            Key::MeasurementInterval => &self.measurement_interval_ms.to_le_bytes(),
+            Key::HumidityInterval => &self.humidity_interval_ms.to_le_bytes(),
+            Key::EventsInterval => &self.events_interval_ms.to_le_bytes(),
+            Key::Bme280 => &self.bme280.encode(),
+        };
+        buffer[..value.len()].copy_from_slice(value);
+        &buffer[..value.len()]
+    }
+
+    /// Sets a setting back to its default.
+    pub fn reset(&mut self, key: Key) {
+        let mut buffer = [0; MAX_VALUE_LEN];
+        // The default of a setting always decodes
+        let _ = self.decode(key, Self::default().encode(key, &mut buffer));
+    }
+
+    /// Sets a setting from its encoded `value`, see [`Config::encode`].
+    pub fn decode(&mut self, key: Key, value: &[u8]) -> Result<(), InvalidValue> {
+        let invalid = InvalidValue(key);
+        match key {
+            Key::Version | Key::Provisioning => return Err(invalid),
+            Key::Ssid => self.ssid = decode_string(value).ok_or(invalid)?,
+            Key::Auth => {
+                let &[auth] = value else {
+                    return Err(invalid);
+                };
+                self.auth = AuthMethod::from_u8(auth).ok_or(invalid)?;
+            }
+            Key::Passphrase => self.passphrase = decode_string(value).ok_or(invalid)?,
+            Key::Mode => {
+                let &[mode] = value else {
+                    return Err(invalid);
+                };
+                self.mode = WifiMode::from_u8(mode).ok_or(invalid)?;
+            }
+            Key::StationSsid => self.station_ssid = decode_string(value).ok_or(invalid)?,
+            Key::StationPassphrase => {
+                self.station_passphrase = decode_string(value).ok_or(invalid)?
+            }
+            Key::Hostname => self.hostname = decode_string(value).ok_or(invalid)?,
+            Key::DhcpFirstHost => self.dhcp_first_host = decode_u8(value).ok_or(invalid)?,
+            Key::DhcpLastHost => self.dhcp_last_host = decode_u8(value).ok_or(invalid)?,
+            Key::DhcpLease => self.dhcp_lease_secs = decode_u32(value).ok_or(invalid)?,
+            Key::DhcpMaxLeases => self.dhcp_max_leases = decode_u8(value).ok_or(invalid)?,
+            Key::MqttBroker => {
+                let octets: [u8; 4] = value.try_into().map_err(|_| invalid)?;
+                self.mqtt_broker = Ipv4Addr::from(octets);
+            }
+            Key::MqttPort => {
+                let bytes: [u8; 2] = value.try_into().map_err(|_| invalid)?;
+                self.mqtt_port = u16::from_le_bytes(bytes);
+            }
+            Key::MqttUsername => self.mqtt_username = decode_string(value).ok_or(invalid)?,
+            Key::MqttPassword => self.mqtt_password = decode_string(value).ok_or(invalid)?,
+            Key::MqttTopic => self.mqtt_topic = decode_string(value).ok_or(invalid)?,
+            Key::InfluxServer => {
+                let octets: [u8; 4] = value.try_into().map_err(|_| invalid)?;
+                self.influx_server = Ipv4Addr::from(octets);
+            }
+            Key::InfluxPort => {
+                let bytes: [u8; 2] = value.try_into().map_err(|_| invalid)?;
+                self.influx_port = u16::from_le_bytes(bytes);
+            }
+            Key::InfluxTransport => {
+                let &[transport] = value else {
+                    return Err(invalid);
+                };
+                self.influx_transport = InfluxTransport::from_u8(transport).ok_or(invalid)?;
+            }
+            Key::InfluxOrg => self.influx_org = decode_string(value).ok_or(invalid)?,
+            Key::InfluxBucket => self.influx_bucket = decode_string(value).ok_or(invalid)?,
+            Key::InfluxToken => self.influx_token = decode_string(value).ok_or(invalid)?,
+            Key::Address => {
+                let octets: [u8; 4] = value.try_into().map_err(|_| invalid)?;
+                self.address = Ipv4Addr::from(octets);
+            }
+            Key::MeasurementInterval => {
+                self.measurement_interval_ms = decode_u32(value).ok_or(invalid)?
+            }
+            Key::HumidityInterval => {
+                self.humidity_interval_ms = decode_u32(value).ok_or(invalid)?
+            }
+            Key::EventsInterval => self.events_interval_ms = decode_u32(value).ok_or(invalid)?,
+            Key::Bme280 => {
+                let value = value.try_into().map_err(|_| invalid)?;
+                self.bme280 = bme280::Config::decode(value).ok_or(invalid)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Loads the stored settings, the ones that were never stored or can't be
+    /// decoded keep their default.
+    ///
+    /// The settings that make the result invalid are set back to their
+    /// defaults one check at a time, so the others (like the network to join)
+    /// are kept. Falls back to the defaults if that isn't enough.
+    pub fn load<F: NorFlash>(store: &mut KvStore<F>) -> Result<Self, kv::Error<F::Error>> {
+        let mut buffer = [0; MAX_VALUE_LEN];
+        let version = match store.get(Key::Version as u16, &mut buffer)? {
+            Some(&[low, high]) => u16::from_le_bytes([low, high]),
+            // Nothing was saved yet
+            _ => VERSION,
+        };
+
+        let mut config = Self::default();
+        for key in Key::SETTINGS {
+            if let Some(value) = store.get(key as u16, &mut buffer)?
+                && let Err(InvalidValue(key)) = config.decode(key, value)
+            {
+                warn!("Stored {} is invalid, using the default", key);
+            }
+        }
+        let mut config = migrate(config, version);
+
+        // Every check resets different settings
+        for _ in 0..Key::SETTINGS.len() {
+            let Err(e) = config.validate() else {
+                return Ok(config);
+            };
+            warn!("Stored configuration is invalid ({}), using the default", e);
+            for &key in e.keys() {
+                config.reset(key);
+            }
+        }
+        warn!("Stored configuration is invalid, using the defaults");
+        Ok(Self::default())
+    }
+
+    /// Stores every setting along with the current [`VERSION`].
+    pub fn save<F: NorFlash>(&self, store: &mut KvStore<F>) -> Result<(), kv::Error<F::Error>> {
+        let mut buffer = [0; MAX_VALUE_LEN];
+        for key in Key::SETTINGS {
+            store.set(key as u16, self.encode(key, &mut buffer))?;
+        }
+        // Written last, a configuration interrupted by a power loss is
+        // migrated again
+        store.set(Key::Version as u16, self.encode(Key::Version, &mut buffer))
+    }
+}
+
+/// Whether `hostname` is a valid DNS label.
+fn is_hostname(hostname: &str) -> bool {
+    !hostname.is_empty()
+        && !hostname.starts_with('-')
+        && !hostname.ends_with('-')
+        && hostname
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+}
+
+/// Whether `topic` is a topic name without wildcards nor empty levels.
+fn is_topic(topic: &str) -> bool {
+    topic.split('/').all(|level| {
+        !level.is_empty()
+            && level
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+    })
+}
+
+fn decode_string<const N: usize>(value: &[u8]) -> Option<String<N>> {
+    String::try_from(core::str::from_utf8(value).ok()?).ok()
+}
+
+fn decode_u8(value: &[u8]) -> Option<u8> {
+    match *value {
+        [value] => Some(value),
+        _ => None,
+    }
+}
+
+fn decode_u32(value: &[u8]) -> Option<u32> {
+    value.try_into().ok().map(u32::from_le_bytes)
+}
+
+/// Upgrades settings stored by an older firmware, `version` is the one they
+/// were stored with.
+///
+/// Settings added since then already have their defaults. Settings stored by a
+/// newer firmware are kept as they are. No firmware stored an older version
+/// than the first one yet, so there's nothing to upgrade.
+pub fn migrate(config: Config, version: u16) -> Config {
+    if version > VERSION {
+        warn!(
+            "Configuration version {} is newer than {}, using it as is",
+            version, VERSION
+        );
+    }
+    config
+}
+
+/// Why the configuration couldn't be updated.
+#[derive(Debug, defmt::Format)]
+pub enum UpdateError {
+    Invalid(ConfigError),
+    /// There's no `config` partition.
+    NoStorage,
+    Storage(kv::Error<FlashStorageError>),
+}
+
+/// The configuration loaded at boot and the store it's persisted to.
+///
+/// Updates are only applied after a reboot.
+pub struct ConfigStore {
+    config: Config,
+    store: Option<FlashKvStore>,
+}
+
+impl ConfigStore {
+    /// Loads the configuration from `store`, the defaults are used without one.
+    pub fn load(mut store: Option<FlashKvStore>) -> Self {
+        let config = match store.as_mut().map(Config::load) {
+            Some(Ok(config)) => config,
+            Some(Err(e)) => {
+                warn!("Failed to load the configuration: {:?}", e);
+                Config::default()
+            }
+            None => Config::default(),
+        };
+        Self { config, store }
+    }
+
+    /// The configuration in use.
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// The stored configuration, applied at the next boot.
+    pub fn stored(&mut self) -> Config {
+        match self.store.as_mut().map(Config::load) {
+            Some(Ok(config)) => config,
+            _ => self.config.clone(),
+        }
+    }
+
+    /// Validates and stores `config`.
+    pub fn update(&mut self, config: &Config) -> Result<(), UpdateError> {
+        config.validate().map_err(UpdateError::Invalid)?;
+        let store = self.store.as_mut().ok_or(UpdateError::NoStorage)?;
+        config.save(store).map_err(UpdateError::Storage)
+    }
+
+    /// Makes the station provision its network at the next boot, it's used
+    /// when the configured one can't be joined.
+    pub fn request_provisioning(&mut self) -> Result<(), UpdateError> {
+        let store = self.store.as_mut().ok_or(UpdateError::NoStorage)?;
+        store
+            .set(Key::Provisioning as u16, &[1])
+            .map_err(UpdateError::Storage)
+    }
+
+    /// Whether provisioning was requested, the request is cleared so the
+    /// station tries its network again at the following boot.
+    pub fn take_provisioning_request(&mut self) -> bool {
+        let Some(store) = self.store.as_mut() else {
+            return false;
+        };
+        let mut buffer = [0; 1];
+        match store.get(Key::Provisioning as u16, &mut buffer) {
+            Ok(Some(&[1])) => {}
+            Ok(_) => return false,
+            Err(e) => {
+                warn!("Failed to read the provisioning request: {:?}", e);
+                return false;
+            }
+        }
+        if let Err(e) = store.set(Key::Provisioning as u16, &[0]) {
+            warn!("Failed to clear the provisioning request: {:?}", e);
+        }
+        true
+    }
+}
+
+pub type TheConfig = Mutex<NoopRawMutex, RefCell<ConfigStore>>;
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::sensors::bme280::Standby;
+    use crate::storage::tests::MemFlash;
+
+    type Store = KvStore<MemFlash<4096, 2>>;
+
+    fn store() -> Store {
+        KvStore::open(MemFlash::new()).unwrap()
+    }
+
+    /// A valid configuration with no default setting.
+    fn custom() -> Config {
+        Config {
+            ssid: String::try_from("Garden").unwrap(),
+            auth: AuthMethod::Wpa3,
+            passphrase: String::try_from("correct horse").unwrap(),
+            address: Ipv4Addr::new(10, 0, 0, 1),
+            mode: WifiMode::Station,
+            station_ssid: String::try_from("Home").unwrap(),
+            station_passphrase: String::try_from("battery staple").unwrap(),
+            hostname: String::try_from("garden").unwrap(),
+            dhcp_first_host: 10,
+            dhcp_last_host: 20,
+            dhcp_lease_secs: 600,
+            dhcp_max_leases: 4,
+            mqtt_broker: Ipv4Addr::new(10, 0, 0, 2),
+            mqtt_port: 8883,
+            mqtt_username: String::try_from("station").unwrap(),
+            mqtt_password: String::try_from("secret").unwrap(),
+            mqtt_topic: String::try_from("home/garden").unwrap(),
+            influx_server: Ipv4Addr::new(10, 0, 0, 3),
+            influx_port: 8089,
+            influx_transport: InfluxTransport::Udp,
+            influx_org: String::try_from("home").unwrap(),
+            influx_bucket: String::try_from("garden").unwrap(),
+            influx_token: String::try_from("token").unwrap(),
+            measurement_interval_ms: 5000,
+            humidity_interval_ms: 10_000,
+            events_interval_ms: 2000,
+            bme280: bme280::Config {
+                filter: Filter::X4,
+                mode: Mode::Normal(Standby::Ms250),
+                ..bme280::Config::WEATHER_MONITORING
+            },
+        }
+    }
+
+    #[test]
+    fn encodes_every_setting() {
+        let custom = custom();
+        let mut config = Config::default();
+        let mut buffer = [0; MAX_VALUE_LEN];
+        for key in Key::SETTINGS {
+            config.decode(key, custom.encode(key, &mut buffer)).unwrap();
+        }
+
+        assert_eq!(config, custom);
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        let mut config = Config::default();
+
+        assert!(config.decode(Key::Auth, &[4]).is_err());
+        assert!(config.decode(Key::MqttPort, &[1]).is_err());
+        assert!(config.decode(Key::Ssid, &[0xFF]).is_err());
+        assert!(config.decode(Key::Ssid, &[b'a'; 33]).is_err());
+        assert!(config.decode(Key::Bme280, &[1, 1, 1, 0]).is_err());
+        assert!(config.decode(Key::Bme280, &[1, 1, 1, 5, 1]).is_err());
+        assert!(config.decode(Key::Bme280, &[1, 1, 1, 0, 12]).is_err());
+        assert!(config.decode(Key::Version, &VERSION.to_le_bytes()).is_err());
+        assert_eq!(config, Config::default());
+    }
+
+    #[test]
+    fn saves_and_loads() {
+        let mut store = store();
+        assert_eq!(Config::load(&mut store).unwrap(), Config::default());
+
+        custom().save(&mut store).unwrap();
+        assert_eq!(Config::load(&mut store).unwrap(), custom());
+        let mut buffer = [0; 2];
+        assert_eq!(
+            store.get(Key::Version as u16, &mut buffer).unwrap(),
+            Some(&VERSION.to_le_bytes()[..])
+        );
+    }
+
+    #[test]
+    fn loads_settings_saved_without_their_version() {
+        // A power loss before the version of the first save was written
+        let mut store = store();
+        let mut buffer = [0; MAX_VALUE_LEN];
+        for key in Key::SETTINGS {
+            store
+                .set(key as u16, custom().encode(key, &mut buffer))
+                .unwrap();
+        }
+
+        assert_eq!(Config::load(&mut store).unwrap(), custom());
+    }
+
+    #[test]
+    fn keeps_the_settings_of_a_newer_version() {
+        let mut store = store();
+        custom().save(&mut store).unwrap();
+        store
+            .set(Key::Version as u16, &(VERSION + 1).to_le_bytes())
+            .unwrap();
+
+        assert_eq!(Config::load(&mut store).unwrap(), custom());
+    }
+
+    #[test]
+    fn resets_a_setting_that_does_not_decode() {
+        let mut store = store();
+        custom().save(&mut store).unwrap();
+        store.set(Key::MqttPort as u16, &[1]).unwrap();
+
+        assert_eq!(
+            Config::load(&mut store).unwrap(),
+            Config {
+                mqtt_port: Config::default().mqtt_port,
+                ..custom()
+            }
+        );
+    }
+
+    #[test]
+    fn serves_the_settings_without_the_secrets() {
+        let json: String<2048> = serde_json_core::to_string(&ConfigView::new(&custom())).unwrap();
+
+        assert_eq!(
+            json,
+            concat!(
+                r#"{"version":1,"ssid":"Garden","auth":"wpa3","passphrase_set":true,"#,
+                r#""address":"10.0.0.1","mode":"station","station_ssid":"Home","#,
+                r#""station_passphrase_set":true,"hostname":"garden","dhcp_first_host":10,"#,
+                r#""dhcp_last_host":20,"dhcp_lease_secs":600,"dhcp_max_leases":4,"#,
+                r#""mqtt_broker":"10.0.0.2","mqtt_port":8883,"mqtt_username":"station","#,
+                r#""mqtt_password_set":true,"mqtt_topic":"home/garden","#,
+                r#""influx_server":"10.0.0.3","influx_port":8089,"influx_transport":"udp","#,
+                r#""influx_org":"home","influx_bucket":"garden","influx_token_set":true,"#,
+                r#""measurement_interval_ms":5000,"humidity_interval_ms":10000,"#,
+                r#""events_interval_ms":2000,"bme280_temperature":"x1","#,
+                r#""bme280_pressure":"x1","bme280_humidity":"x1","bme280_filter":"x4","#,
+                r#""bme280_mode":"normal-250ms"}"#
+            )
+        );
+    }
+
+    #[test]
+    fn escapes_the_served_names() {
+        let config = Config {
+            ssid: String::try_from(r#"My "AP"\"#).unwrap(),
+            passphrase: String::new(),
+            ..Config::default()
+        };
+        let json: String<2048> = serde_json_core::to_string(&ConfigView::new(&config)).unwrap();
+
+        assert!(json.contains(r#""ssid":"My \"AP\"\\","auth"#));
+    }
+
+    #[test]
+    fn the_largest_settings_fit_in_the_response() {
+        let control = |len| String::try_from("\u{1}".repeat(len).as_str()).unwrap();
+        let config = Config {
+            ssid: control(32),
+            station_ssid: control(32),
+            hostname: String::try_from("a".repeat(32).as_str()).unwrap(),
+            mqtt_username: control(32),
+            mqtt_topic: String::try_from("a".repeat(64).as_str()).unwrap(),
+            influx_org: control(32),
+            influx_bucket: control(32),
+            mqtt_broker: Ipv4Addr::new(255, 255, 255, 255),
+            influx_server: Ipv4Addr::new(255, 255, 255, 255),
+            dhcp_lease_secs: u32::MAX,
+            measurement_interval_ms: u32::MAX,
+            humidity_interval_ms: u32::MAX,
+            events_interval_ms: u32::MAX,
+            ..custom()
+        };
+
+        assert!(serde_json_core::to_string::<_, 2048>(&ConfigView::new(&config)).is_ok());
+    }
+
+    #[test]
+    fn rejects_an_mqtt_password_without_username() {
+        let mut config = Config {
+            mqtt_username: String::new(),
+            ..custom()
+        };
+        assert_eq!(
+            config.validate(),
+            Err(ConfigError::MqttPasswordWithoutUsername)
+        );
+
+        config.mqtt_password.clear();
+        assert_eq!(config.validate(), Ok(()));
+    }
+
+    #[test]
+    fn resets_the_settings_that_are_invalid() {
+        let mut store = store();
+        custom().save(&mut store).unwrap();
+        store.set(Key::Hostname as u16, b"-garden").unwrap();
+        store.set(Key::Passphrase as u16, b"short").unwrap();
+        store.set(Key::DhcpLastHost as u16, &[5]).unwrap();
+
+        let default = Config::default();
+        assert_eq!(
+            Config::load(&mut store).unwrap(),
+            Config {
+                hostname: default.hostname.clone(),
+                auth: default.auth,
+                passphrase: default.passphrase.clone(),
+                address: default.address,
+                dhcp_first_host: default.dhcp_first_host,
+                dhcp_last_host: default.dhcp_last_host,
+                ..custom()
+            }
+        );
+    }
+}
@@ -0,0 +1,345 @@
+// Downsampled measurements kept in RAM, so clients that were offline can backfill
+use core::cell::RefCell;
+
+use defmt::warn;
+use embassy_futures::select::{Either, select};
+use embassy_sync::blocking_mutex::{Mutex, raw::NoopRawMutex};
+use embassy_time::{Duration, Instant, Timer};
+use embedded_storage::nor_flash::NorFlash;
+use heapless::Deque;
+
+use crate::storage::history_log::{self, FlashHistoryLog, HistoryLog};
+use crate::{DataReceiver, Field, NormalizedMeasurments, Quality};
+
+/// 24 h at 5-minute resolution.
+pub const HISTORY_LEN: usize = 288;
+
+/// The average of the fresh values measured during one history interval.
+#[derive(Copy, Clone, Debug, PartialEq, defmt::Format)]
+pub struct Record {
+    /// Station time at the end of the interval: milliseconds since boot,
+    /// continued from the last persisted record so it keeps increasing across
+    /// reboots.
+    pub timestamp_ms: u64,
+    pub temperature: Option<f32>,
+    pub humidity: Option<f32>,
+    pub pressure: Option<f32>,
+}
+
+/// Ring buffer of the latest [`HISTORY_LEN`] records, the oldest ones are dropped first.
+#[derive(Default)]
+pub struct History {
+    records: Deque<Record, HISTORY_LEN>,
+}
+
+impl History {
+    pub const fn new() -> Self {
+        Self {
+            records: Deque::new(),
+        }
+    }
+
+    pub fn push(&mut self, record: Record) {
+        if self.records.is_full() {
+            self.records.pop_front();
+        }
+        // There's room after dropping the oldest record
+        let _ = self.records.push_back(record);
+    }
+
+    /// The oldest record newer than `since_ms`.
+    pub fn first_after(&self, since_ms: u64) -> Option<Record> {
+        self.records
+            .iter()
+            .find(|record| record.timestamp_ms > since_ms)
+            .copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+}
+
+pub type TheHistory = Mutex<NoopRawMutex, RefCell<History>>;
+
+/// Walks the records newer than a timestamp oldest first, up to a limit.
+///
+/// Only the timestamp of the latest record is kept, so the history can change
+/// between two steps.
+pub struct Cursor {
+    since_ms: u64,
+    remaining: u32,
+}
+
+impl Cursor {
+    pub fn new(since_ms: u64, limit: u32) -> Self {
+        Self {
+            since_ms,
+            remaining: limit,
+        }
+    }
+
+    /// The record after the previous one, `None` once there's none or the
+    /// limit is reached.
+    pub fn next(&mut self, history: &History) -> Option<Record> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let record = history.first_after(self.since_ms)?;
+        self.since_ms = record.timestamp_ms;
+        self.remaining -= 1;
+        Some(record)
+    }
+}
+
+/// Loads the persisted records into `history`.
+///
+/// Returns the station time of the newest record, the offset to add to the
+/// uptime from now on.
+pub fn restore<F: NorFlash>(
+    history: &mut History,
+    log: &mut HistoryLog<F>,
+) -> Result<u64, history_log::Error<F::Error>> {
+    let mut newest_ms = 0;
+    for record in log.records(..) {
+        let record = record?;
+        newest_ms = record.timestamp_ms;
+        history.push(record);
+    }
+    Ok(newest_ms)
+}
+
+#[derive(Copy, Clone, Default)]
+struct Mean {
+    sum: f32,
+    count: u32,
+}
+
+impl Mean {
+    fn add(&mut self, field: Option<Field>) {
+        if let Some(field) = field.filter(|field| field.quality == Quality::Fresh) {
+            self.sum += field.value;
+            self.count += 1;
+        }
+    }
+
+    fn get(self) -> Option<f32> {
+        (self.count > 0).then(|| self.sum / self.count as f32)
+    }
+}
+
+/// Averages measurements until a record is taken.
+#[derive(Copy, Clone, Default)]
+pub struct Accumulator {
+    temperature: Mean,
+    humidity: Mean,
+    pressure: Mean,
+}
+
+impl Accumulator {
+    /// Adds the fresh fields of `measurements`, held and stale ones were already counted.
+    pub fn add(&mut self, measurements: &NormalizedMeasurments) {
+        self.temperature.add(measurements.temperature);
+        self.humidity.add(measurements.humidity);
+        self.pressure.add(measurements.pressure);
+    }
+
+    /// Returns the averages and starts over, `None` if nothing was measured.
+    pub fn take(&mut self, timestamp_ms: u64) -> Option<Record> {
+        let accumulator = core::mem::take(self);
+        let record = Record {
+            timestamp_ms,
+            temperature: accumulator.temperature.get(),
+            humidity: accumulator.humidity.get(),
+            pressure: accumulator.pressure.get(),
+        };
+
+        (record.temperature.is_some() || record.humidity.is_some() || record.pressure.is_some())
+            .then_some(record)
+    }
+}
+
+/// Records the average of the measurements every `interval`, and persists it
+/// when there's a `log`.
+///
+/// `clock_offset_ms` is added to the uptime, see [`restore`].
+#[embassy_executor::task]
+pub async fn history_task(
+    mut receiver: DataReceiver,
+    history: &'static TheHistory,
+    mut log: Option<FlashHistoryLog>,
+    clock_offset_ms: u64,
+    interval: Duration,
+) {
+    let mut accumulator = Accumulator::default();
+    let mut deadline = Instant::now() + interval;
+    loop {
+        match select(receiver.changed(), Timer::at(deadline)).await {
+            Either::First(measurements) => accumulator.add(&measurements),
+            Either::Second(()) => {
+                if let Some(record) = accumulator.take(clock_offset_ms + deadline.as_millis()) {
+                    history.lock(|history| history.borrow_mut().push(record));
+                    if let Some(Err(e)) = log.as_mut().map(|log| log.append(&record)) {
+                        warn!("Failed to persist the history: {:?}", e);
+                    }
+                }
+                deadline += interval;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(timestamp_ms: u64) -> Record {
+        Record {
+            timestamp_ms,
+            temperature: Some(20.0),
+            humidity: None,
+            pressure: Some(101.3),
+        }
+    }
+
+    /// Records every 100 ms from 100 to `100 * count`.
+    fn history(count: u64) -> History {
+        let mut history = History::new();
+        for i in 1..=count {
+            history.push(record(100 * i));
+        }
+        history
+    }
+
+    fn walk(history: &History, mut cursor: Cursor) -> [Option<u64>; 4] {
+        core::array::from_fn(|_| cursor.next(history).map(|record| record.timestamp_ms))
+    }
+
+    #[test]
+    fn drops_the_oldest_records_when_full() {
+        let history = history(HISTORY_LEN as u64 + 2);
+
+        assert_eq!(history.len(), HISTORY_LEN);
+        assert_eq!(history.first_after(0), Some(record(300)));
+        assert_eq!(
+            history.first_after(100 * HISTORY_LEN as u64 + 100),
+            Some(record(100 * HISTORY_LEN as u64 + 200))
+        );
+    }
+
+    #[test]
+    fn selects_the_records_after_since() {
+        let history = history(5);
+
+        assert_eq!(
+            walk(&history, Cursor::new(0, u32::MAX)),
+            [Some(100), Some(200), Some(300), Some(400)]
+        );
+        // Strictly newer, between two records
+        assert_eq!(
+            walk(&history, Cursor::new(300, u32::MAX)),
+            [Some(400), Some(500), None, None]
+        );
+        assert_eq!(
+            walk(&history, Cursor::new(250, u32::MAX)),
+            [Some(300), Some(400), Some(500), None]
+        );
+        assert_eq!(walk(&history, Cursor::new(500, u32::MAX)), [None; 4]);
+        assert_eq!(walk(&History::new(), Cursor::new(0, u32::MAX)), [None; 4]);
+    }
+
+    #[test]
+    fn stops_at_the_limit() {
+        let history = history(5);
+
+        assert_eq!(
+            walk(&history, Cursor::new(100, 2)),
+            [Some(200), Some(300), None, None]
+        );
+        assert_eq!(walk(&history, Cursor::new(0, 0)), [None; 4]);
+    }
+
+    #[test]
+    fn follows_records_pushed_while_walking() {
+        let mut history = history(2);
+        let mut cursor = Cursor::new(0, u32::MAX);
+
+        assert_eq!(cursor.next(&history), Some(record(100)));
+        assert_eq!(cursor.next(&history), Some(record(200)));
+        assert_eq!(cursor.next(&history), None);
+        history.push(record(300));
+        assert_eq!(cursor.next(&history), Some(record(300)));
+    }
+
+    fn field(value: f32, quality: Quality) -> Option<Field> {
+        Some(Field {
+            value,
+            sensor: "BME280",
+            measured_at_ms: 0,
+            quality,
+        })
+    }
+
+    #[test]
+    fn averages_the_fresh_values() {
+        let mut accumulator = Accumulator::default();
+        for (temperature, humidity) in [
+            (field(20.0, Quality::Fresh), field(40.0, Quality::Fresh)),
+            (field(21.0, Quality::Fresh), field(40.0, Quality::Held)),
+            (field(22.5, Quality::Fresh), field(40.0, Quality::Stale)),
+            (None, field(50.0, Quality::Fresh)),
+        ] {
+            accumulator.add(&NormalizedMeasurments {
+                timestamp_ms: 0,
+                pressure: None,
+                humidity,
+                temperature,
+            });
+        }
+
+        assert_eq!(
+            accumulator.take(1000),
+            Some(Record {
+                timestamp_ms: 1000,
+                temperature: Some(21.166666),
+                humidity: Some(45.0),
+                pressure: None,
+            })
+        );
+    }
+
+    #[test]
+    fn records_nothing_without_fresh_values() {
+        let mut accumulator = Accumulator::default();
+        assert_eq!(accumulator.take(1000), None);
+
+        accumulator.add(&NormalizedMeasurments {
+            timestamp_ms: 0,
+            pressure: field(101.3, Quality::Held),
+            humidity: field(40.0, Quality::Stale),
+            temperature: None,
+        });
+        assert_eq!(accumulator.take(2000), None);
+    }
+
+    #[test]
+    fn starts_over_after_a_record() {
+        let mut accumulator = Accumulator::default();
+        let measurements = |temperature| NormalizedMeasurments {
+            timestamp_ms: 0,
+            pressure: None,
+            humidity: None,
+            temperature: field(temperature, Quality::Fresh),
+        };
+
+        accumulator.add(&measurements(10.0));
+        assert_eq!(accumulator.take(1000).unwrap().temperature, Some(10.0));
+        assert_eq!(accumulator.take(2000), None);
+        accumulator.add(&measurements(30.0));
+        assert_eq!(accumulator.take(3000).unwrap().temperature, Some(30.0));
+    }
+}
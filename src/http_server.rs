@@ -1 +1,3 @@
+pub mod metrics;
 pub mod server;
+pub mod websocket;
@@ -0,0 +1,126 @@
+// Prometheus text exposition of the station state
+use core::fmt::{self, Write};
+
+use crate::network::station::LinkState;
+use crate::sensors::weather_sensor::SensorErrors;
+use crate::{Field, NormalizedMeasurments};
+
+/// Content type of the text exposition format.
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// What `/metrics` reports.
+pub struct Metrics<'a> {
+    pub measurements: NormalizedMeasurments,
+    pub errors: &'a SensorErrors,
+    pub uptime_s: u64,
+    pub free_heap_bytes: usize,
+    /// Clients holding a DHCP lease of the access point.
+    pub wifi_clients: usize,
+    /// State of the station link, `None` in access point mode.
+    pub link_state: Option<LinkState>,
+}
+
+/// Writes the HELP and TYPE lines of a metric.
+fn header(out: &mut impl Write, name: &str, kind: &str, help: &str) -> fmt::Result {
+    write!(out, "# HELP {} {}\n# TYPE {} {}\n", name, help, name, kind)
+}
+
+/// A measurement labelled with its sensor, nothing when it's unknown.
+fn gauge(
+    out: &mut impl Write,
+    name: &str,
+    help: &str,
+    field: Option<Field>,
+    scale: f32,
+) -> fmt::Result {
+    header(out, name, "gauge", help)?;
+    match field.filter(|field| field.value.is_finite()) {
+        Some(field) => writeln!(
+            out,
+            "{}{{sensor=\"{}\"}} {}",
+            name,
+            field.sensor,
+            field.value * scale
+        ),
+        None => Ok(()),
+    }
+}
+
+/// Writes `metrics` in the text exposition format.
+pub fn write_metrics(out: &mut impl Write, metrics: &Metrics) -> fmt::Result {
+    let measurements = &metrics.measurements;
+    gauge(
+        out,
+        "weather_temperature_celsius",
+        "Latest temperature.",
+        measurements.temperature,
+        1.0,
+    )?;
+    gauge(
+        out,
+        "weather_humidity_percent",
+        "Latest relative humidity.",
+        measurements.humidity,
+        1.0,
+    )?;
+    // Measured in kilopascals
+    gauge(
+        out,
+        "weather_pressure_pascals",
+        "Latest atmospheric pressure.",
+        measurements.pressure,
+        1000.0,
+    )?;
+
+    header(
+        out,
+        "weather_sensor_errors_total",
+        "counter",
+        "Failed sensor reads by kind.",
+    )?;
+    for (sensor, kind, count) in metrics.errors.iter() {
+        writeln!(
+            out,
+            "weather_sensor_errors_total{{sensor=\"{}\",kind=\"{}\"}} {}",
+            sensor,
+            kind.as_str(),
+            count
+        )?;
+    }
+
+    header(
+        out,
+        "weather_uptime_seconds",
+        "gauge",
+        "Seconds since the station booted.",
+    )?;
+    writeln!(out, "weather_uptime_seconds {}", metrics.uptime_s)?;
+    header(
+        out,
+        "weather_heap_free_bytes",
+        "gauge",
+        "Free bytes of the heap.",
+    )?;
+    writeln!(out, "weather_heap_free_bytes {}", metrics.free_heap_bytes)?;
+    header(
+        out,
+        "weather_wifi_clients",
+        "gauge",
+        "Clients leasing an address of the access point.",
+    )?;
+    writeln!(out, "weather_wifi_clients {}", metrics.wifi_clients)?;
+    if let Some(state) = metrics.link_state {
+        header(
+            out,
+            "weather_wifi_link_up",
+            "gauge",
+            "Whether the station is connected and has an address.",
+        )?;
+        writeln!(
+            out,
+            "weather_wifi_link_up {}",
+            u8::from(state == LinkState::Up)
+        )?;
+    }
+    Ok(())
+}
@@ -1,32 +1,518 @@
-use core::fmt::Write;
+use core::cell::RefCell;
+use core::fmt::{self, Display, Write};
+use core::net::Ipv4Addr;
 
 
-use defmt::println;
-use embassy_time::Duration;
+use defmt::warn;
+use embassy_futures::select::{Either, select};
+use embassy_sync::blocking_mutex::{Mutex, raw::NoopRawMutex};
+use embassy_time::{Duration, Instant, Timer};
 use heapless::String;
 use picoserve::AppRouter;
 use picoserve::AppWithStateBuilder;
-use picoserve::extract::State;
+use picoserve::extract::{Form, Query, State};
+use picoserve::response::chunked::{ChunkWriter, Chunks, ChunkedResponse, ChunksWritten};
+use picoserve::response::sse::{EventSource, EventStream, EventWriter};
+use picoserve::response::ws::{
+    CallbackNotUsingState, UnspecifiedProtocol, UpgradedWebSocket, WebSocketUpgrade,
+};
+use picoserve::response::{Content, Redirect, StatusCode};
 
 use picoserve::routing::get;
+use serde::Deserialize;
 
-use crate::ServerReceiver;
+use super::metrics::{self, Metrics};
+use super::websocket::Session;
+use crate::config::{
+    AuthMethod, Config, ConfigView, InfluxTransport, TheConfig, UpdateError, WifiMode,
+};
+use crate::history::{Cursor, TheHistory};
+use crate::network::dhcp::TheLeases;
+use crate::network::provisioning::{TheNetworks, TheReboot};
+use crate::network::station::TheLinkState;
+use crate::sampling::SamplingControl;
+use crate::sensors::bme280::{self, Filter, Mode, Oversampling, Preset};
+use crate::sensors::weather_sensor::TheSensorErrors;
+use crate::{DataReceiver, NormalizedMeasurments, TheWatch};
 
+/// What the handlers share, each field is extracted with [`State`].
 pub struct AppState {
-    receiver: ServerReceiver,
+    pub measurements: &'static TheWatch,
+    pub history: &'static TheHistory,
+    pub config: &'static TheConfig,
+    /// Networks found while provisioning.
+    pub networks: &'static TheNetworks,
+    pub reboot: &'static TheReboot,
+    /// Path the connectivity checks are redirected to.
+    pub portal: &'static str,
+    /// The station booted to be provisioned, `/setup` only stores credentials then.
+    pub provisioning: bool,
+    pub leases: &'static TheLeases,
+    pub sensor_errors: &'static TheSensorErrors,
+    pub event_streams: &'static TheEventStreams,
+    pub sampling: &'static SamplingControl,
+    /// Link of the station, nothing is sent in access point mode.
+    pub link_state: &'static TheLinkState,
 }
 
-impl AppState {
-    pub fn new(receiver: ServerReceiver) -> Self {
-        Self { receiver }
+pub struct AppProps;
+
+/// Port the web server listens on.
+pub const PORT: u16 = 80;
+
+/// How many connections the web server handles at the same time, each one
+/// with a [`web_task`] and its own buffers. Each task takes about 28 KiB of
+/// static RAM, which leaves less for the stack.
+pub const WEB_TASKS: usize = 2;
+
+/// Most `/events` streams and `/ws` sessions open at the same time however
+/// many web tasks there are, each one takes a measurement receiver and the
+/// buffers of its task.
+const STREAM_LIMIT: usize = 4;
+
+/// How many `/events` streams and `/ws` sessions can be open at the same
+/// time. Each one holds a web task for as long as it's open so one is always
+/// left for the other requests, with a single web task they're refused.
+pub const MAX_EVENT_STREAMS: usize = if WEB_TASKS > STREAM_LIMIT {
+    STREAM_LIMIT
+} else {
+    WEB_TASKS - 1
+};
+
+const _: () = assert!(
+    MAX_EVENT_STREAMS < WEB_TASKS,
+    "a stream would starve the other requests"
+);
+
+/// A comment is sent on an `/events` stream when nothing was measured for
+/// this long, so proxies and browsers keep it open.
+pub(super) const EVENTS_KEEPALIVE: Duration = Duration::from_secs(15);
+
+/// The open `/events` streams and `/ws` sessions.
+pub struct EventStreams {
+    /// Shortest time between two events of a stream.
+    min_interval: Duration,
+    open: usize,
+}
+
+impl EventStreams {
+    pub const fn new(min_interval: Duration) -> Self {
+        Self {
+            min_interval,
+            open: 0,
+        }
     }
 }
 
-pub struct AppProps;
+pub type TheEventStreams = Mutex<NoopRawMutex, RefCell<EventStreams>>;
 
-impl picoserve::extract::FromRef<AppState> for ServerReceiver {
+/// Counts a stream as open until it's dropped.
+pub(super) struct StreamSlot(&'static TheEventStreams);
+
+impl StreamSlot {
+    /// `None` if [`MAX_EVENT_STREAMS`] are already open, always with a single
+    /// web task.
+    fn take(streams: &'static TheEventStreams) -> Option<Self> {
+        let opened = streams.lock(|streams| {
+            let mut streams = streams.borrow_mut();
+            // Never more than the limit, which may be 0
+            let opened = streams.open != MAX_EVENT_STREAMS;
+            if opened {
+                streams.open += 1;
+            }
+            opened
+        });
+        opened.then_some(Self(streams))
+    }
+}
+
+impl Drop for StreamSlot {
+    fn drop(&mut self) {
+        self.0.lock(|streams| streams.borrow_mut().open -= 1);
+    }
+}
+
+/// Path of the page the connectivity checks of the access point clients are
+/// redirected to.
+#[derive(Copy, Clone)]
+struct Portal(&'static str);
+
+/// Whether the station booted to be provisioned.
+#[derive(Copy, Clone)]
+struct Provisioning(bool);
+
+/// A body serialized with serde-json-core, sent as `application/json`.
+struct Json<const N: usize>(String<N>);
+
+impl<const N: usize> Content for Json<N> {
+    fn content_type(&self) -> &'static str {
+        "application/json"
+    }
+
+    fn content_length(&self) -> usize {
+        self.0.len()
+    }
+
+    async fn write_content<W: picoserve::io::Write>(self, writer: W) -> Result<(), W::Error> {
+        self.0.as_str().write_content(writer).await
+    }
+}
+
+/// A number as JSON, `null` when it's not available.
+struct JsonNumber(Option<f32>);
+
+impl Display for JsonNumber {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(value) if value.is_finite() => write!(f, "{}", value),
+            _ => f.write_str("null"),
+        }
+    }
+}
+
+/// A string as JSON, with quotes, backslashes and control characters escaped.
+pub(super) struct JsonStr<'a>(pub(super) &'a str);
+
+impl Display for JsonStr<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_char('"')?;
+        for c in self.0.chars() {
+            match c {
+                '"' => f.write_str("\\\"")?,
+                '\\' => f.write_str("\\\\")?,
+                c if c.is_control() => write!(f, "\\u{:04x}", c as u32)?,
+                c => f.write_char(c)?,
+            }
+        }
+        f.write_char('"')
+    }
+}
+
+/// A string as HTML text or attribute value, with markup characters escaped.
+struct HtmlStr<'a>(&'a str);
+
+impl Display for HtmlStr<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for c in self.0.chars() {
+            match c {
+                '&' => f.write_str("&amp;")?,
+                '<' => f.write_str("&lt;")?,
+                '>' => f.write_str("&gt;")?,
+                '"' => f.write_str("&quot;")?,
+                '\'' => f.write_str("&#39;")?,
+                c => f.write_char(c)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A number as a CSV cell, empty when it's not available.
+struct CsvNumber(Option<f32>);
+
+impl Display for CsvNumber {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(value) if value.is_finite() => write!(f, "{}", value),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for &'static TheWatch {
+    fn from_ref(state: &AppState) -> Self {
+        state.measurements
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for &'static TheHistory {
+    fn from_ref(state: &AppState) -> Self {
+        state.history
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for &'static TheConfig {
+    fn from_ref(state: &AppState) -> Self {
+        state.config
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for &'static TheNetworks {
+    fn from_ref(state: &AppState) -> Self {
+        state.networks
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for &'static TheReboot {
+    fn from_ref(state: &AppState) -> Self {
+        state.reboot
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for &'static TheLeases {
     fn from_ref(state: &AppState) -> Self {
-        state.receiver
+        state.leases
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for &'static TheSensorErrors {
+    fn from_ref(state: &AppState) -> Self {
+        state.sensor_errors
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for &'static TheEventStreams {
+    fn from_ref(state: &AppState) -> Self {
+        state.event_streams
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for &'static SamplingControl {
+    fn from_ref(state: &AppState) -> Self {
+        state.sampling
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for &'static TheLinkState {
+    fn from_ref(state: &AppState) -> Self {
+        state.link_state
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for Portal {
+    fn from_ref(state: &AppState) -> Self {
+        Portal(state.portal)
+    }
+}
+
+impl picoserve::extract::FromRef<AppState> for Provisioning {
+    fn from_ref(state: &AppState) -> Self {
+        Provisioning(state.provisioning)
+    }
+}
+
+#[derive(Copy, Clone, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum HistoryFormat {
+    #[default]
+    Json,
+    Csv,
+}
+
+/// Query parameters of `/history`.
+#[derive(Deserialize)]
+struct HistoryQuery {
+    /// Only records newer than this (in station time, see [`crate::history::Record::timestamp_ms`]).
+    since: Option<u64>,
+    /// Maximum number of records.
+    limit: Option<u32>,
+    format: Option<HistoryFormat>,
+}
+
+/// Streams the history one record per chunk, oldest first.
+///
+/// The history is only locked while a record is copied, so the recorder is
+/// never blocked by a slow client.
+struct HistoryChunks {
+    history: &'static TheHistory,
+    since_ms: u64,
+    limit: u32,
+    format: HistoryFormat,
+}
+
+impl Chunks for HistoryChunks {
+    fn content_type(&self) -> &'static str {
+        match self.format {
+            HistoryFormat::Json => "application/json",
+            HistoryFormat::Csv => "text/csv",
+        }
+    }
+
+    async fn write_chunks<W: picoserve::io::Write>(
+        self,
+        mut writer: ChunkWriter<W>,
+    ) -> Result<ChunksWritten, W::Error> {
+        match self.format {
+            HistoryFormat::Json => writer.write_chunk(b"[").await?,
+            HistoryFormat::Csv => {
+                writer
+                    .write_chunk(b"timestamp_ms,temperature,humidity,pressure\r\n")
+                    .await?
+            }
+        }
+
+        let mut cursor = Cursor::new(self.since_ms, self.limit);
+        let mut separator = "";
+        while let Some(record) = self.history.lock(|history| cursor.next(&history.borrow())) {
+            match self.format {
+                HistoryFormat::Json => {
+                    write!(
+                        writer,
+                        r#"{}{{"timestamp_ms":{},"temperature":{},"humidity":{},"pressure":{}}}"#,
+                        core::mem::replace(&mut separator, ","),
+                        record.timestamp_ms,
+                        JsonNumber(record.temperature),
+                        JsonNumber(record.humidity),
+                        JsonNumber(record.pressure),
+                    )
+                    .await?
+                }
+                HistoryFormat::Csv => {
+                    write!(
+                        writer,
+                        "{},{},{},{}\r\n",
+                        record.timestamp_ms,
+                        CsvNumber(record.temperature),
+                        CsvNumber(record.humidity),
+                        CsvNumber(record.pressure),
+                    )
+                    .await?
+                }
+            }
+        }
+
+        if let HistoryFormat::Json = self.format {
+            writer.write_chunk(b"]").await?;
+        }
+
+        writer.finalize().await
+    }
+}
+
+/// Streams the active DHCP leases one per chunk.
+struct LeaseChunks {
+    leases: &'static TheLeases,
+}
+
+impl Chunks for LeaseChunks {
+    fn content_type(&self) -> &'static str {
+        "application/json"
+    }
+
+    async fn write_chunks<W: picoserve::io::Write>(
+        self,
+        mut writer: ChunkWriter<W>,
+    ) -> Result<ChunksWritten, W::Error> {
+        writer.write_chunk(b"[").await?;
+        let mut i = 0;
+        loop {
+            let now_s = Instant::now().as_secs();
+            let Some(lease) = self
+                .leases
+                .lock(|leases| leases.borrow().active(now_s).nth(i).copied())
+            else {
+                break;
+            };
+            let [m0, m1, m2, m3, m4, m5] = lease.mac;
+            write!(
+                writer,
+                r#"{}{{"mac":"{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}","ip":"{}","expires_in_s":{}}}"#,
+                if i == 0 { "" } else { "," },
+                m0,
+                m1,
+                m2,
+                m3,
+                m4,
+                m5,
+                lease.ip,
+                lease.expires_s - now_s,
+            )
+            .await?;
+            i += 1;
+        }
+        writer.write_chunk(b"]").await?;
+        writer.finalize().await
+    }
+}
+
+/// The metrics of [`metrics::write_metrics`] in the text exposition format.
+struct MetricsText(String<3072>);
+
+impl Content for MetricsText {
+    fn content_type(&self) -> &'static str {
+        metrics::CONTENT_TYPE
+    }
+
+    fn content_length(&self) -> usize {
+        self.0.len()
+    }
+
+    async fn write_content<W: picoserve::io::Write>(self, writer: W) -> Result<(), W::Error> {
+        self.0.as_str().write_content(writer).await
+    }
+}
+
+/// Sends an `event: measurement` with the measurements as JSON each time they
+/// change, at most once per [`EventStreams::min_interval`].
+struct MeasurementEvents {
+    receiver: DataReceiver,
+    min_interval: Duration,
+    _slot: StreamSlot,
+}
+
+impl EventSource for MeasurementEvents {
+    async fn write_events<W: picoserve::io::Write>(
+        mut self,
+        mut writer: EventWriter<'_, W>,
+    ) -> Result<(), W::Error> {
+        loop {
+            match select(self.receiver.changed(), Timer::after(EVENTS_KEEPALIVE)).await {
+                Either::First(measurements) => {
+                    match measurements_json(&measurements) {
+                        Ok(json) => writer.write_event("measurement", json.as_str()).await?,
+                        Err(_) => warn!("The measurements don't fit in an event"),
+                    }
+                    // The latest measurements are sent afterwards
+                    Timer::after(self.min_interval).await;
+                }
+                Either::Second(()) => writer.write_keepalive().await?,
+            }
+        }
+    }
+}
+
+/// The setup page, a form to pick the network to join among the scanned ones.
+struct SetupChunks {
+    networks: &'static TheNetworks,
+}
+
+impl Chunks for SetupChunks {
+    fn content_type(&self) -> &'static str {
+        "text/html"
+    }
+
+    async fn write_chunks<W: picoserve::io::Write>(
+        self,
+        mut writer: ChunkWriter<W>,
+    ) -> Result<ChunksWritten, W::Error> {
+        writer
+            .write_chunk(
+                br#"<!DOCTYPE html><html><head><meta charset="utf-8"><meta name="viewport" content="width=device-width"><title>Weather station setup</title></head><body><h1>Join a network</h1><form method="post" action="/setup"><p><label>Network <input name="ssid" list="networks" maxlength="32" required></label></p><datalist id="networks">"#,
+            )
+            .await?;
+
+        let mut i = 0;
+        while let Some(network) = self
+            .networks
+            .lock(|networks| networks.borrow().get(i).cloned())
+        {
+            i += 1;
+            write!(
+                writer,
+                r#"<option value="{}">{} dBm{}</option>"#,
+                HtmlStr(&network.ssid),
+                network.signal_strength,
+                if network.open { ", open" } else { "" },
+            )
+            .await?;
+        }
+
+        writer
+            .write_chunk(
+                br#"</datalist><p><label>Passphrase <input name="passphrase" type="password" maxlength="64"></label></p><p><button>Save and reboot</button></p></form></body></html>"#,
+            )
+            .await?;
+        writer.finalize().await
     }
 }
 
@@ -35,44 +521,377 @@ impl AppWithStateBuilder for AppProps {
     type PathRouter = impl picoserve::routing::PathRouter<AppState>;
 
     fn build_app(self) -> picoserve::Router<Self::PathRouter, Self::State> {
-        
-        picoserve::Router::new().route(
-            "/",
-            get(move |State(receiver): State<ServerReceiver>| async move {
-                let mut message = String::<200>::new();
-                let measturments = receiver.receive().await;
-                println!("{:?}", measturments);
-                message.clear();
-                writeln!(
-                    &mut message,
-                    r#"{{
-                        "pressure": {},
-                        "humidity": {},
-                        "temperature":{}
-                }}"#,
-                    measturments.pressure, measturments.humidity, measturments.temperature,
-                )
-                .unwrap();
-                message
-            }),
+        picoserve::Router::new()
+            .route("/", get(current_measurements))
+            .route("/history", get(history))
+            .route("/config", get(read_config).post(update_config))
+            .route("/setup", get(setup_page).post(setup))
+            .route("/leases", get(leases))
+            .route("/metrics", get(prometheus_metrics))
+            .route("/events", get(events))
+            .route("/ws", get(websocket))
+            // Operating systems check they're online with these, a redirect
+            // makes them open the portal
+            .route("/generate_204", get(captive_portal))
+            .route("/gen_204", get(captive_portal))
+            .route("/hotspot-detect.html", get(captive_portal))
+            .route("/library/test/success.html", get(captive_portal))
+            .route("/connecttest.txt", get(captive_portal))
+            .route("/ncsi.txt", get(captive_portal))
+            .route("/redirect", get(captive_portal))
+            .route("/canonical.html", get(captive_portal))
+            .route("/success.txt", get(captive_portal))
+    }
+}
+
+/// The latest measurements, see [`NormalizedMeasurments`] for the schema.
+async fn current_measurements(
+    State(measurements): State<&'static TheWatch>,
+) -> Result<Json<512>, (StatusCode, &'static str)> {
+    // Nothing was measured yet if the watch is empty
+    let measurements = measurements.try_get().unwrap_or_default();
+    measurements_json(&measurements).map(Json).map_err(|_| {
+        warn!("The measurements don't fit in the response");
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "the measurements don't fit in the response",
+        )
+    })
+}
+
+/// `measurements` as a JSON object, the body of `/`.
+fn measurements_json(
+    measurements: &NormalizedMeasurments,
+) -> serde_json_core::ser::Result<String<512>> {
+    // Each quantity takes at most about 130 bytes
+    serde_json_core::to_string(measurements)
+}
+
+/// Streams the measurements as Server-Sent Events.
+async fn events(
+    State(measurements): State<&'static TheWatch>,
+    State(streams): State<&'static TheEventStreams>,
+) -> Result<EventStream<MeasurementEvents>, (StatusCode, &'static str)> {
+    let busy = (StatusCode::SERVICE_UNAVAILABLE, "too many event streams");
+    let slot = StreamSlot::take(streams).ok_or(busy)?;
+    let receiver = measurements.receiver().ok_or(busy)?;
+    Ok(EventStream(MeasurementEvents {
+        receiver,
+        min_interval: streams.lock(|streams| streams.borrow().min_interval),
+        _slot: slot,
+    }))
+}
+
+/// Upgrades to a WebSocket [`Session`], for live measurements and commands.
+async fn websocket(
+    State(measurements): State<&'static TheWatch>,
+    State(streams): State<&'static TheEventStreams>,
+    State(sampling): State<&'static SamplingControl>,
+    upgrade: WebSocketUpgrade,
+) -> Result<
+    UpgradedWebSocket<UnspecifiedProtocol, CallbackNotUsingState<Session>>,
+    (StatusCode, &'static str),
+> {
+    let busy = (StatusCode::SERVICE_UNAVAILABLE, "too many live connections");
+    let slot = StreamSlot::take(streams).ok_or(busy)?;
+    let receiver = measurements.receiver().ok_or(busy)?;
+    Ok(upgrade.on_upgrade(Session {
+        receiver,
+        sampling,
+        min_interval: streams.lock(|streams| streams.borrow().min_interval),
+        _slot: slot,
+    }))
+}
+
+async fn history(
+    State(history): State<&'static TheHistory>,
+    Query(query): Query<HistoryQuery>,
+) -> ChunkedResponse<HistoryChunks> {
+    ChunkedResponse::new(HistoryChunks {
+        history,
+        since_ms: query.since.unwrap_or(0),
+        limit: query.limit.unwrap_or(u32::MAX),
+        format: query.format.unwrap_or_default(),
+    })
+}
+
+/// Settings to change with `POST /config`, the others are kept.
+#[derive(Deserialize)]
+struct ConfigUpdate {
+    ssid: Option<String<32>>,
+    auth: Option<AuthMethod>,
+    passphrase: Option<String<64>>,
+    address: Option<Ipv4Addr>,
+    mode: Option<WifiMode>,
+    station_ssid: Option<String<32>>,
+    station_passphrase: Option<String<64>>,
+    hostname: Option<String<32>>,
+    dhcp_first_host: Option<u8>,
+    dhcp_last_host: Option<u8>,
+    dhcp_lease_secs: Option<u32>,
+    dhcp_max_leases: Option<u8>,
+    mqtt_broker: Option<Ipv4Addr>,
+    mqtt_port: Option<u16>,
+    mqtt_username: Option<String<32>>,
+    mqtt_password: Option<String<64>>,
+    mqtt_topic: Option<String<64>>,
+    influx_server: Option<Ipv4Addr>,
+    influx_port: Option<u16>,
+    influx_transport: Option<InfluxTransport>,
+    influx_org: Option<String<32>>,
+    influx_bucket: Option<String<32>>,
+    influx_token: Option<String<96>>,
+    measurement_interval_ms: Option<u32>,
+    humidity_interval_ms: Option<u32>,
+    events_interval_ms: Option<u32>,
+    /// Replaces the BME280 settings, before the other `bme280_` fields are applied.
+    bme280_preset: Option<Preset>,
+    bme280_temperature: Option<Oversampling>,
+    bme280_pressure: Option<Oversampling>,
+    bme280_humidity: Option<Oversampling>,
+    bme280_filter: Option<Filter>,
+    bme280_mode: Option<Mode>,
+}
+
+/// The configuration as JSON, without the secrets.
+fn config_json(config: &Config) -> Result<Json<2048>, (StatusCode, &'static str)> {
+    serde_json_core::to_string(&ConfigView::new(config))
+        .map(Json)
+        .map_err(|_| {
+            warn!("The configuration doesn't fit in the response");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "the configuration doesn't fit in the response",
+            )
+        })
+}
+
+/// The stored configuration, it's applied at the next boot.
+async fn read_config(
+    State(config): State<&'static TheConfig>,
+) -> Result<Json<2048>, (StatusCode, &'static str)> {
+    config_json(&config.lock(|config| config.borrow_mut().stored()))
+}
+
+async fn update_config(
+    State(config): State<&'static TheConfig>,
+    Form(update): Form<ConfigUpdate>,
+) -> Result<Json<2048>, (StatusCode, &'static str)> {
+    config.lock(|config| {
+        let mut config = config.borrow_mut();
+        let mut stored = config.stored();
+        if let Some(ssid) = update.ssid {
+            stored.ssid = ssid;
+        }
+        if let Some(auth) = update.auth {
+            stored.auth = auth;
+        }
+        if let Some(passphrase) = update.passphrase {
+            stored.passphrase = passphrase;
+        }
+        if let Some(address) = update.address {
+            stored.address = address;
+        }
+        if let Some(mode) = update.mode {
+            stored.mode = mode;
+        }
+        if let Some(ssid) = update.station_ssid {
+            stored.station_ssid = ssid;
+        }
+        if let Some(passphrase) = update.station_passphrase {
+            stored.station_passphrase = passphrase;
+        }
+        if let Some(hostname) = update.hostname {
+            stored.hostname = hostname;
+        }
+        if let Some(host) = update.dhcp_first_host {
+            stored.dhcp_first_host = host;
+        }
+        if let Some(host) = update.dhcp_last_host {
+            stored.dhcp_last_host = host;
+        }
+        if let Some(lease) = update.dhcp_lease_secs {
+            stored.dhcp_lease_secs = lease;
+        }
+        if let Some(max_leases) = update.dhcp_max_leases {
+            stored.dhcp_max_leases = max_leases;
+        }
+        if let Some(broker) = update.mqtt_broker {
+            stored.mqtt_broker = broker;
+        }
+        if let Some(port) = update.mqtt_port {
+            stored.mqtt_port = port;
+        }
+        if let Some(username) = update.mqtt_username {
+            stored.mqtt_username = username;
+        }
+        if let Some(password) = update.mqtt_password {
+            stored.mqtt_password = password;
+        }
+        if let Some(topic) = update.mqtt_topic {
+            stored.mqtt_topic = topic;
+        }
+        if let Some(server) = update.influx_server {
+            stored.influx_server = server;
+        }
+        if let Some(port) = update.influx_port {
+            stored.influx_port = port;
+        }
+        if let Some(transport) = update.influx_transport {
+            stored.influx_transport = transport;
+        }
+        if let Some(org) = update.influx_org {
+            stored.influx_org = org;
+        }
+        if let Some(bucket) = update.influx_bucket {
+            stored.influx_bucket = bucket;
+        }
+        if let Some(token) = update.influx_token {
+            stored.influx_token = token;
+        }
+        if let Some(interval) = update.measurement_interval_ms {
+            stored.measurement_interval_ms = interval;
+        }
+        if let Some(interval) = update.humidity_interval_ms {
+            stored.humidity_interval_ms = interval;
+        }
+        if let Some(interval) = update.events_interval_ms {
+            stored.events_interval_ms = interval;
+        }
+        stored.bme280 = bme280::ConfigUpdate {
+            preset: update.bme280_preset,
+            temperature: update.bme280_temperature,
+            pressure: update.bme280_pressure,
+            humidity: update.bme280_humidity,
+            filter: update.bme280_filter,
+            mode: update.bme280_mode,
+        }
+        .apply(stored.bme280);
+
+        config.update(&stored).map_err(update_error)?;
+        config_json(&stored)
+    })
+}
+
+/// The response to a failed [`crate::config::ConfigStore::update`].
+fn update_error(error: UpdateError) -> (StatusCode, &'static str) {
+    match error {
+        UpdateError::Invalid(e) => (StatusCode::BAD_REQUEST, e.as_str()),
+        UpdateError::NoStorage => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "there's no partition to store the configuration",
+        ),
+        UpdateError::Storage(e) => {
+            warn!("Failed to store the configuration: {:?}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "failed to store the configuration",
+            )
+        }
+    }
+}
+
+/// The clients of the access point.
+async fn leases(State(leases): State<&'static TheLeases>) -> ChunkedResponse<LeaseChunks> {
+    ChunkedResponse::new(LeaseChunks { leases })
+}
+
+/// The metrics, rendered before answering so a truncated body is never served.
+async fn prometheus_metrics(
+    State(measurements): State<&'static TheWatch>,
+    State(leases): State<&'static TheLeases>,
+    State(sensor_errors): State<&'static TheSensorErrors>,
+    State(link_state): State<&'static TheLinkState>,
+) -> Result<MetricsText, (StatusCode, &'static str)> {
+    let now = Instant::now();
+    let wifi_clients = leases.lock(|leases| leases.borrow().active(now.as_secs()).count());
+    let mut body = String::new();
+    let written = sensor_errors.lock(|errors| {
+        metrics::write_metrics(
+            &mut body,
+            &Metrics {
+                measurements: measurements.try_get().unwrap_or_default(),
+                errors: &errors.borrow(),
+                uptime_s: now.as_secs(),
+                free_heap_bytes: esp_alloc::HEAP.free(),
+                wifi_clients,
+                link_state: link_state.try_get(),
+            },
         )
+    });
+    match written {
+        Ok(()) => Ok(MetricsText(body)),
+        Err(_) => {
+            warn!("The metrics don't fit in {} bytes", body.capacity());
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "the metrics don't fit in the response",
+            ))
+        }
     }
 }
 
-#[embassy_executor::task]
+async fn captive_portal(State(Portal(portal)): State<Portal>) -> Redirect {
+    Redirect::to(portal)
+}
+
+async fn setup_page(State(networks): State<&'static TheNetworks>) -> ChunkedResponse<SetupChunks> {
+    ChunkedResponse::new(SetupChunks { networks })
+}
+
+/// Credentials posted by the setup page.
+#[derive(Deserialize)]
+struct SetupForm {
+    ssid: String<32>,
+    /// Empty for an open network.
+    #[serde(default)]
+    passphrase: String<64>,
+}
+
+/// Stores the network to join and reboots to join it, only while provisioning
+/// so nobody on the joined network can replace it.
+async fn setup(
+    State(Provisioning(provisioning)): State<Provisioning>,
+    State(config): State<&'static TheConfig>,
+    State(reboot): State<&'static TheReboot>,
+    Form(form): Form<SetupForm>,
+) -> Result<String<128>, (StatusCode, &'static str)> {
+    if !provisioning {
+        return Err((StatusCode::FORBIDDEN, "the station isn't being provisioned"));
+    }
+    let mut message = String::<128>::new();
+    // An SSID takes at most 32 bytes
+    write!(&mut message, "Rebooting to join {}", form.ssid).unwrap();
+
+    config.lock(|config| {
+        let mut config = config.borrow_mut();
+        let mut stored = config.stored();
+        stored
+            .join(form.ssid, form.passphrase)
+            .map_err(|e| (StatusCode::BAD_REQUEST, e.as_str()))?;
+        config.update(&stored).map_err(update_error)
+    })?;
+    reboot.signal(());
+    Ok(message)
+}
+
+/// Serves one connection at a time, spawn [`WEB_TASKS`] of them with
+/// different `id`s.
+#[embassy_executor::task(pool_size = WEB_TASKS)]
 pub async fn web_task(
+    id: usize,
     stack: embassy_net::Stack<'static>,
     app: &'static AppRouter<AppProps>,
     config: &'static picoserve::Config<Duration>,
-    state: AppState,
+    state: &'static AppState,
 ) -> ! {
-    let port = 80;
+    let port = PORT;
     let mut tcp_rx_buffer = [0; 1024];
     let mut tcp_tx_buffer = [0; 1024];
     let mut http_buffer = [0; 2048];
 
    picoserve::Server::new(&app.shared().with_state(state), config, &mut http_buffer)
-        .listen_and_serve(0, stack, port, &mut tcp_rx_buffer, &mut tcp_tx_buffer)
+        .listen_and_serve(id, stack, port, &mut tcp_rx_buffer, &mut tcp_tx_buffer)
         .await.into_never()
      
 }
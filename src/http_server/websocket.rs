@@ -0,0 +1,369 @@
+// Live measurements and commands over a WebSocket, as JSON text messages
+use core::fmt::Write;
+
+use defmt::{debug, info, warn};
+use embassy_futures::select::{self, select};
+use embassy_time::{Duration, Instant, Timer};
+use heapless::{String, Vec};
+use picoserve::futures::Either;
+use picoserve::io::{Read, Write as IoWrite};
+use picoserve::response::ws::{Message, SocketRx, SocketTx, WebSocketCallback};
+use serde::Deserialize;
+use serde::ser::{Serialize, SerializeStruct, Serializer};
+
+use super::server::{EVENTS_KEEPALIVE, JsonStr, StreamSlot};
+use crate::config::{EVENTS_INTERVAL_MS, MEASUREMENT_INTERVAL_MS};
+use crate::sampling::SamplingControl;
+use crate::sensors::bme280::{ConfigUpdate, Filter, Mode, Oversampling, Preset};
+use crate::sensors::weather_sensor::Quantity;
+use crate::{DataReceiver, Measured, NormalizedMeasurments};
+
+/// Longest command, longer messages close the connection.
+pub const MAX_MESSAGE_LEN: usize = 256;
+
+#[derive(Copy, Clone, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum CommandName {
+    Subscribe,
+    Unsubscribe,
+    SetInterval,
+    Read,
+    Calibrate,
+    ConfigureBme280,
+}
+
+/// A command as sent by the client, the fields depend on the command.
+#[derive(Deserialize)]
+struct RawCommand {
+    command: CommandName,
+    quantities: Option<Vec<Quantity, 3>>,
+    interval_ms: Option<u32>,
+    quantity: Option<Quantity>,
+    offset: Option<f32>,
+    preset: Option<Preset>,
+    temperature: Option<Oversampling>,
+    pressure: Option<Oversampling>,
+    humidity: Option<Oversampling>,
+    filter: Option<Filter>,
+    mode: Option<Mode>,
+}
+
+/// What a client can ask for.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    /// Sends the measurements of `quantities`, each one once and in the order
+    /// of [`Quantity::ALL`], each time they change, at most once per `interval`.
+    Subscribe {
+        quantities: Vec<Quantity, 3>,
+        interval: Option<Duration>,
+    },
+    Unsubscribe,
+    /// Sets the time between two sampling rounds.
+    SetInterval(Duration),
+    /// Takes a sampling round right away.
+    Read,
+    /// Sets the offset added to the values of `quantity`.
+    Calibrate {
+        quantity: Quantity,
+        offset: f32,
+    },
+    /// Changes the oversampling, filter or mode of the BME280.
+    ConfigureBme280(ConfigUpdate),
+}
+
+impl Command {
+    fn name(&self) -> &'static str {
+        match self {
+            Command::Subscribe { .. } => "subscribe",
+            Command::Unsubscribe => "unsubscribe",
+            Command::SetInterval(_) => "set-interval",
+            Command::Read => "read",
+            Command::Calibrate { .. } => "calibrate",
+            Command::ConfigureBme280(_) => "configure-bme280",
+        }
+    }
+}
+
+/// Why a command was refused.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, defmt::Format)]
+pub enum CommandError {
+    /// Not a JSON object with a known `command`.
+    Invalid,
+    MissingIntervalMs,
+    IntervalOutOfRange,
+    MissingQuantity,
+    MissingOffset,
+    OffsetOutOfRange,
+    /// Commands are JSON text, not binary messages.
+    NotText,
+}
+
+impl CommandError {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CommandError::Invalid => "not a known command",
+            CommandError::MissingIntervalMs => "interval_ms is missing",
+            CommandError::IntervalOutOfRange => "the interval is out of range",
+            CommandError::MissingQuantity => "quantity is missing",
+            CommandError::MissingOffset => "offset is missing",
+            CommandError::OffsetOutOfRange => "the offset is out of range",
+            CommandError::NotText => "commands are JSON text messages",
+        }
+    }
+}
+
+/// Parses a command, e.g. `{"command": "set-interval", "interval_ms": 5000}`.
+pub fn parse_command(text: &str) -> Result<Command, CommandError> {
+    let (raw, _) =
+        serde_json_core::from_str::<RawCommand>(text).map_err(|_| CommandError::Invalid)?;
+    match raw.command {
+        CommandName::Subscribe => {
+            let interval = match raw.interval_ms {
+                Some(ms) if !EVENTS_INTERVAL_MS.contains(&ms) => {
+                    return Err(CommandError::IntervalOutOfRange);
+                }
+                ms => ms.map(|ms| Duration::from_millis(ms.into())),
+            };
+            Ok(Command::Subscribe {
+                // Each quantity once so the messages have no duplicate keys,
+                // every quantity by default
+                quantities: Quantity::ALL
+                    .into_iter()
+                    .filter(|quantity| {
+                        raw.quantities
+                            .as_ref()
+                            .is_none_or(|quantities| quantities.contains(quantity))
+                    })
+                    .collect(),
+                interval,
+            })
+        }
+        CommandName::Unsubscribe => Ok(Command::Unsubscribe),
+        CommandName::SetInterval => {
+            let ms = raw.interval_ms.ok_or(CommandError::MissingIntervalMs)?;
+            if !MEASUREMENT_INTERVAL_MS.contains(&ms) {
+                return Err(CommandError::IntervalOutOfRange);
+            }
+            Ok(Command::SetInterval(Duration::from_millis(ms.into())))
+        }
+        CommandName::Read => Ok(Command::Read),
+        CommandName::Calibrate => {
+            let quantity = raw.quantity.ok_or(CommandError::MissingQuantity)?;
+            let offset = raw.offset.ok_or(CommandError::MissingOffset)?;
+            if !offset.is_finite() || offset.abs() > quantity.max_offset() {
+                return Err(CommandError::OffsetOutOfRange);
+            }
+            Ok(Command::Calibrate { quantity, offset })
+        }
+        CommandName::ConfigureBme280 => Ok(Command::ConfigureBme280(ConfigUpdate {
+            preset: raw.preset,
+            temperature: raw.temperature,
+            pressure: raw.pressure,
+            humidity: raw.humidity,
+            filter: raw.filter,
+            mode: raw.mode,
+        })),
+    }
+}
+
+/// `{"type": "ok", "command": ...}`, the answer to an applied command.
+pub fn ok_json(command: &Command) -> String<64> {
+    let mut message = String::new();
+    write!(
+        message,
+        r#"{{"type": "ok", "command": "{}"}}"#,
+        command.name()
+    )
+    .unwrap();
+    message
+}
+
+/// `{"type": "error", "error": ...}`, the answer to a refused command.
+pub fn error_json(error: CommandError) -> String<64> {
+    let mut message = String::new();
+    // The longest message takes 31 bytes
+    write!(
+        message,
+        r#"{{"type": "error", "error": {}}}"#,
+        JsonStr(error.as_str())
+    )
+    .unwrap();
+    message
+}
+
+/// `{"type": "measurement", "timestamp_ms": ...}` with the values of
+/// `quantities`, as in the JSON of `/`.
+struct MeasurementMessage<'a> {
+    measurements: &'a NormalizedMeasurments,
+    quantities: &'a [Quantity],
+}
+
+impl Serialize for MeasurementMessage<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut object =
+            serializer.serialize_struct("MeasurementMessage", 2 + self.quantities.len())?;
+        object.serialize_field("type", "measurement")?;
+        object.serialize_field("timestamp_ms", &self.measurements.timestamp_ms)?;
+        for &quantity in self.quantities {
+            object.serialize_field(
+                quantity.as_str(),
+                &Measured::new(quantity, self.measurements),
+            )?;
+        }
+        object.end()
+    }
+}
+
+/// The values of `quantities` as a measurement message.
+pub fn measurement_json(
+    measurements: &NormalizedMeasurments,
+    quantities: &[Quantity],
+) -> serde_json_core::ser::Result<String<512>> {
+    // Each quantity takes at most about 130 bytes
+    serde_json_core::to_string(&MeasurementMessage {
+        measurements,
+        quantities,
+    })
+}
+
+/// Sends the measurements `subscription` asks for, nothing if they don't fit
+/// in a message.
+async fn send_measurements<W: IoWrite>(
+    tx: &mut SocketTx<W>,
+    measurements: &NormalizedMeasurments,
+    subscription: &Subscription,
+) -> Result<(), W::Error> {
+    match measurement_json(measurements, &subscription.quantities) {
+        Ok(message) => tx.send_text(&message).await,
+        Err(_) => {
+            warn!("The measurements don't fit in a WebSocket message");
+            Ok(())
+        }
+    }
+}
+
+/// What wakes a session up, besides a message of the client.
+enum Wake {
+    Measurements(NormalizedMeasurments),
+    /// Nothing was sent for [`EVENTS_KEEPALIVE`].
+    Idle,
+}
+
+/// What a client subscribed to.
+struct Subscription {
+    quantities: Vec<Quantity, 3>,
+    interval: Duration,
+}
+
+/// A WebSocket connection, sends the measurements the client subscribed to
+/// and applies its commands.
+pub struct Session {
+    pub(super) receiver: DataReceiver,
+    pub(super) sampling: &'static SamplingControl,
+    /// Shortest time between two measurements sent to a client.
+    pub(super) min_interval: Duration,
+    pub(super) _slot: StreamSlot,
+}
+
+impl Session {
+    /// Applies `command`, returns the answer to send.
+    fn apply(&mut self, command: Command, subscription: &mut Option<Subscription>) -> String<64> {
+        let answer = ok_json(&command);
+        match command {
+            Command::Subscribe {
+                quantities,
+                interval,
+            } => {
+                *subscription = Some(Subscription {
+                    quantities,
+                    interval: interval.unwrap_or(self.min_interval).max(self.min_interval),
+                })
+            }
+            Command::Unsubscribe => *subscription = None,
+            Command::SetInterval(interval) => {
+                info!("Sampling every {} ms", interval.as_millis());
+                self.sampling.set_interval(interval)
+            }
+            Command::Read => self.sampling.read_now(),
+            Command::Calibrate { quantity, offset } => {
+                info!(
+                    "Calibrating the {} with an offset of {}",
+                    quantity.as_str(),
+                    offset
+                );
+                self.sampling.calibrate(quantity, offset)
+            }
+            Command::ConfigureBme280(update) => {
+                info!("Changing the BME280 settings: {}", update);
+                self.sampling.configure_bme280(update)
+            }
+        }
+        answer
+    }
+}
+
+impl WebSocketCallback for Session {
+    async fn run<R: Read, W: IoWrite<Error = R::Error>>(
+        mut self,
+        mut rx: SocketRx<R>,
+        mut tx: SocketTx<W>,
+    ) -> Result<(), W::Error> {
+        let mut buffer = [0; MAX_MESSAGE_LEN];
+        let mut subscription = None::<Subscription>;
+        // When the next measurement can be sent
+        let mut next_at = Instant::MIN;
+        loop {
+            let subscribed = subscription.is_some();
+            let receiver = &mut self.receiver;
+            let wake = async {
+                let measurements = async {
+                    if !subscribed {
+                        return core::future::pending().await;
+                    }
+                    Timer::at(next_at).await;
+                    receiver.changed().await
+                };
+                match select(measurements, Timer::after(EVENTS_KEEPALIVE)).await {
+                    select::Either::First(measurements) => Wake::Measurements(measurements),
+                    select::Either::Second(()) => Wake::Idle,
+                }
+            };
+            match rx.next_message(&mut buffer, wake).await? {
+                Either::First(Ok(Message::Text(text))) => match parse_command(text) {
+                    Ok(command) => {
+                        let subscribed = matches!(command, Command::Subscribe { .. });
+                        tx.send_text(&self.apply(command, &mut subscription))
+                            .await?;
+                        // The latest measurements right away, even if they
+                        // were already sent
+                        if subscribed
+                            && let Some(subscription) = &subscription
+                            && let Some(measurements) = self.receiver.try_get()
+                        {
+                            send_measurements(&mut tx, &measurements, subscription).await?;
+                            next_at = Instant::now() + subscription.interval;
+                        }
+                    }
+                    Err(e) => tx.send_text(&error_json(e)).await?,
+                },
+                Either::First(Ok(Message::Binary(_))) => {
+                    tx.send_text(&error_json(CommandError::NotText)).await?
+                }
+                Either::First(Ok(Message::Ping(data))) => tx.send_pong(data).await?,
+                Either::First(Ok(Message::Pong(_))) => {}
+                Either::First(Ok(Message::Close(_))) => return tx.close(None).await,
+                Either::First(Err(e)) => {
+                    debug!("Closing a WebSocket: {:?}", e);
+                    return tx.close((e.code(), "")).await;
+                }
+                Either::Second(Wake::Measurements(measurements)) => {
+                    if let Some(subscription) = &subscription {
+                        send_measurements(&mut tx, &measurements, subscription).await?;
+                        next_at = Instant::now() + subscription.interval;
+                    }
+                }
+                Either::Second(Wake::Idle) => tx.send_ping(&[]).await?,
+            }
+        }
+    }
+}
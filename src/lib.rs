@@ -1,16 +1,25 @@
 #![no_std]
 
 #![feature(impl_trait_in_assoc_type)]
+// The router of the web server nests a type per route
+#![recursion_limit = "256"]
 
 use embassy_sync::{
     blocking_mutex::raw::NoopRawMutex,
-    channel::{Channel, Receiver, Sender},
+    watch::{Receiver, Sender, Watch},
 };
+use serde::ser::{Serialize, SerializeStruct, Serializer};
 
+use sensors::weather_sensor::{Quantity, Sourced, SourcedReading};
 
+
+pub mod config;
+pub mod history;
 pub mod http_server;
 pub mod network;
+pub mod sampling;
 pub mod sensors;
+pub mod storage;
 /*
 
 The macro makes a some object to have a static lifetime
@@ -25,23 +34,239 @@ macro_rules! make_static {
         x
     }};
 }
-pub const MESSAGES: usize = 1;
+/// How many tasks can wait for new measurements with [`Watch::receiver`]: the
+/// history and CoAP tasks, and the `/events` streams and `/ws` sessions.
+///
+/// Reading the latest value with [`Watch::try_get`] doesn't need a receiver.
+pub const RECEIVERS: usize = 2 + http_server::server::MAX_EVENT_STREAMS;
+
+/// Values not refreshed for longer than this are [`Quality::Stale`].
+pub const STALE_AFTER_MS: u64 = 10_000;
+
+/// How a value relates to the latest sampling round.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, serde::Serialize, defmt::Format)]
+#[serde(rename_all = "lowercase")]
+pub enum Quality {
+    /// Measured in the latest round.
+    Fresh,
+    /// Kept from an earlier round, the sensor didn't provide it this time.
+    Held,
+    /// Kept for longer than [`STALE_AFTER_MS`], the sensor probably stopped responding.
+    Stale,
+}
+
+impl Quality {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Quality::Fresh => "fresh",
+            Quality::Held => "held",
+            Quality::Stale => "stale",
+        }
+    }
+}
+
+/// A single measured quantity.
+#[derive(Copy, Clone, Debug, defmt::Format)]
+pub struct Field {
+    pub value: f32,
+    /// Name of the sensor that measured the value.
+    pub sensor: &'static str,
+    /// Milliseconds since boot.
+    pub measured_at_ms: u64,
+    pub quality: Quality,
+}
 
-#[derive(Debug, defmt::Format)]
+/// The latest known values of the station.
+///
+/// A value is `None` when no sensor of the station provided it (yet).
+#[derive(Copy, Clone, Default, Debug, defmt::Format)]
 pub struct NormalizedMeasurments {
-    pub pressure: f32,
-    pub humidity: f32,
-    pub temperature: f32,
+    /// Milliseconds since boot of the latest sampling round.
+    pub timestamp_ms: u64,
+    pub pressure: Option<Field>,
+    pub humidity: Option<Field>,
+    pub temperature: Option<Field>,
+}
+
+impl NormalizedMeasurments {
+    /// Applies a sampling round taken at `now_ms`.
+    ///
+    /// Values missing from `reading` are kept, but lose their [`Quality::Fresh`] flag.
+    pub fn update(&mut self, reading: SourcedReading, now_ms: u64) {
+        self.timestamp_ms = now_ms;
+        update_field(&mut self.pressure, reading.pressure, now_ms);
+        update_field(&mut self.humidity, reading.humidity, now_ms);
+        update_field(&mut self.temperature, reading.temperature, now_ms);
+    }
+
+    /// The latest value of `quantity`.
+    pub fn field(&self, quantity: Quantity) -> Option<Field> {
+        match quantity {
+            Quantity::Temperature => self.temperature,
+            Quantity::Humidity => self.humidity,
+            Quantity::Pressure => self.pressure,
+        }
+    }
+}
+
+/// Serialized as `{"timestamp_ms": ..., "temperature": ..., "humidity": ...,
+/// "pressure": ...}`, each quantity being a [`Measured`].
+impl Serialize for NormalizedMeasurments {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut object = serializer.serialize_struct("NormalizedMeasurments", 4)?;
+        object.serialize_field("timestamp_ms", &self.timestamp_ms)?;
+        for quantity in Quantity::ALL {
+            object.serialize_field(quantity.as_str(), &Measured::new(quantity, self))?;
+        }
+        object.end()
+    }
+}
+
+/// The value of a quantity with its unit, serialized as
+/// `{"value": 21.5, "unit": "°C", "sensor": "BME280", "measured_at_ms": 1200,
+/// "quality": "fresh"}`, or `null` when it's unknown or not finite.
+#[derive(Copy, Clone, Debug)]
+pub struct Measured {
+    pub quantity: Quantity,
+    pub field: Option<Field>,
 }
 
-pub type ServerReceiver = Receiver<'static, NoopRawMutex, NormalizedMeasurments, MESSAGES>;
-pub type DataSender = Sender<'static, NoopRawMutex, NormalizedMeasurments, MESSAGES>;
-pub type TheChannel = Channel<NoopRawMutex, NormalizedMeasurments, MESSAGES>;
+impl Measured {
+    pub fn new(quantity: Quantity, measurements: &NormalizedMeasurments) -> Self {
+        Self {
+            quantity,
+            field: measurements.field(quantity),
+        }
+    }
+}
 
-pub type HumidityReceiver = Receiver<'static, NoopRawMutex, f32, MESSAGES>;
-pub type HumiditySender = Sender<'static, NoopRawMutex, f32, MESSAGES>;
-pub type TheHumidityChannel = Channel<NoopRawMutex, f32, MESSAGES>;
+impl Serialize for Measured {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let Some(field) = self.field.filter(|field| field.value.is_finite()) else {
+            return serializer.serialize_none();
+        };
+        let mut object = serializer.serialize_struct("Measured", 5)?;
+        object.serialize_field("value", &field.value)?;
+        object.serialize_field("unit", self.quantity.unit())?;
+        object.serialize_field("sensor", field.sensor)?;
+        object.serialize_field("measured_at_ms", &field.measured_at_ms)?;
+        object.serialize_field("quality", &field.quality)?;
+        object.end()
+    }
+}
+
+fn update_field(field: &mut Option<Field>, sample: Option<Sourced>, now_ms: u64) {
+    match (sample, field.as_mut()) {
+        (Some(sample), _) => {
+            *field = Some(Field {
+                value: sample.value,
+                sensor: sample.sensor,
+                measured_at_ms: now_ms,
+                quality: Quality::Fresh,
+            })
+        }
+        (None, Some(field)) => {
+            field.quality = if now_ms.saturating_sub(field.measured_at_ms) > STALE_AFTER_MS {
+                Quality::Stale
+            } else {
+                Quality::Held
+            }
+        }
+        (None, None) => {}
+    }
+}
+
+/// Holds the latest measurements, readers never consume them.
+pub type TheWatch = Watch<NoopRawMutex, NormalizedMeasurments, RECEIVERS>;
+pub type DataSender = Sender<'static, NoopRawMutex, NormalizedMeasurments, RECEIVERS>;
+pub type DataReceiver = Receiver<'static, NoopRawMutex, NormalizedMeasurments, RECEIVERS>;
 
 pub fn to_kpa(pressure: f32) -> f32 {
     pressure / 1000.0
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field(value: f32) -> Option<Field> {
+        Some(Field {
+            value,
+            sensor: "BME280",
+            measured_at_ms: 1200,
+            quality: Quality::Fresh,
+        })
+    }
+
+    fn json<T: Serialize>(value: &T) -> heapless::String<512> {
+        serde_json_core::to_string(value).unwrap()
+    }
+
+    #[test]
+    fn measured_value() {
+        let measured = Measured {
+            quantity: Quantity::Temperature,
+            field: field(21.5),
+        };
+        assert_eq!(
+            json(&measured),
+            r#"{"value":21.5,"unit":"°C","sensor":"BME280","measured_at_ms":1200,"quality":"fresh"}"#
+        );
+    }
+
+    #[test]
+    fn unknown_or_not_finite_values_are_null() {
+        for field in [
+            None,
+            field(f32::NAN),
+            field(f32::INFINITY),
+            field(f32::NEG_INFINITY),
+        ] {
+            let measured = Measured {
+                quantity: Quantity::Pressure,
+                field,
+            };
+            assert_eq!(json(&measured), "null");
+        }
+    }
+
+    #[test]
+    fn measurements() {
+        let measurements = NormalizedMeasurments {
+            timestamp_ms: 5000,
+            pressure: field(f32::NAN),
+            humidity: None,
+            temperature: Some(Field {
+                value: -4.25,
+                sensor: "DHT22",
+                measured_at_ms: 4000,
+                quality: Quality::Held,
+            }),
+        };
+        assert_eq!(
+            json(&measurements),
+            concat!(
+                r#"{"timestamp_ms":5000,"#,
+                r#""temperature":{"value":-4.25,"unit":"°C","sensor":"DHT22","measured_at_ms":4000,"quality":"held"},"#,
+                r#""humidity":null,"pressure":null}"#
+            )
+        );
+    }
+
+    #[test]
+    fn largest_measurements_fit() {
+        let field = Some(Field {
+            value: -f32::MAX,
+            sensor: "BME280",
+            measured_at_ms: u64::MAX,
+            quality: Quality::Stale,
+        });
+        let measurements = NormalizedMeasurments {
+            timestamp_ms: u64::MAX,
+            pressure: field,
+            humidity: field,
+            temperature: field,
+        };
+        assert!(serde_json_core::to_string::<_, 512>(&measurements).is_ok());
+    }
+}
@@ -2,19 +2,20 @@
 #![no_main]
 #![feature(impl_trait_in_assoc_type)]
 
-use bme280::i2c::AsyncBME280;
-
 use esp_hal::time::Rate;
 
-use core::{net::Ipv4Addr, str::FromStr};
+use core::cell::RefCell;
+use core::fmt::Write;
+use core::net::SocketAddrV4;
 
 use embassy_executor::Spawner;
 use embassy_net::Ipv4Cidr;
 use embassy_net::{StackResources, StaticConfigV4};
-use embassy_time::{Delay, Duration, Timer};
+use embassy_time::{Delay, Duration, Instant, Timer};
 
 use esp_hal::gpio::{Flex, InputConfig, OutputConfig, Pull};
 use esp_hal::i2c;
+use esp_hal::rmt::Rmt;
 use esp_hal::{clock::CpuClock, rng::Rng, timer::timg::TimerGroup};
 
 
@@ -22,23 +23,54 @@ use num_traits::float::FloatCore;
 use picoserve::{AppRouter, AppWithStateBuilder};
 
 use esp_hal::i2c::master::I2c;
-use weather_station::http_server::server::{AppProps, AppState, web_task};
-use weather_station::network::dhcp::run_dhcp;
+use esp_storage::FlashStorage;
+use heapless::String;
+use weather_station::config::{ConfigStore, TheConfig, WifiMode};
+use weather_station::history::{History, TheHistory, history_task, restore};
+use weather_station::http_server::server::{
+    self, AppProps, AppState, EventStreams, TheEventStreams, WEB_TASKS, web_task,
+};
+use weather_station::network::coap::run_coap;
+use weather_station::network::dhcp::leases::{Leases, Pool};
+use weather_station::network::dhcp::{TheLeases, run_dhcp};
+use weather_station::network::dns::run_dns;
+use weather_station::network::influx::{InfluxSettings, influx_task};
+use weather_station::network::mdns::packet::{Responder, Service};
+use weather_station::network::mdns::run_mdns;
+use weather_station::network::mqtt::{MqttSettings, mqtt_task};
 use weather_station::network::network_tasks::connection;
 use weather_station::network::network_tasks::net_task;
-use weather_station::sensors::dht11::Dht11;
-use weather_station::{
-    HumiditySender, NormalizedMeasurments, TheChannel, TheHumidityChannel, make_static, to_kpa,
+use weather_station::network::provisioning::{
+    PROVISIONING_TIMEOUT, TheNetworks, TheReboot, reboot_task, scan,
 };
-
-use defmt::{debug, error, info};
-
-const GW_IP_ADDR_ENV: Option<&'static str> = Some("192.168.1.1");
-const SSID: &str = "WeatherStation";
-
-const HUMIDITY_MEASURMENT_INTERVAL: Duration = Duration::from_millis(1250);
-const INTERVAL: Duration = Duration::from_millis(100);
-type Dht = Dht11<Flex<'static>>;
+use weather_station::network::station::{TheLinkState, station};
+use weather_station::sampling::SamplingControl;
+use weather_station::sensors::bme280::{
+    Bme280, Bme280Sensor, Config as Bme280Config, PRIMARY_ADDRESS,
+};
+use weather_station::sensors::dht::EmbassyClock;
+use weather_station::sensors::dht::rmt::{RMT_FREQUENCY_MHZ, RmtCapture};
+use weather_station::sensors::dht11::{Dht11, DhtSensor};
+use weather_station::sensors::weather_sensor::{
+    SensorErrors, SensorSet, SourcedReading, TheSensorErrors,
+};
+use weather_station::storage::history_log::HistoryLog;
+use weather_station::storage::kv::KvStore;
+use weather_station::storage::partition::{Partition, TheFlash};
+use weather_station::{NormalizedMeasurments, TheWatch, make_static, to_kpa};
+
+use defmt::{debug, info, warn};
+
+const HISTORY_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// Labels of the flash partitions, see `partitions.csv`.
+const HISTORY_PARTITION: &str = "history";
+const CONFIG_PARTITION: &str = "config";
+/// Sockets of the network stack: one per web task, at most six for the other
+/// services (the DHCP client, mDNS, CoAP, MQTT and InfluxDB over TCP and UDP
+/// of a station) and one to spare.
+const SOCKETS: usize = WEB_TASKS + 7;
+/// TXT attribute of the `_weather._tcp` service.
+const VERSION_TXT: &str = concat!("version=", env!("CARGO_PKG_VERSION"));
 
 use panic_rtt_target as _;
 // use esp_alloc as _;
@@ -62,12 +94,53 @@ async fn main(spawner: Spawner) {
 
     esp_rtos::start(timg0.timer0, software_interrupt.software_interrupt0);
 
+    let flash = make_static!(
+        TheFlash,
+        TheFlash::new(RefCell::new(FlashStorage::new(peripherals.FLASH)))
+    );
+    let mut config_store = ConfigStore::load(find_partition(flash, CONFIG_PARTITION).and_then(|partition| {
+        KvStore::open(partition)
+            .inspect_err(|e| warn!("Failed to open the configuration store: {:?}", e))
+            .ok()
+    }));
+    let settings = config_store.config().clone();
+    let provisioning_requested = config_store.take_provisioning_request();
+    let provisioning = settings.needs_provisioning()
+        || (settings.mode == WifiMode::Station && provisioning_requested);
+    // Without a network to join the station waits to be provisioned, after
+    // failing to join it the station tries again later
+    let reboot_after =
+        (provisioning && !settings.needs_provisioning()).then_some(PROVISIONING_TIMEOUT);
+    let access_point = settings.mode == WifiMode::AccessPoint || provisioning;
+    let config_store = make_static!(TheConfig, TheConfig::new(RefCell::new(config_store)));
+
     let esp_wifi_ctrl =
         &*make_static!(esp_radio::Controller<'static> , esp_radio::init().unwrap());
 
-    let (controller, interfaces) = esp_radio::wifi::new(esp_wifi_ctrl, peripherals.WIFI, Default::default()).unwrap();
+    let (mut controller, interfaces) = esp_radio::wifi::new(esp_wifi_ctrl, peripherals.WIFI, Default::default()).unwrap();
 
-    let device = interfaces.ap;
+    let device = if access_point {
+        interfaces.ap
+    } else {
+        interfaces.sta
+    };
+
+    let leases = make_static!(
+        TheLeases,
+        TheLeases::new(RefCell::new(Leases::new(Pool::from_config(&settings))))
+    );
+    let networks = make_static!(TheNetworks, TheNetworks::new(RefCell::new(heapless::Vec::new())));
+    let reboot = make_static!(TheReboot, TheReboot::new());
+    if provisioning {
+        info!("Provisioning the network to join at /setup");
+        match scan(&mut controller).await {
+            Ok(found) => networks.lock(|networks| *networks.borrow_mut() = found),
+            Err(e) => warn!("Failed to scan for networks: {:?}", e),
+        }
+    }
+    spawner
+        .spawn(reboot_task(reboot, reboot_after))
+        .ok();
 
     
     let mut delay = Delay;
@@ -84,7 +157,12 @@ async fn main(spawner: Spawner) {
     dht11_pin.set_output_enable(true);
     dht11_pin.set_input_enable(true);
 
-    let dht11 = Dht11::new(dht11_pin);
+    // The RMT times the pulses of the sensor, whatever the network tasks do
+    let rmt = Rmt::new(peripherals.RMT, Rate::from_mhz(RMT_FREQUENCY_MHZ))
+        .unwrap()
+        .into_async();
+    let dht11_capture = RmtCapture::new(rmt.channel2, &dht11_pin).unwrap();
+    let dht11 = Dht11::new(dht11_pin, dht11_capture, EmbassyClock);
 
    
     let i2c0 = I2c::new(
@@ -97,17 +175,63 @@ async fn main(spawner: Spawner) {
     .into_async();
     // I use BMP280, it is similar, except humidity
 
-    let mut bme280 = AsyncBME280::new_primary(i2c0);
-    bme280.init(&mut delay).await.unwrap();
+    let mut bme280 = Bme280::new(i2c0, PRIMARY_ADDRESS, Bme280Config::default(), &mut delay)
+        .await
+        .unwrap();
+    info!("Detected {}", bme280.chip());
+    if settings.bme280 != Bme280Config::default()
+        && let Err(e) = bme280.set_config(settings.bme280).await
+    {
+        warn!("Failed to apply the stored BME280 settings: {:?}", e);
+    }
 
-    let gw_ip_addr_str = GW_IP_ADDR_ENV.unwrap_or("192.168.2.1");
-    let gw_ip_addr = Ipv4Addr::from_str(gw_ip_addr_str).expect("failed to parse gateway ip");
+    let mut sensors = (
+        Bme280Sensor::new(bme280, Delay),
+        DhtSensor::new(
+            dht11,
+            Delay,
+            Duration::from_millis(settings.humidity_interval_ms.into()),
+        ),
+    );
 
-    let config = embassy_net::Config::ipv4_static(StaticConfigV4 {
-        address: Ipv4Cidr::new(gw_ip_addr, 24),
-        gateway: Some(gw_ip_addr),
-        dns_servers: Default::default(),
-    });
+    // Advertised over mDNS
+    let sensor_errors = make_static!(
+        TheSensorErrors,
+        TheSensorErrors::new(RefCell::new(SensorErrors::new(sensors.names())))
+    );
+    let sensor_names = make_static!(String<64>, String::new());
+    for (i, name) in sensors.names().iter().enumerate() {
+        // At most 4 names of a few characters
+        write!(sensor_names, "{}{}", if i == 0 { "sensors=" } else { "," }, name).unwrap();
+    }
+    let weather_txt = make_static!([&str; 2], [VERSION_TXT, sensor_names]);
+    let services = make_static!(
+        [Service<'static>; 2],
+        [
+            Service {
+                kind: "_http._tcp",
+                port: server::PORT,
+                txt: &["path=/"],
+            },
+            Service {
+                kind: "_weather._tcp",
+                port: server::PORT,
+                txt: weather_txt,
+            },
+        ]
+    );
+
+    let gw_ip_addr = settings.address;
+
+    let config = if access_point {
+        embassy_net::Config::ipv4_static(StaticConfigV4 {
+            address: Ipv4Cidr::new(gw_ip_addr, 24),
+            gateway: Some(gw_ip_addr),
+            dns_servers: Default::default(),
+        })
+    } else {
+        embassy_net::Config::dhcpv4(Default::default())
+    };
 
     let seed = (rng.random() as u64) << 32 | rng.random() as u64;
 
@@ -115,13 +239,45 @@ async fn main(spawner: Spawner) {
     let (stack, runner) = embassy_net::new(
         device,
         config,
-        make_static!(StackResources<8>, StackResources::<8>::new()),
+        make_static!(StackResources<SOCKETS>, StackResources::<SOCKETS>::new()),
         seed,
     );
 
-    spawner.spawn(connection(controller, SSID)).ok();
     spawner.spawn(net_task(runner)).ok();
-    spawner.spawn(run_dhcp(stack, gw_ip_addr_str)).ok();
+    let link_state = make_static!(TheLinkState, TheLinkState::new());
+    if access_point {
+        let ssid = make_static!(String<32>, settings.ssid.clone());
+        let passphrase = make_static!(String<64>, settings.passphrase.clone());
+        spawner
+            .spawn(connection(controller, ssid, settings.auth, passphrase))
+            .ok();
+        spawner.spawn(run_dhcp(stack, gw_ip_addr, leases)).ok();
+        spawner.spawn(run_dns(stack, gw_ip_addr)).ok();
+    } else {
+        let ssid = make_static!(String<32>, settings.station_ssid.clone());
+        let passphrase = make_static!(String<64>, settings.station_passphrase.clone());
+        spawner
+            .spawn(station(
+                controller,
+                stack,
+                ssid,
+                passphrase,
+                link_state,
+                config_store,
+            ))
+            .ok();
+    }
+
+    let hostname: &'static str = make_static!(String<32>, settings.hostname.clone());
+    spawner
+        .spawn(run_mdns(
+            stack,
+            Responder {
+                hostname,
+                services,
+            },
+        ))
+        .ok();
 
     loop {
         if stack.is_link_up() {
@@ -138,15 +294,35 @@ async fn main(spawner: Spawner) {
         .config_v4()
         .inspect(|c| debug!("ipv4 config: {:?}", c));
 
-    let channel = make_static!(TheChannel, TheChannel::new());
-    let server_receiver = channel.receiver();
-    let data_sender = channel.sender();
+    let measurements = make_static!(TheWatch, TheWatch::new());
+    let data_sender = measurements.sender();
+
+    let mut history_log = find_partition(flash, HISTORY_PARTITION).and_then(|partition| {
+        HistoryLog::open(partition)
+            .inspect_err(|e| warn!("Failed to open the history log: {:?}", e))
+            .ok()
+    });
+    let mut restored = History::new();
+    let clock_offset_ms = match history_log.as_mut().map(|log| restore(&mut restored, log)) {
+        Some(Ok(clock_offset_ms)) => {
+            info!("Restored {} history records", restored.len());
+            clock_offset_ms
+        }
+        Some(Err(e)) => {
+            warn!("Failed to restore the history: {:?}", e);
+            0
+        }
+        None => 0,
+    };
+    let history = make_static!(TheHistory, TheHistory::new(RefCell::new(restored)));
 
-    let humidity_channel = make_static!(TheHumidityChannel, TheHumidityChannel::new());
-    let humidity_receiver = humidity_channel.receiver();
-    let humidity_sender = humidity_channel.sender();
+    let sampling = make_static!(
+        SamplingControl,
+        SamplingControl::new(Duration::from_millis(settings.measurement_interval_ms.into()))
+    );
 
     let app = make_static!(AppRouter<AppProps>, AppProps.build_app());
+    let portal = if provisioning { "/setup" } else { "/" };
 
     let config = make_static!(
         picoserve::Config::<Duration>,
@@ -159,30 +335,125 @@ async fn main(spawner: Spawner) {
         .keep_connection_alive()
     );
 
-    spawner.must_spawn(web_task(stack, app, config, AppState::new(server_receiver)));
-    spawner.must_spawn(measure_humidity(dht11, humidity_sender));
-    let mut humidity = 0.0f32;
+    let state = make_static!(
+        AppState,
+        AppState {
+            measurements,
+            history,
+            config: config_store,
+            networks,
+            reboot,
+            portal,
+            provisioning,
+            leases,
+            sensor_errors,
+            event_streams: make_static!(
+                TheEventStreams,
+                TheEventStreams::new(RefCell::new(EventStreams::new(Duration::from_millis(
+                    settings.events_interval_ms.into()
+                ))))
+            ),
+            sampling,
+            link_state,
+        }
+    );
+    for id in 0..WEB_TASKS {
+        spawner.must_spawn(web_task(id, stack, app, config, state));
+    }
+
+    spawner.must_spawn(history_task(
+        measurements.receiver().unwrap(),
+        history,
+        history_log,
+        clock_offset_ms,
+        HISTORY_INTERVAL,
+    ));
+
+    spawner.must_spawn(run_coap(
+        stack,
+        measurements,
+        measurements.receiver().unwrap(),
+    ));
+
+    if !provisioning && !settings.mqtt_broker.is_unspecified() {
+        let username = make_static!(String<32>, settings.mqtt_username.clone());
+        let password = make_static!(String<64>, settings.mqtt_password.clone());
+        let topic = make_static!(String<64>, settings.mqtt_topic.clone());
+        spawner.must_spawn(mqtt_task(
+            stack,
+            MqttSettings {
+                broker: SocketAddrV4::new(settings.mqtt_broker, settings.mqtt_port),
+                client_id: hostname,
+                username: (!username.is_empty()).then_some(username.as_str()),
+                password: (!password.is_empty()).then_some(password.as_str()),
+                topic,
+            },
+            measurements,
+        ));
+    }
+
+    if !provisioning && !settings.influx_server.is_unspecified() {
+        let org = make_static!(String<32>, settings.influx_org.clone());
+        let bucket = make_static!(String<32>, settings.influx_bucket.clone());
+        let token = make_static!(String<96>, settings.influx_token.clone());
+        spawner.must_spawn(influx_task(
+            stack,
+            InfluxSettings {
+                server: SocketAddrV4::new(settings.influx_server, settings.influx_port),
+                transport: settings.influx_transport,
+                org,
+                bucket,
+                token: (!token.is_empty()).then_some(token.as_str()),
+                station: hostname,
+            },
+            measurements,
+        ));
+    }
+
+    let mut normalized = NormalizedMeasurments::default();
     loop {
         info!("Measurments");
 
-        let measurments = bme280.measure(&mut delay).await;
+        if let Some(update) = sampling.take_bme280_update() {
+            let bme280 = sensors.0.driver();
+            let config = update.apply(bme280.config());
+            match bme280.set_config(config).await {
+                Ok(()) => info!("BME280 settings: {}", config),
+                Err(e) => warn!("Failed to change the BME280 settings: {:?}", e),
+            }
+        }
+        let reading = sensors.read_all(sensor_errors).await;
+        let offsets = sampling.offsets();
+        let reading = SourcedReading {
+            pressure: reading
+                .pressure
+                .map(|p| p.map(|p| round_up(to_kpa(p) + offsets.pressure))),
+            humidity: reading
+                .humidity
+                .map(|h| h.map(|h| round_up(h + offsets.humidity))),
+            temperature: reading
+                .temperature
+                .map(|t| t.map(|t| round_up(t + offsets.temperature))),
+        };
+        normalized.update(reading, Instant::now().as_millis());
+
+        data_sender.send(normalized);
+        sampling.wait().await;
+    }
+}
 
-        if let Ok(received_humidity) = humidity_receiver.try_receive()
-           && received_humidity <= 100.0
-        {
-            humidity = round_up(received_humidity);
+/// Looks up a partition by its label, `None` if there's no such partition.
+fn find_partition(flash: &'static TheFlash, label: &str) -> Option<Partition> {
+    match Partition::find(flash, label) {
+        Ok(Some(partition)) => Some(partition),
+        Ok(None) => {
+            warn!("No {} partition, it won't be persisted", label);
+            None
         }
-        // Todo error handling
-        if let Ok(measurments) = measurments {
-            let normalized = NormalizedMeasurments {
-                pressure: round_up(to_kpa(measurments.pressure)),
-                humidity,
-                temperature: round_up(measurments.temperature),
-            };
-
-            data_sender.send(normalized).await;
+        Err(e) => {
+            warn!("Failed to read the partition table: {:?}", e);
+            None
         }
-        Timer::after(INTERVAL).await;
     }
 }
 
@@ -190,18 +461,3 @@ fn round_up(val: f32) -> f32 {
     let shifted = val * 10.0;
     shifted.round() / 10.0
 }
-
-#[embassy_executor::task]
-async fn measure_humidity(mut dht11: Dht, sender: HumiditySender) {
-    let mut delay = Delay;
-    loop {
-        let humidity_and_temp = critical_section::with(|_| dht11.read(&mut delay)).await;
-        Timer::after(HUMIDITY_MEASURMENT_INTERVAL).await;
-        debug!("humidity_and_temp: {}",humidity_and_temp);
-        match humidity_and_temp {
-            Ok(humidity_and_temp) => sender.send(humidity_and_temp.humidity).await,
-          
-            Err(e) => error!("{:?}", e),
-        }
-    }
-}
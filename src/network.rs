@@ -1,3 +1,10 @@
 // the code was taken from examples
+pub mod coap;
 pub mod dhcp;
+pub mod dns;
+pub mod influx;
+pub mod mdns;
+pub mod mqtt;
 pub mod network_tasks;
+pub mod provisioning;
+pub mod station;
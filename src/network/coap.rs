@@ -0,0 +1,610 @@
+// CoAP server exposing the measurements as observable resources
+use defmt::{debug, error, warn};
+use embassy_futures::select::{Either3, select3};
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::{IpEndpoint, Stack};
+use embassy_time::{Duration, Instant, Timer};
+use heapless::Vec;
+
+use packet::{ParseError, Request, Response, Type};
+use resource::{ContentFormat, MAX_REPRESENTATION_SIZE, Resource};
+
+use crate::{DataReceiver, NormalizedMeasurments, TheWatch};
+
+pub mod packet;
+pub mod resource;
+
+pub const PORT: u16 = 5683;
+
+/// How many observations the server keeps, later registrations are answered
+/// without notifications.
+pub const MAX_OBSERVERS: usize = 8;
+
+/// How long a representation stays fresh, an unchanged one is sent again
+/// after half of it so observers don't consider it stale.
+pub const MAX_AGE: Duration = Duration::from_secs(60);
+
+/// Longest time between two confirmable notifications, an observer that
+/// doesn't acknowledge one is dropped (RFC 7641 §4.5).
+pub const CONFIRMABLE_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How long to wait for the acknowledgement of a confirmable notification
+/// before sending it again, doubled after each retransmission (RFC 7252 §4.8).
+const ACK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Retransmissions of a confirmable notification before the observer is
+/// dropped.
+const MAX_RETRANSMIT: u8 = 4;
+
+/// A confirmable notification waiting for its acknowledgement.
+#[derive(Copy, Clone, Debug)]
+struct Retransmission {
+    /// How many times it was sent again.
+    count: u8,
+    timeout: Duration,
+    /// When it's sent again, or the observer dropped after [`MAX_RETRANSMIT`].
+    at: Instant,
+}
+
+/// A client observing a resource.
+struct Observer {
+    endpoint: IpEndpoint,
+    token: Vec<u8, { packet::MAX_TOKEN_LEN }>,
+    resource: Resource,
+    format: ContentFormat,
+    /// Message id of the latest notification, a Reset with it cancels the
+    /// observation.
+    message_id: u16,
+    /// Observe value of the latest notification.
+    sequence: u32,
+    notified: Vec<u8, MAX_REPRESENTATION_SIZE>,
+    notified_at: Instant,
+    /// The next notification sent from then on is confirmable.
+    confirmable_at: Instant,
+    /// The latest notification is confirmable and wasn't acknowledged yet.
+    unacknowledged: Option<Retransmission>,
+}
+
+impl Observer {
+    /// The latest notification, as a message of type `kind`.
+    fn notification(&self, kind: Type) -> Response<'_> {
+        Response {
+            kind,
+            code: packet::CONTENT,
+            message_id: self.message_id,
+            token: &self.token,
+            observe: Some(self.sequence),
+            content_format: Some(self.format as u16),
+            max_age: Some(MAX_AGE.as_secs() as u32),
+            payload: &self.notified,
+        }
+    }
+}
+
+/// Ids of the messages sent by the server and sequence numbers of the
+/// notifications.
+struct Counters {
+    message_id: u16,
+    sequence: u32,
+}
+
+impl Counters {
+    fn next_message_id(&mut self) -> u16 {
+        self.message_id = self.message_id.wrapping_add(1);
+        self.message_id
+    }
+
+    fn next_sequence(&mut self) -> u32 {
+        // Observe values have 24 bits
+        self.sequence = (self.sequence + 1) & 0xFF_FFFF;
+        self.sequence
+    }
+}
+
+/// What the server does with a message.
+#[derive(Debug, PartialEq, Eq, defmt::Format)]
+pub enum Action {
+    Ignore,
+    /// Sends a Reset, e.g. to a CoAP ping.
+    Reset,
+    /// Answers with an error code and no payload.
+    Error(u8),
+    /// Serves `resource`, `observe` tells whether to register (`true`) or
+    /// cancel (`false`) an observation.
+    Serve {
+        resource: Resource,
+        format: ContentFormat,
+        observe: bool,
+    },
+}
+
+/// Decides what to answer to `request`.
+pub fn action(request: &Request) -> Action {
+    match (request.kind, request.code) {
+        (Type::Acknowledgement | Type::Reset, _) => return Action::Ignore,
+        (Type::Confirmable, packet::EMPTY) => return Action::Reset,
+        (_, packet::GET) => {}
+        // Other requests, responses aren't expected
+        (_, 0x02..=0x1F) => return Action::Error(packet::METHOD_NOT_ALLOWED),
+        _ => return Action::Ignore,
+    }
+    let Some(resource) = Resource::from_path(&request.path) else {
+        return Action::Error(packet::NOT_FOUND);
+    };
+    let format = match request.accept {
+        Some(number) => match ContentFormat::from_number(number) {
+            Some(format) => format,
+            None => return Action::Error(packet::NOT_ACCEPTABLE),
+        },
+        None => ContentFormat::default(),
+    };
+    Action::Serve {
+        resource,
+        format,
+        observe: request.observe == Some(0),
+    }
+}
+
+/// The type of the response to a request of type `kind`.
+fn response_kind(kind: Type) -> Type {
+    match kind {
+        Type::Confirmable => Type::Acknowledgement,
+        _ => Type::NonConfirmable,
+    }
+}
+
+/// The CoAP server, answering requests and notifying the observers.
+struct Server {
+    observers: Vec<Observer, MAX_OBSERVERS>,
+    counters: Counters,
+    measurements: NormalizedMeasurments,
+}
+
+impl Server {
+    /// Answers `message` received from `endpoint` at `now`, returns the size
+    /// of the response in `buffer`.
+    fn handle(
+        &mut self,
+        message: &[u8],
+        endpoint: IpEndpoint,
+        now: Instant,
+        buffer: &mut [u8],
+    ) -> Option<usize> {
+        let request = match packet::parse_request(message) {
+            Ok(request) => request,
+            Err(e) => {
+                debug!("Invalid CoAP message: {:?}", e);
+                let code = match e {
+                    ParseError::Invalid => return None,
+                    ParseError::UnsupportedOption => packet::BAD_OPTION,
+                    ParseError::InvalidPath => packet::BAD_REQUEST,
+                };
+                // The message id and token are valid
+                let [first, _, id_high, id_low, ..] = *message else {
+                    return None;
+                };
+                let token = &message[4..4 + usize::from(first & 0x0F)];
+                let kind = response_kind(Type::from_first_byte(first));
+                let message_id = match kind {
+                    Type::Acknowledgement => u16::from_be_bytes([id_high, id_low]),
+                    _ => self.counters.next_message_id(),
+                };
+                return packet::encode_response(
+                    &Response::error(kind, code, message_id, token),
+                    buffer,
+                );
+            }
+        };
+
+        match request.kind {
+            Type::Reset => self.observers.retain(|observer| {
+                observer.endpoint != endpoint || observer.message_id != request.message_id
+            }),
+            Type::Acknowledgement => {
+                for observer in &mut self.observers {
+                    if observer.endpoint == endpoint
+                        && observer.message_id == request.message_id
+                        && observer.unacknowledged.take().is_some()
+                    {
+                        observer.confirmable_at = now + CONFIRMABLE_INTERVAL;
+                    }
+                }
+            }
+            _ => {}
+        }
+        let kind = response_kind(request.kind);
+        let message_id = match kind {
+            Type::Acknowledgement => request.message_id,
+            _ => self.counters.next_message_id(),
+        };
+        match action(&request) {
+            Action::Ignore => None,
+            Action::Reset => packet::encode_response(
+                &Response::error(Type::Reset, packet::EMPTY, request.message_id, &[]),
+                buffer,
+            ),
+            Action::Error(code) => packet::encode_response(
+                &Response::error(kind, code, message_id, request.token),
+                buffer,
+            ),
+            Action::Serve {
+                resource,
+                format,
+                observe,
+            } => {
+                // A new registration or a GET without Observe replaces the
+                // previous observation of the token
+                self.observers.retain(|observer| {
+                    observer.endpoint != endpoint || observer.token != request.token
+                });
+                let payload = resource::represent(resource, format, &self.measurements);
+                let sequence = observe.then(|| self.counters.next_sequence());
+                if let Some(sequence) = sequence {
+                    let observer = Observer {
+                        endpoint,
+                        // At most `MAX_TOKEN_LEN`
+                        token: Vec::from_slice(request.token).unwrap(),
+                        resource,
+                        format,
+                        message_id,
+                        sequence,
+                        notified: payload.clone(),
+                        notified_at: now,
+                        confirmable_at: now + CONFIRMABLE_INTERVAL,
+                        unacknowledged: None,
+                    };
+                    if self.observers.push(observer).is_err() {
+                        warn!("Too many CoAP observers, {} isn't notified", endpoint);
+                    }
+                }
+                let registered = sequence.filter(|_| {
+                    self.observers.iter().any(|observer| {
+                        observer.endpoint == endpoint && observer.token == request.token
+                    })
+                });
+                packet::encode_response(
+                    &Response {
+                        kind,
+                        code: packet::CONTENT,
+                        message_id,
+                        token: request.token,
+                        observe: registered,
+                        content_format: Some(format as u16),
+                        max_age: Some(MAX_AGE.as_secs() as u32),
+                        payload: &payload,
+                    },
+                    buffer,
+                )
+            }
+        }
+    }
+}
+
+impl Server {
+    /// The next notification due at `now`, returns the observer to send it to
+    /// and its size in `buffer`.
+    ///
+    /// Observers that didn't acknowledge a confirmable notification after
+    /// [`MAX_RETRANSMIT`] retransmissions are dropped.
+    fn next_notification(
+        &mut self,
+        now: Instant,
+        buffer: &mut [u8],
+    ) -> Option<(IpEndpoint, usize)> {
+        self.observers.retain(|observer| {
+            !observer
+                .unacknowledged
+                .is_some_and(|sent| sent.count == MAX_RETRANSMIT && now >= sent.at)
+        });
+
+        for observer in &mut self.observers {
+            let kind = match &mut observer.unacknowledged {
+                // Sent again as it was, newer measurements wait for the
+                // acknowledgement
+                Some(sent) if now >= sent.at => {
+                    sent.count += 1;
+                    sent.timeout *= 2;
+                    sent.at = now + sent.timeout;
+                    Type::Confirmable
+                }
+                Some(_) => continue,
+                None => {
+                    let payload =
+                        resource::represent(observer.resource, observer.format, &self.measurements);
+                    if payload == observer.notified && now - observer.notified_at < MAX_AGE / 2 {
+                        continue;
+                    }
+                    observer.message_id = self.counters.next_message_id();
+                    observer.sequence = self.counters.next_sequence();
+                    observer.notified = payload;
+                    observer.notified_at = now;
+                    if now < observer.confirmable_at {
+                        Type::NonConfirmable
+                    } else {
+                        observer.unacknowledged = Some(Retransmission {
+                            count: 0,
+                            timeout: ACK_TIMEOUT,
+                            at: now + ACK_TIMEOUT,
+                        });
+                        Type::Confirmable
+                    }
+                }
+            };
+            // Representations fit in a message
+            let len = packet::encode_response(&observer.notification(kind), buffer)?;
+            return Some((observer.endpoint, len));
+        }
+        None
+    }
+
+    /// When a notification is due next, without new measurements.
+    fn next_deadline(&self) -> Instant {
+        self.observers
+            .iter()
+            .map(|observer| match observer.unacknowledged {
+                Some(sent) => sent.at,
+                None => observer.notified_at + MAX_AGE / 2,
+            })
+            .min()
+            .unwrap_or(Instant::MAX)
+    }
+}
+
+/// Answers GET requests for `/temperature`, `/humidity`, `/pressure` and
+/// `/all` with the latest measurements, as text, JSON or CBOR.
+///
+/// Clients registered with the Observe option are notified with
+/// non-confirmable messages when their representation changes, and at least
+/// every half [`MAX_AGE`]. Answering a notification with a Reset cancels the
+/// observation. At least every [`CONFIRMABLE_INTERVAL`] a notification is
+/// confirmable, observers that don't acknowledge it are dropped.
+#[embassy_executor::task]
+pub async fn run_coap(
+    stack: Stack<'static>,
+    measurements: &'static TheWatch,
+    mut receiver: DataReceiver,
+) {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0; 1024];
+    let mut tx_meta = [PacketMetadata::EMPTY; MAX_OBSERVERS];
+    let mut tx_buffer = [0; 2048];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    if let Err(e) = socket.bind(PORT) {
+        error!("Failed to bind the CoAP server: {:?}", e);
+        return;
+    }
+
+    let mut server = Server {
+        observers: Vec::new(),
+        counters: Counters {
+            // Ids shouldn't repeat across reboots
+            message_id: Instant::now().as_ticks() as u16,
+            sequence: 0,
+        },
+        measurements: measurements.try_get().unwrap_or_default(),
+    };
+    let mut message = [0; packet::MAX_MESSAGE_SIZE];
+    let mut response = [0; packet::MAX_MESSAGE_SIZE];
+    loop {
+        match select3(
+            socket.recv_from(&mut message),
+            receiver.changed(),
+            Timer::at(server.next_deadline()),
+        )
+        .await
+        {
+            Either3::First(Ok((len, meta))) => {
+                if let Some(len) = server.handle(
+                    &message[..len],
+                    meta.endpoint,
+                    Instant::now(),
+                    &mut response,
+                ) && let Err(e) = socket.send_to(&response[..len], meta.endpoint).await
+                {
+                    warn!("Failed to answer a CoAP request: {:?}", e);
+                }
+            }
+            Either3::First(Err(e)) => debug!("Dropped a CoAP message: {:?}", e),
+            Either3::Second(measurements) => server.measurements = measurements,
+            Either3::Third(()) => {}
+        }
+
+        while let Some((endpoint, len)) = server.next_notification(Instant::now(), &mut response) {
+            if let Err(e) = socket.send_to(&response[..len], endpoint).await {
+                warn!("Failed to notify a CoAP observer: {:?}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use embassy_net::IpAddress;
+
+    use super::*;
+    use crate::{Field, Quality};
+
+    const TOKEN: u8 = 0x77;
+
+    fn endpoint() -> IpEndpoint {
+        IpEndpoint::new(IpAddress::v4(192, 168, 1, 10), 40_000)
+    }
+
+    fn measurements(temperature: f32) -> NormalizedMeasurments {
+        NormalizedMeasurments {
+            temperature: Some(Field {
+                value: temperature,
+                sensor: "BME280",
+                measured_at_ms: 0,
+                quality: Quality::Fresh,
+            }),
+            ..Default::default()
+        }
+    }
+
+    /// A server with a client observing `/temperature` since `now`.
+    fn observed(now: Instant) -> Server {
+        let mut server = Server {
+            observers: heapless::Vec::new(),
+            counters: Counters {
+                message_id: 100,
+                sequence: 0,
+            },
+            measurements: measurements(21.5),
+        };
+        let mut register = Vec::from([0x41, packet::GET, 0x00, 0x01, TOKEN, 0x60, 0x5B]);
+        register.extend(b"temperature");
+        let mut buffer = [0; packet::MAX_MESSAGE_SIZE];
+        let len = server
+            .handle(&register, endpoint(), now, &mut buffer)
+            .unwrap();
+
+        let response = packet::parse_request(&buffer[..len]).unwrap();
+        assert_eq!(response.kind, Type::Acknowledgement);
+        assert_eq!(response.observe, Some(1));
+        assert_eq!(server.observers.len(), 1);
+        server
+    }
+
+    /// The notification due at `now`: its type, message id and Observe value.
+    fn notification(server: &mut Server, now: Instant) -> Option<(Type, u16, u32)> {
+        let mut buffer = [0; packet::MAX_MESSAGE_SIZE];
+        let (to, len) = server.next_notification(now, &mut buffer)?;
+        assert_eq!(to, endpoint());
+        let message = packet::parse_request(&buffer[..len]).unwrap();
+        assert_eq!(message.token, [TOKEN]);
+        Some((message.kind, message.message_id, message.observe.unwrap()))
+    }
+
+    /// Answers a notification with an empty message of type `kind`.
+    fn answer(server: &mut Server, kind: Type, message_id: u16, now: Instant) {
+        let [high, low] = message_id.to_be_bytes();
+        let message = [0x40 | (kind as u8) << 4, packet::EMPTY, high, low];
+        assert_eq!(server.handle(&message, endpoint(), now, &mut [0; 64]), None);
+    }
+
+    #[test]
+    fn notifies_changes_without_confirmation() {
+        let start = Instant::from_secs(10);
+        let mut server = observed(start);
+        assert_eq!(notification(&mut server, start), None);
+
+        server.measurements = measurements(22.0);
+        let now = start + Duration::from_secs(1);
+        assert!(matches!(
+            notification(&mut server, now),
+            Some((Type::NonConfirmable, _, 2))
+        ));
+        assert_eq!(notification(&mut server, now), None);
+    }
+
+    #[test]
+    fn notifies_an_unchanged_value_after_half_the_max_age() {
+        let start = Instant::from_secs(10);
+        let mut server = observed(start);
+        assert_eq!(server.next_deadline(), start + MAX_AGE / 2);
+
+        assert!(matches!(
+            notification(&mut server, start + MAX_AGE / 2),
+            Some((Type::NonConfirmable, _, 2))
+        ));
+    }
+
+    #[test]
+    fn confirms_a_notification_every_interval() {
+        let start = Instant::from_secs(10);
+        let mut server = observed(start);
+        server.measurements = measurements(22.0);
+        let now = start + CONFIRMABLE_INTERVAL;
+        let (kind, message_id, _) = notification(&mut server, now).unwrap();
+        assert_eq!(kind, Type::Confirmable);
+
+        // Acknowledged in time, the next ones are non-confirmable for a day
+        let now = now + Duration::from_secs(1);
+        answer(&mut server, Type::Acknowledgement, message_id, now);
+        server.measurements = measurements(22.5);
+        assert!(matches!(
+            notification(&mut server, now),
+            Some((Type::NonConfirmable, ..))
+        ));
+        server.measurements = measurements(23.0);
+        assert!(matches!(
+            notification(&mut server, now + CONFIRMABLE_INTERVAL),
+            Some((Type::Confirmable, ..))
+        ));
+    }
+
+    #[test]
+    fn drops_an_observer_that_does_not_acknowledge() {
+        let start = Instant::from_secs(10);
+        let mut server = observed(start);
+        let now = start + CONFIRMABLE_INTERVAL;
+        let (_, message_id, sequence) = notification(&mut server, now).unwrap();
+        // Another message id doesn't acknowledge it
+        answer(&mut server, Type::Acknowledgement, message_id + 1, now);
+
+        // Sent again as is, after 2, 4, 8 and 16 s
+        let mut retransmitted_at = Vec::new();
+        server.measurements = measurements(22.0);
+        for _ in 0..MAX_RETRANSMIT {
+            let at = server.next_deadline();
+            assert_eq!(
+                notification(&mut server, at - Duration::from_millis(1)),
+                None
+            );
+            assert_eq!(
+                notification(&mut server, at),
+                Some((Type::Confirmable, message_id, sequence))
+            );
+            retransmitted_at.push((at - now).as_secs());
+        }
+        assert_eq!(retransmitted_at, [2, 6, 14, 30]);
+
+        // And dropped when the last one isn't acknowledged either
+        let at = server.next_deadline();
+        assert_eq!((at - now).as_secs(), 62);
+        assert_eq!(notification(&mut server, at), None);
+        assert!(server.observers.is_empty());
+        assert_eq!(server.next_deadline(), Instant::MAX);
+    }
+
+    #[test]
+    fn acknowledging_a_retransmission_keeps_the_observer() {
+        let start = Instant::from_secs(10);
+        let mut server = observed(start);
+        let now = start + CONFIRMABLE_INTERVAL;
+        let (_, message_id, _) = notification(&mut server, now).unwrap();
+        let at = server.next_deadline();
+        notification(&mut server, at).unwrap();
+        answer(&mut server, Type::Acknowledgement, message_id, at);
+
+        // The newer measurements are sent
+        server.measurements = measurements(22.0);
+        assert!(matches!(
+            notification(&mut server, at),
+            Some((Type::NonConfirmable, ..))
+        ));
+        assert_eq!(server.observers.len(), 1);
+    }
+
+    #[test]
+    fn cancels_an_observation_reset_by_the_client() {
+        let start = Instant::from_secs(10);
+        let mut server = observed(start);
+        server.measurements = measurements(22.0);
+        let (_, message_id, _) = notification(&mut server, start).unwrap();
+        answer(&mut server, Type::Reset, message_id, start);
+
+        assert!(server.observers.is_empty());
+    }
+}
@@ -0,0 +1,445 @@
+// Decoding of CoAP requests and encoding of responses (RFC 7252), with the
+// Observe option (RFC 7641)
+use heapless::Vec;
+
+pub const VERSION: u8 = 1;
+
+/// Largest message, fits in any IPv4 path.
+pub const MAX_MESSAGE_SIZE: usize = 512;
+
+/// Longest token.
+pub const MAX_TOKEN_LEN: usize = 8;
+
+/// Most path segments of a request.
+pub const MAX_PATH_SEGMENTS: usize = 4;
+
+pub const GET: u8 = 0x01;
+pub const EMPTY: u8 = 0x00;
+/// 2.05
+pub const CONTENT: u8 = 0x45;
+/// 4.00
+pub const BAD_REQUEST: u8 = 0x80;
+/// 4.02
+pub const BAD_OPTION: u8 = 0x82;
+/// 4.04
+pub const NOT_FOUND: u8 = 0x84;
+/// 4.05
+pub const METHOD_NOT_ALLOWED: u8 = 0x85;
+/// 4.06
+pub const NOT_ACCEPTABLE: u8 = 0x86;
+
+pub const OPTION_OBSERVE: u16 = 6;
+pub const OPTION_URI_PATH: u16 = 11;
+pub const OPTION_CONTENT_FORMAT: u16 = 12;
+pub const OPTION_MAX_AGE: u16 = 14;
+pub const OPTION_ACCEPT: u16 = 17;
+
+/// Type of a message.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, defmt::Format)]
+pub enum Type {
+    Confirmable,
+    NonConfirmable,
+    Acknowledgement,
+    Reset,
+}
+
+impl Type {
+    /// The type in the first byte of a message.
+    pub fn from_first_byte(first: u8) -> Self {
+        match first >> 4 & 0b11 {
+            0 => Type::Confirmable,
+            1 => Type::NonConfirmable,
+            2 => Type::Acknowledgement,
+            _ => Type::Reset,
+        }
+    }
+}
+
+/// Why a message can't be decoded.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, defmt::Format)]
+pub enum ParseError {
+    /// Not a message of [`VERSION`], it's ignored.
+    Invalid,
+    /// A well-formed message the server can't process, it's answered with
+    /// [`BAD_OPTION`] when confirmable.
+    UnsupportedOption,
+    /// Longer path than [`MAX_PATH_SEGMENTS`] or not UTF-8.
+    InvalidPath,
+}
+
+/// A message received by the server, with the options it understands.
+#[derive(Clone, Debug, PartialEq, Eq, defmt::Format)]
+pub struct Request<'a> {
+    pub kind: Type,
+    pub code: u8,
+    pub message_id: u16,
+    pub token: &'a [u8],
+    pub path: Vec<&'a str, MAX_PATH_SEGMENTS>,
+    /// 0 registers an observation, 1 cancels it.
+    pub observe: Option<u32>,
+    pub accept: Option<u16>,
+}
+
+/// Reads an option delta or length, extended by the bytes after the header.
+fn extended(nibble: u8, bytes: &mut &[u8]) -> Result<u16, ParseError> {
+    let take = |bytes: &mut &[u8], n: usize| -> Result<u16, ParseError> {
+        let (value, rest) = bytes.split_at_checked(n).ok_or(ParseError::Invalid)?;
+        *bytes = rest;
+        Ok(value.iter().fold(0, |acc, &b| acc << 8 | u16::from(b)))
+    };
+    match nibble {
+        0..=12 => Ok(nibble.into()),
+        13 => Ok(take(bytes, 1)? + 13),
+        14 => take(bytes, 2)?.checked_add(269).ok_or(ParseError::Invalid),
+        _ => Err(ParseError::Invalid),
+    }
+}
+
+fn decode_uint(value: &[u8]) -> Result<u32, ParseError> {
+    if value.len() > 4 {
+        return Err(ParseError::Invalid);
+    }
+    Ok(value.iter().fold(0, |acc, &b| acc << 8 | u32::from(b)))
+}
+
+/// Decodes a message, requests and empty messages alike.
+pub fn parse_request(message: &[u8]) -> Result<Request<'_>, ParseError> {
+    let [first, code, id_high, id_low, rest @ ..] = message else {
+        return Err(ParseError::Invalid);
+    };
+    let token_len = usize::from(first & 0x0F);
+    if first >> 6 != VERSION || token_len > MAX_TOKEN_LEN {
+        return Err(ParseError::Invalid);
+    }
+    let (token, mut bytes) = rest
+        .split_at_checked(token_len)
+        .ok_or(ParseError::Invalid)?;
+
+    let mut request = Request {
+        kind: Type::from_first_byte(*first),
+        code: *code,
+        message_id: u16::from_be_bytes([*id_high, *id_low]),
+        token,
+        path: Vec::new(),
+        observe: None,
+        accept: None,
+    };
+    let mut number = 0u16;
+    let mut unsupported = false;
+    let mut invalid_path = false;
+    while let Some((&header, rest)) = bytes.split_first() {
+        // The payload marker
+        if header == 0xFF {
+            break;
+        }
+        bytes = rest;
+        let delta = extended(header >> 4, &mut bytes)?;
+        let len = extended(header & 0x0F, &mut bytes)?;
+        number = number.checked_add(delta).ok_or(ParseError::Invalid)?;
+        let (value, rest) = bytes
+            .split_at_checked(len.into())
+            .ok_or(ParseError::Invalid)?;
+        bytes = rest;
+
+        match number {
+            OPTION_OBSERVE => request.observe = Some(decode_uint(value)?),
+            OPTION_URI_PATH => match core::str::from_utf8(value) {
+                Ok(segment) if request.path.push(segment).is_ok() => {}
+                _ => invalid_path = true,
+            },
+            OPTION_ACCEPT => request.accept = Some(decode_uint(value)? as u16),
+            // Unknown critical options can't be ignored
+            number if number & 1 == 1 => unsupported = true,
+            _ => {}
+        }
+    }
+
+    if unsupported {
+        return Err(ParseError::UnsupportedOption);
+    }
+    if invalid_path {
+        return Err(ParseError::InvalidPath);
+    }
+    Ok(request)
+}
+
+/// A message sent by the server.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Response<'a> {
+    pub kind: Type,
+    pub code: u8,
+    pub message_id: u16,
+    pub token: &'a [u8],
+    pub observe: Option<u32>,
+    pub content_format: Option<u16>,
+    pub max_age: Option<u32>,
+    pub payload: &'a [u8],
+}
+
+impl<'a> Response<'a> {
+    /// A response without options nor payload.
+    pub fn error(kind: Type, code: u8, message_id: u16, token: &'a [u8]) -> Self {
+        Self {
+            kind,
+            code,
+            message_id,
+            token,
+            observe: None,
+            content_format: None,
+            max_age: None,
+            payload: &[],
+        }
+    }
+}
+
+struct Writer<'b> {
+    buffer: &'b mut [u8],
+    len: usize,
+    /// Number of the last option written.
+    option: u16,
+}
+
+impl Writer<'_> {
+    fn put(&mut self, bytes: &[u8]) -> Option<()> {
+        self.buffer
+            .get_mut(self.len..self.len + bytes.len())?
+            .copy_from_slice(bytes);
+        self.len += bytes.len();
+        Some(())
+    }
+
+    /// Writes an option, in increasing order of `number`.
+    fn put_option(&mut self, number: u16, value: &[u8]) -> Option<()> {
+        // The nibble and the bytes extending it
+        fn split(value: u16) -> (u8, Vec<u8, 2>) {
+            match value {
+                0..=12 => (value as u8, Vec::new()),
+                13..=268 => (13, Vec::from_slice(&[(value - 13) as u8]).unwrap()),
+                _ => (14, Vec::from_slice(&(value - 269).to_be_bytes()).unwrap()),
+            }
+        }
+        let (delta, delta_ext) = split(number - self.option);
+        let (len, len_ext) = split(value.len() as u16);
+        self.put(&[delta << 4 | len])?;
+        self.put(&delta_ext)?;
+        self.put(&len_ext)?;
+        self.put(value)?;
+        self.option = number;
+        Some(())
+    }
+
+    /// Writes an unsigned option in as few bytes as possible.
+    fn put_uint_option(&mut self, number: u16, value: u32) -> Option<()> {
+        let bytes = value.to_be_bytes();
+        let skip = (value.leading_zeros() / 8) as usize;
+        self.put_option(number, &bytes[skip..])
+    }
+}
+
+/// Encodes `response` into `buffer`, returns its size or `None` if it
+/// doesn't fit.
+pub fn encode_response(response: &Response, buffer: &mut [u8]) -> Option<usize> {
+    if response.token.len() > MAX_TOKEN_LEN {
+        return None;
+    }
+    let mut writer = Writer {
+        buffer,
+        len: 0,
+        option: 0,
+    };
+    writer.put(&[
+        VERSION << 6 | (response.kind as u8) << 4 | response.token.len() as u8,
+        response.code,
+    ])?;
+    writer.put(&response.message_id.to_be_bytes())?;
+    writer.put(response.token)?;
+    if let Some(observe) = response.observe {
+        // The sequence number has 24 bits
+        writer.put_uint_option(OPTION_OBSERVE, observe & 0xFF_FFFF)?;
+    }
+    if let Some(format) = response.content_format {
+        writer.put_uint_option(OPTION_CONTENT_FORMAT, format.into())?;
+    }
+    if let Some(max_age) = response.max_age {
+        writer.put_uint_option(OPTION_MAX_AGE, max_age)?;
+    }
+    if !response.payload.is_empty() {
+        writer.put(&[0xFF])?;
+        writer.put(response.payload)?;
+    }
+    Some(writer.len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_a_request() {
+        // Observe 0, Uri-Path "all" and Accept 50
+        let message = [
+            0x42, GET, 0x12, 0x34, 0xAB, 0xCD, 0x60, 0x53, b'a', b'l', b'l', 0x61, 50,
+        ];
+
+        assert_eq!(
+            parse_request(&message),
+            Ok(Request {
+                kind: Type::Confirmable,
+                code: GET,
+                message_id: 0x1234,
+                token: &[0xAB, 0xCD],
+                path: Vec::from_slice(&["all"]).unwrap(),
+                observe: Some(0),
+                accept: Some(50),
+            })
+        );
+    }
+
+    #[test]
+    fn parses_empty_messages() {
+        let request = parse_request(&[0x60, EMPTY, 0x12, 0x34]).unwrap();
+
+        assert_eq!(request.kind, Type::Acknowledgement);
+        assert_eq!(request.code, EMPTY);
+        assert_eq!(request.message_id, 0x1234);
+        assert!(request.token.is_empty());
+    }
+
+    #[test]
+    fn parses_extended_options() {
+        let mut message = heapless::Vec::<u8, 64>::new();
+        message.extend_from_slice(&[0x50, GET, 0, 1]).unwrap();
+        // A 20 bytes segment, its length extended by a byte
+        message.extend_from_slice(&[0xBD, 20 - 13]).unwrap();
+        message.extend_from_slice(b"abcdefghijklmnopqrst").unwrap();
+        // Elective options 60 and 2000, their delta extended by one and two bytes
+        message.extend_from_slice(&[0xD1, 60 - 11 - 13, 0]).unwrap();
+        let delta = (2000 - 60 - 269u16).to_be_bytes();
+        message
+            .extend_from_slice(&[0xE0, delta[0], delta[1]])
+            .unwrap();
+        // Options end at the payload
+        message.extend_from_slice(&[0xFF, 0x61, 50]).unwrap();
+        let request = parse_request(&message).unwrap();
+
+        assert_eq!(request.kind, Type::NonConfirmable);
+        assert_eq!(request.path, ["abcdefghijklmnopqrst"]);
+        assert_eq!(request.accept, None);
+    }
+
+    #[test]
+    fn rejects_malformed_messages() {
+        for message in [
+            &[0x40, GET, 0][..],
+            // Version 2
+            &[0x80, GET, 0, 1],
+            // Token longer than 8 bytes or cut
+            &[0x49, GET, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            &[0x42, GET, 0, 1, 0xAB],
+            // Reserved nibble, cut option value and extension
+            &[0x40, GET, 0, 1, 0xF0],
+            &[0x40, GET, 0, 1, 0xB3, b'a'],
+            &[0x40, GET, 0, 1, 0xD0],
+            // Observe value longer than 4 bytes
+            &[0x40, GET, 0, 1, 0x65, 0, 0, 0, 0, 1],
+        ] {
+            assert_eq!(parse_request(message), Err(ParseError::Invalid));
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_critical_options() {
+        // If-Match
+        assert_eq!(
+            parse_request(&[0x40, GET, 0, 1, 0x10]),
+            Err(ParseError::UnsupportedOption)
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_paths() {
+        assert_eq!(
+            parse_request(&[0x40, GET, 0, 1, 0xB1, 0xFF]),
+            Err(ParseError::InvalidPath)
+        );
+        let segments = [0xB1, b'a', 0x01, b'b', 0x01, b'c', 0x01, b'd', 0x01, b'e'];
+        let mut message = [0; 14];
+        message[..4].copy_from_slice(&[0x40, GET, 0, 1]);
+        message[4..].copy_from_slice(&segments);
+        assert_eq!(parse_request(&message), Err(ParseError::InvalidPath));
+        assert_eq!(
+            parse_request(&message[..12]).unwrap().path,
+            ["a", "b", "c", "d"]
+        );
+    }
+
+    #[test]
+    fn encodes_a_response() {
+        let response = Response {
+            kind: Type::Acknowledgement,
+            code: CONTENT,
+            message_id: 0x1234,
+            token: &[0xAB],
+            observe: Some(5),
+            content_format: Some(50),
+            max_age: Some(60),
+            payload: b"21.5",
+        };
+        let mut buffer = [0; MAX_MESSAGE_SIZE];
+        let len = encode_response(&response, &mut buffer).unwrap();
+
+        assert_eq!(
+            &buffer[..len],
+            [
+                0x61, CONTENT, 0x12, 0x34, 0xAB, 0x61, 5, 0x61, 50, 0x21, 60, 0xFF, b'2', b'1',
+                b'.', b'5'
+            ]
+        );
+        assert_eq!(encode_response(&response, &mut buffer[..len - 1]), None);
+    }
+
+    #[test]
+    fn encodes_unsigned_options_in_as_few_bytes_as_possible() {
+        let response = Response {
+            observe: Some(0x0123_4567),
+            content_format: Some(0),
+            ..Response::error(Type::NonConfirmable, CONTENT, 1, &[])
+        };
+        let mut buffer = [0; MAX_MESSAGE_SIZE];
+        let len = encode_response(&response, &mut buffer).unwrap();
+
+        // 24 bits of Observe, an empty Content-Format
+        assert_eq!(
+            &buffer[..len],
+            [0x50, CONTENT, 0, 1, 0x63, 0x23, 0x45, 0x67, 0x60]
+        );
+    }
+
+    #[test]
+    fn encodes_extended_options() {
+        let mut buffer = [0; 32];
+        let mut writer = Writer {
+            buffer: &mut buffer,
+            len: 0,
+            option: 0,
+        };
+        writer.put_option(20, &[]).unwrap();
+        writer.put_option(300, &[0xAA; 14]).unwrap();
+
+        // Deltas of 20 and 280
+        assert_eq!(&buffer[..5], [0xD0, 20 - 13, 0xED, 0, 11]);
+        assert_eq!(buffer[5], 14 - 13);
+        assert_eq!(&buffer[6..20], [0xAA; 14]);
+    }
+
+    #[test]
+    fn encodes_errors_and_rejects_long_tokens() {
+        let mut buffer = [0; MAX_MESSAGE_SIZE];
+        let error = Response::error(Type::Acknowledgement, NOT_FOUND, 0x1234, &[1, 2]);
+        let len = encode_response(&error, &mut buffer).unwrap();
+        assert_eq!(&buffer[..len], [0x62, NOT_FOUND, 0x12, 0x34, 1, 2]);
+
+        let long = Response::error(Type::Acknowledgement, NOT_FOUND, 0x1234, &[0; 9]);
+        assert_eq!(encode_response(&long, &mut buffer), None);
+    }
+}
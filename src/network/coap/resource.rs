@@ -0,0 +1,286 @@
+// Resources of the CoAP server and their representations
+use core::fmt::Write;
+
+use heapless::String;
+
+use crate::{Field, NormalizedMeasurments};
+
+/// Longest representation, the one of [`Resource::All`] as JSON.
+pub const MAX_REPRESENTATION_SIZE: usize = 128;
+
+/// Representations the resources are served in, identified by their CoAP
+/// Content-Format number.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, defmt::Format)]
+pub enum ContentFormat {
+    /// `text/plain; charset=utf-8`, the value alone or one `key=value` per line.
+    #[default]
+    Text = 0,
+    Json = 50,
+    Cbor = 60,
+}
+
+impl ContentFormat {
+    /// The format of an Accept option, `None` if it isn't served.
+    pub fn from_number(number: u16) -> Option<Self> {
+        [Self::Text, Self::Json, Self::Cbor]
+            .into_iter()
+            .find(|&format| format as u16 == number)
+    }
+}
+
+/// What the server exposes.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, defmt::Format)]
+pub enum Resource {
+    Temperature,
+    Humidity,
+    Pressure,
+    /// Every quantity.
+    All,
+}
+
+impl Resource {
+    const QUANTITIES: [Resource; 3] = [
+        Resource::Temperature,
+        Resource::Humidity,
+        Resource::Pressure,
+    ];
+
+    /// The resource at `path`.
+    pub fn from_path(path: &[&str]) -> Option<Self> {
+        match path {
+            ["temperature"] => Some(Resource::Temperature),
+            ["humidity"] => Some(Resource::Humidity),
+            ["pressure"] => Some(Resource::Pressure),
+            ["all"] => Some(Resource::All),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Resource::Temperature => "temperature",
+            Resource::Humidity => "humidity",
+            Resource::Pressure => "pressure",
+            Resource::All => "all",
+        }
+    }
+
+    fn field(self, measurements: &NormalizedMeasurments) -> Option<Field> {
+        match self {
+            Resource::Temperature => measurements.temperature,
+            Resource::Humidity => measurements.humidity,
+            Resource::Pressure => measurements.pressure,
+            Resource::All => None,
+        }
+    }
+}
+
+/// The value of `field`, `None` if it's unknown or not finite.
+fn value(field: Option<Field>) -> Option<f32> {
+    field
+        .map(|field| field.value)
+        .filter(|value| value.is_finite())
+}
+
+/// A value or null.
+struct JsonValue(Option<f32>);
+
+impl core::fmt::Display for JsonValue {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self.0 {
+            Some(value) => write!(f, "{}", value),
+            None => f.write_str("null"),
+        }
+    }
+}
+
+/// Appends a CBOR single-precision float, or null.
+fn put_cbor_value(out: &mut heapless::Vec<u8, MAX_REPRESENTATION_SIZE>, value: Option<f32>) {
+    // The representation of all the quantities takes 47 bytes
+    match value {
+        Some(value) => {
+            out.push(0xFA).unwrap();
+            out.extend_from_slice(&value.to_be_bytes()).unwrap();
+        }
+        None => out.push(0xF6).unwrap(),
+    }
+}
+
+/// The representation of `resource` in `format`.
+///
+/// Unknown values are `null` in JSON and CBOR, and empty in text.
+pub fn represent(
+    resource: Resource,
+    format: ContentFormat,
+    measurements: &NormalizedMeasurments,
+) -> heapless::Vec<u8, MAX_REPRESENTATION_SIZE> {
+    let mut out = heapless::Vec::new();
+    match format {
+        ContentFormat::Text | ContentFormat::Json => {
+            let mut text = String::<MAX_REPRESENTATION_SIZE>::new();
+            // Three keys and values of at most 16 bytes each fit
+            match (resource, format) {
+                (Resource::All, ContentFormat::Json) => {
+                    text.push('{').unwrap();
+                    for (i, quantity) in Resource::QUANTITIES.into_iter().enumerate() {
+                        write!(
+                            text,
+                            r#"{}"{}":{}"#,
+                            if i == 0 { "" } else { "," },
+                            quantity.as_str(),
+                            JsonValue(value(quantity.field(measurements)))
+                        )
+                        .unwrap();
+                    }
+                    text.push('}').unwrap();
+                }
+                (Resource::All, _) => {
+                    for quantity in Resource::QUANTITIES {
+                        if let Some(value) = value(quantity.field(measurements)) {
+                            writeln!(text, "{}={}", quantity.as_str(), value).unwrap();
+                        }
+                    }
+                }
+                (_, ContentFormat::Json) => {
+                    write!(text, "{}", JsonValue(value(resource.field(measurements)))).unwrap()
+                }
+                _ => {
+                    if let Some(value) = value(resource.field(measurements)) {
+                        write!(text, "{}", value).unwrap();
+                    }
+                }
+            }
+            out.extend_from_slice(text.as_bytes()).unwrap();
+        }
+        ContentFormat::Cbor => match resource {
+            Resource::All => {
+                // A map of 3 pairs
+                out.push(0xA3).unwrap();
+                for quantity in Resource::QUANTITIES {
+                    let key = quantity.as_str().as_bytes();
+                    // A text string of less than 24 bytes
+                    out.push(0x60 | key.len() as u8).unwrap();
+                    out.extend_from_slice(key).unwrap();
+                    put_cbor_value(&mut out, value(quantity.field(measurements)));
+                }
+            }
+            _ => put_cbor_value(&mut out, value(resource.field(measurements))),
+        },
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Quality;
+
+    fn field(value: f32) -> Option<Field> {
+        Some(Field {
+            value,
+            sensor: "BME280",
+            measured_at_ms: 0,
+            quality: Quality::Fresh,
+        })
+    }
+
+    fn measurements() -> NormalizedMeasurments {
+        NormalizedMeasurments {
+            timestamp_ms: 0,
+            temperature: field(21.5),
+            humidity: field(40.0),
+            pressure: None,
+        }
+    }
+
+    fn represent(resource: Resource, format: ContentFormat) -> heapless::Vec<u8, 128> {
+        super::represent(resource, format, &measurements())
+    }
+
+    #[test]
+    fn finds_resources_and_formats() {
+        assert_eq!(Resource::from_path(&["humidity"]), Some(Resource::Humidity));
+        assert_eq!(Resource::from_path(&["all"]), Some(Resource::All));
+        assert_eq!(Resource::from_path(&[]), None);
+        assert_eq!(Resource::from_path(&["all", "temperature"]), None);
+        assert_eq!(ContentFormat::from_number(60), Some(ContentFormat::Cbor));
+        assert_eq!(ContentFormat::from_number(40), None);
+    }
+
+    #[test]
+    fn represents_values_as_text() {
+        assert_eq!(
+            represent(Resource::Temperature, ContentFormat::Text),
+            b"21.5"
+        );
+        assert_eq!(represent(Resource::Pressure, ContentFormat::Text), b"");
+        assert_eq!(
+            represent(Resource::All, ContentFormat::Text),
+            b"temperature=21.5\nhumidity=40\n"
+        );
+    }
+
+    #[test]
+    fn represents_values_as_json() {
+        assert_eq!(represent(Resource::Humidity, ContentFormat::Json), b"40");
+        assert_eq!(represent(Resource::Pressure, ContentFormat::Json), b"null");
+        assert_eq!(
+            represent(Resource::All, ContentFormat::Json),
+            br#"{"temperature":21.5,"humidity":40,"pressure":null}"#
+        );
+    }
+
+    #[test]
+    fn represents_values_as_cbor() {
+        assert_eq!(
+            represent(Resource::Temperature, ContentFormat::Cbor),
+            [0xFA, 0x41, 0xAC, 0x00, 0x00]
+        );
+        assert_eq!(represent(Resource::Pressure, ContentFormat::Cbor), [0xF6]);
+
+        let mut all = heapless::Vec::<u8, 64>::new();
+        all.extend_from_slice(b"\xA3\x6Btemperature\xFA\x41\xAC\x00\x00")
+            .unwrap();
+        all.extend_from_slice(b"\x68humidity\xFA\x42\x20\x00\x00")
+            .unwrap();
+        all.extend_from_slice(b"\x68pressure\xF6").unwrap();
+        assert_eq!(represent(Resource::All, ContentFormat::Cbor), all);
+    }
+
+    #[test]
+    fn leaves_out_values_that_are_not_finite() {
+        let measurements = NormalizedMeasurments {
+            temperature: field(f32::NAN),
+            humidity: field(f32::INFINITY),
+            ..measurements()
+        };
+
+        assert_eq!(
+            super::represent(Resource::All, ContentFormat::Json, &measurements),
+            br#"{"temperature":null,"humidity":null,"pressure":null}"#
+        );
+        assert_eq!(
+            super::represent(Resource::All, ContentFormat::Text, &measurements),
+            b""
+        );
+    }
+
+    #[test]
+    fn fits_long_values() {
+        let measurements = NormalizedMeasurments {
+            temperature: field(-0.000_012_345_678),
+            humidity: field(-0.000_012_345_678),
+            pressure: field(-0.000_012_345_678),
+            ..measurements()
+        };
+
+        // Overflowing the representation panics
+        for format in [
+            ContentFormat::Text,
+            ContentFormat::Json,
+            ContentFormat::Cbor,
+        ] {
+            super::represent(Resource::All, format, &measurements);
+        }
+    }
+}
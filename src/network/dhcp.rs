@@ -1,44 +1,124 @@
-use core::str::FromStr;
+use core::cell::RefCell;
+use core::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
 
+use defmt::{Debug2Format, debug, error, info, warn};
+use edge_dhcp::server::{Action, ServerOptions};
+use edge_dhcp::{Options, Packet};
+use edge_nal::{UdpBind, UdpReceive, UdpSend};
+use edge_nal_embassy::{Udp, UdpBuffers};
 use embassy_net::Stack;
-use embassy_time::{Duration, Timer};
+use embassy_sync::blocking_mutex::{Mutex, raw::NoopRawMutex};
+use embassy_time::{Duration, Instant, Timer};
 
-#[embassy_executor::task]
-pub async fn run_dhcp(stack: Stack<'static>, gw_ip_addr: &'static str) {
-    use core::net::{Ipv4Addr, SocketAddrV4};
+use leases::Leases;
 
-    use edge_dhcp::{
-        io::{self, DEFAULT_SERVER_PORT},
-        server::{Server, ServerOptions},
-    };
-    use edge_nal::UdpBind;
-    use edge_nal_embassy::{Udp, UdpBuffers};
+pub mod leases;
+
+/// The leases of the DHCP server, shared with the web server.
+pub type TheLeases = Mutex<NoopRawMutex, RefCell<Leases>>;
 
-    let ip = Ipv4Addr::from_str(gw_ip_addr).expect("dhcp task failed to parse gw ip");
+/// Delay before receiving again after a socket error.
+const RETRY_DELAY: Duration = Duration::from_millis(500);
 
+/// Leases addresses of the pool of `leases` to the access point clients, `ip`
+/// is their gateway and DNS server, see [`super::dns::run_dns`].
+#[embassy_executor::task]
+pub async fn run_dhcp(stack: Stack<'static>, ip: Ipv4Addr, leases: &'static TheLeases) {
     let mut buf = [0u8; 1500];
 
     let mut gw_buf = [Ipv4Addr::UNSPECIFIED];
+    let dns = [ip];
 
     let buffers = UdpBuffers::<3, 1024, 1024, 10>::new();
     let unbound_socket = Udp::new(stack, &buffers);
-    let mut bound_socket = unbound_socket
-        .bind(core::net::SocketAddr::V4(SocketAddrV4::new(
+    let mut socket = match unbound_socket
+        .bind(SocketAddr::V4(SocketAddrV4::new(
             Ipv4Addr::UNSPECIFIED,
-            DEFAULT_SERVER_PORT,
+            edge_dhcp::io::DEFAULT_SERVER_PORT,
         )))
         .await
-        .unwrap();
+    {
+        Ok(socket) => socket,
+        Err(e) => {
+            error!("Failed to bind the DHCP server: {:?}", e);
+            return;
+        }
+    };
+
+    let mut options = ServerOptions::new(ip, Some(&mut gw_buf));
+    options.dns = &dns;
+    options.lease_duration_secs = leases.lock(|leases| leases.borrow().pool().lease_secs);
+    info!(
+        "Running the DHCP server for {:?}",
+        leases.lock(|leases| *leases.borrow().pool())
+    );
 
     loop {
-        _ = io::server::run(
-            &mut Server::<_, 64>::new_with_et(ip),
-            &ServerOptions::new(ip, Some(&mut gw_buf)),
-            &mut bound_socket,
-            &mut buf,
-        )
-        .await
-        .inspect_err(|e| defmt::warn!("DHCP server error: {:?}", e));
-        Timer::after(Duration::from_millis(500)).await;
+        let (len, remote) = match socket.receive(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                warn!("DHCP server error: {:?}", e);
+                Timer::after(RETRY_DELAY).await;
+                continue;
+            }
+        };
+        let request = match Packet::decode(&buf[..len]) {
+            Ok(request) => request,
+            Err(e) => {
+                debug!("Invalid DHCP request: {:?}", Debug2Format(&e));
+                continue;
+            }
+        };
+
+        let mut opt_buf = Options::buf();
+        let Some(reply) = leases.lock(|leases| {
+            let mut leases = leases.borrow_mut();
+            let now_s = Instant::now().as_secs();
+            match options.process(&request)? {
+                Action::Discover(requested, mac) => {
+                    let ip = leases.offer(hardware_address(mac), requested, now_s)?;
+                    Some(options.offer(&request, ip, &mut opt_buf))
+                }
+                Action::Request(ip, mac) => {
+                    let acknowledged = leases.acknowledge(hardware_address(mac), ip, now_s);
+                    if acknowledged {
+                        info!("Leased {} to {:02x}", ip, hardware_address(mac));
+                    }
+                    Some(options.ack_nak(&request, acknowledged.then_some(ip), &mut opt_buf))
+                }
+                Action::Release(_, mac) | Action::Decline(_, mac) => {
+                    leases.release(hardware_address(mac));
+                    None
+                }
+            }
+        }) else {
+            continue;
+        };
+
+        // Clients without an address yet can only receive broadcasts
+        let remote = match remote {
+            SocketAddr::V4(remote) if request.broadcast || remote.ip().is_unspecified() => {
+                SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::BROADCAST, remote.port()))
+            }
+            remote => remote,
+        };
+        let mut reply_buf = [0u8; 1500];
+        let result = match reply.encode(&mut reply_buf) {
+            Ok(reply) => socket.send(remote, reply).await,
+            Err(e) => {
+                warn!("Failed to encode a DHCP reply: {:?}", Debug2Format(&e));
+                continue;
+            }
+        };
+        if let Err(e) = result {
+            warn!("Failed to send a DHCP reply: {:?}", e);
+        }
     }
 }
+
+/// The Ethernet address in the client hardware address field.
+fn hardware_address(chaddr: &[u8; 16]) -> [u8; 6] {
+    let mut mac = [0; 6];
+    mac.copy_from_slice(&chaddr[..6]);
+    mac
+}
@@ -0,0 +1,295 @@
+// Addresses leased by the DHCP server
+use core::net::Ipv4Addr;
+
+use heapless::Vec;
+
+use crate::config::{Config, DHCP_MAX_LEASES};
+
+/// How many leases the table can hold.
+pub const CAPACITY: usize = *DHCP_MAX_LEASES.end() as usize;
+
+/// Addresses the server leases and for how long.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, defmt::Format)]
+pub struct Pool {
+    pub first: Ipv4Addr,
+    pub last: Ipv4Addr,
+    pub lease_secs: u32,
+    /// At most [`CAPACITY`].
+    pub max_leases: usize,
+}
+
+impl Pool {
+    /// The pool in the subnet of the station's address.
+    pub fn from_config(config: &Config) -> Self {
+        let [a, b, c, _] = config.address.octets();
+        Self {
+            first: Ipv4Addr::new(a, b, c, config.dhcp_first_host),
+            last: Ipv4Addr::new(a, b, c, config.dhcp_last_host),
+            lease_secs: config.dhcp_lease_secs,
+            max_leases: usize::from(config.dhcp_max_leases).min(CAPACITY),
+        }
+    }
+
+    fn contains(&self, ip: Ipv4Addr) -> bool {
+        (self.first..=self.last).contains(&ip)
+    }
+}
+
+/// An address leased to a client.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, defmt::Format)]
+pub struct Lease {
+    pub mac: [u8; 6],
+    pub ip: Ipv4Addr,
+    /// Seconds since boot.
+    pub expires_s: u64,
+}
+
+impl Lease {
+    pub fn is_expired(&self, now_s: u64) -> bool {
+        self.expires_s <= now_s
+    }
+}
+
+/// The leases of a [`Pool`].
+///
+/// Expired leases are kept until their address or room is needed, so a client
+/// coming back gets the same address.
+#[derive(Clone, Debug)]
+pub struct Leases {
+    pool: Pool,
+    leases: Vec<Lease, CAPACITY>,
+}
+
+impl Leases {
+    pub const fn new(pool: Pool) -> Self {
+        Self {
+            pool,
+            leases: Vec::new(),
+        }
+    }
+
+    pub fn pool(&self) -> &Pool {
+        &self.pool
+    }
+
+    /// The leases that didn't expire at `now_s`.
+    pub fn active(&self, now_s: u64) -> impl Iterator<Item = &Lease> {
+        self.leases
+            .iter()
+            .filter(move |lease| !lease.is_expired(now_s))
+    }
+
+    /// The address to offer to `mac`, the `requested` one if it's available.
+    pub fn offer(&self, mac: [u8; 6], requested: Option<Ipv4Addr>, now_s: u64) -> Option<Ipv4Addr> {
+        if let Some(ip) = requested.filter(|&ip| self.is_available(mac, ip, now_s)) {
+            return Some(ip);
+        }
+        if let Some(lease) = self.leases.iter().find(|lease| lease.mac == mac) {
+            return Some(lease.ip);
+        }
+        let has_room = self.leases.len() < self.pool.max_leases
+            || self.leases.iter().any(|lease| lease.is_expired(now_s));
+        if !has_room {
+            return None;
+        }
+        (u32::from(self.pool.first)..=u32::from(self.pool.last))
+            .map(Ipv4Addr::from)
+            .find(|&ip| self.is_available(mac, ip, now_s))
+    }
+
+    /// Leases `ip` to `mac` if it's available, replacing its previous lease.
+    pub fn acknowledge(&mut self, mac: [u8; 6], ip: Ipv4Addr, now_s: u64) -> bool {
+        if !self.is_available(mac, ip, now_s) {
+            return false;
+        }
+        // The lease of the client, or an expired one of the address
+        self.leases
+            .retain(|lease| lease.mac != mac && lease.ip != ip);
+        if self.leases.len() >= self.pool.max_leases {
+            let Some(expired) = self.leases.iter().position(|lease| lease.is_expired(now_s)) else {
+                return false;
+            };
+            self.leases.swap_remove(expired);
+        }
+        self.leases
+            .push(Lease {
+                mac,
+                ip,
+                expires_s: now_s + u64::from(self.pool.lease_secs),
+            })
+            .is_ok()
+    }
+
+    /// Removes the lease of `mac`.
+    pub fn release(&mut self, mac: [u8; 6]) {
+        self.leases.retain(|lease| lease.mac != mac);
+    }
+
+    fn is_available(&self, mac: [u8; 6], ip: Ipv4Addr, now_s: u64) -> bool {
+        self.pool.contains(ip)
+            && self
+                .leases
+                .iter()
+                .all(|lease| lease.ip != ip || lease.mac == mac || lease.is_expired(now_s))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::*;
+
+    const A: [u8; 6] = [2, 0, 0, 0, 0, 0xA];
+    const B: [u8; 6] = [2, 0, 0, 0, 0, 0xB];
+    const C: [u8; 6] = [2, 0, 0, 0, 0, 0xC];
+
+    /// Leases of a minute.
+    const LEASE_SECS: u32 = 60;
+
+    fn ip(host: u8) -> Ipv4Addr {
+        Ipv4Addr::new(192, 168, 4, host)
+    }
+
+    /// Hosts 10 to 12, at most `max_leases` of them.
+    fn leases(max_leases: usize) -> Leases {
+        Leases::new(Pool {
+            first: ip(10),
+            last: ip(12),
+            lease_secs: LEASE_SECS,
+            max_leases,
+        })
+    }
+
+    /// Offers an address to `mac` and acknowledges it.
+    fn lease(leases: &mut Leases, mac: [u8; 6], now_s: u64) -> Ipv4Addr {
+        let ip = leases.offer(mac, None, now_s).unwrap();
+        assert!(leases.acknowledge(mac, ip, now_s));
+        ip
+    }
+
+    fn active(leases: &Leases, now_s: u64) -> Vec<([u8; 6], Ipv4Addr)> {
+        leases
+            .active(now_s)
+            .map(|lease| (lease.mac, lease.ip))
+            .collect()
+    }
+
+    #[test]
+    fn leases_the_first_free_addresses() {
+        let mut leases = leases(3);
+
+        assert_eq!(lease(&mut leases, A, 0), ip(10));
+        assert_eq!(lease(&mut leases, B, 0), ip(11));
+        assert_eq!(active(&leases, 0), [(A, ip(10)), (B, ip(11))]);
+        assert_eq!(
+            leases.active(0).next().unwrap().expires_s,
+            u64::from(LEASE_SECS)
+        );
+    }
+
+    #[test]
+    fn a_returning_client_keeps_its_address() {
+        let mut leases = leases(3);
+        lease(&mut leases, A, 0);
+        lease(&mut leases, B, 0);
+
+        // Even once its lease expired
+        assert_eq!(leases.offer(A, None, 10), Some(ip(10)));
+        assert_eq!(leases.offer(A, None, 1000), Some(ip(10)));
+        assert!(leases.acknowledge(A, ip(10), 1000));
+        assert_eq!(active(&leases, 1000), [(A, ip(10))]);
+    }
+
+    #[test]
+    fn honours_an_available_requested_address() {
+        let mut leases = leases(3);
+        lease(&mut leases, A, 0);
+
+        assert_eq!(leases.offer(B, Some(ip(12)), 0), Some(ip(12)));
+        // Leased to another client or outside the pool
+        assert_eq!(leases.offer(B, Some(ip(10)), 0), Some(ip(11)));
+        assert_eq!(leases.offer(B, Some(ip(13)), 0), Some(ip(11)));
+        assert!(!leases.acknowledge(B, ip(10), 0));
+        assert!(!leases.acknowledge(B, ip(13), 0));
+        // The address of an expired lease
+        assert_eq!(leases.offer(B, Some(ip(10)), 60), Some(ip(10)));
+    }
+
+    #[test]
+    fn runs_out_of_addresses() {
+        let mut leases = leases(CAPACITY);
+        for mac in [A, B, C] {
+            lease(&mut leases, mac, 0);
+        }
+
+        let other = [2, 0, 0, 0, 0, 0xD];
+        assert_eq!(leases.offer(other, None, 0), None);
+        assert!(!leases.acknowledge(other, ip(12), 0));
+    }
+
+    #[test]
+    fn limits_the_leases() {
+        let mut leases = leases(2);
+        lease(&mut leases, A, 0);
+        lease(&mut leases, B, 0);
+
+        // Host 12 is free but the table is full
+        assert_eq!(leases.offer(C, None, 0), None);
+        assert_eq!(leases.offer(C, Some(ip(12)), 0), Some(ip(12)));
+        assert!(!leases.acknowledge(C, ip(12), 0));
+        assert_eq!(active(&leases, 0), [(A, ip(10)), (B, ip(11))]);
+    }
+
+    #[test]
+    fn reuses_expired_leases() {
+        let mut leases = leases(2);
+        lease(&mut leases, A, 0);
+        lease(&mut leases, B, 30);
+
+        // The lease of A expired, its address is the first free one
+        assert_eq!(lease(&mut leases, C, 60), ip(10));
+        assert_eq!(active(&leases, 60), [(B, ip(11)), (C, ip(10))]);
+        assert_eq!(leases.offer(A, None, 60), None);
+    }
+
+    #[test]
+    fn makes_room_by_dropping_an_expired_lease() {
+        let mut leases = leases(2);
+        lease(&mut leases, A, 0);
+        lease(&mut leases, B, 30);
+
+        // Host 12 is free, the expired lease of A makes room for it
+        assert!(leases.acknowledge(C, ip(12), 60));
+        assert_eq!(active(&leases, 60), [(B, ip(11)), (C, ip(12))]);
+        // A lost its lease and the table is full again
+        assert_eq!(leases.offer(A, None, 60), None);
+    }
+
+    #[test]
+    fn replaces_the_previous_lease_of_a_client() {
+        let mut leases = leases(2);
+        lease(&mut leases, A, 0);
+
+        assert!(leases.acknowledge(A, ip(12), 10));
+        assert_eq!(active(&leases, 10), [(A, ip(12))]);
+        // Renewing keeps a single lease
+        assert!(leases.acknowledge(A, ip(12), 20));
+        assert_eq!(active(&leases, 20), [(A, ip(12))]);
+        assert_eq!(leases.active(20).next().unwrap().expires_s, 80);
+        assert_eq!(lease(&mut leases, B, 20), ip(10));
+    }
+
+    #[test]
+    fn releases_the_address() {
+        let mut leases = leases(3);
+        lease(&mut leases, A, 0);
+        lease(&mut leases, B, 0);
+        leases.release(A);
+
+        assert_eq!(active(&leases, 0), [(B, ip(11))]);
+        assert_eq!(lease(&mut leases, C, 0), ip(10));
+    }
+}
@@ -0,0 +1,50 @@
+// Captive-portal DNS server, every name resolves to the station
+use core::net::Ipv4Addr;
+
+use defmt::{debug, error, warn};
+use embassy_net::Stack;
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+
+pub mod packet;
+
+pub const PORT: u16 = 53;
+
+/// Answers the A queries of the access point clients with `ip`, so they reach
+/// the station whatever name they look up.
+#[embassy_executor::task]
+pub async fn run_dns(stack: Stack<'static>, ip: Ipv4Addr) {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0; 1024];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buffer = [0; 1024];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    if let Err(e) = socket.bind(PORT) {
+        error!("Failed to bind the DNS server: {:?}", e);
+        return;
+    }
+
+    let mut query = [0; packet::MAX_MESSAGE_SIZE];
+    let mut response = [0; packet::MAX_MESSAGE_SIZE];
+    loop {
+        let (len, meta) = match socket.recv_from(&mut query).await {
+            Ok(received) => received,
+            // Longer than a message without EDNS, the client retries without it
+            Err(e) => {
+                debug!("Dropped a DNS query: {:?}", e);
+                continue;
+            }
+        };
+        let Some(len) = packet::respond(&query[..len], ip, &mut response) else {
+            continue;
+        };
+        if let Err(e) = socket.send_to(&response[..len], meta.endpoint).await {
+            warn!("Failed to answer a DNS query: {:?}", e);
+        }
+    }
+}
@@ -0,0 +1,401 @@
+// Parsing of DNS queries and encoding of the responses, see RFC 1035
+use core::net::Ipv4Addr;
+
+/// Size of the message header.
+pub const HEADER_SIZE: usize = 12;
+
+/// Longest message over UDP without EDNS.
+pub const MAX_MESSAGE_SIZE: usize = 512;
+
+/// How long clients may cache an answer, in seconds.
+pub const TTL: u32 = 60;
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_ANY: u16 = 255;
+pub const CLASS_IN: u16 = 1;
+
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_AUTHORITATIVE: u16 = 0x0400;
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+const OPCODE_MASK: u16 = 0x7800;
+
+/// Pointer to the name of the question, which follows the header.
+const QUESTION_NAME_POINTER: [u8; 2] = [0xC0, HEADER_SIZE as u8];
+
+/// Response code of a message.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, defmt::Format)]
+#[repr(u8)]
+pub enum ResponseCode {
+    NoError = 0,
+    FormatError = 1,
+    NotImplemented = 4,
+}
+
+/// The question of a query.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Question<'a> {
+    /// The name as it's encoded in the message, a sequence of labels.
+    pub name: &'a [u8],
+    pub qtype: u16,
+    pub qclass: u16,
+}
+
+/// A query, with a single question.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Query<'a> {
+    pub id: u16,
+    /// Header flags, copied to the response.
+    pub flags: u16,
+    pub question: Question<'a>,
+}
+
+/// Why a message can't be answered.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, defmt::Format)]
+pub enum ParseError {
+    /// Shorter than a header, or a response, it's ignored.
+    NotAQuery,
+    /// A query that can't be answered, the client gets an error with `id`.
+    Invalid {
+        id: u16,
+        flags: u16,
+        code: ResponseCode,
+    },
+}
+
+/// Parses a query holding a single question.
+pub fn parse_query(message: &[u8]) -> Result<Query<'_>, ParseError> {
+    let Some((header, body)) = message.split_first_chunk::<HEADER_SIZE>() else {
+        return Err(ParseError::NotAQuery);
+    };
+    let id = u16::from_be_bytes([header[0], header[1]]);
+    let flags = u16::from_be_bytes([header[2], header[3]]);
+    let questions = u16::from_be_bytes([header[4], header[5]]);
+    if flags & FLAG_RESPONSE != 0 {
+        return Err(ParseError::NotAQuery);
+    }
+    let invalid = |code| ParseError::Invalid { id, flags, code };
+    // Only standard queries are supported
+    if flags & OPCODE_MASK != 0 {
+        return Err(invalid(ResponseCode::NotImplemented));
+    }
+    if questions != 1 {
+        return Err(invalid(ResponseCode::FormatError));
+    }
+
+    // Labels are prefixed by their length and end with an empty one, queries
+    // don't use compression
+    let mut end = 0;
+    loop {
+        let Some(&len) = body.get(end) else {
+            return Err(invalid(ResponseCode::FormatError));
+        };
+        end += 1;
+        if len == 0 {
+            break;
+        }
+        if len > 63 {
+            return Err(invalid(ResponseCode::FormatError));
+        }
+        end += len as usize;
+    }
+    if end > 255 {
+        return Err(invalid(ResponseCode::FormatError));
+    }
+    let Some([t0, t1, c0, c1]) = body.get(end..end + 4) else {
+        return Err(invalid(ResponseCode::FormatError));
+    };
+
+    Ok(Query {
+        id,
+        flags,
+        question: Question {
+            name: &body[..end],
+            qtype: u16::from_be_bytes([*t0, *t1]),
+            qclass: u16::from_be_bytes([*c0, *c1]),
+        },
+    })
+}
+
+/// Encodes the response to `query` into `buffer`, returns its size.
+///
+/// A and ANY questions of the internet class are answered with `address`,
+/// other ones get an empty answer. `None` if the buffer is too small.
+pub fn encode_response(query: &Query, address: Ipv4Addr, buffer: &mut [u8]) -> Option<usize> {
+    let question = query.question;
+    let answered = question.qclass == CLASS_IN && matches!(question.qtype, TYPE_A | TYPE_ANY);
+
+    let mut writer = Writer::new(buffer);
+    writer.header(
+        query.id,
+        query.flags,
+        ResponseCode::NoError,
+        1,
+        answered.into(),
+    )?;
+    writer.put(question.name)?;
+    writer.put(&question.qtype.to_be_bytes())?;
+    writer.put(&question.qclass.to_be_bytes())?;
+    if answered {
+        writer.put(&QUESTION_NAME_POINTER)?;
+        writer.put(&TYPE_A.to_be_bytes())?;
+        writer.put(&CLASS_IN.to_be_bytes())?;
+        writer.put(&TTL.to_be_bytes())?;
+        writer.put(&4u16.to_be_bytes())?;
+        writer.put(&address.octets())?;
+    }
+    Some(writer.len())
+}
+
+/// Encodes an error response without the question into `buffer`, returns its size.
+pub fn encode_error(id: u16, flags: u16, code: ResponseCode, buffer: &mut [u8]) -> Option<usize> {
+    let mut writer = Writer::new(buffer);
+    writer.header(id, flags, code, 0, 0)?;
+    Some(writer.len())
+}
+
+/// Encodes the response to `message`, `None` if there's nothing to answer.
+pub fn respond(message: &[u8], address: Ipv4Addr, buffer: &mut [u8]) -> Option<usize> {
+    match parse_query(message) {
+        Ok(query) => encode_response(&query, address, buffer),
+        Err(ParseError::Invalid { id, flags, code }) => encode_error(id, flags, code, buffer),
+        Err(ParseError::NotAQuery) => None,
+    }
+}
+
+/// Writes a message into a buffer, every method returns `None` when it's full.
+pub(crate) struct Writer<'b> {
+    buffer: &'b mut [u8],
+    len: usize,
+}
+
+impl<'b> Writer<'b> {
+    pub(crate) fn new(buffer: &'b mut [u8]) -> Self {
+        Self { buffer, len: 0 }
+    }
+
+    /// Size of the message written so far.
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn put(&mut self, bytes: &[u8]) -> Option<()> {
+        self.buffer
+            .get_mut(self.len..self.len + bytes.len())?
+            .copy_from_slice(bytes);
+        self.len += bytes.len();
+        Some(())
+    }
+
+    /// Overwrites the bytes written at `offset`.
+    pub(crate) fn set(&mut self, offset: usize, bytes: &[u8]) -> Option<()> {
+        self.buffer
+            .get_mut(offset..offset + bytes.len())?
+            .copy_from_slice(bytes);
+        Some(())
+    }
+
+    /// Writes the name made of the dot separated `parts`, uncompressed.
+    pub(crate) fn put_name(&mut self, parts: &[&str]) -> Option<()> {
+        for label in parts.iter().flat_map(|part| part.split('.')) {
+            self.put(&[label.len() as u8])?;
+            self.put(label.as_bytes())?;
+        }
+        self.put(&[0])
+    }
+
+    fn header(
+        &mut self,
+        id: u16,
+        query_flags: u16,
+        code: ResponseCode,
+        questions: u16,
+        answers: u16,
+    ) -> Option<()> {
+        let flags = FLAG_RESPONSE
+            | FLAG_AUTHORITATIVE
+            | (query_flags & (OPCODE_MASK | FLAG_RECURSION_DESIRED))
+            | code as u16;
+        self.put(&id.to_be_bytes())?;
+        self.put(&flags.to_be_bytes())?;
+        self.put(&questions.to_be_bytes())?;
+        self.put(&answers.to_be_bytes())?;
+        // No authority nor additional records
+        self.put(&[0; 4])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::*;
+
+    const NAME: &[u8] = b"\x07station\x05local\x00";
+    const ADDRESS: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 1);
+
+    /// A query with recursion desired, `body` holds the questions.
+    fn query(questions: u16, body: &[u8]) -> Vec<u8> {
+        let mut message = Vec::from([0x12, 0x34, 0x01, 0x00]);
+        message.extend(questions.to_be_bytes());
+        message.extend([0; 6]);
+        message.extend(body);
+        message
+    }
+
+    fn question(name: &[u8], qtype: u16) -> Vec<u8> {
+        let mut question = Vec::from(name);
+        question.extend(qtype.to_be_bytes());
+        question.extend(CLASS_IN.to_be_bytes());
+        question
+    }
+
+    fn format_error() -> Result<Query<'static>, ParseError> {
+        Err(ParseError::Invalid {
+            id: 0x1234,
+            flags: 0x0100,
+            code: ResponseCode::FormatError,
+        })
+    }
+
+    #[test]
+    fn parses_a_query() {
+        let message = query(1, &question(NAME, TYPE_A));
+
+        assert_eq!(
+            parse_query(&message),
+            Ok(Query {
+                id: 0x1234,
+                flags: 0x0100,
+                question: Question {
+                    name: NAME,
+                    qtype: TYPE_A,
+                    qclass: CLASS_IN,
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn answers_with_a_pointer_to_the_question() {
+        let message = query(1, &question(NAME, TYPE_A));
+        let mut buffer = [0; MAX_MESSAGE_SIZE];
+        let len = respond(&message, ADDRESS, &mut buffer).unwrap();
+
+        let mut expected = Vec::from([0x12, 0x34, 0x85, 0x00, 0, 1, 0, 1, 0, 0, 0, 0]);
+        expected.extend(question(NAME, TYPE_A));
+        expected.extend([0xC0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 192, 168, 1, 1]);
+        assert_eq!(&buffer[..len], expected);
+    }
+
+    #[test]
+    fn answers_other_types_without_records() {
+        const TYPE_AAAA: u16 = 28;
+        let message = query(1, &question(NAME, TYPE_AAAA));
+        let mut buffer = [0; MAX_MESSAGE_SIZE];
+        let len = respond(&message, ADDRESS, &mut buffer).unwrap();
+
+        assert_eq!(&buffer[4..8], [0, 1, 0, 0]);
+        assert_eq!(len, message.len());
+    }
+
+    #[test]
+    fn ignores_truncated_headers_and_responses() {
+        let message = query(1, &question(NAME, TYPE_A));
+        assert_eq!(
+            parse_query(&message[..HEADER_SIZE - 1]),
+            Err(ParseError::NotAQuery)
+        );
+
+        let mut response = message.clone();
+        response[2] |= 0x80;
+        assert_eq!(parse_query(&response), Err(ParseError::NotAQuery));
+        assert_eq!(
+            respond(&response, ADDRESS, &mut [0; MAX_MESSAGE_SIZE]),
+            None
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_questions() {
+        let message = query(1, &question(NAME, TYPE_A));
+
+        // In the name, at its end and in the type and class
+        for len in [
+            HEADER_SIZE,
+            HEADER_SIZE + 5,
+            HEADER_SIZE + NAME.len(),
+            message.len() - 1,
+        ] {
+            assert_eq!(parse_query(&message[..len]), format_error());
+        }
+    }
+
+    #[test]
+    fn rejects_compression_pointers() {
+        let message = query(1, &question(b"\x07station\xC0\x0C", TYPE_A));
+
+        assert_eq!(parse_query(&message), format_error());
+    }
+
+    #[test]
+    fn rejects_names_longer_than_255_bytes() {
+        let mut name = Vec::new();
+        for _ in 0..4 {
+            name.push(63);
+            name.extend([b'a'; 63]);
+        }
+        name.push(0);
+
+        assert_eq!(
+            parse_query(&query(1, &question(&name, TYPE_A))),
+            format_error()
+        );
+    }
+
+    #[test]
+    fn rejects_multiple_questions() {
+        let mut body = question(NAME, TYPE_A);
+        body.extend(question(b"\x03www\x07example\x03com\x00", TYPE_A));
+        let message = query(2, &body);
+        assert_eq!(parse_query(&message), format_error());
+        assert_eq!(parse_query(&query(0, &[])), format_error());
+
+        // The error has no question
+        let mut buffer = [0; MAX_MESSAGE_SIZE];
+        let len = respond(&message, ADDRESS, &mut buffer).unwrap();
+        assert_eq!(
+            &buffer[..len],
+            [0x12, 0x34, 0x85, 0x01, 0, 0, 0, 0, 0, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn does_not_implement_other_opcodes() {
+        let mut message = query(1, &question(NAME, TYPE_A));
+        // Inverse query
+        message[2] |= 0x08;
+
+        assert!(matches!(
+            parse_query(&message),
+            Err(ParseError::Invalid {
+                code: ResponseCode::NotImplemented,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn needs_room_for_the_response() {
+        let message = query(1, &question(NAME, TYPE_A));
+        let query = parse_query(&message).unwrap();
+
+        let mut buffer = [0; MAX_MESSAGE_SIZE];
+        let len = encode_response(&query, ADDRESS, &mut buffer).unwrap();
+        assert_eq!(
+            encode_response(&query, ADDRESS, &mut buffer[..len - 1]),
+            None
+        );
+    }
+}
@@ -0,0 +1,337 @@
+// Pushes the measurements to InfluxDB over HTTP or UDP
+use core::fmt::Write;
+use core::net::SocketAddrV4;
+
+use defmt::{debug, info, warn};
+use embassy_net::Stack;
+use embassy_net::tcp::{self, ConnectError, TcpSocket};
+use embassy_net::udp::{self as net_udp, PacketMetadata, UdpSocket};
+use embassy_time::{Duration, Instant, Ticker, with_timeout};
+use heapless::{Deque, String};
+
+use line::{Point, QueryValue};
+
+use super::station::{Backoff, INITIAL_BACKOFF, MAX_BACKOFF};
+use crate::TheWatch;
+use crate::config::InfluxTransport;
+
+pub mod http;
+pub mod line;
+
+/// How often the latest measurements are sampled and pushed.
+pub const PUSH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How many points are kept while the server can't be reached over HTTP, the
+/// oldest ones are dropped first.
+pub const BUFFERED_POINTS: usize = 128;
+
+/// Longest batch of lines, an HTTP body or a UDP datagram.
+const MAX_BATCH_SIZE: usize = 1024;
+
+/// How long the server has to answer a request.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where and how to push the points.
+#[derive(Copy, Clone, Debug)]
+pub struct InfluxSettings {
+    pub server: SocketAddrV4,
+    pub transport: InfluxTransport,
+    pub org: &'static str,
+    pub bucket: &'static str,
+    pub token: Option<&'static str>,
+    /// Value of the `station` tag.
+    pub station: &'static str,
+}
+
+/// Why a batch wasn't written.
+#[derive(Debug, defmt::Format)]
+pub enum Error {
+    Connect(ConnectError),
+    Tcp(tcp::Error),
+    Udp(net_udp::SendError),
+    /// The server didn't answer in time.
+    Timeout,
+    /// The response isn't HTTP.
+    InvalidResponse,
+    /// The server answered with an unexpected status code.
+    Status(u16),
+}
+
+impl From<tcp::Error> for Error {
+    fn from(e: tcp::Error) -> Self {
+        Error::Tcp(e)
+    }
+}
+
+/// Converts the uptime to the Unix time, learned from the `Date` header of
+/// the responses of the server.
+#[derive(Copy, Clone, Default, Debug)]
+struct Clock {
+    /// Unix time of the boot in milliseconds.
+    boot_ms: Option<u64>,
+}
+
+impl Clock {
+    /// Sets the clock from the response received at `uptime_ms`.
+    fn sync(&mut self, head: &[u8], uptime_ms: u64) {
+        if let Some(unix_s) = http::header(head, "Date").and_then(http::parse_date) {
+            self.boot_ms = (unix_s * 1000).checked_sub(uptime_ms);
+        }
+    }
+
+    /// Nanoseconds since the Unix epoch of `uptime_ms`.
+    fn timestamp_ns(&self, uptime_ms: u64) -> Option<u64> {
+        self.boot_ms
+            .map(|boot_ms| (boot_ms + uptime_ms).saturating_mul(1_000_000))
+    }
+}
+
+/// Formats the oldest points of `points` that fit in `batch`, returns how
+/// many.
+fn fill_batch(
+    batch: &mut String<MAX_BATCH_SIZE>,
+    points: &Deque<Point, BUFFERED_POINTS>,
+    station: &str,
+    clock: &Clock,
+) -> usize {
+    batch.clear();
+    let mut count = 0;
+    for point in points {
+        let len = batch.len();
+        let timestamp_ns = clock.timestamp_ns(point.timestamp_ms);
+        if line::write_line(batch, station, point, timestamp_ns).is_err() {
+            // A line cut by the end of the buffer is sent next time
+            batch.truncate(len);
+            break;
+        }
+        count += 1;
+    }
+    count
+}
+
+async fn write_all(socket: &mut TcpSocket<'_>, mut bytes: &[u8]) -> Result<(), tcp::Error> {
+    while !bytes.is_empty() {
+        let written = socket.write(bytes).await?;
+        bytes = &bytes[written..];
+    }
+    Ok(())
+}
+
+/// Sends a request of `head` and `body`, returns the status code and sets
+/// `clock` from the response.
+async fn request(
+    stack: Stack<'static>,
+    settings: &InfluxSettings,
+    head: &str,
+    body: &[u8],
+    clock: &mut Clock,
+) -> Result<u16, Error> {
+    let mut rx_buffer = [0; 512];
+    let mut tx_buffer = [0; 1024];
+    let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+    socket.set_timeout(Some(RESPONSE_TIMEOUT));
+    let result = async {
+        socket
+            .connect(settings.server)
+            .await
+            .map_err(Error::Connect)?;
+        write_all(&mut socket, head.as_bytes()).await?;
+        write_all(&mut socket, body).await?;
+
+        // Only the status line and the headers are needed
+        let mut response = [0; 512];
+        let mut len = 0;
+        while len < response.len() && !response[..len].windows(4).any(|w| w == b"\r\n\r\n") {
+            match socket.read(&mut response[len..]).await? {
+                0 => break,
+                read => len += read,
+            }
+        }
+        let status = http::status(&response[..len]).ok_or(Error::InvalidResponse)?;
+        clock.sync(&response[..len], Instant::now().as_millis());
+        Ok(status)
+    };
+    let result = with_timeout(RESPONSE_TIMEOUT, result)
+        .await
+        .unwrap_or(Err(Error::Timeout));
+    socket.abort();
+    let _ = socket.flush().await;
+    result
+}
+
+/// Writes `head` with the request line and headers of `path`.
+fn request_head(
+    head: &mut String<512>,
+    settings: &InfluxSettings,
+    method: &str,
+    path: &str,
+    content_length: usize,
+) {
+    head.clear();
+    // The org and bucket are at most 32 * 3 bytes encoded, the token 96
+    write!(
+        head,
+        "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: {}\r\n",
+        method, path, settings.server, content_length
+    )
+    .unwrap();
+    if let Some(token) = settings.token {
+        write!(head, "Authorization: Token {}\r\n", token).unwrap();
+    }
+    if content_length > 0 {
+        head.push_str("Content-Type: text/plain; charset=utf-8\r\n")
+            .unwrap();
+    }
+    head.push_str("\r\n").unwrap();
+}
+
+/// Writes the oldest points over HTTP, dropping them when they're written or
+/// rejected.
+async fn push_http(
+    stack: Stack<'static>,
+    settings: &InfluxSettings,
+    points: &mut Deque<Point, BUFFERED_POINTS>,
+    clock: &mut Clock,
+) -> Result<(), Error> {
+    let mut head = String::new();
+    // Points can't be timestamped before the clock is known
+    if clock.boot_ms.is_none() {
+        request_head(&mut head, settings, "GET", "/ping", 0);
+        request(stack, settings, &head, &[], clock).await?;
+        if clock.boot_ms.is_none() {
+            warn!("InfluxDB didn't send its time, points are stamped when received");
+        }
+    }
+
+    let mut path = String::<128>::new();
+    write!(
+        path,
+        "/api/v2/write?org={}&bucket={}&precision=ns",
+        QueryValue(settings.org),
+        QueryValue(settings.bucket)
+    )
+    .unwrap();
+    let mut batch = String::new();
+    while !points.is_empty() {
+        let count = fill_batch(&mut batch, points, settings.station, clock);
+        request_head(&mut head, settings, "POST", &path, batch.len());
+        match request(stack, settings, &head, batch.as_bytes(), clock).await? {
+            204 => debug!("Wrote {} points to InfluxDB", count),
+            // Retrying can't help, e.g. a wrong bucket or token
+            status @ 400..=499 if status != 429 => {
+                warn!("InfluxDB rejected {} points: {}", count, status)
+            }
+            status => return Err(Error::Status(status)),
+        }
+        for _ in 0..count {
+            points.pop_front();
+        }
+    }
+    Ok(())
+}
+
+/// Sends the buffered points as datagrams, dropping them once sent.
+async fn push_udp(
+    socket: &mut UdpSocket<'_>,
+    settings: &InfluxSettings,
+    points: &mut Deque<Point, BUFFERED_POINTS>,
+    clock: &Clock,
+) -> Result<(), Error> {
+    let mut batch = String::new();
+    while !points.is_empty() {
+        let count = fill_batch(&mut batch, points, settings.station, clock);
+        socket
+            .send_to(batch.as_bytes(), settings.server)
+            .await
+            .map_err(Error::Udp)?;
+        for _ in 0..count {
+            points.pop_front();
+        }
+    }
+    Ok(())
+}
+
+/// Samples the latest measurements every [`PUSH_INTERVAL`] and pushes them
+/// to the InfluxDB server of `settings`.
+///
+/// Points are kept while the server can't be reached and retried with
+/// exponential backoff. Over HTTP they're stamped with the time of the
+/// server, over UDP the server stamps them when they're received so only the
+/// latest one is kept: an older one would be stamped with the wrong time.
+#[embassy_executor::task]
+pub async fn influx_task(
+    stack: Stack<'static>,
+    settings: InfluxSettings,
+    measurements: &'static TheWatch,
+) {
+    let mut rx_meta = [PacketMetadata::EMPTY; 1];
+    let mut rx_buffer = [0; 64];
+    let mut tx_meta = [PacketMetadata::EMPTY; 2];
+    let mut tx_buffer = [0; 2 * MAX_BATCH_SIZE];
+    let mut udp = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    if settings.transport == InfluxTransport::Udp {
+        // Any local port
+        if let Err(e) = udp.bind(0) {
+            warn!("Failed to bind the InfluxDB socket: {:?}", e);
+            return;
+        }
+    }
+    info!(
+        "Pushing measurements to InfluxDB at {} over {}",
+        settings.server,
+        settings.transport.as_str()
+    );
+
+    let mut points = Deque::<Point, BUFFERED_POINTS>::new();
+    let mut clock = Clock::default();
+    let mut backoff = Backoff::new(INITIAL_BACKOFF, MAX_BACKOFF);
+    let mut retry_at = Instant::MIN;
+    let mut sampled_ms = None;
+    let mut ticker = Ticker::every(PUSH_INTERVAL);
+    loop {
+        ticker.next().await;
+
+        if let Some(latest) = measurements.try_get()
+            && sampled_ms != Some(latest.timestamp_ms)
+            && let Some(point) = Point::from_measurements(&latest)
+        {
+            sampled_ms = Some(latest.timestamp_ms);
+            // The clock is only learned over HTTP
+            if settings.transport == InfluxTransport::Udp {
+                points.clear();
+            }
+            if points.is_full() {
+                points.pop_front();
+            }
+            // Room was made
+            points.push_back(point).unwrap();
+        }
+        if points.is_empty() || Instant::now() < retry_at || !stack.is_config_up() {
+            continue;
+        }
+
+        let result = match settings.transport {
+            InfluxTransport::Http => push_http(stack, &settings, &mut points, &mut clock).await,
+            InfluxTransport::Udp => push_udp(&mut udp, &settings, &mut points, &clock).await,
+        };
+        match result {
+            Ok(()) => backoff.reset(),
+            Err(e) => {
+                let delay = backoff.next_delay();
+                warn!(
+                    "Failed to push {} points to InfluxDB, retrying in {} s: {:?}",
+                    points.len(),
+                    delay.as_secs(),
+                    e
+                );
+                retry_at = Instant::now() + delay;
+            }
+        }
+    }
+}
@@ -0,0 +1,140 @@
+// Parsing of the HTTP responses of InfluxDB
+/// The status code of the response starting with `head`.
+pub fn status(head: &[u8]) -> Option<u16> {
+    let line = head.split(|&b| b == b'\r').next()?;
+    let mut parts = line.split(|&b| b == b' ');
+    if !parts.next()?.starts_with(b"HTTP/1.") {
+        return None;
+    }
+    core::str::from_utf8(parts.next()?).ok()?.parse().ok()
+}
+
+/// The value of the header `name` in `head`, names are case-insensitive.
+pub fn header<'h>(head: &'h [u8], name: &str) -> Option<&'h str> {
+    head.split(|&b| b == b'\n').skip(1).find_map(|line| {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let colon = line.iter().position(|&b| b == b':')?;
+        let (key, value) = line.split_at(colon);
+        if !key.eq_ignore_ascii_case(name.as_bytes()) {
+            return None;
+        }
+        core::str::from_utf8(&value[1..]).ok().map(str::trim)
+    })
+}
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Seconds since the Unix epoch of a `Date` header, e.g.
+/// `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub fn parse_date(date: &str) -> Option<u64> {
+    let mut parts = date.split(' ');
+    let _weekday = parts.next()?;
+    let day: u64 = parts.next()?.parse().ok()?;
+    let month = parts.next()?;
+    let month = MONTHS.iter().position(|&name| name == month)? as u64 + 1;
+    let year: u64 = parts.next()?.parse().ok()?;
+    let mut time = parts
+        .next()?
+        .split(':')
+        .map(|part| part.parse::<u64>().ok());
+    let (hours, minutes, seconds) = (time.next()??, time.next()??, time.next()??);
+    if parts.next()? != "GMT"
+        || year < 1970
+        || !(1..=31).contains(&day)
+        || hours > 23
+        || minutes > 59
+        || seconds > 60
+    {
+        return None;
+    }
+    Some(days_from_civil(year, month, day) * 86_400 + hours * 3600 + minutes * 60 + seconds)
+}
+
+/// Days since 1970-01-01 of a date of the proleptic Gregorian calendar.
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    // Years start in March so the leap day is the last one
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year % 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    // 1970-01-01 is day 719_468 counted from 0000-03-01
+    era * 146_097 + day_of_era - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEAD: &[u8] = b"HTTP/1.1 204 No Content\r\n\
+        Content-Type: application/json\r\n\
+        date:  Sun, 06 Nov 1994 08:49:37 GMT \r\n\r\n";
+
+    #[test]
+    fn parses_the_status_code() {
+        assert_eq!(status(HEAD), Some(204));
+        assert_eq!(status(b"HTTP/1.0 404 Not Found\r\n"), Some(404));
+        assert_eq!(status(b"SSH-2.0-OpenSSH\r\n"), None);
+        assert_eq!(status(b"HTTP/1.1 OK\r\n"), None);
+        assert_eq!(status(b""), None);
+    }
+
+    #[test]
+    fn finds_headers_by_name() {
+        assert_eq!(header(HEAD, "Date"), Some("Sun, 06 Nov 1994 08:49:37 GMT"));
+        assert_eq!(header(HEAD, "content-type"), Some("application/json"));
+        assert_eq!(header(HEAD, "Content-Length"), None);
+        // Not in the status line
+        assert_eq!(
+            header(b"HTTP/1.1 204 No: Content\r\n\r\n", "HTTP/1.1 204 No"),
+            None
+        );
+    }
+
+    #[test]
+    fn parses_dates() {
+        assert_eq!(parse_date("Thu, 01 Jan 1970 00:00:00 GMT"), Some(0));
+        assert_eq!(
+            parse_date("Sun, 06 Nov 1994 08:49:37 GMT"),
+            Some(784_111_777)
+        );
+        // Leap days, in a year divisible by 400 and not in one divisible by 100
+        assert_eq!(
+            parse_date("Tue, 29 Feb 2000 12:00:00 GMT"),
+            Some(951_825_600)
+        );
+        assert_eq!(
+            parse_date("Thu, 29 Feb 2024 00:00:00 GMT"),
+            Some(1_709_164_800)
+        );
+        assert_eq!(
+            parse_date("Mon, 01 Mar 2100 00:00:00 GMT"),
+            Some(4_107_542_400)
+        );
+        assert_eq!(
+            parse_date("Sat, 31 Dec 2016 23:59:60 GMT"),
+            Some(1_483_228_800)
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_dates() {
+        for date in [
+            "",
+            "Sun, 06 Nov 1994 08:49:37",
+            "Sun, 06 Nov 1994 08:49:37 UTC",
+            "Sun, 06 nov 1994 08:49:37 GMT",
+            "Sun, 32 Nov 1994 08:49:37 GMT",
+            "Sun, 00 Nov 1994 08:49:37 GMT",
+            "Sun, 06 Nov 1994 24:00:00 GMT",
+            "Sun, 06 Nov 1994 08:60:00 GMT",
+            "Sun, 06 Nov 1994 08:49 GMT",
+            "Wed, 31 Dec 1969 23:59:59 GMT",
+            "Sunday, 06-Nov-94 08:49:37 GMT",
+        ] {
+            assert_eq!(parse_date(date), None, "{}", date);
+        }
+    }
+}
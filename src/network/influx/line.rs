@@ -0,0 +1,225 @@
+// Formatting of measurements in the InfluxDB line protocol
+use core::fmt::{self, Display, Write};
+
+use crate::{Field, NormalizedMeasurments, Quality};
+
+/// Measurement the points are written to.
+pub const MEASUREMENT: &str = "weather";
+
+/// The quantities measured in one sampling round.
+#[derive(Copy, Clone, Debug, PartialEq, defmt::Format)]
+pub struct Point {
+    /// Milliseconds since boot.
+    pub timestamp_ms: u64,
+    /// Degrees Celsius.
+    pub temperature: Option<f32>,
+    /// Relative humidity in percent.
+    pub humidity: Option<f32>,
+    /// Kilopascals.
+    pub pressure: Option<f32>,
+}
+
+impl Point {
+    /// The values of `measurements` measured in its latest round, `None` if
+    /// there's none.
+    pub fn from_measurements(measurements: &NormalizedMeasurments) -> Option<Self> {
+        let fresh = |field: Option<Field>| {
+            field
+                .filter(|field| field.quality == Quality::Fresh && field.value.is_finite())
+                .map(|field| field.value)
+        };
+        let point = Self {
+            timestamp_ms: measurements.timestamp_ms,
+            temperature: fresh(measurements.temperature),
+            humidity: fresh(measurements.humidity),
+            pressure: fresh(measurements.pressure),
+        };
+        (point.temperature.is_some() || point.humidity.is_some() || point.pressure.is_some())
+            .then_some(point)
+    }
+
+    fn fields(&self) -> [(&'static str, Option<f32>); 3] {
+        [
+            ("temperature", self.temperature),
+            ("humidity", self.humidity),
+            ("pressure", self.pressure),
+        ]
+    }
+}
+
+/// Escapes the characters of `special` and the backslash.
+struct Escaped<'a>(&'a str, &'a [char]);
+
+impl Display for Escaped<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for c in self.0.chars() {
+            if c == '\\' || self.1.contains(&c) {
+                f.write_char('\\')?;
+            }
+            f.write_char(c)?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes `point` as a line of [`MEASUREMENT`] tagged with `station`.
+///
+/// Without a `timestamp_ns` the server uses the time it receives the line.
+/// Values that aren't finite are left out, a point without any isn't written.
+pub fn write_line(
+    out: &mut impl Write,
+    station: &str,
+    point: &Point,
+    timestamp_ns: Option<u64>,
+) -> fmt::Result {
+    let mut fields = point
+        .fields()
+        .into_iter()
+        .filter_map(|(key, value)| Some((key, value.filter(|value| value.is_finite())?)))
+        .peekable();
+    if fields.peek().is_none() {
+        return Ok(());
+    }
+
+    write!(
+        out,
+        "{},station={}",
+        Escaped(MEASUREMENT, &[',', ' ']),
+        Escaped(station, &[',', '=', ' '])
+    )?;
+    for (i, (key, value)) in fields.enumerate() {
+        write!(out, "{}{}={}", if i == 0 { ' ' } else { ',' }, key, value)?;
+    }
+    if let Some(timestamp_ns) = timestamp_ns {
+        write!(out, " {}", timestamp_ns)?;
+    }
+    out.write_char('\n')
+}
+
+/// Percent-encodes `value` for a query string.
+pub struct QueryValue<'a>(pub &'a str);
+
+impl Display for QueryValue<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for b in self.0.bytes() {
+            if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+                f.write_char(b as char)?;
+            } else {
+                write!(f, "%{:02X}", b)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use heapless::String;
+
+    use super::*;
+
+    fn point(temperature: Option<f32>, humidity: Option<f32>, pressure: Option<f32>) -> Point {
+        Point {
+            timestamp_ms: 5000,
+            temperature,
+            humidity,
+            pressure,
+        }
+    }
+
+    fn line(station: &str, point: &Point, timestamp_ns: Option<u64>) -> String<128> {
+        let mut line = String::new();
+        write_line(&mut line, station, point, timestamp_ns).unwrap();
+        line
+    }
+
+    fn field(value: f32, quality: Quality) -> Option<Field> {
+        Some(Field {
+            value,
+            sensor: "BME280",
+            measured_at_ms: 5000,
+            quality,
+        })
+    }
+
+    #[test]
+    fn writes_a_line_per_point() {
+        let point = point(Some(21.5), Some(40.0), Some(101.325));
+
+        assert_eq!(
+            line("station", &point, Some(1_700_000_000_000_000_000)),
+            "weather,station=station temperature=21.5,humidity=40,pressure=101.325 \
+             1700000000000000000\n"
+        );
+        assert_eq!(
+            line("station", &point, None),
+            "weather,station=station temperature=21.5,humidity=40,pressure=101.325\n"
+        );
+    }
+
+    #[test]
+    fn leaves_out_missing_values() {
+        let point = point(None, Some(f32::NAN), Some(100.0));
+
+        assert_eq!(
+            line("station", &point, None),
+            "weather,station=station pressure=100\n"
+        );
+        assert_eq!(
+            line(
+                "station",
+                &self::point(None, Some(f32::INFINITY), None),
+                None
+            ),
+            ""
+        );
+    }
+
+    #[test]
+    fn escapes_the_station_tag() {
+        let point = point(Some(20.0), None, None);
+
+        assert_eq!(
+            line(r"back yard,1=a\b", &point, None),
+            "weather,station=back\\ yard\\,1\\=a\\\\b temperature=20\n"
+        );
+    }
+
+    #[test]
+    fn fails_when_the_line_does_not_fit() {
+        let mut line = String::<32>::new();
+        let point = point(Some(21.5), Some(40.0), Some(101.325));
+
+        assert!(write_line(&mut line, "station", &point, None).is_err());
+    }
+
+    #[test]
+    fn keeps_the_fresh_values_of_the_measurements() {
+        let measurements = NormalizedMeasurments {
+            timestamp_ms: 5000,
+            temperature: field(21.5, Quality::Fresh),
+            humidity: field(40.0, Quality::Held),
+            pressure: field(f32::NAN, Quality::Fresh),
+        };
+
+        assert_eq!(
+            Point::from_measurements(&measurements),
+            Some(point(Some(21.5), None, None))
+        );
+        assert_eq!(
+            Point::from_measurements(&NormalizedMeasurments {
+                temperature: field(21.5, Quality::Stale),
+                ..measurements
+            }),
+            None
+        );
+    }
+
+    #[test]
+    fn percent_encodes_query_values() {
+        let mut value = String::<64>::new();
+        write!(value, "{}", QueryValue("my org/bucket~1_é")).unwrap();
+
+        assert_eq!(value, "my%20org%2Fbucket~1_%C3%A9");
+    }
+}
@@ -0,0 +1,99 @@
+// mDNS responder, so clients find the station by name rather than by address
+use core::net::{Ipv4Addr, SocketAddrV4};
+
+use defmt::{Debug2Format, debug, error, info, warn};
+use embassy_net::Stack;
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_time::{Duration, Timer};
+
+use packet::Responder;
+
+pub mod packet;
+
+pub const PORT: u16 = 5353;
+
+/// Group the queries and responses are sent to.
+pub const GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+
+/// Messages can be larger than unicast DNS ones.
+const MAX_MESSAGE_SIZE: usize = 1024;
+
+/// How many times the records are announced when the responder starts.
+const ANNOUNCEMENTS: u32 = 2;
+
+/// Delay between the announcements.
+const ANNOUNCEMENT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Answers the mDNS queries for the host and services of `responder`.
+///
+/// Waits for the stack to have an address, then announces the records so
+/// caches are refreshed. Names aren't probed for conflicts.
+#[embassy_executor::task]
+pub async fn run_mdns(stack: Stack<'static>, responder: Responder<'static>) {
+    stack.wait_config_up().await;
+    if let Err(e) = stack.join_multicast_group(GROUP) {
+        error!("Failed to join the mDNS group: {:?}", Debug2Format(&e));
+        return;
+    }
+
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0; 2048];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buffer = [0; 2048];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    if let Err(e) = socket.bind(PORT) {
+        error!("Failed to bind the mDNS responder: {:?}", e);
+        return;
+    }
+    let group = SocketAddrV4::new(GROUP, PORT);
+
+    let mut query = [0; MAX_MESSAGE_SIZE];
+    let mut response = [0; MAX_MESSAGE_SIZE];
+    for _ in 0..ANNOUNCEMENTS {
+        if let Some(config) = stack.config_v4()
+            && let Some(len) = responder.announce(config.address.address(), &mut response)
+            && let Err(e) = socket.send_to(&response[..len], group).await
+        {
+            warn!("Failed to announce the mDNS records: {:?}", e);
+        }
+        Timer::after(ANNOUNCEMENT_INTERVAL).await;
+    }
+    info!("Answering mDNS queries for {}.local", responder.hostname);
+
+    loop {
+        let (len, meta) = match socket.recv_from(&mut query).await {
+            Ok(received) => received,
+            Err(e) => {
+                debug!("Dropped an mDNS query: {:?}", e);
+                continue;
+            }
+        };
+        // The address may change when the DHCP lease is renewed
+        let Some(config) = stack.config_v4() else {
+            continue;
+        };
+        let legacy = meta.endpoint.port != PORT;
+        let Some(len) = responder.respond(
+            &query[..len],
+            config.address.address(),
+            legacy,
+            &mut response,
+        ) else {
+            continue;
+        };
+        let result = if legacy {
+            socket.send_to(&response[..len], meta.endpoint).await
+        } else {
+            socket.send_to(&response[..len], group).await
+        };
+        if let Err(e) = result {
+            warn!("Failed to answer an mDNS query: {:?}", e);
+        }
+    }
+}
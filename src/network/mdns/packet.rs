@@ -0,0 +1,700 @@
+// mDNS responses advertising the station and its services, see RFC 6762 and
+// RFC 6763
+use core::net::Ipv4Addr;
+
+use heapless::Vec;
+
+use crate::network::dns::packet::{CLASS_IN, HEADER_SIZE, TYPE_A, TYPE_ANY, Writer};
+
+pub const TYPE_PTR: u16 = 12;
+pub const TYPE_TXT: u16 = 16;
+pub const TYPE_SRV: u16 = 33;
+
+/// How many services can be advertised.
+pub const MAX_SERVICES: usize = 4;
+
+/// Domain of the multicast names.
+const DOMAIN: &str = "local";
+
+/// Name listing the advertised service types.
+const SERVICE_TYPES: &str = "_services._dns-sd._udp.local";
+
+/// TTL of the records naming the host, in seconds.
+const HOST_TTL: u32 = 120;
+
+/// TTL of the other records, in seconds.
+const SERVICE_TTL: u32 = 4500;
+
+/// Flags of a response, which is authoritative.
+const RESPONSE_FLAGS: u16 = 0x8400;
+
+const FLAG_RESPONSE: u16 = 0x8000;
+const OPCODE_MASK: u16 = 0x7800;
+
+/// Set in the class of a record replacing the cached ones, and of a question
+/// asking for a unicast response.
+const CLASS_FLAG: u16 = 0x8000;
+
+/// Longest encoded name.
+const MAX_NAME_LEN: usize = 255;
+
+/// Compression pointers followed before a name is considered a loop.
+const MAX_POINTERS: usize = 16;
+
+/// A service of the station.
+#[derive(Copy, Clone, Debug)]
+pub struct Service<'a> {
+    /// Service type and protocol, e.g. `_http._tcp`.
+    pub kind: &'a str,
+    pub port: u16,
+    /// `key=value` attributes of the TXT record.
+    pub txt: &'a [&'a str],
+}
+
+/// Answers for `<hostname>.local` and the instances `<hostname>.<kind>.local`
+/// of `services`, at most [`MAX_SERVICES`].
+#[derive(Copy, Clone, Debug)]
+pub struct Responder<'a> {
+    pub hostname: &'a str,
+    pub services: &'a [Service<'a>],
+}
+
+/// A record of the [`Responder`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Record {
+    /// Address of the host.
+    Address,
+    /// Service type listed by [`SERVICE_TYPES`].
+    ServiceType(usize),
+    /// Instance of a service type.
+    Pointer(usize),
+    /// Host and port of an instance.
+    Srv(usize),
+    Txt(usize),
+}
+
+impl Record {
+    fn bit(self) -> u32 {
+        match self {
+            Record::Address => 1,
+            Record::ServiceType(i) => 1 << (1 + 4 * i),
+            Record::Pointer(i) => 1 << (2 + 4 * i),
+            Record::Srv(i) => 1 << (3 + 4 * i),
+            Record::Txt(i) => 1 << (4 + 4 * i),
+        }
+    }
+}
+
+/// A set of records.
+#[derive(Copy, Clone, Default, Debug, PartialEq, Eq)]
+struct Records(u32);
+
+impl Records {
+    fn insert(&mut self, record: Record) {
+        self.0 |= record.bit();
+    }
+
+    fn contains(self, record: Record) -> bool {
+        self.0 & record.bit() != 0
+    }
+
+    fn without(self, other: Records) -> Records {
+        Records(self.0 & !other.0)
+    }
+
+    fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+}
+
+/// Reads the possibly compressed name at `offset` of `message` as dot
+/// separated labels, returns it with the offset following it.
+fn read_name(message: &[u8], offset: usize) -> Option<(Vec<u8, MAX_NAME_LEN>, usize)> {
+    let mut name = Vec::new();
+    let mut position = offset;
+    let mut next = None;
+    let mut pointers = 0;
+    loop {
+        let len = *message.get(position)?;
+        match len {
+            0 => return Some((name, next.unwrap_or(position + 1))),
+            0xC0.. => {
+                let low = *message.get(position + 1)?;
+                next.get_or_insert(position + 2);
+                pointers += 1;
+                if pointers > MAX_POINTERS {
+                    return None;
+                }
+                position = usize::from(u16::from_be_bytes([len & 0x3F, low]));
+            }
+            1..=63 => {
+                let label = message.get(position + 1..position + 1 + len as usize)?;
+                if !name.is_empty() {
+                    name.push(b'.').ok()?;
+                }
+                name.extend_from_slice(label).ok()?;
+                position += 1 + len as usize;
+            }
+            _ => return None,
+        }
+    }
+}
+
+/// Whether `name` is made of the dot separated `parts`, ignoring the case.
+fn name_is(name: &[u8], parts: &[&str]) -> bool {
+    let mut rest = name;
+    for (i, part) in parts.iter().enumerate() {
+        if i > 0 {
+            let Some((b'.', after)) = rest.split_first() else {
+                return false;
+            };
+            rest = after;
+        }
+        let Some((start, after)) = rest.split_at_checked(part.len()) else {
+            return false;
+        };
+        if !start.eq_ignore_ascii_case(part.as_bytes()) {
+            return false;
+        }
+        rest = after;
+    }
+    rest.is_empty()
+}
+
+impl Responder<'_> {
+    fn services(&self) -> impl Iterator<Item = (usize, &Service<'_>)> {
+        self.services.iter().take(MAX_SERVICES).enumerate()
+    }
+
+    /// Every record.
+    fn all(&self) -> Records {
+        let mut records = Records::default();
+        records.insert(Record::Address);
+        for (i, _) in self.services() {
+            records.insert(Record::ServiceType(i));
+            records.insert(Record::Pointer(i));
+            records.insert(Record::Srv(i));
+            records.insert(Record::Txt(i));
+        }
+        records
+    }
+
+    /// Adds the records answering a question to `answers`, and the ones the
+    /// client will need next to `additional`.
+    fn answer(&self, name: &[u8], qtype: u16, answers: &mut Records, additional: &mut Records) {
+        let wants = |wanted| qtype == wanted || qtype == TYPE_ANY;
+        if name_is(name, &[self.hostname, DOMAIN]) && wants(TYPE_A) {
+            answers.insert(Record::Address);
+        }
+        for (i, service) in self.services() {
+            if name_is(name, &[SERVICE_TYPES]) && wants(TYPE_PTR) {
+                answers.insert(Record::ServiceType(i));
+            }
+            if name_is(name, &[service.kind, DOMAIN]) && wants(TYPE_PTR) {
+                answers.insert(Record::Pointer(i));
+                additional.insert(Record::Srv(i));
+                additional.insert(Record::Txt(i));
+                additional.insert(Record::Address);
+            }
+            if name_is(name, &[self.hostname, service.kind, DOMAIN]) {
+                if wants(TYPE_SRV) {
+                    answers.insert(Record::Srv(i));
+                    additional.insert(Record::Address);
+                }
+                if wants(TYPE_TXT) {
+                    answers.insert(Record::Txt(i));
+                }
+            }
+        }
+    }
+
+    fn put_records(
+        &self,
+        writer: &mut Writer,
+        records: Records,
+        address: Ipv4Addr,
+        unique_class: u16,
+    ) -> Option<u16> {
+        let mut count = 0;
+        let mut put = |record| -> Option<()> {
+            if records.contains(record) {
+                self.put_record(writer, record, address, unique_class)?;
+                count += 1;
+            }
+            Some(())
+        };
+        put(Record::Address)?;
+        for (i, _) in self.services() {
+            put(Record::ServiceType(i))?;
+            put(Record::Pointer(i))?;
+            put(Record::Srv(i))?;
+            put(Record::Txt(i))?;
+        }
+        Some(count)
+    }
+
+    fn put_record(
+        &self,
+        writer: &mut Writer,
+        record: Record,
+        address: Ipv4Addr,
+        unique_class: u16,
+    ) -> Option<()> {
+        let host = [self.hostname, DOMAIN];
+        let (rtype, class, ttl) = match record {
+            Record::Address => {
+                writer.put_name(&host)?;
+                (TYPE_A, unique_class, HOST_TTL)
+            }
+            Record::ServiceType(_) => {
+                writer.put_name(&[SERVICE_TYPES])?;
+                (TYPE_PTR, CLASS_IN, SERVICE_TTL)
+            }
+            Record::Pointer(i) => {
+                writer.put_name(&[self.services[i].kind, DOMAIN])?;
+                (TYPE_PTR, CLASS_IN, SERVICE_TTL)
+            }
+            Record::Srv(i) => {
+                writer.put_name(&[self.hostname, self.services[i].kind, DOMAIN])?;
+                (TYPE_SRV, unique_class, HOST_TTL)
+            }
+            Record::Txt(i) => {
+                writer.put_name(&[self.hostname, self.services[i].kind, DOMAIN])?;
+                (TYPE_TXT, unique_class, SERVICE_TTL)
+            }
+        };
+        writer.put(&rtype.to_be_bytes())?;
+        writer.put(&class.to_be_bytes())?;
+        writer.put(&ttl.to_be_bytes())?;
+
+        // The data length is known once the data is written
+        let length_offset = writer.len();
+        writer.put(&[0; 2])?;
+        match record {
+            Record::Address => writer.put(&address.octets())?,
+            Record::ServiceType(i) => writer.put_name(&[self.services[i].kind, DOMAIN])?,
+            Record::Pointer(i) => {
+                writer.put_name(&[self.hostname, self.services[i].kind, DOMAIN])?
+            }
+            Record::Srv(i) => {
+                // Priority and weight
+                writer.put(&[0; 4])?;
+                writer.put(&self.services[i].port.to_be_bytes())?;
+                writer.put_name(&host)?;
+            }
+            Record::Txt(i) => {
+                let txt = self.services[i].txt;
+                // A TXT record holds at least one string
+                if txt.is_empty() {
+                    writer.put(&[0])?;
+                }
+                for attribute in txt {
+                    writer.put(&[u8::try_from(attribute.len()).ok()?])?;
+                    writer.put(attribute.as_bytes())?;
+                }
+            }
+        }
+        let length = (writer.len() - length_offset - 2) as u16;
+        writer.set(length_offset, &length.to_be_bytes())
+    }
+
+    /// Encodes the response to the query `message` into `buffer`, returns its
+    /// size, `None` when there's nothing to answer.
+    ///
+    /// A `legacy` query comes from a plain DNS resolver rather than the mDNS
+    /// port, its response is sent to the resolver and repeats the questions.
+    pub fn respond(
+        &self,
+        message: &[u8],
+        address: Ipv4Addr,
+        legacy: bool,
+        buffer: &mut [u8],
+    ) -> Option<usize> {
+        let header = message.first_chunk::<HEADER_SIZE>()?;
+        let id = u16::from_be_bytes([header[0], header[1]]);
+        let flags = u16::from_be_bytes([header[2], header[3]]);
+        let questions = u16::from_be_bytes([header[4], header[5]]);
+        if flags & (FLAG_RESPONSE | OPCODE_MASK) != 0 {
+            return None;
+        }
+
+        let mut answers = Records::default();
+        let mut additional = Records::default();
+        let mut offset = HEADER_SIZE;
+        for _ in 0..questions {
+            let (name, next) = read_name(message, offset)?;
+            let [t0, t1, _, _] = *message.get(next..next + 4)?.first_chunk::<4>()?;
+            self.answer(
+                &name,
+                u16::from_be_bytes([t0, t1]),
+                &mut answers,
+                &mut additional,
+            );
+            offset = next + 4;
+        }
+        if answers.is_empty() {
+            return None;
+        }
+
+        let mut writer = Writer::new(buffer);
+        // Legacy resolvers don't know the cache flush flag
+        let unique_class = if legacy {
+            writer.put(&id.to_be_bytes())?;
+            writer.put(&RESPONSE_FLAGS.to_be_bytes())?;
+            writer.put(&questions.to_be_bytes())?;
+            writer.put(&[0; 6])?;
+            // The questions start right after the header in both messages, so
+            // their compression pointers stay valid
+            writer.put(&message[HEADER_SIZE..offset])?;
+            CLASS_IN
+        } else {
+            writer.put(&0u16.to_be_bytes())?;
+            writer.put(&RESPONSE_FLAGS.to_be_bytes())?;
+            writer.put(&[0; 8])?;
+            CLASS_IN | CLASS_FLAG
+        };
+        let answer_count = self.put_records(&mut writer, answers, address, unique_class)?;
+        let additional = additional.without(answers);
+        let additional_count = self.put_records(&mut writer, additional, address, unique_class)?;
+        writer.set(6, &answer_count.to_be_bytes())?;
+        writer.set(10, &additional_count.to_be_bytes())?;
+        Some(writer.len())
+    }
+
+    /// Encodes an unsolicited response with every record into `buffer`,
+    /// returns its size.
+    pub fn announce(&self, address: Ipv4Addr, buffer: &mut [u8]) -> Option<usize> {
+        let mut writer = Writer::new(buffer);
+        writer.put(&0u16.to_be_bytes())?;
+        writer.put(&RESPONSE_FLAGS.to_be_bytes())?;
+        writer.put(&[0; 8])?;
+        let count = self.put_records(&mut writer, self.all(), address, CLASS_IN | CLASS_FLAG)?;
+        writer.set(6, &count.to_be_bytes())?;
+        Some(writer.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::string::String;
+    use std::vec::Vec;
+
+    use super::*;
+
+    const ADDRESS: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 20);
+    const TYPE_AAAA: u16 = 28;
+    const RESPONDER: Responder = Responder {
+        hostname: "station",
+        services: &[
+            Service {
+                kind: "_http._tcp",
+                port: 80,
+                txt: &["path=/"],
+            },
+            Service {
+                kind: "_weather._tcp",
+                port: 8080,
+                txt: &[],
+            },
+        ],
+    };
+
+    /// A record of a response, its data as sent.
+    #[derive(Debug, PartialEq)]
+    struct Answer {
+        name: String,
+        rtype: u16,
+        class: u16,
+        ttl: u32,
+        data: Vec<u8>,
+    }
+
+    /// The uncompressed encoding of a dot separated name.
+    fn name(name: &str) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        for label in name.split('.') {
+            encoded.push(label.len() as u8);
+            encoded.extend(label.as_bytes());
+        }
+        encoded.push(0);
+        encoded
+    }
+
+    fn question(encoded_name: &[u8], qtype: u16) -> Vec<u8> {
+        let mut question = Vec::from(encoded_name);
+        question.extend(qtype.to_be_bytes());
+        question.extend(CLASS_IN.to_be_bytes());
+        question
+    }
+
+    fn query(id: u16, questions: &[Vec<u8>]) -> Vec<u8> {
+        let mut message = Vec::from(id.to_be_bytes());
+        message.extend([0, 0]);
+        message.extend((questions.len() as u16).to_be_bytes());
+        message.extend([0; 6]);
+        for question in questions {
+            message.extend(question);
+        }
+        message
+    }
+
+    fn respond(message: &[u8], legacy: bool) -> Option<Vec<u8>> {
+        let mut buffer = [0; 1024];
+        let len = RESPONDER.respond(message, ADDRESS, legacy, &mut buffer)?;
+        Some(Vec::from(&buffer[..len]))
+    }
+
+    fn record(name: &str, rtype: u16, class: u16, ttl: u32, data: &[u8]) -> Answer {
+        Answer {
+            name: String::from(name),
+            rtype,
+            class,
+            ttl,
+            data: Vec::from(data),
+        }
+    }
+
+    /// The address record of the station.
+    fn address() -> Answer {
+        record("station.local", TYPE_A, 0x8001, 120, &[192, 168, 1, 20])
+    }
+
+    /// Splits a response without questions into its answers and additional
+    /// records.
+    fn parse(response: &[u8]) -> (Vec<Answer>, Vec<Answer>) {
+        assert_eq!(&response[..6], [0, 0, 0x84, 0x00, 0, 0]);
+        let answers = u16::from_be_bytes([response[6], response[7]]);
+        let additional = u16::from_be_bytes([response[10], response[11]]);
+        let mut offset = HEADER_SIZE;
+        let mut records = Vec::new();
+        for _ in 0..answers + additional {
+            let (name, next) = read_name(response, offset).unwrap();
+            let field =
+                |at: usize| u16::from_be_bytes([response[next + at], response[next + at + 1]]);
+            let length = field(8) as usize;
+            records.push(Answer {
+                name: String::from_utf8(name.to_vec()).unwrap(),
+                rtype: field(0),
+                class: field(2),
+                ttl: u32::from(field(4)) << 16 | u32::from(field(6)),
+                data: Vec::from(&response[next + 10..next + 10 + length]),
+            });
+            offset = next + 10 + length;
+        }
+        assert_eq!(offset, response.len());
+        let additional = records.split_off(answers.into());
+        (records, additional)
+    }
+
+    #[test]
+    fn answers_the_address() {
+        let message = query(0, &[question(&name("station.local"), TYPE_A)]);
+        let (answers, additional) = parse(&respond(&message, false).unwrap());
+
+        assert_eq!(answers, [address()]);
+        assert_eq!(additional, []);
+    }
+
+    #[test]
+    fn answers_an_instance_with_its_records() {
+        let message = query(0, &[question(&name("_http._tcp.local"), TYPE_PTR)]);
+        let (answers, additional) = parse(&respond(&message, false).unwrap());
+
+        let mut srv = Vec::from([0, 0, 0, 0, 0, 80]);
+        srv.extend(name("station.local"));
+        assert_eq!(
+            answers,
+            [record(
+                "_http._tcp.local",
+                TYPE_PTR,
+                CLASS_IN,
+                4500,
+                &name("station._http._tcp.local")
+            )]
+        );
+        assert_eq!(
+            additional,
+            [
+                address(),
+                record("station._http._tcp.local", TYPE_SRV, 0x8001, 120, &srv),
+                record(
+                    "station._http._tcp.local",
+                    TYPE_TXT,
+                    0x8001,
+                    4500,
+                    b"\x06path=/"
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn lists_the_service_types() {
+        let message = query(
+            0,
+            &[question(&name("_services._dns-sd._udp.local"), TYPE_PTR)],
+        );
+        let (answers, additional) = parse(&respond(&message, false).unwrap());
+
+        let pointer = |kind| record(SERVICE_TYPES, TYPE_PTR, CLASS_IN, 4500, &name(kind));
+        assert_eq!(
+            answers,
+            [pointer("_http._tcp.local"), pointer("_weather._tcp.local")]
+        );
+        assert_eq!(additional, []);
+    }
+
+    #[test]
+    fn answers_srv_and_txt_of_an_instance() {
+        let message = query(
+            0,
+            &[question(&name("station._weather._tcp.local"), TYPE_ANY)],
+        );
+        let (answers, additional) = parse(&respond(&message, false).unwrap());
+
+        let mut srv = Vec::from([0, 0, 0, 0, 0x1F, 0x90]);
+        srv.extend(name("station.local"));
+        assert_eq!(
+            answers,
+            [
+                record("station._weather._tcp.local", TYPE_SRV, 0x8001, 120, &srv),
+                // A TXT record without attributes holds an empty string
+                record("station._weather._tcp.local", TYPE_TXT, 0x8001, 4500, &[0]),
+            ]
+        );
+        assert_eq!(additional, [address()]);
+    }
+
+    #[test]
+    fn does_not_repeat_answers_as_additional_records() {
+        let message = query(
+            0,
+            &[
+                question(&name("_http._tcp.local"), TYPE_PTR),
+                question(&name("station.local"), TYPE_A),
+            ],
+        );
+        let (answers, additional) = parse(&respond(&message, false).unwrap());
+
+        assert_eq!(answers.len(), 2);
+        assert_eq!(answers[0], address());
+        assert_eq!(
+            additional
+                .iter()
+                .map(|record| record.rtype)
+                .collect::<Vec<_>>(),
+            [TYPE_SRV, TYPE_TXT]
+        );
+    }
+
+    #[test]
+    fn matches_names_ignoring_the_case() {
+        let message = query(0, &[question(&name("STATION.Local"), TYPE_A)]);
+        let (answers, _) = parse(&respond(&message, false).unwrap());
+
+        assert_eq!(answers, [address()]);
+    }
+
+    #[test]
+    fn ignores_other_names_types_and_responses() {
+        for message in [
+            query(0, &[question(&name("other.local"), TYPE_A)]),
+            query(0, &[question(&name("station.local"), TYPE_AAAA)]),
+            query(0, &[question(&name("station.local.local"), TYPE_A)]),
+        ] {
+            assert_eq!(respond(&message, false), None);
+        }
+
+        let mut response = query(0, &[question(&name("station.local"), TYPE_A)]);
+        response[2] = 0x84;
+        assert_eq!(respond(&response, false), None);
+    }
+
+    #[test]
+    fn follows_compression_pointers() {
+        // The instance name points to the service type of the first question
+        let message = query(
+            0,
+            &[
+                question(&name("_http._tcp.local"), TYPE_PTR),
+                question(b"\x07station\xC0\x0C", TYPE_TXT),
+            ],
+        );
+        let (answers, _) = parse(&respond(&message, false).unwrap());
+
+        assert_eq!(answers.len(), 2);
+        assert_eq!(answers[1].rtype, TYPE_TXT);
+    }
+
+    #[test]
+    fn rejects_compression_pointer_loops() {
+        let message = query(0, &[question(b"\xC0\x0C", TYPE_A)]);
+
+        assert_eq!(read_name(&message, HEADER_SIZE), None);
+        assert_eq!(respond(&message, false), None);
+    }
+
+    #[test]
+    fn rejects_truncated_questions() {
+        let message = query(0, &[question(&name("station.local"), TYPE_A)]);
+
+        for len in [HEADER_SIZE - 1, HEADER_SIZE + 5, message.len() - 3] {
+            assert_eq!(respond(&message[..len], false), None);
+        }
+    }
+
+    #[test]
+    fn answers_legacy_queries_with_the_id_and_questions() {
+        let question = question(&name("station.local"), TYPE_A);
+        let message = query(0x1234, core::slice::from_ref(&question));
+        let response = respond(&message, true).unwrap();
+
+        let mut expected = Vec::from([0x12, 0x34, 0x84, 0x00, 0, 1, 0, 1, 0, 0, 0, 0]);
+        expected.extend(question);
+        expected.extend(name("station.local"));
+        // Without the cache flush bit
+        expected.extend([0, 1, 0, 1, 0, 0, 0, 120, 0, 4, 192, 168, 1, 20]);
+        assert_eq!(response, expected);
+    }
+
+    #[test]
+    fn announces_every_record() {
+        let mut buffer = [0; 1024];
+        let len = RESPONDER.announce(ADDRESS, &mut buffer).unwrap();
+        let (answers, additional) = parse(&buffer[..len]);
+
+        let records: Vec<_> = answers
+            .iter()
+            .map(|record| (record.name.as_str(), record.rtype, record.class))
+            .collect();
+        assert_eq!(
+            records,
+            [
+                ("station.local", TYPE_A, 0x8001),
+                (SERVICE_TYPES, TYPE_PTR, CLASS_IN),
+                ("_http._tcp.local", TYPE_PTR, CLASS_IN),
+                ("station._http._tcp.local", TYPE_SRV, 0x8001),
+                ("station._http._tcp.local", TYPE_TXT, 0x8001),
+                (SERVICE_TYPES, TYPE_PTR, CLASS_IN),
+                ("_weather._tcp.local", TYPE_PTR, CLASS_IN),
+                ("station._weather._tcp.local", TYPE_SRV, 0x8001),
+                ("station._weather._tcp.local", TYPE_TXT, 0x8001),
+            ]
+        );
+        assert_eq!(additional, []);
+    }
+
+    #[test]
+    fn needs_room_for_the_response() {
+        let message = query(0, &[question(&name("_http._tcp.local"), TYPE_PTR)]);
+        let mut buffer = [0; 64];
+
+        assert_eq!(
+            RESPONDER.respond(&message, ADDRESS, false, &mut buffer),
+            None
+        );
+        assert_eq!(RESPONDER.announce(ADDRESS, &mut buffer), None);
+    }
+}
@@ -0,0 +1,286 @@
+// MQTT client publishing the measurements, with Home Assistant discovery
+use core::fmt::Write;
+use core::net::SocketAddrV4;
+
+use defmt::{info, warn};
+use embassy_futures::select::{Either, select};
+use embassy_net::Stack;
+use embassy_net::tcp::{self, ConnectError, TcpSocket};
+use embassy_time::{Duration, Instant, Ticker, Timer, with_timeout};
+use heapless::String;
+
+use packet::{Connect, ConnectReturnCode, Will};
+
+use super::station::{Backoff, INITIAL_BACKOFF, MAX_BACKOFF};
+use crate::TheWatch;
+use crate::sensors::weather_sensor::Quantity;
+
+pub mod packet;
+
+/// How often the latest measurements are published.
+pub const PUBLISH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The broker drops the connection after 1.5 times this without a packet.
+pub const KEEP_ALIVE: Duration = Duration::from_secs(60);
+
+/// Topic prefix Home Assistant reads the discovery payloads from.
+pub const DISCOVERY_PREFIX: &str = "homeassistant";
+
+/// How long the broker has to accept the connection.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Payloads of the availability topic.
+const ONLINE: &[u8] = b"online";
+const OFFLINE: &[u8] = b"offline";
+
+/// Longest packet sent, a discovery payload with its topic.
+const MAX_PACKET_SIZE: usize = 1024;
+
+/// Where and how to connect.
+#[derive(Copy, Clone, Debug)]
+pub struct MqttSettings {
+    pub broker: SocketAddrV4,
+    /// Identifies the station to the broker and Home Assistant.
+    pub client_id: &'static str,
+    pub username: Option<&'static str>,
+    pub password: Option<&'static str>,
+    /// Prefix of the topics measurements are published to.
+    pub topic: &'static str,
+}
+
+/// Name and device class of the Home Assistant sensor of a quantity, the
+/// topics and unit come from the quantity itself.
+fn home_assistant_sensor(quantity: Quantity) -> (&'static str, &'static str) {
+    match quantity {
+        Quantity::Temperature => ("Temperature", "temperature"),
+        Quantity::Humidity => ("Humidity", "humidity"),
+        Quantity::Pressure => ("Pressure", "pressure"),
+    }
+}
+
+/// Why the connection was lost.
+#[derive(Debug, defmt::Format)]
+pub enum Error {
+    Connect(ConnectError),
+    Tcp(tcp::Error),
+    Packet(packet::Error),
+    /// The broker refused the connection.
+    Refused(ConnectReturnCode),
+    /// The broker didn't accept the connection in time.
+    Timeout,
+    /// The broker closed the connection.
+    Closed,
+}
+
+impl From<tcp::Error> for Error {
+    fn from(e: tcp::Error) -> Self {
+        Error::Tcp(e)
+    }
+}
+
+impl From<packet::Error> for Error {
+    fn from(e: packet::Error) -> Self {
+        Error::Packet(e)
+    }
+}
+
+/// Topic of a quantity, or of the availability with `status`.
+pub fn topic(prefix: &str, key: &str) -> String<96> {
+    let mut topic = String::new();
+    // The prefix has at most 64 bytes and the keys are short
+    write!(topic, "{}/{}", prefix, key).unwrap();
+    topic
+}
+
+/// Topic of the Home Assistant discovery payload of a quantity.
+pub fn discovery_topic(client_id: &str, key: &str) -> String<96> {
+    let mut topic = String::new();
+    // The client id is a hostname of at most 32 bytes
+    write!(
+        topic,
+        "{}/sensor/{}/{}/config",
+        DISCOVERY_PREFIX, client_id, key
+    )
+    .unwrap();
+    topic
+}
+
+/// The Home Assistant discovery payload of a quantity.
+///
+/// The client id and topic are validated by the configuration and don't need
+/// escaping.
+fn discovery_payload(settings: &MqttSettings, quantity: Quantity) -> String<768> {
+    let (name, device_class) = home_assistant_sensor(quantity);
+    let mut payload = String::new();
+    write!(
+        payload,
+        r#"{{"name":"{}","unique_id":"{}_{}","state_topic":"{}","availability_topic":"{}","unit_of_measurement":"{}","device_class":"{}","state_class":"measurement","device":{{"identifiers":["{}"],"name":"{}","model":"Weather station","sw_version":"{}"}}}}"#,
+        name,
+        settings.client_id,
+        quantity.as_str(),
+        topic(settings.topic, quantity.as_str()),
+        topic(settings.topic, "status"),
+        quantity.unit(),
+        device_class,
+        settings.client_id,
+        settings.client_id,
+        env!("CARGO_PKG_VERSION"),
+    )
+    .unwrap();
+    payload
+}
+
+async fn write_all(socket: &mut TcpSocket<'_>, mut bytes: &[u8]) -> Result<(), tcp::Error> {
+    while !bytes.is_empty() {
+        let written = socket.write(bytes).await?;
+        bytes = &bytes[written..];
+    }
+    Ok(())
+}
+
+async fn publish(
+    socket: &mut TcpSocket<'_>,
+    topic: &str,
+    payload: &[u8],
+    retain: bool,
+) -> Result<(), Error> {
+    let mut buffer = [0; MAX_PACKET_SIZE];
+    let len = packet::encode_publish(topic, payload, retain, &mut buffer)?;
+    write_all(socket, &buffer[..len]).await?;
+    Ok(())
+}
+
+/// Reads the CONNACK packet.
+async fn read_connack(socket: &mut TcpSocket<'_>) -> Result<ConnectReturnCode, Error> {
+    let mut buffer = [0; 4];
+    let mut len = 0;
+    while len < buffer.len() {
+        match socket.read(&mut buffer[len..]).await? {
+            0 => return Err(Error::Closed),
+            read => len += read,
+        }
+    }
+    Ok(packet::decode_connack(&buffer)?)
+}
+
+/// Connects, then publishes the measurements until the connection is lost.
+async fn session(
+    socket: &mut TcpSocket<'_>,
+    settings: &MqttSettings,
+    measurements: &'static TheWatch,
+    backoff: &mut Backoff,
+) -> Result<(), Error> {
+    socket
+        .connect(settings.broker)
+        .await
+        .map_err(Error::Connect)?;
+
+    let status = topic(settings.topic, "status");
+    let mut buffer = [0; MAX_PACKET_SIZE];
+    let len = packet::encode_connect(
+        &Connect {
+            client_id: settings.client_id,
+            keep_alive_secs: KEEP_ALIVE.as_secs() as u16,
+            username: settings.username,
+            password: settings.password,
+            will: Some(Will {
+                topic: &status,
+                payload: OFFLINE,
+                retain: true,
+            }),
+        },
+        &mut buffer,
+    )?;
+    write_all(socket, &buffer[..len]).await?;
+    match with_timeout(CONNECT_TIMEOUT, read_connack(socket)).await {
+        Ok(Ok(ConnectReturnCode::Accepted)) => {}
+        Ok(Ok(code)) => return Err(Error::Refused(code)),
+        Ok(Err(e)) => return Err(e),
+        Err(_) => return Err(Error::Timeout),
+    }
+    info!("Connected to the MQTT broker {}", settings.broker);
+    backoff.reset();
+
+    publish(socket, &status, ONLINE, true).await?;
+    for quantity in Quantity::ALL {
+        let payload = discovery_payload(settings, quantity);
+        publish(
+            socket,
+            &discovery_topic(settings.client_id, quantity.as_str()),
+            payload.as_bytes(),
+            true,
+        )
+        .await?;
+    }
+
+    let mut ticker = Ticker::every(PUBLISH_INTERVAL);
+    let mut published_ms = None;
+    let mut sent_at = Instant::now();
+    loop {
+        // Only PINGRESP is expected, the client doesn't subscribe
+        match select(ticker.next(), socket.read(&mut buffer)).await {
+            Either::First(()) => {}
+            Either::Second(Ok(0)) => return Err(Error::Closed),
+            Either::Second(Ok(_)) => continue,
+            Either::Second(Err(e)) => return Err(e.into()),
+        }
+
+        if let Some(latest) = measurements.try_get()
+            && published_ms != Some(latest.timestamp_ms)
+        {
+            published_ms = Some(latest.timestamp_ms);
+            for quantity in Quantity::ALL {
+                let Some(field) = latest
+                    .field(quantity)
+                    .filter(|field| field.value.is_finite())
+                else {
+                    continue;
+                };
+                let mut payload = String::<16>::new();
+                // A finite f32 fits
+                write!(payload, "{}", field.value).unwrap();
+                publish(
+                    socket,
+                    &topic(settings.topic, quantity.as_str()),
+                    payload.as_bytes(),
+                    false,
+                )
+                .await?;
+                sent_at = Instant::now();
+            }
+        }
+        if sent_at.elapsed() >= KEEP_ALIVE / 2 {
+            write_all(socket, &packet::PINGREQ).await?;
+            sent_at = Instant::now();
+        }
+    }
+}
+
+/// Publishes the latest measurements to the broker of `settings` every
+/// [`PUBLISH_INTERVAL`], reconnecting with exponential backoff.
+///
+/// The station is announced to Home Assistant with retained discovery
+/// payloads, and its availability is `offline` when the connection is lost.
+#[embassy_executor::task]
+pub async fn mqtt_task(
+    stack: Stack<'static>,
+    settings: MqttSettings,
+    measurements: &'static TheWatch,
+) {
+    let mut rx_buffer = [0; 512];
+    let mut tx_buffer = [0; 2048];
+    let mut backoff = Backoff::new(INITIAL_BACKOFF, MAX_BACKOFF);
+    loop {
+        stack.wait_config_up().await;
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(KEEP_ALIVE * 2));
+        if let Err(e) = session(&mut socket, &settings, measurements, &mut backoff).await {
+            warn!("MQTT connection to {} lost: {:?}", settings.broker, e);
+        }
+        socket.abort();
+        let _ = socket.flush().await;
+        drop(socket);
+
+        Timer::after(backoff.next_delay()).await;
+    }
+}
@@ -0,0 +1,372 @@
+// Encoding of the MQTT 3.1.1 packets sent by the client and decoding of the
+// ones it reads
+/// Protocol level of MQTT 3.1.1.
+const PROTOCOL_LEVEL: u8 = 4;
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+
+/// Asks the broker to answer, so it knows the client is alive.
+pub const PINGREQ: [u8; 2] = [0xC0, 0];
+
+/// Closes the connection without publishing the will.
+pub const DISCONNECT: [u8; 2] = [0xE0, 0];
+
+const FLAG_USERNAME: u8 = 0x80;
+const FLAG_PASSWORD: u8 = 0x40;
+const FLAG_WILL_RETAIN: u8 = 0x20;
+const FLAG_WILL: u8 = 0x04;
+const FLAG_CLEAN_SESSION: u8 = 0x02;
+
+const FLAG_RETAIN: u8 = 0x01;
+
+/// Largest remaining length of a packet.
+const MAX_REMAINING_LENGTH: usize = 268_435_455;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, defmt::Format)]
+pub enum Error {
+    /// The packet doesn't fit in the buffer.
+    BufferTooSmall,
+    /// A string or payload is longer than the protocol allows.
+    TooLong,
+    /// The bytes read aren't the expected packet.
+    Malformed,
+}
+
+/// Message published by the broker when the client disconnects unexpectedly.
+#[derive(Copy, Clone, Debug)]
+pub struct Will<'a> {
+    pub topic: &'a str,
+    pub payload: &'a [u8],
+    pub retain: bool,
+}
+
+/// Opens a clean session, messages are published with QoS 0.
+#[derive(Copy, Clone, Debug)]
+pub struct Connect<'a> {
+    pub client_id: &'a str,
+    /// The broker closes the connection when nothing is received for 1.5
+    /// times this, 0 disables it.
+    pub keep_alive_secs: u16,
+    pub username: Option<&'a str>,
+    pub password: Option<&'a str>,
+    pub will: Option<Will<'a>>,
+}
+
+/// Answer of the broker to [`Connect`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq, defmt::Format)]
+pub enum ConnectReturnCode {
+    Accepted,
+    UnacceptableProtocolVersion,
+    IdentifierRejected,
+    ServerUnavailable,
+    BadUsernameOrPassword,
+    NotAuthorized,
+    Other(u8),
+}
+
+impl From<u8> for ConnectReturnCode {
+    fn from(code: u8) -> Self {
+        match code {
+            0 => Self::Accepted,
+            1 => Self::UnacceptableProtocolVersion,
+            2 => Self::IdentifierRejected,
+            3 => Self::ServerUnavailable,
+            4 => Self::BadUsernameOrPassword,
+            5 => Self::NotAuthorized,
+            code => Self::Other(code),
+        }
+    }
+}
+
+struct Writer<'b> {
+    buffer: &'b mut [u8],
+    len: usize,
+}
+
+impl Writer<'_> {
+    fn put(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        self.buffer
+            .get_mut(self.len..self.len + bytes.len())
+            .ok_or(Error::BufferTooSmall)?
+            .copy_from_slice(bytes);
+        self.len += bytes.len();
+        Ok(())
+    }
+
+    /// Bytes prefixed by their length.
+    fn put_prefixed(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        let len = u16::try_from(bytes.len()).map_err(|_| Error::TooLong)?;
+        self.put(&len.to_be_bytes())?;
+        self.put(bytes)
+    }
+
+    /// The fixed header of a packet of `remaining` bytes.
+    fn put_fixed_header(&mut self, first: u8, mut remaining: usize) -> Result<(), Error> {
+        if remaining > MAX_REMAINING_LENGTH {
+            return Err(Error::TooLong);
+        }
+        self.put(&[first])?;
+        // 7 bits per byte, the high bit tells another one follows
+        loop {
+            let byte = (remaining % 128) as u8;
+            remaining /= 128;
+            if remaining == 0 {
+                return self.put(&[byte]);
+            }
+            self.put(&[byte | 0x80])?;
+        }
+    }
+}
+
+/// Size of `bytes` prefixed by their length.
+fn prefixed_len(bytes: &[u8]) -> usize {
+    2 + bytes.len()
+}
+
+/// Encodes `connect` into `buffer`, returns the size of the packet.
+pub fn encode_connect(connect: &Connect, buffer: &mut [u8]) -> Result<usize, Error> {
+    let mut flags = FLAG_CLEAN_SESSION;
+    // Protocol name, level, flags and keep alive
+    let mut remaining = prefixed_len(b"MQTT") + 4 + prefixed_len(connect.client_id.as_bytes());
+    if let Some(will) = connect.will {
+        flags |= FLAG_WILL;
+        if will.retain {
+            flags |= FLAG_WILL_RETAIN;
+        }
+        remaining += prefixed_len(will.topic.as_bytes()) + prefixed_len(will.payload);
+    }
+    if let Some(username) = connect.username {
+        flags |= FLAG_USERNAME;
+        remaining += prefixed_len(username.as_bytes());
+    }
+    if let Some(password) = connect.password {
+        flags |= FLAG_PASSWORD;
+        remaining += prefixed_len(password.as_bytes());
+    }
+
+    let mut writer = Writer { buffer, len: 0 };
+    writer.put_fixed_header(CONNECT, remaining)?;
+    writer.put_prefixed(b"MQTT")?;
+    writer.put(&[PROTOCOL_LEVEL, flags])?;
+    writer.put(&connect.keep_alive_secs.to_be_bytes())?;
+    writer.put_prefixed(connect.client_id.as_bytes())?;
+    if let Some(will) = connect.will {
+        writer.put_prefixed(will.topic.as_bytes())?;
+        writer.put_prefixed(will.payload)?;
+    }
+    if let Some(username) = connect.username {
+        writer.put_prefixed(username.as_bytes())?;
+    }
+    if let Some(password) = connect.password {
+        writer.put_prefixed(password.as_bytes())?;
+    }
+    Ok(writer.len)
+}
+
+/// Encodes a QoS 0 publication into `buffer`, returns the size of the packet.
+pub fn encode_publish(
+    topic: &str,
+    payload: &[u8],
+    retain: bool,
+    buffer: &mut [u8],
+) -> Result<usize, Error> {
+    let first = if retain {
+        PUBLISH | FLAG_RETAIN
+    } else {
+        PUBLISH
+    };
+    let mut writer = Writer { buffer, len: 0 };
+    writer.put_fixed_header(first, prefixed_len(topic.as_bytes()) + payload.len())?;
+    writer.put_prefixed(topic.as_bytes())?;
+    writer.put(payload)?;
+    Ok(writer.len)
+}
+
+/// Decodes the fixed header at the start of `bytes`, returns the packet type
+/// and flags, the remaining length and the size of the header. `None` if
+/// more bytes are needed.
+pub fn decode_fixed_header(bytes: &[u8]) -> Result<Option<(u8, usize, usize)>, Error> {
+    let Some((&first, rest)) = bytes.split_first() else {
+        return Ok(None);
+    };
+    let mut remaining = 0;
+    for (i, &byte) in rest.iter().enumerate().take(4) {
+        remaining |= usize::from(byte & 0x7F) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(Some((first, remaining, i + 2)));
+        }
+    }
+    if rest.len() >= 4 {
+        return Err(Error::Malformed);
+    }
+    Ok(None)
+}
+
+/// Decodes a CONNACK packet.
+pub fn decode_connack(bytes: &[u8]) -> Result<ConnectReturnCode, Error> {
+    match *bytes {
+        [CONNACK, 2, _, code] => Ok(code.into()),
+        _ => Err(Error::Malformed),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixed_header(remaining: usize) -> ([u8; 5], usize) {
+        let mut buffer = [0; 5];
+        let mut writer = Writer {
+            buffer: &mut buffer,
+            len: 0,
+        };
+        writer.put_fixed_header(PUBLISH, remaining).unwrap();
+        let len = writer.len;
+        (buffer, len)
+    }
+
+    #[test]
+    fn encodes_the_remaining_length() {
+        for (remaining, header) in [
+            (0, &[0x30, 0x00][..]),
+            (127, &[0x30, 0x7F]),
+            (128, &[0x30, 0x80, 0x01]),
+            (16_383, &[0x30, 0xFF, 0x7F]),
+            (16_384, &[0x30, 0x80, 0x80, 0x01]),
+            (2_097_151, &[0x30, 0xFF, 0xFF, 0x7F]),
+            (2_097_152, &[0x30, 0x80, 0x80, 0x80, 0x01]),
+            (MAX_REMAINING_LENGTH, &[0x30, 0xFF, 0xFF, 0xFF, 0x7F]),
+        ] {
+            let (buffer, len) = fixed_header(remaining);
+            assert_eq!(&buffer[..len], header);
+            assert_eq!(
+                decode_fixed_header(header),
+                Ok(Some((0x30, remaining, len)))
+            );
+        }
+
+        let mut buffer = [0; 5];
+        let mut writer = Writer {
+            buffer: &mut buffer,
+            len: 0,
+        };
+        assert_eq!(
+            writer.put_fixed_header(PUBLISH, MAX_REMAINING_LENGTH + 1),
+            Err(Error::TooLong)
+        );
+    }
+
+    #[test]
+    fn decodes_partial_and_malformed_fixed_headers() {
+        assert_eq!(decode_fixed_header(&[]), Ok(None));
+        assert_eq!(decode_fixed_header(&[0x20]), Ok(None));
+        assert_eq!(decode_fixed_header(&[0x30, 0x80, 0x80]), Ok(None));
+        assert_eq!(
+            decode_fixed_header(&[0x30, 0x80, 0x80, 0x80, 0x80]),
+            Err(Error::Malformed)
+        );
+        // The rest of the packet follows
+        assert_eq!(
+            decode_fixed_header(&[0x20, 0x02, 0x00, 0x00]),
+            Ok(Some((0x20, 2, 2)))
+        );
+    }
+
+    #[test]
+    fn encodes_an_anonymous_connect() {
+        let connect = Connect {
+            client_id: "ws",
+            keep_alive_secs: 60,
+            username: None,
+            password: None,
+            will: None,
+        };
+        let mut buffer = [0; 64];
+        let len = encode_connect(&connect, &mut buffer).unwrap();
+
+        assert_eq!(
+            &buffer[..len],
+            b"\x10\x0E\x00\x04MQTT\x04\x02\x00\x3C\x00\x02ws"
+        );
+    }
+
+    #[test]
+    fn encodes_a_connect_with_credentials_and_will() {
+        let connect = Connect {
+            client_id: "ws",
+            keep_alive_secs: 60,
+            username: Some("user"),
+            password: Some("pass"),
+            will: Some(Will {
+                topic: "ws/status",
+                payload: b"offline",
+                retain: true,
+            }),
+        };
+        let mut buffer = [0; 64];
+        let len = encode_connect(&connect, &mut buffer).unwrap();
+
+        assert_eq!(
+            &buffer[..len],
+            b"\x10\x2E\x00\x04MQTT\x04\xE6\x00\x3C\x00\x02ws\
+              \x00\x09ws/status\x00\x07offline\x00\x04user\x00\x04pass"
+        );
+        assert_eq!(
+            encode_connect(&connect, &mut buffer[..len - 1]),
+            Err(Error::BufferTooSmall)
+        );
+    }
+
+    #[test]
+    fn encodes_publications() {
+        let mut buffer = [0; 32];
+        let len = encode_publish("ws/temperature", b"21.5", false, &mut buffer).unwrap();
+        assert_eq!(&buffer[..len], b"\x30\x14\x00\x0Ews/temperature21.5");
+
+        let len = encode_publish("ws/status", b"online", true, &mut buffer).unwrap();
+        assert_eq!(&buffer[..len], b"\x31\x11\x00\x09ws/statusonline");
+    }
+
+    #[test]
+    fn encodes_a_long_publication() {
+        let payload = [b'x'; 200];
+        let mut buffer = [0; 256];
+        let len = encode_publish("t", &payload, false, &mut buffer).unwrap();
+
+        // 3 bytes of topic and 200 of payload
+        assert_eq!(&buffer[..6], b"\x30\xCB\x01\x00\x01t");
+        assert_eq!(len, 206);
+        assert_eq!(
+            encode_publish("t", &payload, false, &mut buffer[..205]),
+            Err(Error::BufferTooSmall)
+        );
+    }
+
+    #[test]
+    fn encodes_pings_and_disconnections() {
+        assert_eq!(decode_fixed_header(&PINGREQ), Ok(Some((0xC0, 0, 2))));
+        assert_eq!(decode_fixed_header(&DISCONNECT), Ok(Some((0xE0, 0, 2))));
+    }
+
+    #[test]
+    fn decodes_connack() {
+        assert_eq!(
+            decode_connack(&[0x20, 0x02, 0x00, 0x00]),
+            Ok(ConnectReturnCode::Accepted)
+        );
+        assert_eq!(
+            decode_connack(&[0x20, 0x02, 0x00, 0x04]),
+            Ok(ConnectReturnCode::BadUsernameOrPassword)
+        );
+        assert_eq!(
+            decode_connack(&[0x20, 0x02, 0x00, 0x09]),
+            Ok(ConnectReturnCode::Other(9))
+        );
+        assert_eq!(
+            decode_connack(&[0x20, 0x03, 0x00, 0x00]),
+            Err(Error::Malformed)
+        );
+    }
+}
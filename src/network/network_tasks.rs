@@ -1,14 +1,50 @@
-use defmt::{debug, info};
+use defmt::{debug, error, info, warn};
 use embassy_net::Runner;
 use embassy_time::{Duration, Timer};
 use esp_radio::wifi::{
-    AccessPointConfig,  ModeConfig, WifiController, WifiDevice, WifiEvent, WifiApState
+    AccessPointConfig, AuthMethod as RadioAuthMethod, ModeConfig, WifiApState, WifiController,
+    WifiDevice, WifiEvent,
 };
 
+use crate::config::{AuthMethod, validate_passphrase};
+
+/// How long to wait before retrying to start the access point.
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+fn radio_auth_method(auth: AuthMethod) -> RadioAuthMethod {
+    match auth {
+        AuthMethod::Open => RadioAuthMethod::None,
+        AuthMethod::Wpa2 => RadioAuthMethod::Wpa2Personal,
+        AuthMethod::Wpa3 => RadioAuthMethod::Wpa3Personal,
+        AuthMethod::Wpa2Wpa3 => RadioAuthMethod::Wpa2Wpa3Personal,
+    }
+}
+
+/// Runs the access point, the passphrase is ignored if it's `AuthMethod::Open`.
 #[embassy_executor::task]
-pub async fn connection(mut controller: WifiController<'static>, ssid: &'static str) {
+pub async fn connection(
+    mut controller: WifiController<'static>,
+    ssid: &'static str,
+    auth: AuthMethod,
+    passphrase: &'static str,
+) {
     info!("start connection task");
     debug!("Device capabilities: {:?}", controller.capabilities());
+
+    if let Err(e) = validate_passphrase(auth, passphrase) {
+        error!("Not starting the access point, {}", e.as_str());
+        return;
+    }
+    let mut ap_config = AccessPointConfig::default()
+        .with_ssid(ssid.into())
+        .with_auth_method(radio_auth_method(auth));
+    if auth == AuthMethod::Open {
+        warn!("The access point {} is open", ssid);
+    } else {
+        ap_config = ap_config.with_password(passphrase.into());
+    }
+    let ap_config = ModeConfig::AccessPoint(ap_config);
+
     loop {
         if esp_radio::wifi::ap_state() == WifiApState::Started {
             // wait until we're no longer connected
@@ -16,10 +52,17 @@ pub async fn connection(mut controller: WifiController<'static>, ssid: &'static
             Timer::after(Duration::from_millis(5000)).await
         }
         if !matches!(controller.is_started(), Ok(true)) {
-            let client_config = ModeConfig::AccessPoint(AccessPointConfig::default().with_ssid(ssid.into()));
-            controller.set_config(&client_config).unwrap();
+            if let Err(e) = controller.set_config(&ap_config) {
+                error!("Failed to configure the access point: {:?}", e);
+                Timer::after(RETRY_DELAY).await;
+                continue;
+            }
             info!("Starting wifi");
-            controller.start_async().await.unwrap();
+            if let Err(e) = controller.start_async().await {
+                error!("Failed to start the access point: {:?}", e);
+                Timer::after(RETRY_DELAY).await;
+                continue;
+            }
             info!("Wifi started!");
         }
     }
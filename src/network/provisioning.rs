@@ -0,0 +1,99 @@
+// Provisioning of the network to join, the station runs its access point with a setup page
+use core::cell::RefCell;
+
+use defmt::{info, warn};
+use embassy_futures::select::{Either, select};
+use embassy_sync::blocking_mutex::{Mutex, raw::NoopRawMutex};
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Timer};
+use esp_radio::wifi::{
+    AuthMethod, ClientConfig, ModeConfig, ScanConfig, WifiController, WifiError,
+};
+use heapless::{String, Vec};
+
+/// Failed attempts to join the network before the station falls back to
+/// provisioning.
+pub const PROVISIONING_AFTER: u32 = 5;
+
+/// How long provisioning lasts when the network couldn't be joined, the
+/// station then tries it again.
+pub const PROVISIONING_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// How many scanned networks are kept.
+pub const MAX_NETWORKS: usize = 16;
+
+/// Delay before rebooting, so the response to the request is sent.
+const REBOOT_DELAY: Duration = Duration::from_secs(1);
+
+/// A network found by [`scan`].
+#[derive(Clone, Debug, defmt::Format)]
+pub struct Network {
+    pub ssid: String<32>,
+    /// RSSI in dBm.
+    pub signal_strength: i8,
+    pub open: bool,
+}
+
+/// The networks found before the access point was started, strongest first.
+pub type TheNetworks = Mutex<NoopRawMutex, RefCell<Vec<Network, MAX_NETWORKS>>>;
+
+/// Signaled to reboot the station, e.g. once it's provisioned.
+pub type TheReboot = Signal<NoopRawMutex, ()>;
+
+/// Scans for networks, strongest first and without hidden or duplicate ones.
+///
+/// The access point can't scan, so the controller is started as a client and
+/// stopped afterwards.
+pub async fn scan(
+    controller: &mut WifiController<'static>,
+) -> Result<Vec<Network, MAX_NETWORKS>, WifiError> {
+    controller.set_config(&ModeConfig::Client(ClientConfig::default()))?;
+    controller.start_async().await?;
+    let found = controller
+        .scan_with_config_async(ScanConfig::default())
+        .await;
+    controller.stop_async().await?;
+
+    let mut found = found?;
+    found.sort_unstable_by_key(|info| core::cmp::Reverse(info.signal_strength));
+    let mut networks = Vec::new();
+    for info in found {
+        let Ok(ssid) = String::try_from(info.ssid.as_str()) else {
+            continue;
+        };
+        if ssid.is_empty()
+            || networks
+                .iter()
+                .any(|network: &Network| network.ssid == ssid)
+        {
+            continue;
+        }
+        let network = Network {
+            ssid,
+            signal_strength: info.signal_strength,
+            open: matches!(info.auth_method, None | Some(AuthMethod::None)),
+        };
+        if networks.push(network).is_err() {
+            break;
+        }
+    }
+    info!("Found {} networks", networks.len());
+    Ok(networks)
+}
+
+/// Reboots when `reboot` is signaled, or after `timeout`.
+#[embassy_executor::task]
+pub async fn reboot_task(reboot: &'static TheReboot, timeout: Option<Duration>) {
+    let timeout = async {
+        match timeout {
+            Some(timeout) => Timer::after(timeout).await,
+            None => core::future::pending().await,
+        }
+    };
+    match select(reboot.wait(), timeout).await {
+        Either::First(()) => info!("Rebooting"),
+        Either::Second(()) => warn!("Nothing was provisioned, rebooting"),
+    }
+    Timer::after(REBOOT_DELAY).await;
+    esp_hal::system::software_reset()
+}
@@ -0,0 +1,267 @@
+// Joins an existing network, reconnecting with exponential backoff
+use defmt::{error, info, warn};
+use embassy_futures::select::{Either, select};
+use embassy_net::Stack;
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::watch::{Sender, Watch};
+use embassy_time::{Duration, Timer};
+use esp_radio::wifi::{AuthMethod, ClientConfig, ModeConfig, WifiController, WifiEvent};
+
+use super::provisioning::PROVISIONING_AFTER;
+use crate::config::TheConfig;
+
+/// Delay before the first reconnection attempt.
+pub const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Longest delay between reconnection attempts.
+pub const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+/// State of the connection to the configured network.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, defmt::Format)]
+pub enum LinkState {
+    /// Not connected, waiting before the next attempt.
+    Down,
+    Connecting,
+    /// Associated with the access point, waiting for an address.
+    Associated,
+    /// Has an address.
+    Up,
+}
+
+impl LinkState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LinkState::Down => "down",
+            LinkState::Connecting => "connecting",
+            LinkState::Associated => "associated",
+            LinkState::Up => "up",
+        }
+    }
+}
+
+/// Delays doubling from `initial` up to `max`.
+#[derive(Copy, Clone, Debug)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    next: Duration,
+}
+
+impl Backoff {
+    pub const fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            initial,
+            max,
+            next: initial,
+        }
+    }
+
+    /// The delay to wait now, the next one is twice as long.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (self.next * 2).min(self.max);
+        delay
+    }
+
+    pub fn reset(&mut self) {
+        self.next = self.initial;
+    }
+}
+
+/// Tracks the [`LinkState`] and how long to wait before reconnecting.
+#[derive(Copy, Clone, Debug)]
+pub struct Link {
+    state: LinkState,
+    backoff: Backoff,
+    failures: u32,
+}
+
+impl Link {
+    pub const fn new(backoff: Backoff) -> Self {
+        Self {
+            state: LinkState::Down,
+            backoff,
+            failures: 0,
+        }
+    }
+
+    pub fn state(&self) -> LinkState {
+        self.state
+    }
+
+    /// Attempts that failed since the link was last up.
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    pub fn connecting(&mut self) {
+        self.state = LinkState::Connecting;
+    }
+
+    pub fn associated(&mut self) {
+        self.state = LinkState::Associated;
+    }
+
+    pub fn up(&mut self) {
+        self.state = LinkState::Up;
+        self.failures = 0;
+        self.backoff.reset();
+    }
+
+    /// The attempt failed or the link was lost, returns how long to wait
+    /// before the next attempt.
+    pub fn down(&mut self) -> Duration {
+        if self.state != LinkState::Up {
+            self.failures += 1;
+        }
+        self.state = LinkState::Down;
+        self.backoff.next_delay()
+    }
+}
+
+/// The latest [`LinkState`] of the station.
+pub type TheLinkState = Watch<NoopRawMutex, LinkState, 2>;
+
+fn report(sender: &Sender<'static, NoopRawMutex, LinkState, 2>, link: &Link) {
+    info!("Station link is {}", link.state());
+    sender.send(link.state());
+}
+
+/// Keeps the station connected to `ssid`, an empty `passphrase` is an open
+/// network.
+///
+/// After [`PROVISIONING_AFTER`] failed attempts the station reboots to be
+/// provisioned, if the request can be stored in `config`.
+#[embassy_executor::task]
+pub async fn station(
+    mut controller: WifiController<'static>,
+    stack: Stack<'static>,
+    ssid: &'static str,
+    passphrase: &'static str,
+    link_state: &'static TheLinkState,
+    config: &'static TheConfig,
+) {
+    // The auth method is the weakest one accepted
+    let client_config = ClientConfig::default()
+        .with_ssid(ssid.into())
+        .with_auth_method(if passphrase.is_empty() {
+            AuthMethod::None
+        } else {
+            AuthMethod::Wpa2Personal
+        })
+        .with_password(passphrase.into());
+    if let Err(e) = controller.set_config(&ModeConfig::Client(client_config)) {
+        error!("Failed to configure the station: {:?}", e);
+        return;
+    }
+
+    let sender = link_state.sender();
+    let mut link = Link::new(Backoff::new(INITIAL_BACKOFF, MAX_BACKOFF));
+    report(&sender, &link);
+    loop {
+        if !matches!(controller.is_started(), Ok(true))
+            && let Err(e) = controller.start_async().await
+        {
+            error!("Failed to start wifi: {:?}", e);
+        } else {
+            link.connecting();
+            report(&sender, &link);
+            match controller.connect_async().await {
+                Ok(()) => {
+                    link.associated();
+                    report(&sender, &link);
+                    // Pending events are kept, so a disconnection while
+                    // waiting for the address isn't missed
+                    let disconnected =
+                        controller.wait_for_events(WifiEvent::StaDisconnected.into(), false);
+                    if let Either::First(()) = select(stack.wait_config_up(), disconnected).await {
+                        link.up();
+                        report(&sender, &link);
+                        controller
+                            .wait_for_events(WifiEvent::StaDisconnected.into(), false)
+                            .await;
+                    }
+                }
+                Err(e) => warn!("Failed to connect to {}: {:?}", ssid, e),
+            }
+        }
+
+        let delay = link.down();
+        report(&sender, &link);
+        info!(
+            "Reconnecting in {} s, {} failed attempts",
+            delay.as_secs(),
+            link.failures()
+        );
+        if link.failures() == PROVISIONING_AFTER {
+            match config.lock(|config| config.borrow_mut().request_provisioning()) {
+                Ok(()) => {
+                    warn!("Failed to join {}, rebooting to be provisioned", ssid);
+                    esp_hal::system::software_reset()
+                }
+                Err(e) => warn!("Can't fall back to provisioning: {:?}", e),
+            }
+        }
+        Timer::after(delay).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::*;
+
+    /// The next `count` delays in seconds.
+    fn delays(backoff: &mut Backoff, count: usize) -> Vec<u64> {
+        (0..count).map(|_| backoff.next_delay().as_secs()).collect()
+    }
+
+    #[test]
+    fn doubles_the_delay_up_to_the_maximum() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(10));
+
+        assert_eq!(delays(&mut backoff, 6), [1, 2, 4, 8, 10, 10]);
+    }
+
+    #[test]
+    fn resets_to_the_initial_delay() {
+        let mut backoff = Backoff::new(Duration::from_secs(3), Duration::from_secs(60));
+        delays(&mut backoff, 3);
+        backoff.reset();
+
+        assert_eq!(delays(&mut backoff, 2), [3, 6]);
+    }
+
+    #[test]
+    fn counts_the_failed_attempts() {
+        let mut link = Link::new(Backoff::new(INITIAL_BACKOFF, MAX_BACKOFF));
+        link.connecting();
+        assert_eq!(link.down(), INITIAL_BACKOFF);
+        link.connecting();
+        link.associated();
+        assert_eq!(link.down(), INITIAL_BACKOFF * 2);
+
+        assert_eq!(link.state(), LinkState::Down);
+        assert_eq!(link.failures(), 2);
+    }
+
+    #[test]
+    fn starts_over_once_up() {
+        let mut link = Link::new(Backoff::new(INITIAL_BACKOFF, MAX_BACKOFF));
+        for _ in 0..20 {
+            link.down();
+        }
+        link.connecting();
+        link.up();
+        assert_eq!(link.failures(), 0);
+
+        // Losing the link isn't a failed attempt
+        assert_eq!(link.down(), INITIAL_BACKOFF);
+        assert_eq!(link.failures(), 0);
+        assert_eq!(link.down(), INITIAL_BACKOFF * 2);
+        assert_eq!(link.failures(), 1);
+    }
+}
@@ -0,0 +1,129 @@
+// Changes to the sampling loop requested at runtime, e.g. over the WebSocket
+use core::cell::RefCell;
+
+use embassy_futures::select::{Either, select};
+use embassy_sync::blocking_mutex::{Mutex, raw::NoopRawMutex};
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Timer};
+
+use crate::sensors::bme280;
+use crate::sensors::weather_sensor::Quantity;
+
+/// Added to the measured values, in the units they're published in.
+#[derive(Copy, Clone, Default, Debug, defmt::Format)]
+pub struct Offsets {
+    pub temperature: f32,
+    pub humidity: f32,
+    pub pressure: f32,
+}
+
+impl Offsets {
+    fn get_mut(&mut self, quantity: Quantity) -> &mut f32 {
+        match quantity {
+            Quantity::Temperature => &mut self.temperature,
+            Quantity::Humidity => &mut self.humidity,
+            Quantity::Pressure => &mut self.pressure,
+        }
+    }
+}
+
+struct Sampling {
+    interval: Duration,
+    offsets: Offsets,
+    /// A round was requested before the end of the interval.
+    read_now: bool,
+    /// Settings of the BME280 to apply before the next round.
+    bme280: Option<bme280::ConfigUpdate>,
+}
+
+/// The interval, calibration and sensor settings of the sampling loop, they
+/// last until the next boot.
+pub struct SamplingControl {
+    sampling: Mutex<NoopRawMutex, RefCell<Sampling>>,
+    /// Wakes [`SamplingControl::wait`] up to take the change into account.
+    changed: Signal<NoopRawMutex, ()>,
+}
+
+impl SamplingControl {
+    pub const fn new(interval: Duration) -> Self {
+        Self {
+            sampling: Mutex::new(RefCell::new(Sampling {
+                interval,
+                offsets: Offsets {
+                    temperature: 0.0,
+                    humidity: 0.0,
+                    pressure: 0.0,
+                },
+                read_now: false,
+                bme280: None,
+            })),
+            changed: Signal::new(),
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.sampling.lock(|sampling| sampling.borrow().interval)
+    }
+
+    /// Sets the time between two rounds, the current wait is shortened or
+    /// extended accordingly.
+    pub fn set_interval(&self, interval: Duration) {
+        self.sampling
+            .lock(|sampling| sampling.borrow_mut().interval = interval);
+        self.changed.signal(());
+    }
+
+    /// Ends the current wait, so a round is taken right away.
+    pub fn read_now(&self) {
+        self.sampling
+            .lock(|sampling| sampling.borrow_mut().read_now = true);
+        self.changed.signal(());
+    }
+
+    pub fn offsets(&self) -> Offsets {
+        self.sampling.lock(|sampling| sampling.borrow().offsets)
+    }
+
+    /// Sets the offset added to the values of `quantity`, from the next round on.
+    pub fn calibrate(&self, quantity: Quantity, offset: f32) {
+        self.sampling
+            .lock(|sampling| *sampling.borrow_mut().offsets.get_mut(quantity) = offset);
+    }
+
+    /// Changes the settings of the BME280 and takes a round with them right
+    /// away, replaces a change that wasn't applied yet.
+    pub fn configure_bme280(&self, update: bme280::ConfigUpdate) {
+        self.sampling.lock(|sampling| {
+            let mut sampling = sampling.borrow_mut();
+            sampling.bme280 = Some(update);
+            sampling.read_now = true;
+        });
+        self.changed.signal(());
+    }
+
+    /// The change of the BME280 settings to apply, if one was requested.
+    pub fn take_bme280_update(&self) -> Option<bme280::ConfigUpdate> {
+        self.sampling
+            .lock(|sampling| sampling.borrow_mut().bme280.take())
+    }
+
+    /// Waits for the interval to elapse since the call, or for a round to be
+    /// requested.
+    pub async fn wait(&self) {
+        let started = Instant::now();
+        loop {
+            let (interval, read_now) = self.sampling.lock(|sampling| {
+                let mut sampling = sampling.borrow_mut();
+                (sampling.interval, core::mem::take(&mut sampling.read_now))
+            });
+            if read_now {
+                return;
+            }
+            if let Either::First(()) =
+                select(Timer::at(started + interval), self.changed.wait()).await
+            {
+                return;
+            }
+        }
+    }
+}
@@ -1 +1,5 @@
+pub mod bme280;
+pub mod dht;
 pub mod dht11;
+pub mod dht22;
+pub mod weather_sensor;
@@ -0,0 +1,719 @@
+// Driver for the Bosch BME280 and its humidity-less sibling, the BMP280.
+// The chip is detected at runtime, compensation follows the floating point
+// formulas of the BME280 datasheet (section 8.1)
+use embedded_hal_async::{delay::DelayNs, i2c::I2c};
+
+pub mod config;
+
+pub use config::{Config, ConfigUpdate, Filter, Mode, Oversampling, Preset, Standby};
+
+use super::weather_sensor::{
+    ErrorKind, PartialReading, Quantities, Quantity, SensorError, WeatherSensor,
+};
+
+/// I2C address with SDO pulled low.
+pub const PRIMARY_ADDRESS: u8 = 0x76;
+
+/// I2C address with SDO pulled high.
+pub const SECONDARY_ADDRESS: u8 = 0x77;
+
+const CHIP_ID_ADDR: u8 = 0xD0;
+const RESET_ADDR: u8 = 0xE0;
+const CTRL_HUM_ADDR: u8 = 0xF2;
+const CTRL_MEAS_ADDR: u8 = 0xF4;
+const CONFIG_ADDR: u8 = 0xF5;
+const DATA_ADDR: u8 = 0xF7;
+const PT_CALIB_ADDR: u8 = 0x88;
+const H_CALIB_ADDR: u8 = 0xE1;
+
+const SOFT_RESET_CMD: u8 = 0xB6;
+
+// Value of the data registers of a skipped channel
+const SKIPPED_PT: u32 = 0x80000;
+const SKIPPED_H: u32 = 0x8000;
+
+/// Error type for this driver.
+#[derive(Debug, defmt::Format)]
+pub enum Error<E> {
+    /// The chip id is neither a BMP280 nor a BME280 one.
+    UnsupportedChip(u8),
+
+    /// The calibration data can't be used to compensate a measurement.
+    InvalidData,
+
+    /// The standby time of the configuration isn't supported by the chip.
+    UnsupportedConfig,
+
+    /// The sensor is configured in [`Mode::Sleep`].
+    Sleeping,
+
+    /// I2C error.
+    Bus(E),
+}
+
+impl<E: defmt::Format> SensorError for Error<E> {
+    fn kind(&self) -> ErrorKind {
+        ErrorKind::Other
+    }
+}
+
+/// The detected chip.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, defmt::Format)]
+pub enum Chip {
+    /// Temperature and pressure.
+    Bmp280,
+
+    /// Temperature, pressure and humidity.
+    Bme280,
+}
+
+impl Chip {
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0x58 => Some(Chip::Bmp280),
+            0x60 => Some(Chip::Bme280),
+            _ => None,
+        }
+    }
+
+    pub fn has_humidity(self) -> bool {
+        self == Chip::Bme280
+    }
+}
+
+/// Compensated results of a measurement, `None` for skipped channels.
+#[derive(Copy, Clone, Debug, defmt::Format)]
+pub struct Measurements {
+    /// Degrees Celsius.
+    pub temperature: Option<f32>,
+
+    /// Pascals.
+    pub pressure: Option<f32>,
+
+    /// Relative humidity in percent, always `None` on a BMP280.
+    pub humidity: Option<f32>,
+}
+
+#[derive(Debug)]
+struct Calibration {
+    t1: u16,
+    t2: i16,
+    t3: i16,
+    p1: u16,
+    p2: i16,
+    p3: i16,
+    p4: i16,
+    p5: i16,
+    p6: i16,
+    p7: i16,
+    p8: i16,
+    p9: i16,
+    humidity: Option<HumidityCalibration>,
+}
+
+#[derive(Debug)]
+struct HumidityCalibration {
+    h1: u8,
+    h2: i16,
+    h3: u8,
+    h4: i16,
+    h5: i16,
+    h6: i8,
+}
+
+/// A BME280 or BMP280 device on an I2C bus.
+pub struct Bme280<I2C> {
+    i2c: I2C,
+    address: u8,
+    chip: Chip,
+    config: Config,
+    calibration: Calibration,
+}
+
+impl<I2C: I2c> Bme280<I2C> {
+    /// Probes the chip at `address`, resets it, reads its calibration and applies `config`.
+    pub async fn new<D: DelayNs>(
+        mut i2c: I2C,
+        address: u8,
+        config: Config,
+        delay: &mut D,
+    ) -> Result<Self, Error<I2C::Error>> {
+        let mut id = [0u8];
+        i2c.write_read(address, &[CHIP_ID_ADDR], &mut id)
+            .await
+            .map_err(Error::Bus)?;
+        let chip = Chip::from_id(id[0]).ok_or(Error::UnsupportedChip(id[0]))?;
+
+        i2c.write(address, &[RESET_ADDR, SOFT_RESET_CMD])
+            .await
+            .map_err(Error::Bus)?;
+        // startup time is 2ms
+        delay.delay_ms(2).await;
+
+        let mut pt = [0u8; 26];
+        i2c.write_read(address, &[PT_CALIB_ADDR], &mut pt)
+            .await
+            .map_err(Error::Bus)?;
+
+        let humidity = if chip.has_humidity() {
+            let mut h = [0u8; 7];
+            i2c.write_read(address, &[H_CALIB_ADDR], &mut h)
+                .await
+                .map_err(Error::Bus)?;
+            Some(HumidityCalibration::parse(pt[25], &h))
+        } else {
+            None
+        };
+
+        let mut bme280 = Self {
+            i2c,
+            address,
+            chip,
+            config,
+            calibration: Calibration::parse(&pt, humidity),
+        };
+        bme280.set_config(config).await?;
+
+        Ok(bme280)
+    }
+
+    /// The chip detected at initialization.
+    pub fn chip(&self) -> Chip {
+        self.chip
+    }
+
+    /// Destroys the driver, returning the I2C bus.
+    pub fn destroy(self) -> I2C {
+        self.i2c
+    }
+
+    /// The settings currently applied.
+    pub fn config(&self) -> Config {
+        self.config
+    }
+
+    /// Applies new settings, the sensor keeps its calibration.
+    pub async fn set_config(&mut self, config: Config) -> Result<(), Error<I2C::Error>> {
+        let config_register = config.config(self.chip).ok_or(Error::UnsupportedConfig)?;
+
+        // Writes to the config register are ignored in normal mode
+        self.write_register(CTRL_MEAS_ADDR, config.ctrl_meas() & !0b11)
+            .await?;
+        if self.chip.has_humidity() {
+            // ctrl_hum only takes effect after the next write to ctrl_meas
+            self.write_register(CTRL_HUM_ADDR, config.ctrl_hum())
+                .await?;
+        }
+        self.write_register(CONFIG_ADDR, config_register).await?;
+        self.write_register(CTRL_MEAS_ADDR, config.ctrl_meas())
+            .await?;
+
+        self.config = config;
+        Ok(())
+    }
+
+    /// Returns the latest results, in forced mode a measurement is triggered first.
+    pub async fn measure<D: DelayNs>(
+        &mut self,
+        delay: &mut D,
+    ) -> Result<Measurements, Error<I2C::Error>> {
+        match self.config.mode {
+            Mode::Sleep => return Err(Error::Sleeping),
+            Mode::Forced => {
+                self.write_register(CTRL_MEAS_ADDR, self.config.ctrl_meas())
+                    .await?;
+                delay
+                    .delay_us(self.config.measurement_time_us(self.chip))
+                    .await;
+            }
+            // The data registers always hold the latest completed measurement
+            Mode::Normal(_) => {}
+        }
+
+        // The BMP280 has no humidity registers, so only the first 6 bytes are read
+        let mut data = [0u8; 8];
+        let len = if self.chip.has_humidity() { 8 } else { 6 };
+        self.i2c
+            .write_read(self.address, &[DATA_ADDR], &mut data[..len])
+            .await
+            .map_err(Error::Bus)?;
+
+        self.calibration.compensate(&data).ok_or(Error::InvalidData)
+    }
+
+    async fn write_register(&mut self, register: u8, value: u8) -> Result<(), Error<I2C::Error>> {
+        self.i2c
+            .write(self.address, &[register, value])
+            .await
+            .map_err(Error::Bus)
+    }
+}
+
+impl Calibration {
+    fn parse(pt: &[u8; 26], humidity: Option<HumidityCalibration>) -> Self {
+        let u16_at = |i: usize| u16::from_le_bytes([pt[i], pt[i + 1]]);
+        let i16_at = |i: usize| i16::from_le_bytes([pt[i], pt[i + 1]]);
+
+        Self {
+            t1: u16_at(0),
+            t2: i16_at(2),
+            t3: i16_at(4),
+            p1: u16_at(6),
+            p2: i16_at(8),
+            p3: i16_at(10),
+            p4: i16_at(12),
+            p5: i16_at(14),
+            p6: i16_at(16),
+            p7: i16_at(18),
+            p8: i16_at(20),
+            p9: i16_at(22),
+            humidity,
+        }
+    }
+
+    /// Compensates a burst read of the data registers.
+    fn compensate(&self, data: &[u8; 8]) -> Option<Measurements> {
+        let adc_p =
+            (u32::from(data[0]) << 12) | (u32::from(data[1]) << 4) | (u32::from(data[2]) >> 4);
+        let adc_t =
+            (u32::from(data[3]) << 12) | (u32::from(data[4]) << 4) | (u32::from(data[5]) >> 4);
+        let adc_h = (u32::from(data[6]) << 8) | u32::from(data[7]);
+
+        // Pressure and humidity compensation depend on the temperature
+        if adc_t == SKIPPED_PT {
+            return Some(Measurements {
+                temperature: None,
+                pressure: None,
+                humidity: None,
+            });
+        }
+
+        let t_fine = self.t_fine(adc_t as f32);
+
+        let pressure = if adc_p == SKIPPED_PT {
+            None
+        } else {
+            Some(self.pressure(adc_p as f32, t_fine)?)
+        };
+
+        Some(Measurements {
+            temperature: Some(t_fine / 5120.0),
+            pressure,
+            humidity: self
+                .humidity
+                .as_ref()
+                .filter(|_| adc_h != SKIPPED_H)
+                .map(|h| h.humidity(adc_h as f32, t_fine)),
+        })
+    }
+
+    fn t_fine(&self, adc_t: f32) -> f32 {
+        let var1 = (adc_t / 16384.0 - f32::from(self.t1) / 1024.0) * f32::from(self.t2);
+        let var2 = adc_t / 131072.0 - f32::from(self.t1) / 8192.0;
+        let var2 = var2 * var2 * f32::from(self.t3);
+
+        var1 + var2
+    }
+
+    fn pressure(&self, adc_p: f32, t_fine: f32) -> Option<f32> {
+        let var1 = t_fine / 2.0 - 64000.0;
+        let var2 = var1 * var1 * f32::from(self.p6) / 32768.0;
+        let var2 = var2 + var1 * f32::from(self.p5) * 2.0;
+        let var2 = var2 / 4.0 + f32::from(self.p4) * 65536.0;
+        let var1 =
+            (f32::from(self.p3) * var1 * var1 / 524288.0 + f32::from(self.p2) * var1) / 524288.0;
+        let var1 = (1.0 + var1 / 32768.0) * f32::from(self.p1);
+
+        // avoid a division by zero
+        if var1 <= 0.0 {
+            return None;
+        }
+
+        let p = 1048576.0 - adc_p;
+        let p = (p - var2 / 4096.0) * 6250.0 / var1;
+        let var1 = f32::from(self.p9) * p * p / 2147483648.0;
+        let var2 = p * f32::from(self.p8) / 32768.0;
+
+        Some(p + (var1 + var2 + f32::from(self.p7)) / 16.0)
+    }
+}
+
+impl HumidityCalibration {
+    fn parse(h1: u8, h: &[u8; 7]) -> Self {
+        Self {
+            h1,
+            h2: i16::from_le_bytes([h[0], h[1]]),
+            h3: h[2],
+            h4: (i16::from(h[3] as i8) << 4) | i16::from(h[4] & 0x0F),
+            h5: (i16::from(h[5] as i8) << 4) | i16::from(h[4] >> 4),
+            h6: h[6] as i8,
+        }
+    }
+
+    fn humidity(&self, adc_h: f32, t_fine: f32) -> f32 {
+        let var_h = t_fine - 76800.0;
+        let var_h = (adc_h - (f32::from(self.h4) * 64.0 + f32::from(self.h5) / 16384.0 * var_h))
+            * (f32::from(self.h2) / 65536.0
+                * (1.0
+                    + f32::from(self.h6) / 67108864.0
+                        * var_h
+                        * (1.0 + f32::from(self.h3) / 67108864.0 * var_h)));
+        let var_h = var_h * (1.0 - f32::from(self.h1) * var_h / 524288.0);
+
+        var_h.clamp(0.0, 100.0)
+    }
+}
+
+/// [`WeatherSensor`] adapter for a [`Bme280`], providing humidity only on a BME280
+/// and nothing while it's in [`Mode::Sleep`].
+pub struct Bme280Sensor<I2C, D> {
+    bme280: Bme280<I2C>,
+    delay: D,
+}
+
+impl<I2C, D> Bme280Sensor<I2C, D> {
+    pub fn new(bme280: Bme280<I2C>, delay: D) -> Self {
+        Self { bme280, delay }
+    }
+
+    /// The wrapped driver, e.g. to change its configuration.
+    pub fn driver(&mut self) -> &mut Bme280<I2C> {
+        &mut self.bme280
+    }
+}
+
+impl<I2C, D> WeatherSensor for Bme280Sensor<I2C, D>
+where
+    I2C: I2c,
+    I2C::Error: defmt::Format,
+    D: DelayNs,
+{
+    type Error = Error<I2C::Error>;
+
+    fn name(&self) -> &'static str {
+        match self.bme280.chip() {
+            Chip::Bmp280 => "BMP280",
+            Chip::Bme280 => "BME280",
+        }
+    }
+
+    fn quantities(&self) -> Quantities {
+        let config = self.bme280.config();
+        let mut quantities = Quantities::NONE;
+
+        if config.mode != Mode::Sleep && config.temperature != Oversampling::Skip {
+            quantities = quantities.with(Quantity::Temperature);
+
+            if config.pressure != Oversampling::Skip {
+                quantities = quantities.with(Quantity::Pressure);
+            }
+            if config.humidity != Oversampling::Skip && self.bme280.chip().has_humidity() {
+                quantities = quantities.with(Quantity::Humidity);
+            }
+        }
+
+        quantities
+    }
+
+    async fn read(&mut self) -> Result<PartialReading, Self::Error> {
+        // Put to sleep on purpose, it's not a failure
+        if self.bme280.config().mode == Mode::Sleep {
+            return Ok(PartialReading::default());
+        }
+        let measurments = self.bme280.measure(&mut self.delay).await?;
+
+        Ok(PartialReading {
+            temperature: measurments.temperature,
+            humidity: measurments.humidity,
+            pressure: measurments.pressure,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use embassy_futures::block_on;
+    use embedded_hal_mock::eh1::delay::NoopDelay;
+    use embedded_hal_mock::eh1::i2c::{Mock as I2cMock, Transaction};
+    use std::vec;
+    use std::vec::Vec;
+
+    use super::*;
+
+    /// Calibration of the example in section 3.12 of the BMP280 datasheet.
+    const T: (u16, i16, i16) = (27504, 26435, -1000);
+    const P: (u16, [i16; 8]) = (36477, [-10685, 3024, 2855, 140, -7, 15500, -14600, 6000]);
+
+    /// The 26 bytes at 0x88, with `h1` in the last one as on a BME280.
+    fn pt_calibration(h1: u8) -> [u8; 26] {
+        let mut pt = Vec::new();
+        pt.extend(T.0.to_le_bytes());
+        pt.extend(T.1.to_le_bytes());
+        pt.extend(T.2.to_le_bytes());
+        pt.extend(P.0.to_le_bytes());
+        for p in P.1 {
+            pt.extend(p.to_le_bytes());
+        }
+        pt.extend([0, h1]);
+        pt.try_into().unwrap()
+    }
+
+    /// The 7 bytes at 0xE1 for H2 = 362, H3 = 0, H4 = 313, H5 = 50 and H6 = 30,
+    /// H4 and H5 share the nibbles of 0xE5.
+    const H_CALIBRATION: [u8; 7] = [0x6A, 0x01, 0x00, 0x13, 0x29, 0x03, 0x1E];
+    const H1: u8 = 75;
+
+    fn calibration(chip: Chip) -> Calibration {
+        let humidity = chip
+            .has_humidity()
+            .then(|| HumidityCalibration::parse(H1, &H_CALIBRATION));
+        Calibration::parse(&pt_calibration(H1), humidity)
+    }
+
+    /// Burst read of the data registers: pressure, temperature and humidity.
+    fn data(adc_p: u32, adc_t: u32, adc_h: u32) -> [u8; 8] {
+        [
+            (adc_p >> 12) as u8,
+            (adc_p >> 4) as u8,
+            (adc_p << 4) as u8,
+            (adc_t >> 12) as u8,
+            (adc_t >> 4) as u8,
+            (adc_t << 4) as u8,
+            (adc_h >> 8) as u8,
+            adc_h as u8,
+        ]
+    }
+
+    // Raw values of the datasheet example
+    const ADC_P: u32 = 415148;
+    const ADC_T: u32 = 519888;
+
+    fn assert_close(value: Option<f32>, expected: f32, tolerance: f32) {
+        let value = value.unwrap();
+        assert!(
+            (value - expected).abs() <= tolerance,
+            "{value} isn't {expected}"
+        );
+    }
+
+    #[test]
+    fn parses_the_calibration() {
+        let calibration = calibration(Chip::Bme280);
+
+        assert_eq!((calibration.t1, calibration.t2, calibration.t3), T);
+        assert_eq!(calibration.p1, P.0);
+        assert_eq!(
+            [
+                calibration.p2,
+                calibration.p3,
+                calibration.p4,
+                calibration.p5,
+                calibration.p6,
+                calibration.p7,
+                calibration.p8,
+                calibration.p9,
+            ],
+            P.1
+        );
+        let h = calibration.humidity.unwrap();
+        assert_eq!(
+            (h.h1, h.h2, h.h3, h.h4, h.h5, h.h6),
+            (75, 362, 0, 313, 50, 30)
+        );
+    }
+
+    #[test]
+    fn parses_negative_humidity_calibration() {
+        let h = HumidityCalibration::parse(0, &[0xFF, 0xFF, 0x00, 0xFF, 0xF7, 0xFE, 0x80]);
+
+        assert_eq!(h.h2, -1);
+        // 0xFF << 4 | 0x7 and 0xFE << 4 | 0xF
+        assert_eq!(h.h4, -9);
+        assert_eq!(h.h5, -17);
+        assert_eq!(h.h6, -128);
+    }
+
+    #[test]
+    fn compensates_the_datasheet_example() {
+        let measurements = calibration(Chip::Bmp280)
+            .compensate(&data(ADC_P, ADC_T, 0))
+            .unwrap();
+
+        // 25.08 °C and 100653.27 Pa in double precision
+        assert_close(measurements.temperature, 25.08, 0.01);
+        assert_close(measurements.pressure, 100653.27, 1.0);
+        assert_eq!(measurements.humidity, None);
+    }
+
+    #[test]
+    fn compensates_the_humidity_of_a_bme280() {
+        let calibration = calibration(Chip::Bme280);
+
+        // 55.00 % and 21.47 % in double precision at 25.08 °C
+        let measurements = calibration.compensate(&data(ADC_P, ADC_T, 30000)).unwrap();
+        assert_close(measurements.temperature, 25.08, 0.01);
+        assert_close(measurements.humidity, 55.0, 0.05);
+        let measurements = calibration.compensate(&data(ADC_P, ADC_T, 24000)).unwrap();
+        assert_close(measurements.humidity, 21.47, 0.05);
+
+        // Clamped to the physical range
+        let measurements = calibration.compensate(&data(ADC_P, ADC_T, 0xFFFF)).unwrap();
+        assert_eq!(measurements.humidity, Some(100.0));
+        let measurements = calibration.compensate(&data(ADC_P, ADC_T, 0)).unwrap();
+        assert_eq!(measurements.humidity, Some(0.0));
+    }
+
+    #[test]
+    fn skipped_channels_are_none() {
+        let calibration = calibration(Chip::Bme280);
+
+        let measurements = calibration
+            .compensate(&data(SKIPPED_PT, ADC_T, SKIPPED_H))
+            .unwrap();
+        assert!(measurements.temperature.is_some());
+        assert_eq!(measurements.pressure, None);
+        assert_eq!(measurements.humidity, None);
+
+        // Pressure and humidity can't be compensated without the temperature
+        let measurements = calibration
+            .compensate(&data(ADC_P, SKIPPED_PT, 30000))
+            .unwrap();
+        assert_eq!(measurements.temperature, None);
+        assert_eq!(measurements.pressure, None);
+        assert_eq!(measurements.humidity, None);
+    }
+
+    #[test]
+    fn unusable_pressure_calibration() {
+        let mut calibration = calibration(Chip::Bmp280);
+        calibration.p1 = 0;
+
+        assert!(calibration.compensate(&data(ADC_P, ADC_T, 0)).is_none());
+    }
+
+    const ADDRESS: u8 = PRIMARY_ADDRESS;
+
+    /// The transactions of [`Bme280::new`] with the default configuration.
+    fn initialization(chip: Chip) -> Vec<Transaction> {
+        let id = match chip {
+            Chip::Bmp280 => 0x58,
+            Chip::Bme280 => 0x60,
+        };
+        let mut transactions = vec![
+            Transaction::write_read(ADDRESS, vec![CHIP_ID_ADDR], vec![id]),
+            Transaction::write(ADDRESS, vec![RESET_ADDR, SOFT_RESET_CMD]),
+            Transaction::write_read(ADDRESS, vec![PT_CALIB_ADDR], pt_calibration(H1).to_vec()),
+        ];
+        if chip.has_humidity() {
+            transactions.push(Transaction::write_read(
+                ADDRESS,
+                vec![H_CALIB_ADDR],
+                H_CALIBRATION.to_vec(),
+            ));
+        }
+        // Temperature x2, pressure x16, sleeping while configured
+        transactions.push(Transaction::write(ADDRESS, vec![CTRL_MEAS_ADDR, 0x54]));
+        if chip.has_humidity() {
+            transactions.push(Transaction::write(ADDRESS, vec![CTRL_HUM_ADDR, 0x01]));
+        }
+        // Filter x16
+        transactions.push(Transaction::write(ADDRESS, vec![CONFIG_ADDR, 0x10]));
+        transactions.push(Transaction::write(ADDRESS, vec![CTRL_MEAS_ADDR, 0x55]));
+        transactions
+    }
+
+    #[test]
+    fn reads_6_bytes_from_a_bmp280() {
+        let mut transactions = initialization(Chip::Bmp280);
+        transactions.extend([
+            Transaction::write(ADDRESS, vec![CTRL_MEAS_ADDR, 0x55]),
+            Transaction::write_read(
+                ADDRESS,
+                vec![DATA_ADDR],
+                data(ADC_P, ADC_T, 0)[..6].to_vec(),
+            ),
+        ]);
+        let i2c = I2cMock::new(&transactions);
+
+        let mut bme280 = block_on(Bme280::new(
+            i2c,
+            ADDRESS,
+            Config::default(),
+            &mut NoopDelay::new(),
+        ))
+        .unwrap();
+        assert_eq!(bme280.chip(), Chip::Bmp280);
+        let measurements = block_on(bme280.measure(&mut NoopDelay::new())).unwrap();
+
+        assert_close(measurements.temperature, 25.08, 0.01);
+        assert_close(measurements.pressure, 100653.27, 1.0);
+        assert_eq!(measurements.humidity, None);
+        bme280.destroy().done();
+    }
+
+    #[test]
+    fn reads_8_bytes_from_a_bme280() {
+        let mut transactions = initialization(Chip::Bme280);
+        transactions.extend([
+            Transaction::write(ADDRESS, vec![CTRL_MEAS_ADDR, 0x55]),
+            Transaction::write_read(ADDRESS, vec![DATA_ADDR], data(ADC_P, ADC_T, 30000).to_vec()),
+        ]);
+        let i2c = I2cMock::new(&transactions);
+
+        let mut bme280 = block_on(Bme280::new(
+            i2c,
+            ADDRESS,
+            Config::default(),
+            &mut NoopDelay::new(),
+        ))
+        .unwrap();
+        assert_eq!(bme280.chip(), Chip::Bme280);
+        let measurements = block_on(bme280.measure(&mut NoopDelay::new())).unwrap();
+
+        assert_close(measurements.humidity, 55.0, 0.05);
+        bme280.destroy().done();
+    }
+
+    #[test]
+    fn refuses_an_unknown_chip() {
+        let mut i2c = I2cMock::new(&[Transaction::write_read(
+            ADDRESS,
+            vec![CHIP_ID_ADDR],
+            vec![0x61],
+        )]);
+
+        let result = block_on(Bme280::new(
+            i2c.clone(),
+            ADDRESS,
+            Config::default(),
+            &mut NoopDelay::new(),
+        ));
+
+        assert!(matches!(result, Err(Error::UnsupportedChip(0x61))));
+        i2c.done();
+    }
+
+    #[test]
+    fn refuses_a_standby_time_of_the_other_chip() {
+        let i2c = I2cMock::new(&initialization(Chip::Bmp280));
+        let mut bme280 = block_on(Bme280::new(
+            i2c,
+            ADDRESS,
+            Config::default(),
+            &mut NoopDelay::new(),
+        ))
+        .unwrap();
+
+        let config = Config {
+            mode: Mode::Normal(Standby::Ms10),
+            ..Config::default()
+        };
+        let result = block_on(bme280.set_config(config));
+
+        assert!(matches!(result, Err(Error::UnsupportedConfig)));
+        assert_eq!(bme280.config(), Config::default());
+        bme280.destroy().done();
+    }
+}
@@ -0,0 +1,483 @@
+// Measurement settings, see sections 3.3 to 3.5 of the BME280 datasheet
+
+use heapless::String;
+use serde::de::{Deserializer, Error as _};
+use serde::{Deserialize, Serialize, Serializer};
+
+use super::Chip;
+
+/// Oversampling of a single channel, `Skip` disables the channel.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, Serialize, defmt::Format)]
+#[serde(rename_all = "lowercase")]
+pub enum Oversampling {
+    Skip,
+    X1,
+    X2,
+    X4,
+    X8,
+    X16,
+}
+
+impl Oversampling {
+    const ALL: [Self; 6] = [
+        Self::Skip,
+        Self::X1,
+        Self::X2,
+        Self::X4,
+        Self::X8,
+        Self::X16,
+    ];
+
+    pub(super) fn bits(self) -> u8 {
+        match self {
+            Oversampling::Skip => 0b000,
+            Oversampling::X1 => 0b001,
+            Oversampling::X2 => 0b010,
+            Oversampling::X4 => 0b011,
+            Oversampling::X8 => 0b100,
+            Oversampling::X16 => 0b101,
+        }
+    }
+
+    /// Number of samples taken per measurement.
+    fn samples(self) -> u32 {
+        match self {
+            Oversampling::Skip => 0,
+            Oversampling::X1 => 1,
+            Oversampling::X2 => 2,
+            Oversampling::X4 => 4,
+            Oversampling::X8 => 8,
+            Oversampling::X16 => 16,
+        }
+    }
+}
+
+/// Coefficient of the IIR filter applied to temperature and pressure.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, Serialize, defmt::Format)]
+#[serde(rename_all = "lowercase")]
+pub enum Filter {
+    Off,
+    X2,
+    X4,
+    X8,
+    X16,
+}
+
+impl Filter {
+    const ALL: [Self; 5] = [Self::Off, Self::X2, Self::X4, Self::X8, Self::X16];
+
+    fn bits(self) -> u8 {
+        match self {
+            Filter::Off => 0b000,
+            Filter::X2 => 0b001,
+            Filter::X4 => 0b010,
+            Filter::X8 => 0b011,
+            Filter::X16 => 0b100,
+        }
+    }
+}
+
+/// Inactive time between two measurements in normal mode.
+///
+/// The two longest settings differ between the chips.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, defmt::Format)]
+pub enum Standby {
+    Ms0_5,
+    Ms62_5,
+    Ms125,
+    Ms250,
+    Ms500,
+    Ms1000,
+    /// BME280 only.
+    Ms10,
+    /// BME280 only.
+    Ms20,
+    /// BMP280 only.
+    Ms2000,
+    /// BMP280 only.
+    Ms4000,
+}
+
+impl Standby {
+    fn bits(self, chip: Chip) -> Option<u8> {
+        match (self, chip) {
+            (Standby::Ms0_5, _) => Some(0b000),
+            (Standby::Ms62_5, _) => Some(0b001),
+            (Standby::Ms125, _) => Some(0b010),
+            (Standby::Ms250, _) => Some(0b011),
+            (Standby::Ms500, _) => Some(0b100),
+            (Standby::Ms1000, _) => Some(0b101),
+            (Standby::Ms10, Chip::Bme280) | (Standby::Ms2000, Chip::Bmp280) => Some(0b110),
+            (Standby::Ms20, Chip::Bme280) | (Standby::Ms4000, Chip::Bmp280) => Some(0b111),
+            _ => None,
+        }
+    }
+}
+
+/// Power mode of the sensor.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, defmt::Format)]
+pub enum Mode {
+    /// No measurements are performed.
+    Sleep,
+
+    /// A single measurement is triggered for every read, the sensor sleeps in between.
+    Forced,
+
+    /// The sensor measures continuously, pausing for the standby time between measurements.
+    Normal(Standby),
+}
+
+impl Mode {
+    /// Every mode, a normal one per standby time.
+    pub const ALL: [Self; 12] = [
+        Mode::Sleep,
+        Mode::Forced,
+        Mode::Normal(Standby::Ms0_5),
+        Mode::Normal(Standby::Ms10),
+        Mode::Normal(Standby::Ms20),
+        Mode::Normal(Standby::Ms62_5),
+        Mode::Normal(Standby::Ms125),
+        Mode::Normal(Standby::Ms250),
+        Mode::Normal(Standby::Ms500),
+        Mode::Normal(Standby::Ms1000),
+        Mode::Normal(Standby::Ms2000),
+        Mode::Normal(Standby::Ms4000),
+    ];
+
+    /// `sleep`, `forced` or `normal-<standby time>`, e.g. `normal-62.5ms`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Mode::Sleep => "sleep",
+            Mode::Forced => "forced",
+            Mode::Normal(Standby::Ms0_5) => "normal-0.5ms",
+            Mode::Normal(Standby::Ms10) => "normal-10ms",
+            Mode::Normal(Standby::Ms20) => "normal-20ms",
+            Mode::Normal(Standby::Ms62_5) => "normal-62.5ms",
+            Mode::Normal(Standby::Ms125) => "normal-125ms",
+            Mode::Normal(Standby::Ms250) => "normal-250ms",
+            Mode::Normal(Standby::Ms500) => "normal-500ms",
+            Mode::Normal(Standby::Ms1000) => "normal-1000ms",
+            Mode::Normal(Standby::Ms2000) => "normal-2000ms",
+            Mode::Normal(Standby::Ms4000) => "normal-4000ms",
+        }
+    }
+
+    fn bits(self) -> u8 {
+        match self {
+            Mode::Sleep => 0b00,
+            Mode::Forced => 0b01,
+            Mode::Normal(_) => 0b11,
+        }
+    }
+}
+
+impl Serialize for Mode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Mode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::<16>::deserialize(deserializer)?;
+        Mode::ALL
+            .into_iter()
+            .find(|mode| mode.as_str() == name)
+            .ok_or_else(|| D::Error::custom("unknown mode"))
+    }
+}
+
+/// Measurement settings of a BME280/BMP280.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, defmt::Format)]
+pub struct Config {
+    pub temperature: Oversampling,
+    pub pressure: Oversampling,
+    /// Ignored on a BMP280.
+    pub humidity: Oversampling,
+    pub filter: Filter,
+    pub mode: Mode,
+}
+
+impl Config {
+    /// Datasheet recommendation for weather monitoring: lowest power, one
+    /// forced measurement per minute is enough.
+    pub const WEATHER_MONITORING: Self = Self {
+        temperature: Oversampling::X1,
+        pressure: Oversampling::X1,
+        humidity: Oversampling::X1,
+        filter: Filter::Off,
+        mode: Mode::Forced,
+    };
+
+    /// Datasheet recommendation for humidity sensing, pressure is skipped.
+    pub const HUMIDITY_SENSING: Self = Self {
+        temperature: Oversampling::X1,
+        pressure: Oversampling::Skip,
+        humidity: Oversampling::X1,
+        filter: Filter::Off,
+        mode: Mode::Forced,
+    };
+
+    /// Datasheet recommendation for indoor navigation: lowest noise, continuous measurements.
+    pub const INDOOR_NAVIGATION: Self = Self {
+        temperature: Oversampling::X2,
+        pressure: Oversampling::X16,
+        humidity: Oversampling::X1,
+        filter: Filter::X16,
+        mode: Mode::Normal(Standby::Ms0_5),
+    };
+
+    /// The settings as stored: the variant of each oversampling and of the
+    /// filter, and the index of the mode in [`Mode::ALL`].
+    pub fn encode(&self) -> [u8; 5] {
+        // Every mode is listed
+        let mode = Mode::ALL.iter().position(|&mode| mode == self.mode);
+        [
+            self.temperature as u8,
+            self.pressure as u8,
+            self.humidity as u8,
+            self.filter as u8,
+            mode.unwrap_or_default() as u8,
+        ]
+    }
+
+    /// `None` if a setting isn't a known one, see [`Config::encode`].
+    pub fn decode(value: [u8; 5]) -> Option<Self> {
+        let [temperature, pressure, humidity, filter, mode] = value.map(usize::from);
+        Some(Self {
+            temperature: *Oversampling::ALL.get(temperature)?,
+            pressure: *Oversampling::ALL.get(pressure)?,
+            humidity: *Oversampling::ALL.get(humidity)?,
+            filter: *Filter::ALL.get(filter)?,
+            mode: *Mode::ALL.get(mode)?,
+        })
+    }
+
+    /// Maximum duration of a measurement with these settings (in microseconds).
+    pub fn measurement_time_us(&self, chip: Chip) -> u32 {
+        let channel = |oversampling: Oversampling| match oversampling.samples() {
+            0 => 0,
+            samples => 2300 * samples + 575,
+        };
+
+        let humidity = if chip.has_humidity() {
+            channel(self.humidity)
+        } else {
+            0
+        };
+
+        1250 + 2300 * self.temperature.samples() + channel(self.pressure) + humidity
+    }
+
+    pub(super) fn ctrl_hum(&self) -> u8 {
+        self.humidity.bits()
+    }
+
+    pub(super) fn ctrl_meas(&self) -> u8 {
+        (self.temperature.bits() << 5) | (self.pressure.bits() << 2) | self.mode.bits()
+    }
+
+    /// `None` when the standby time isn't supported by `chip`.
+    pub(super) fn config(&self, chip: Chip) -> Option<u8> {
+        let standby = match self.mode {
+            Mode::Normal(standby) => standby.bits(chip)?,
+            _ => 0,
+        };
+
+        Some((standby << 5) | (self.filter.bits() << 2))
+    }
+}
+
+/// A recommended [`Config`] of section 3.5 of the datasheet.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, defmt::Format)]
+#[serde(rename_all = "kebab-case")]
+pub enum Preset {
+    WeatherMonitoring,
+    HumiditySensing,
+    IndoorNavigation,
+}
+
+impl Preset {
+    pub fn config(self) -> Config {
+        match self {
+            Preset::WeatherMonitoring => Config::WEATHER_MONITORING,
+            Preset::HumiditySensing => Config::HUMIDITY_SENSING,
+            Preset::IndoorNavigation => Config::INDOOR_NAVIGATION,
+        }
+    }
+}
+
+/// Changes to a [`Config`], the settings left out are kept.
+#[derive(Copy, Clone, Debug, Default, PartialEq, defmt::Format)]
+pub struct ConfigUpdate {
+    /// Replaces every setting, before the other changes are applied.
+    pub preset: Option<Preset>,
+    pub temperature: Option<Oversampling>,
+    pub pressure: Option<Oversampling>,
+    pub humidity: Option<Oversampling>,
+    pub filter: Option<Filter>,
+    pub mode: Option<Mode>,
+}
+
+impl ConfigUpdate {
+    pub fn apply(self, config: Config) -> Config {
+        let config = self.preset.map_or(config, Preset::config);
+        Config {
+            temperature: self.temperature.unwrap_or(config.temperature),
+            pressure: self.pressure.unwrap_or(config.pressure),
+            humidity: self.humidity.unwrap_or(config.humidity),
+            filter: self.filter.unwrap_or(config.filter),
+            mode: self.mode.unwrap_or(config.mode),
+        }
+    }
+}
+
+impl Default for Config {
+    /// Temperature x2, pressure x16, humidity x1 and IIR filter coefficient 16, in forced mode.
+    fn default() -> Self {
+        Self {
+            mode: Mode::Forced,
+            ..Self::INDOOR_NAVIGATION
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn control_registers() {
+        let config = Config::default();
+        // Temperature x2, pressure x16, forced
+        assert_eq!(config.ctrl_meas(), (0b010 << 5) | (0b101 << 2) | 0b01);
+        assert_eq!(config.ctrl_hum(), 0b001);
+        assert_eq!(
+            Config::INDOOR_NAVIGATION.ctrl_meas(),
+            (0b010 << 5) | (0b101 << 2) | 0b11
+        );
+
+        let sleeping = Config {
+            mode: Mode::Sleep,
+            ..Config::HUMIDITY_SENSING
+        };
+        assert_eq!(sleeping.ctrl_meas(), 0b001 << 5);
+    }
+
+    #[test]
+    fn config_register() {
+        // Filter x16, the standby time only counts in normal mode
+        assert_eq!(Config::default().config(Chip::Bme280), Some(0b100 << 2));
+        assert_eq!(
+            Config::INDOOR_NAVIGATION.config(Chip::Bmp280),
+            Some(0b100 << 2)
+        );
+        assert_eq!(Config::WEATHER_MONITORING.config(Chip::Bmp280), Some(0));
+    }
+
+    fn standby(standby: Standby, chip: Chip) -> Option<u8> {
+        let config = Config {
+            filter: Filter::Off,
+            mode: Mode::Normal(standby),
+            ..Config::default()
+        };
+        config.config(chip).map(|config| config >> 5)
+    }
+
+    #[test]
+    fn standby_encoding_differs_between_the_chips() {
+        for chip in [Chip::Bme280, Chip::Bmp280] {
+            assert_eq!(standby(Standby::Ms0_5, chip), Some(0b000));
+            assert_eq!(standby(Standby::Ms1000, chip), Some(0b101));
+        }
+
+        assert_eq!(standby(Standby::Ms10, Chip::Bme280), Some(0b110));
+        assert_eq!(standby(Standby::Ms20, Chip::Bme280), Some(0b111));
+        assert_eq!(standby(Standby::Ms2000, Chip::Bme280), None);
+        assert_eq!(standby(Standby::Ms4000, Chip::Bme280), None);
+
+        assert_eq!(standby(Standby::Ms2000, Chip::Bmp280), Some(0b110));
+        assert_eq!(standby(Standby::Ms4000, Chip::Bmp280), Some(0b111));
+        assert_eq!(standby(Standby::Ms10, Chip::Bmp280), None);
+        assert_eq!(standby(Standby::Ms20, Chip::Bmp280), None);
+    }
+
+    #[test]
+    fn measurement_time() {
+        // 9.3 ms at x1 in section 9.1 of the datasheet, humidity only on a BME280
+        let config = Config::WEATHER_MONITORING;
+        assert_eq!(config.measurement_time_us(Chip::Bme280), 9300);
+        assert_eq!(config.measurement_time_us(Chip::Bmp280), 6425);
+
+        assert_eq!(
+            Config::HUMIDITY_SENSING.measurement_time_us(Chip::Bme280),
+            6425
+        );
+    }
+
+    #[test]
+    fn encodes_every_setting() {
+        for mode in Mode::ALL {
+            let config = Config {
+                temperature: Oversampling::X16,
+                pressure: Oversampling::Skip,
+                humidity: Oversampling::X4,
+                filter: Filter::X8,
+                mode,
+            };
+            assert_eq!(Config::decode(config.encode()), Some(config));
+        }
+
+        assert_eq!(Config::default().encode(), [2, 5, 1, 4, 1]);
+        assert_eq!(Config::decode([6, 0, 0, 0, 0]), None);
+        assert_eq!(Config::decode([0, 0, 0, 5, 0]), None);
+        assert_eq!(Config::decode([0, 0, 0, 0, 12]), None);
+    }
+
+    #[test]
+    fn names_the_modes() {
+        for mode in Mode::ALL {
+            let json: String<32> = serde_json_core::to_string(&mode).unwrap();
+            let (parsed, _) = serde_json_core::from_str::<Mode>(&json).unwrap();
+            assert_eq!(parsed, mode);
+        }
+
+        let json: String<32> = serde_json_core::to_string(&Mode::Normal(Standby::Ms62_5)).unwrap();
+        assert_eq!(json, r#""normal-62.5ms""#);
+        assert!(serde_json_core::from_str::<Mode>(r#""normal""#).is_err());
+    }
+
+    #[test]
+    fn applies_a_preset_then_the_changes() {
+        let update = ConfigUpdate {
+            preset: Some(Preset::IndoorNavigation),
+            filter: Some(Filter::X2),
+            ..ConfigUpdate::default()
+        };
+        assert_eq!(
+            update.apply(Config::WEATHER_MONITORING),
+            Config {
+                filter: Filter::X2,
+                ..Config::INDOOR_NAVIGATION
+            }
+        );
+
+        let update = ConfigUpdate {
+            pressure: Some(Oversampling::Skip),
+            mode: Some(Mode::Sleep),
+            ..ConfigUpdate::default()
+        };
+        assert_eq!(
+            update.apply(Config::WEATHER_MONITORING),
+            Config {
+                pressure: Oversampling::Skip,
+                mode: Mode::Sleep,
+                ..Config::WEATHER_MONITORING
+            }
+        );
+        assert_eq!(
+            ConfigUpdate::default().apply(Config::default()),
+            Config::default()
+        );
+    }
+}
@@ -0,0 +1,525 @@
+// code from https://github.com/plorefice/dht11-rs
+// I made it async and generic over the DHTxx family
+// Bits are decoded from edge timestamps taken by the hardware, so interrupts
+// can stay enabled during a read
+use core::future::Future;
+use core::marker::PhantomData;
+
+use embassy_futures::join::join;
+use embassy_futures::select::{Either, select};
+use embassy_time::Duration;
+use embedded_hal::digital::OutputPin;
+use embedded_hal_async::delay::DelayNs;
+
+use super::weather_sensor::{
+    ErrorKind, PartialReading, Quantities, Quantity, SensorError, WeatherSensor,
+};
+
+pub mod rmt;
+
+/// How long a transmission may take (in microseconds), a frame lasts about 5 ms.
+const TIMEOUT_US: u32 = 10_000;
+
+/// High pulses longer than this encode a `1` bit (in microseconds).
+///
+/// The sensor sends 26-28 us for a `0` and 70 us for a `1`.
+const BIT_THRESHOLD_US: u64 = 48;
+
+/// Shortest high pulse accepted as a bit (in microseconds).
+const MIN_PULSE_US: u64 = 10;
+
+/// Longest high pulse accepted as a bit (in microseconds).
+const MAX_PULSE_US: u64 = 120;
+
+/// Number of edges captured after the start command: the falling and rising
+/// edges of the 80 us response, the falling edge that starts the first bit and
+/// a rising/falling pair for each of the 40 data bits.
+pub const EDGES: usize = 3 + 40 * 2;
+
+/// Error type for this crate.
+#[derive(Debug, defmt::Format)]
+pub enum Error<E> {
+    /// Timeout during communication.
+    Timeout,
+
+    /// CRC mismatch.
+    CrcMismatch,
+
+    /// A high pulse too short or too long to be a data bit.
+    InvalidPulse,
+
+    /// The hardware recording the edges failed.
+    Capture,
+
+    /// GPIO error.
+    Gpio(E),
+}
+
+impl<E: defmt::Format> SensorError for Error<E> {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            Error::Timeout => ErrorKind::Timeout,
+            Error::CrcMismatch => ErrorKind::Crc,
+            Error::InvalidPulse | Error::Capture | Error::Gpio(_) => ErrorKind::Other,
+        }
+    }
+}
+
+/// Why the edges of a transmission couldn't be recorded.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, defmt::Format)]
+pub enum CaptureError {
+    /// The line went idle before a whole frame was sent.
+    Incomplete,
+
+    /// The hardware failed, e.g. it ran out of memory for the pulses.
+    Hardware,
+}
+
+impl<E> From<CaptureError> for Error<E> {
+    fn from(e: CaptureError) -> Self {
+        match e {
+            CaptureError::Incomplete => Error::Timeout,
+            CaptureError::Hardware => Error::Capture,
+        }
+    }
+}
+
+/// Records when the data line changes level.
+///
+/// The times must be taken by the hardware, e.g. the RMT peripheral or an
+/// interrupt handler: a task woken up by an edge can be late by more than a
+/// pulse while the network is busy.
+pub trait EdgeCapture {
+    /// Records a transmission until the line goes idle, and returns the times
+    /// (in microseconds) of its first [`EDGES`] level changes, see
+    /// [`decode_edges`].
+    ///
+    /// Recording starts at the first poll, which happens before the line is
+    /// released at the end of the start pulse.
+    fn capture(&mut self) -> impl Future<Output = Result<[u64; EDGES], CaptureError>>;
+}
+
+/// A monotonic microsecond clock used to timestamp edges on the data line.
+pub trait Clock {
+    /// Current time in microseconds.
+    fn now_us(&self) -> u64;
+}
+
+/// [`Clock`] backed by the embassy time driver.
+#[derive(Copy, Clone, Default, Debug)]
+pub struct EmbassyClock;
+
+impl Clock for EmbassyClock {
+    fn now_us(&self) -> u64 {
+        embassy_time::Instant::now().as_micros()
+    }
+}
+
+/// Whether the checksum byte of a frame is validated before decoding it.
+#[derive(Copy, Clone, Default, Debug, PartialEq, Eq, defmt::Format)]
+pub enum CrcCheck {
+    /// Frames with a wrong checksum are rejected with [`Error::CrcMismatch`].
+    #[default]
+    Enabled,
+
+    /// Frames are decoded as is, even if they were corrupted on the wire.
+    Disabled,
+}
+
+/// A member of the DHTxx family.
+///
+/// The sensors share the wire protocol, but differ in the length of the start
+/// pulse and in how the 5-byte frame is encoded.
+pub trait DhtKind {
+    /// Model name of the sensor.
+    const NAME: &'static str;
+
+    /// How long the line is pulled low to request a reading (in microseconds).
+    const START_PULSE_US: u32;
+
+    /// Decodes the 4 data bytes of a frame whose checksum was already handled.
+    fn decode(frame: &[u8; 5]) -> Measurement;
+}
+
+/// A DHTxx device.
+pub struct Dht<GPIO, CAP, K, C = EmbassyClock> {
+    /// The concrete GPIO pin implementation.
+    gpio: GPIO,
+
+    /// Records the transmissions on the pin.
+    capture: CAP,
+
+    /// Source of the edge timestamps.
+    clock: C,
+
+    /// Checksum validation policy.
+    crc_check: CrcCheck,
+
+    kind: PhantomData<K>,
+}
+
+/// Results of a reading performed by a DHTxx.
+#[derive(Copy, Clone, Default, Debug, defmt::Format)]
+pub struct Measurement {
+    /// The measured temperature in degrees Celsius.
+    pub temperature: f32,
+
+    /// The measured relative humidity in percent.
+    pub humidity: f32,
+}
+
+/// A raw frame received from the sensor together with its decoded value.
+#[derive(Copy, Clone, Default, Debug, defmt::Format)]
+pub struct Reading {
+    /// The 5 bytes as they were sent by the sensor, checksum included.
+    pub frame: [u8; 5],
+
+    /// The decoded frame.
+    pub measurement: Measurement,
+}
+
+impl<GPIO, E, CAP, K, C> Dht<GPIO, CAP, K, C>
+where
+    GPIO: OutputPin<Error = E>,
+    CAP: EdgeCapture,
+    K: DhtKind,
+    C: Clock,
+{
+    /// Creates a new device connected to the specified pin, whose edges are
+    /// recorded by `capture`.
+    ///
+    /// The checksum of every frame is validated.
+    pub fn new(gpio: GPIO, capture: CAP, clock: C) -> Self {
+        Dht {
+            gpio,
+            capture,
+            clock,
+            crc_check: CrcCheck::default(),
+            kind: PhantomData,
+        }
+    }
+
+    /// Sets the checksum validation policy.
+    pub fn with_crc_check(mut self, crc_check: CrcCheck) -> Self {
+        self.crc_check = crc_check;
+        self
+    }
+
+    /// Destroys the driver, returning the GPIO instance and the capture.
+    pub fn destroy(self) -> (GPIO, CAP) {
+        (self.gpio, self.capture)
+    }
+
+    /// Performs a reading of the sensor.
+    pub async fn read<D: DelayNs>(&mut self, delay: &mut D) -> Result<Reading, Error<E>> {
+        let edges = self.capture_edges(delay).await?;
+        let frame = decode_edges(&edges)?;
+
+        decode_frame::<K, E>(frame, self.crc_check)
+    }
+
+    async fn capture_edges<D>(&mut self, delay: &mut D) -> Result<[u64; EDGES], Error<E>>
+    where
+        D: DelayNs,
+    {
+        self.send_start(delay).await?;
+
+        // The first poll of the capture starts recording, then the line is
+        // released for the sensor to answer.
+        let gpio = &mut self.gpio;
+        let transmission = join(self.capture.capture(), async { gpio.set_high() });
+
+        match select(transmission, delay.delay_us(TIMEOUT_US)).await {
+            Either::First((edges, released)) => {
+                released.map_err(Error::Gpio)?;
+                Ok(edges?)
+            }
+            Either::Second(()) => Err(Error::Timeout),
+        }
+    }
+
+    async fn send_start<D>(&mut self, delay: &mut D) -> Result<(), Error<E>>
+    where
+        D: DelayNs,
+    {
+        // Set pin as floating to let pull-up raise the line and start the reading process.
+        self.set_input()?;
+
+        delay.delay_ms(1).await;
+
+        // Pull line low to send a start command.
+        self.set_low()?;
+
+        // The line is released once the capture started, the sensor answers
+        // within 20-40 us.
+        delay.delay_us(K::START_PULSE_US).await;
+
+        Ok(())
+    }
+
+    fn set_input(&mut self) -> Result<(), Error<E>> {
+        self.gpio.set_high().map_err(Error::Gpio)
+    }
+
+    fn set_low(&mut self) -> Result<(), Error<E>> {
+        self.gpio.set_low().map_err(Error::Gpio)
+    }
+}
+
+/// [`WeatherSensor`] adapter reading a DHTxx at most once per `interval`.
+///
+/// The sensor can't be sampled faster than about once a second, in between
+/// the last successful measurement is reported again.
+pub struct DhtSensor<GPIO, CAP, K, D, C = EmbassyClock> {
+    dht: Dht<GPIO, CAP, K, C>,
+    delay: D,
+    interval: Duration,
+    last_attempt: Option<u64>,
+    last: Option<Measurement>,
+}
+
+impl<GPIO, CAP, K, D, C> DhtSensor<GPIO, CAP, K, D, C> {
+    pub fn new(dht: Dht<GPIO, CAP, K, C>, delay: D, interval: Duration) -> Self {
+        Self {
+            dht,
+            delay,
+            interval,
+            last_attempt: None,
+            last: None,
+        }
+    }
+}
+
+impl<GPIO, E, CAP, K, D, C> WeatherSensor for DhtSensor<GPIO, CAP, K, D, C>
+where
+    GPIO: OutputPin<Error = E>,
+    E: defmt::Format,
+    CAP: EdgeCapture,
+    K: DhtKind,
+    D: DelayNs,
+    C: Clock,
+{
+    type Error = Error<E>;
+
+    fn name(&self) -> &'static str {
+        K::NAME
+    }
+
+    fn quantities(&self) -> Quantities {
+        Quantities::NONE
+            .with(Quantity::Temperature)
+            .with(Quantity::Humidity)
+    }
+
+    async fn read(&mut self) -> Result<PartialReading, Self::Error> {
+        let now = self.dht.clock.now_us();
+        let due = self
+            .last_attempt
+            .is_none_or(|at| now.wrapping_sub(at) >= self.interval.as_micros());
+
+        if due {
+            self.last_attempt = Some(now);
+            let result = self.dht.read(&mut self.delay).await;
+            self.last = result.as_ref().ok().map(|reading| reading.measurement);
+            result?;
+        }
+
+        Ok(PartialReading {
+            temperature: self.last.map(|m| m.temperature),
+            humidity: self.last.map(|m| m.humidity),
+            pressure: None,
+        })
+    }
+}
+
+/// The times of the level changes of a transmission recorded as pulses, e.g.
+/// by the RMT peripheral.
+///
+/// `pulses` are the levels of the line (`true` when high) and how long they
+/// lasted (in microseconds), possibly starting with the end of the start
+/// pulse. The sensor's response starts when the first high pulse ends.
+pub fn edges_from_pulses(
+    pulses: impl IntoIterator<Item = (bool, u32)>,
+) -> Result<[u64; EDGES], CaptureError> {
+    let mut edges = [0u64; EDGES];
+    let mut captured = 0;
+    let mut now = 0u64;
+
+    for (high, duration_us) in pulses {
+        now += u64::from(duration_us);
+
+        // Skip the end of the start pulse
+        if captured == 0 && !high {
+            continue;
+        }
+
+        edges[captured] = now;
+        captured += 1;
+
+        if captured == EDGES {
+            return Ok(edges);
+        }
+    }
+
+    Err(CaptureError::Incomplete)
+}
+
+/// Classifies the 40 data bits from the edge timestamps of a transmission.
+///
+/// `edges` holds the times (in microseconds) at which the line changed level,
+/// starting with the sensor pulling it low in response to the start command.
+/// A bit is `1` when its high pulse is longer than [`BIT_THRESHOLD_US`].
+pub fn decode_edges<E>(edges: &[u64; EDGES]) -> Result<[u8; 5], Error<E>> {
+    let mut frame = [0u8; 5];
+
+    // Skip the response, each bit is a (rise, fall) pair afterwards.
+    let (bits, _) = edges[3..].as_chunks::<2>();
+
+    for (i, [rise, fall]) in bits.iter().enumerate() {
+        let high = fall.wrapping_sub(*rise);
+
+        if !(MIN_PULSE_US..=MAX_PULSE_US).contains(&high) {
+            return Err(Error::InvalidPulse);
+        }
+
+        frame[i / 8] <<= 1;
+
+        if high > BIT_THRESHOLD_US {
+            frame[i / 8] |= 1;
+        }
+    }
+
+    Ok(frame)
+}
+
+/// Decodes a raw frame, validating its checksum unless `crc_check` is disabled.
+pub fn decode_frame<K: DhtKind, E>(
+    frame: [u8; 5],
+    crc_check: CrcCheck,
+) -> Result<Reading, Error<E>> {
+    if crc_check == CrcCheck::Enabled && checksum(&frame) != frame[4] {
+        return Err(Error::CrcMismatch);
+    }
+
+    Ok(Reading {
+        frame,
+        measurement: K::decode(&frame),
+    })
+}
+
+fn checksum(frame: &[u8; 5]) -> u8 {
+    frame[0]
+        .wrapping_add(frame[1])
+        .wrapping_add(frame[2])
+        .wrapping_add(frame[3])
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// The edges of a transmission of `frame` whose high pulses last
+    /// `zero_us` for a `0` and `one_us` for a `1`.
+    pub(crate) fn frame_edges(frame: [u8; 5], zero_us: u64, one_us: u64) -> [u64; EDGES] {
+        let mut edges = [0; EDGES];
+
+        // The sensor answers 30 us after the release, low then high for 80 us
+        edges[..3].copy_from_slice(&[30, 110, 190]);
+        let mut now = 190;
+
+        for i in 0..40 {
+            let one = frame[i / 8] >> (7 - i % 8) & 1 == 1;
+            now += 50;
+            edges[3 + 2 * i] = now;
+            now += if one { one_us } else { zero_us };
+            edges[4 + 2 * i] = now;
+        }
+
+        edges
+    }
+
+    /// The pulses an RMT records for `edges`, from the end of the start pulse
+    /// to the line going idle.
+    fn pulses(edges: &[u64; EDGES]) -> impl Iterator<Item = (bool, u32)> + '_ {
+        // Low until released, then high until the sensor answers
+        let start = [(false, 3), (true, edges[0] as u32)];
+        let frame = edges.windows(2).enumerate().map(|(i, pair)| {
+            // Even edges pull the line low
+            (i % 2 == 1, (pair[1] - pair[0]) as u32)
+        });
+
+        start
+            .into_iter()
+            .chain(frame)
+            .chain([(false, 50), (true, 200)])
+    }
+
+    const FRAME: [u8; 5] = [0x35, 0x00, 0x18, 0x01, 0x4E];
+
+    #[test]
+    fn decodes_a_frame() {
+        let edges = frame_edges(FRAME, 27, 70);
+
+        assert_eq!(decode_edges::<()>(&edges).unwrap(), FRAME);
+    }
+
+    #[test]
+    fn classifies_bits_around_the_threshold() {
+        assert_eq!(
+            decode_edges::<()>(&frame_edges([0xFF, 0, 0xFF, 0, 0xFE], 48, 49)).unwrap(),
+            [0xFF, 0, 0xFF, 0, 0xFE]
+        );
+        assert_eq!(
+            decode_edges::<()>(&frame_edges([0xA5; 5], MIN_PULSE_US, MAX_PULSE_US)).unwrap(),
+            [0xA5; 5]
+        );
+    }
+
+    #[test]
+    fn rejects_pulses_that_are_not_bits() {
+        let short = frame_edges(FRAME, MIN_PULSE_US - 1, 70);
+        let long = frame_edges(FRAME, 27, MAX_PULSE_US + 1);
+
+        assert!(matches!(
+            decode_edges::<()>(&short),
+            Err(Error::InvalidPulse)
+        ));
+        assert!(matches!(
+            decode_edges::<()>(&long),
+            Err(Error::InvalidPulse)
+        ));
+    }
+
+    #[test]
+    fn decodes_edges_from_rmt_pulses() {
+        let edges = frame_edges(FRAME, 27, 70);
+        let captured = edges_from_pulses(pulses(&edges)).unwrap();
+
+        // Shifted by the end of the start pulse
+        assert!(captured.iter().zip(&edges).all(|(c, e)| c - e == 3));
+        assert_eq!(decode_edges::<()>(&captured).unwrap(), FRAME);
+    }
+
+    #[test]
+    fn a_recording_may_start_after_the_release() {
+        let edges = frame_edges(FRAME, 27, 70);
+        let captured = edges_from_pulses(pulses(&edges).skip(1)).unwrap();
+
+        assert_eq!(captured, edges);
+    }
+
+    #[test]
+    fn a_truncated_recording_is_incomplete() {
+        let edges = frame_edges(FRAME, 27, 70);
+
+        assert_eq!(
+            edges_from_pulses(pulses(&edges).take(EDGES)),
+            Err(CaptureError::Incomplete)
+        );
+        // The sensor didn't answer
+        assert_eq!(
+            edges_from_pulses([(false, 3), (true, 200)]),
+            Err(CaptureError::Incomplete)
+        );
+    }
+}
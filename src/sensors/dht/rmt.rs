@@ -0,0 +1,71 @@
+// Edge capture of the DHT data line with the RMT peripheral, which times the
+// pulses in hardware
+use defmt::warn;
+use esp_hal::Async;
+use esp_hal::gpio::{Flex, Level};
+use esp_hal::rmt::{Channel, Error, PulseCode, Rx, RxChannelConfig, RxChannelCreator};
+
+use super::{CaptureError, EDGES, EdgeCapture, edges_from_pulses};
+
+/// Frequency to create the [`esp_hal::rmt::Rmt`] driver with, divided down
+/// to 1 us ticks by the channel.
+pub const RMT_FREQUENCY_MHZ: u32 = 80;
+
+/// The line is idle once it stayed at the same level for this long (in
+/// microseconds), the longest pulse of a frame is the 80 us response.
+const IDLE_US: u16 = 200;
+
+/// Pulses shorter than this are glitches (in RMT clock cycles, 2 us).
+const FILTER_CYCLES: u8 = 160;
+
+/// Room for the pulse codes of a frame: each code holds two pulses and a frame
+/// has about 85.
+const CODES: usize = 64;
+
+/// [`EdgeCapture`] recording the data line with an RMT receive channel.
+pub struct RmtCapture<'d> {
+    channel: Channel<'d, Async, Rx>,
+    codes: [PulseCode; CODES],
+}
+
+impl<'d> RmtCapture<'d> {
+    /// Records the edges of `pin` with `channel`, the pin stays usable to send
+    /// the start command.
+    pub fn new(channel: impl RxChannelCreator<'d, Async>, pin: &Flex<'d>) -> Result<Self, Error> {
+        let config = RxChannelConfig::default()
+            .with_clk_divider(RMT_FREQUENCY_MHZ as u8)
+            .with_idle_threshold(IDLE_US)
+            .with_filter_threshold(FILTER_CYCLES);
+
+        Ok(Self {
+            channel: channel.configure_rx(pin.peripheral_input(), config)?,
+            codes: [PulseCode::end_marker(); CODES],
+        })
+    }
+}
+
+impl EdgeCapture for RmtCapture<'_> {
+    async fn capture(&mut self) -> Result<[u64; EDGES], CaptureError> {
+        self.codes.fill(PulseCode::end_marker());
+
+        if let Err(e) = self.channel.receive(&mut self.codes).await {
+            warn!("Failed to record a DHT transmission: {:?}", e);
+            return Err(CaptureError::Hardware);
+        }
+
+        // A pulse of length 0 ends the recording
+        let pulses = self
+            .codes
+            .iter()
+            .flat_map(|code| {
+                [
+                    (code.level1(), code.length1()),
+                    (code.level2(), code.length2()),
+                ]
+            })
+            .take_while(|&(_, length)| length != 0)
+            .map(|(level, length)| (level == Level::High, u32::from(length)));
+
+        edges_from_pulses(pulses)
+    }
+}
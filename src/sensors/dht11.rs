@@ -1,205 +1,134 @@
-// code from https://github.com/plorefice/dht11-rs
-// I made it async
-// I need to diable interrupts
-use embedded_hal::digital::{InputPin, OutputPin};
-use embedded_hal_async::delay::DelayNs;
-
-/// How long to wait for a pulse on the data line (in microseconds).
-const TIMEOUT_US: u16 = 1000;
-
-/// Error type for this crate.
-#[derive(Debug, defmt::Format)]
-pub enum Error<E> {
-    /// Timeout during communication.
-    Timeout,
-
-    /// CRC mismatch.
-    CrcMismatch,
-
-    /// GPIO error.
-    Gpio(E),
-}
-
-/// A DHT11 device.
-pub struct Dht11<GPIO> {
-    /// The concrete GPIO pin implementation.
-    gpio: GPIO,
-}
-
-/// Results of a reading performed by the DHT11.
-#[derive(Copy, Clone, Default, Debug, defmt::Format)]
-
-pub struct Measurement {
-    /// The measured temperature in tenths of degrees Celsius.
-    pub temperature: f32,
-
-    /// The measured humidity in tenths of a percent.
-    pub humidity: f32,
-}
-
-impl<GPIO, E> Dht11<GPIO>
-where
-    GPIO: InputPin<Error = E> + OutputPin<Error = E>,
-{
-    /// Creates a new DHT11 device connected to the specified pin.
-    pub fn new(gpio: GPIO) -> Self {
-        Dht11 { gpio }
-    }
-
-    /// Destroys the driver, returning the GPIO instance.
-    pub fn destroy(self) -> GPIO {
-        self.gpio
-    }
-
-    /// Performs a reading of the sensor.
-    pub async fn read<D: DelayNs>(&mut self, delay: &mut D) -> Result<Measurement, Error<E>>
-where {
-        let mut data = [0u8; 5];
+use super::dht::{Dht, DhtKind, EmbassyClock};
 
-        // Perform initial handshake
+pub use super::dht::{Clock, CrcCheck, DhtSensor, EdgeCapture, Error, Measurement, Reading};
 
-        self.perform_handshake(delay).await?;
+/// The DHT11: 1 °C / 1 % resolution, positive humidity and temperature as integral and decimal bytes.
+pub struct Dht11Kind;
 
-        // Read bits
-
-        for byte in &mut data {
-            for _ in 0..8 {
-                *byte <<= 1;
+/// A DHT11 device.
+pub type Dht11<GPIO, CAP, C = EmbassyClock> = Dht<GPIO, CAP, Dht11Kind, C>;
 
-                if self.read_bit(delay).await? {
-                    *byte |= 1;
-                }
-            }
-        }
+impl DhtKind for Dht11Kind {
+    const NAME: &'static str = "DHT11";
 
-        // Finally wait for line to go idle again.
-        self.wait_for_pulse(true, delay).await?;
+    // The datasheet asks for at least 18 ms
+    const START_PULSE_US: u32 = 25_000;
 
+    fn decode(frame: &[u8; 5]) -> Measurement {
         // Compute temperature
-        let mut temp = i16::from(data[2] & 0x7f) * 10 + i16::from(data[3]);
+        let mut temp = i16::from(frame[2] & 0x7f) * 10 + i16::from(frame[3]);
 
-        if data[2] & 0x80 != 0 {
+        if frame[2] & 0x80 != 0 {
             temp = -temp;
         }
 
-        Ok(Measurement {
+        Measurement {
             temperature: temp as f32 / 10.0,
 
-            humidity: (u16::from(data[0]) * 10 + u16::from(data[1])) as f32 / 10.0,
-        })
+            humidity: (u16::from(frame[0]) * 10 + u16::from(frame[1])) as f32 / 10.0,
+        }
     }
+}
 
-    pub async fn read_with_crc_check<D: DelayNs>(
-        &mut self,
-        delay: &mut D,
-    ) -> Result<Measurement, Error<E>> {
-        let mut data = [0u8; 5];
-
-        // Perform initial handshake
-        self.perform_handshake(delay).await?;
-
-        // Read bits
-        for byte in &mut data {
-            for _ in 0..8 {
-                *byte <<= 1;
-
-                if self.read_bit(delay).await? {
-                    *byte |= 1;
-                }
+#[cfg(test)]
+mod tests {
+    use embassy_futures::block_on;
+    use embedded_hal_mock::eh1::MockError;
+    use embedded_hal_mock::eh1::delay::{CheckedDelay, NoopDelay, Transaction as DelayTransaction};
+    use embedded_hal_mock::eh1::digital::{Mock as PinMock, State, Transaction as PinTransaction};
+
+    use super::*;
+    use crate::sensors::dht::tests::frame_edges;
+    use crate::sensors::dht::{CaptureError, EDGES};
+
+    /// Replays a recorded transmission.
+    struct Replay(Option<Result<[u64; EDGES], CaptureError>>);
+
+    impl EdgeCapture for Replay {
+        async fn capture(&mut self) -> Result<[u64; EDGES], CaptureError> {
+            match self.0 {
+                Some(edges) => edges,
+                None => core::future::pending().await,
             }
         }
-        // Finally wait for line to go idle again.
-        self.wait_for_pulse(true, delay).await?;
-
-        // Check CRC
-        let crc = data[0]
-            .wrapping_add(data[1])
-            .wrapping_add(data[2])
-            .wrapping_add(data[3]);
-
-        if crc != data[4] {
-            return Err(Error::CrcMismatch);
-        }
-
-        // Compute temperature
-
-        let mut temp = i16::from(data[2] & 0x7f) * 10 + i16::from(data[3]);
-
-        if data[2] & 0x80 != 0 {
-            temp = -temp;
-        }
-
-        Ok(Measurement {
-            temperature: temp as f32 / 10.0,
-
-            humidity: (u16::from(data[0]) * 10 + u16::from(data[1])) as f32 / 10.0,
-        })
     }
 
-    async fn perform_handshake<D>(&mut self, delay: &mut D) -> Result<(), Error<E>>
-    where
-        D: DelayNs,
-    {
-        // Set pin as floating to let pull-up raise the line and start the reading process.
-        self.set_input()?;
-
-        delay.delay_ms(1).await;
-
-        // Pull line low for at least 18ms to send a start command.
-        self.set_low()?;
-
-        delay.delay_ms(25).await;
-
-        // Restore floating
-        self.set_input()?;
-
-        delay.delay_us(40).await;
-
-        // As a response, the device pulls the line low for 80us and then high for 80us.
-        self.read_bit(delay).await?;
-
-        Ok(())
+    /// The line is released, pulled low for the start pulse and released for
+    /// the sensor to answer.
+    fn start_command() -> PinMock {
+        PinMock::new(&[
+            PinTransaction::set(State::High),
+            PinTransaction::set(State::Low),
+            PinTransaction::set(State::High),
+        ])
     }
 
-    async fn read_bit<D>(&mut self, delay: &mut D) -> Result<bool, Error<E>>
-    where
-        D: DelayNs,
-    {
-        let low = self.wait_for_pulse(true, delay).await?;
-
-        let high = self.wait_for_pulse(false, delay).await?;
+    fn read(capture: Replay, crc_check: CrcCheck) -> Result<Reading, Error<MockError>> {
+        let mut dht = Dht11::new(start_command(), capture, EmbassyClock).with_crc_check(crc_check);
+        let result = block_on(dht.read(&mut NoopDelay::new()));
 
-        Ok(high > low)
+        let (mut pin, _) = dht.destroy();
+        pin.done();
+        result
     }
 
-    async fn wait_for_pulse<D>(&mut self, level: bool, delay: &mut D) -> Result<u32, Error<E>>
-    where
-        D: DelayNs,
-    {
-        let mut count = 0;
-
-        while self.read_line()? != level {
-            count += 1;
+    fn answer(frame: [u8; 5]) -> Replay {
+        Replay(Some(Ok(frame_edges(frame, 27, 70))))
+    }
 
-            if count > TIMEOUT_US {
-                return Err(Error::Timeout);
-            }
+    #[test]
+    fn sends_the_start_command_and_decodes_the_answer() {
+        let mut delay = CheckedDelay::new(&[
+            DelayTransaction::async_delay_ms(1),
+            DelayTransaction::async_delay_us(25_000),
+        ]);
+        let frame = [0x32, 0x00, 0x17, 0x05, 0x4E];
+        let mut dht = Dht11::new(start_command(), answer(frame), EmbassyClock);
+
+        let reading = block_on(dht.read(&mut delay)).unwrap();
+
+        assert_eq!(reading.frame, frame);
+        assert_eq!(reading.measurement.humidity, 50.0);
+        assert_eq!(reading.measurement.temperature, 23.5);
+        let (mut pin, _) = dht.destroy();
+        pin.done();
+        delay.done();
+    }
 
-            delay.delay_us(1).await;
-        }
+    #[test]
+    fn decodes_negative_temperatures() {
+        let reading = read(answer([0x32, 0x00, 0x82, 0x05, 0xB9]), CrcCheck::Enabled).unwrap();
 
-        Ok(u32::from(count))
+        assert_eq!(reading.measurement.temperature, -2.5);
+        assert_eq!(reading.measurement.humidity, 50.0);
     }
 
-    fn set_input(&mut self) -> Result<(), Error<E>> {
-        self.gpio.set_high().map_err(Error::Gpio)
-    }
+    #[test]
+    fn rejects_a_wrong_checksum() {
+        let frame = [0x32, 0x00, 0x17, 0x05, 0x4F];
+
+        assert!(matches!(
+            read(answer(frame), CrcCheck::Enabled),
+            Err(Error::CrcMismatch)
+        ));
 
-    fn set_low(&mut self) -> Result<(), Error<E>> {
-        self.gpio.set_low().map_err(Error::Gpio)
+        let reading = read(answer(frame), CrcCheck::Disabled).unwrap();
+        assert_eq!(reading.frame, frame);
+        assert_eq!(reading.measurement.temperature, 23.5);
     }
 
-    fn read_line(&mut self) -> Result<bool, Error<E>> {
-        self.gpio.is_high().map_err(Error::Gpio)
+    #[test]
+    fn times_out_without_an_answer() {
+        assert!(matches!(
+            read(Replay(None), CrcCheck::Enabled),
+            Err(Error::Timeout)
+        ));
+        assert!(matches!(
+            read(
+                Replay(Some(Err(CaptureError::Incomplete))),
+                CrcCheck::Enabled
+            ),
+            Err(Error::Timeout)
+        ));
     }
 }
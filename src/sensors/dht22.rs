@@ -0,0 +1,77 @@
+use super::dht::{Dht, DhtKind, EmbassyClock};
+
+pub use super::dht::{Clock, CrcCheck, DhtSensor, EdgeCapture, Error, Measurement, Reading};
+
+/// The DHT22/AM2302: 0.1 °C / 0.1 % resolution, 16-bit big-endian values, sign in the top bit of the temperature.
+pub struct Dht22Kind;
+
+/// A DHT22 (AM2302) device.
+pub type Dht22<GPIO, CAP, C = EmbassyClock> = Dht<GPIO, CAP, Dht22Kind, C>;
+
+impl DhtKind for Dht22Kind {
+    const NAME: &'static str = "DHT22";
+
+    // The datasheet asks for at least 1 ms, longer pulses may be ignored
+    const START_PULSE_US: u32 = 1_100;
+
+    fn decode(frame: &[u8; 5]) -> Measurement {
+        let humidity = u16::from_be_bytes([frame[0], frame[1]]);
+
+        let mut temp = i16::from_be_bytes([frame[2] & 0x7f, frame[3]]);
+
+        if frame[2] & 0x80 != 0 {
+            temp = -temp;
+        }
+
+        Measurement {
+            temperature: temp as f32 / 10.0,
+
+            humidity: humidity as f32 / 10.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sensors::dht::tests::frame_edges;
+    use crate::sensors::dht::{decode_edges, decode_frame};
+
+    /// Decodes a frame as captured on the wire.
+    fn decode(frame: [u8; 5], crc_check: CrcCheck) -> Result<Reading, Error<()>> {
+        let frame = decode_edges(&frame_edges(frame, 27, 70))?;
+        decode_frame::<Dht22Kind, ()>(frame, crc_check)
+    }
+
+    #[test]
+    fn decodes_a_frame() {
+        // The example of the datasheet: 65.2 % and 35.1 °C
+        let reading = decode([0x02, 0x8C, 0x01, 0x5F, 0xEE], CrcCheck::Enabled).unwrap();
+
+        assert_eq!(reading.measurement.humidity, 65.2);
+        assert_eq!(reading.measurement.temperature, 35.1);
+    }
+
+    #[test]
+    fn decodes_negative_temperatures() {
+        // The sign bit is set on -10.1 °C
+        let reading = decode([0x02, 0x8C, 0x80, 0x65, 0x73], CrcCheck::Enabled).unwrap();
+
+        assert_eq!(reading.measurement.temperature, -10.1);
+        assert_eq!(reading.measurement.humidity, 65.2);
+    }
+
+    #[test]
+    fn rejects_a_wrong_checksum() {
+        let frame = [0x02, 0x8C, 0x80, 0x65, 0x74];
+
+        assert!(matches!(
+            decode(frame, CrcCheck::Enabled),
+            Err(Error::CrcMismatch)
+        ));
+
+        let reading = decode(frame, CrcCheck::Disabled).unwrap();
+        assert_eq!(reading.frame, frame);
+        assert_eq!(reading.measurement.temperature, -10.1);
+    }
+}
@@ -0,0 +1,270 @@
+use core::cell::RefCell;
+use core::future::Future;
+
+use defmt::warn;
+use embassy_sync::blocking_mutex::{Mutex, raw::NoopRawMutex};
+use heapless::Vec;
+use serde::Deserialize;
+
+/// A physical quantity reported by a sensor.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, defmt::Format)]
+#[serde(rename_all = "lowercase")]
+pub enum Quantity {
+    Temperature,
+    Humidity,
+    Pressure,
+}
+
+impl Quantity {
+    pub const ALL: [Quantity; 3] = [
+        Quantity::Temperature,
+        Quantity::Humidity,
+        Quantity::Pressure,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Quantity::Temperature => "temperature",
+            Quantity::Humidity => "humidity",
+            Quantity::Pressure => "pressure",
+        }
+    }
+
+    /// The unit the values are published in.
+    pub fn unit(self) -> &'static str {
+        match self {
+            Quantity::Temperature => "°C",
+            Quantity::Humidity => "%",
+            Quantity::Pressure => "kPa",
+        }
+    }
+
+    /// Largest calibration offset, in the [`unit`](Quantity::unit) of the quantity.
+    pub fn max_offset(self) -> f32 {
+        match self {
+            Quantity::Temperature => 10.0,
+            Quantity::Humidity => 20.0,
+            Quantity::Pressure => 5.0,
+        }
+    }
+
+    const fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+/// A set of [`Quantity`].
+#[derive(Copy, Clone, Default, Debug, PartialEq, Eq, defmt::Format)]
+pub struct Quantities(u8);
+
+impl Quantities {
+    pub const NONE: Self = Self(0);
+
+    /// Returns the set extended with `quantity`.
+    pub const fn with(self, quantity: Quantity) -> Self {
+        Self(self.0 | quantity.bit())
+    }
+
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    pub const fn contains(self, quantity: Quantity) -> bool {
+        self.0 & quantity.bit() != 0
+    }
+}
+
+/// The quantities a sensor managed to measure, `None` for everything else.
+#[derive(Copy, Clone, Default, Debug, PartialEq, defmt::Format)]
+pub struct PartialReading {
+    /// Degrees Celsius.
+    pub temperature: Option<f32>,
+
+    /// Relative humidity in percent.
+    pub humidity: Option<f32>,
+
+    /// Pascals.
+    pub pressure: Option<f32>,
+}
+
+impl PartialReading {
+    /// Fills the quantities missing in `self` from `other`.
+    pub fn merge(self, other: Self) -> Self {
+        Self {
+            temperature: self.temperature.or(other.temperature),
+            humidity: self.humidity.or(other.humidity),
+            pressure: self.pressure.or(other.pressure),
+        }
+    }
+}
+
+/// A value and the sensor that measured it.
+#[derive(Copy, Clone, Debug, PartialEq, defmt::Format)]
+pub struct Sourced {
+    pub value: f32,
+    pub sensor: &'static str,
+}
+
+impl Sourced {
+    /// Converts the value, e.g. to another unit.
+    pub fn map(self, f: impl FnOnce(f32) -> f32) -> Self {
+        Self {
+            value: f(self.value),
+            ..self
+        }
+    }
+}
+
+/// The merged readings of a [`SensorSet`].
+#[derive(Copy, Clone, Default, Debug, PartialEq, defmt::Format)]
+pub struct SourcedReading {
+    pub temperature: Option<Sourced>,
+    pub humidity: Option<Sourced>,
+    pub pressure: Option<Sourced>,
+}
+
+impl SourcedReading {
+    /// Fills the quantities missing in `self` from a reading of `sensor`.
+    pub fn merge(self, other: PartialReading, sensor: &'static str) -> Self {
+        let sourced = |value: Option<f32>| value.map(|value| Sourced { value, sensor });
+
+        Self {
+            temperature: self.temperature.or(sourced(other.temperature)),
+            humidity: self.humidity.or(sourced(other.humidity)),
+            pressure: self.pressure.or(sourced(other.pressure)),
+        }
+    }
+}
+
+/// Why reading a sensor failed, as counted in [`SensorErrors`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq, defmt::Format)]
+pub enum ErrorKind {
+    /// The sensor didn't answer in time.
+    Timeout,
+    /// The checksum of the answer is wrong.
+    Crc,
+    Other,
+}
+
+impl ErrorKind {
+    pub const ALL: [Self; 3] = [Self::Timeout, Self::Crc, Self::Other];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorKind::Timeout => "timeout",
+            ErrorKind::Crc => "crc",
+            ErrorKind::Other => "other",
+        }
+    }
+}
+
+/// The error of a [`WeatherSensor`].
+pub trait SensorError: defmt::Format {
+    fn kind(&self) -> ErrorKind;
+}
+
+/// A device measuring some of the quantities of a [`PartialReading`].
+pub trait WeatherSensor {
+    type Error: SensorError;
+
+    /// Identifies the sensor in logs and published measurements.
+    fn name(&self) -> &'static str;
+
+    /// The quantities filled in by [`WeatherSensor::read`].
+    fn quantities(&self) -> Quantities;
+
+    /// Measures the provided quantities.
+    fn read(&mut self) -> impl Future<Output = Result<PartialReading, Self::Error>>;
+}
+
+/// How many sensors a [`SensorSet`] can have.
+pub const MAX_SENSORS: usize = 4;
+
+/// Several sensors read as one, usually a tuple of [`WeatherSensor`].
+pub trait SensorSet {
+    /// The quantities provided by at least one of the sensors.
+    fn quantities(&self) -> Quantities;
+
+    /// The names of the sensors, in order.
+    fn names(&self) -> Vec<&'static str, MAX_SENSORS>;
+
+    /// Reads every sensor and merges the results.
+    ///
+    /// Sensors listed first win when several provide the same quantity.
+    /// Failures are logged, counted in `errors` and leave the quantities of
+    /// that sensor empty.
+    fn read_all(&mut self, errors: &TheSensorErrors) -> impl Future<Output = SourcedReading>;
+}
+
+/// How many reads of each sensor of a [`SensorSet`] failed, by [`ErrorKind`].
+#[derive(Clone, Debug, defmt::Format)]
+pub struct SensorErrors {
+    names: Vec<&'static str, MAX_SENSORS>,
+    counts: [[u32; ErrorKind::ALL.len()]; MAX_SENSORS],
+}
+
+impl SensorErrors {
+    /// No errors yet for the sensors of [`SensorSet::names`].
+    pub fn new(names: Vec<&'static str, MAX_SENSORS>) -> Self {
+        Self {
+            names,
+            counts: [[0; ErrorKind::ALL.len()]; MAX_SENSORS],
+        }
+    }
+
+    /// Counts a failure of the sensor at `index`.
+    pub fn record(&mut self, index: usize, kind: ErrorKind) {
+        if let Some(counts) = self.counts.get_mut(index) {
+            counts[kind as usize] = counts[kind as usize].wrapping_add(1);
+        }
+    }
+
+    /// The name of each sensor and its failures by kind.
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, ErrorKind, u32)> + '_ {
+        self.names.iter().zip(&self.counts).flat_map(|(&name, counts)| {
+            ErrorKind::ALL
+                .into_iter()
+                .map(move |kind| (name, kind, counts[kind as usize]))
+        })
+    }
+}
+
+/// The sensor errors, counted by the measuring loop and shared with the web server.
+pub type TheSensorErrors = Mutex<NoopRawMutex, RefCell<SensorErrors>>;
+
+macro_rules! impl_sensor_set {
+    ($($sensor:ident: $index:tt),+) => {
+        impl<$($sensor: WeatherSensor),+> SensorSet for ($($sensor,)+) {
+            fn quantities(&self) -> Quantities {
+                Quantities::NONE$(.union(self.$index.quantities()))+
+            }
+
+            fn names(&self) -> Vec<&'static str, MAX_SENSORS> {
+                let mut names = Vec::new();
+                // There are at most `MAX_SENSORS`
+                $(names.push(self.$index.name()).unwrap();)+
+                names
+            }
+
+            async fn read_all(&mut self, errors: &TheSensorErrors) -> SourcedReading {
+                let mut reading = SourcedReading::default();
+                $(
+                    let sensor = self.$index.name();
+                    match self.$index.read().await {
+                        Ok(partial) => reading = reading.merge(partial, sensor),
+                        Err(e) => {
+                            warn!("{} failed: {:?}", sensor, e);
+                            errors.lock(|errors| errors.borrow_mut().record($index, e.kind()));
+                        }
+                    }
+                )+
+                reading
+            }
+        }
+    };
+}
+
+impl_sensor_set!(A: 0);
+impl_sensor_set!(A: 0, B: 1);
+impl_sensor_set!(A: 0, B: 1, C: 2);
+impl_sensor_set!(A: 0, B: 1, C: 2, D: 3);
@@ -0,0 +1,101 @@
+pub mod crc;
+pub mod header;
+pub mod history_log;
+pub mod kv;
+pub mod partition;
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use embedded_storage::nor_flash::{
+        ErrorType, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash, check_erase,
+        check_read, check_write,
+    };
+
+    #[derive(Debug, PartialEq, Eq)]
+    pub(crate) enum MemFlashError {
+        Access(NorFlashErrorKind),
+        /// The write was cut short, as by a power loss.
+        PowerLoss,
+    }
+
+    impl NorFlashError for MemFlashError {
+        fn kind(&self) -> NorFlashErrorKind {
+            match self {
+                MemFlashError::Access(kind) => *kind,
+                MemFlashError::PowerLoss => NorFlashErrorKind::Other,
+            }
+        }
+    }
+
+    /// NOR flash in RAM with `SECTORS` sectors of `SIZE` bytes: writes can
+    /// only clear bits, erasing sets a whole sector back to `0xFF`.
+    pub(crate) struct MemFlash<const SIZE: usize, const SECTORS: usize> {
+        pub(crate) bytes: [[u8; SIZE]; SECTORS],
+        /// Writes left before a power loss, the one it interrupts only
+        /// programs the first half of its bytes.
+        pub(crate) writes_before_power_loss: Option<usize>,
+    }
+
+    impl<const SIZE: usize, const SECTORS: usize> MemFlash<SIZE, SECTORS> {
+        /// A flash that was never written.
+        pub(crate) fn new() -> Self {
+            Self {
+                bytes: [[0xFF; SIZE]; SECTORS],
+                writes_before_power_loss: None,
+            }
+        }
+
+        fn byte(&mut self, offset: usize) -> &mut u8 {
+            &mut self.bytes[offset / SIZE][offset % SIZE]
+        }
+    }
+
+    impl<const SIZE: usize, const SECTORS: usize> ErrorType for MemFlash<SIZE, SECTORS> {
+        type Error = MemFlashError;
+    }
+
+    impl<const SIZE: usize, const SECTORS: usize> ReadNorFlash for MemFlash<SIZE, SECTORS> {
+        const READ_SIZE: usize = 4;
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+            check_read(self, offset, bytes.len()).map_err(MemFlashError::Access)?;
+            for (i, byte) in bytes.iter_mut().enumerate() {
+                *byte = *self.byte(offset as usize + i);
+            }
+            Ok(())
+        }
+
+        fn capacity(&self) -> usize {
+            SIZE * SECTORS
+        }
+    }
+
+    impl<const SIZE: usize, const SECTORS: usize> NorFlash for MemFlash<SIZE, SECTORS> {
+        const WRITE_SIZE: usize = 4;
+        const ERASE_SIZE: usize = SIZE;
+
+        fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+            check_erase(self, from, to).map_err(MemFlashError::Access)?;
+            for offset in from..to {
+                *self.byte(offset as usize) = 0xFF;
+            }
+            Ok(())
+        }
+
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+            check_write(self, offset, bytes.len()).map_err(MemFlashError::Access)?;
+            let (len, result) = match &mut self.writes_before_power_loss {
+                Some(0) => (bytes.len() / 2, Err(MemFlashError::PowerLoss)),
+                Some(left) => {
+                    *left -= 1;
+                    (bytes.len(), Ok(()))
+                }
+                None => (bytes.len(), Ok(())),
+            };
+            for (i, &byte) in bytes[..len].iter().enumerate() {
+                *self.byte(offset as usize + i) &= byte;
+            }
+            result
+        }
+    }
+}
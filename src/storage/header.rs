@@ -0,0 +1,23 @@
+// Sector header shared by the flash logs
+use super::crc::crc32;
+
+/// Size of a sector header.
+pub const HEADER_SIZE: usize = 16;
+
+/// A header identifying a sector of the log `magic` with its `sequence` number.
+pub fn encode_header(magic: u32, sequence: u32) -> [u8; HEADER_SIZE] {
+    let mut header = [0xFF; HEADER_SIZE];
+    header[0..4].copy_from_slice(&magic.to_le_bytes());
+    header[4..8].copy_from_slice(&sequence.to_le_bytes());
+    let crc = crc32(&header[..8]);
+    header[8..12].copy_from_slice(&crc.to_le_bytes());
+    header
+}
+
+/// The sequence number of a sector, `None` if the header isn't a valid one for `magic`.
+pub fn decode_header(magic: u32, header: &[u8; HEADER_SIZE]) -> Option<u32> {
+    let [m0, m1, m2, m3, s0, s1, s2, s3, c0, c1, c2, c3, ..] = *header;
+    (u32::from_le_bytes([m0, m1, m2, m3]) == magic
+        && crc32(&header[..8]) == u32::from_le_bytes([c0, c1, c2, c3]))
+    .then(|| u32::from_le_bytes([s0, s1, s2, s3]))
+}
@@ -0,0 +1,495 @@
+// Append-only log of history records in a flash partition, so they survive reboots
+//
+// The partition is a ring of erase sectors. Every sector starts with a header
+// holding a sequence number, followed by fixed-size records. Records are only
+// appended; when the current sector is full the oldest one is erased and
+// reused, so all sectors wear out evenly.
+use core::ops::{Bound, RangeBounds};
+
+use embedded_storage::nor_flash::NorFlash;
+use num_traits::float::FloatCore;
+
+use super::crc::crc32;
+use super::header::{HEADER_SIZE, decode_header, encode_header};
+use super::partition::Partition;
+use crate::history::Record;
+
+/// Size of an encoded record, the sector header takes one slot.
+pub const SLOT_SIZE: usize = HEADER_SIZE;
+
+const MAGIC: u32 = u32::from_le_bytes(*b"WSH1");
+
+/// Content of a slot that was never written.
+const ERASED: [u8; SLOT_SIZE] = [0xFF; SLOT_SIZE];
+
+const NO_TEMPERATURE: i16 = i16::MIN;
+const NO_HUMIDITY: u16 = u16::MAX;
+const NO_PRESSURE: u32 = u32::MAX;
+
+/// Error type for the log.
+#[derive(Debug, defmt::Format)]
+pub enum Error<E> {
+    /// The sector size isn't a multiple of [`SLOT_SIZE`] or there are fewer than two sectors.
+    UnsupportedFlash,
+
+    /// Flash error.
+    Flash(E),
+}
+
+/// Encodes a record as a slot.
+///
+/// The timestamp is kept in seconds, temperature and humidity in hundredths
+/// and pressure (in kPa) with a resolution of 1 Pa. Missing or non-finite
+/// values are stored as sentinels.
+pub fn encode(record: &Record) -> [u8; SLOT_SIZE] {
+    let seconds = (record.timestamp_ms / 1000).min(u32::MAX as u64) as u32;
+    let temperature = fixed_point(
+        record.temperature,
+        100.0,
+        i16::MIN as f32 + 1.0,
+        i16::MAX as f32,
+    )
+    .map_or(NO_TEMPERATURE, |t| t as i16);
+    let humidity = fixed_point(record.humidity, 100.0, 0.0, u16::MAX as f32 - 1.0)
+        .map_or(NO_HUMIDITY, |h| h as u16);
+    let pressure = fixed_point(record.pressure, 1000.0, 0.0, 4_000_000_000.0)
+        .map_or(NO_PRESSURE, |p| p as u32);
+
+    let mut slot = [0; SLOT_SIZE];
+    slot[0..4].copy_from_slice(&seconds.to_le_bytes());
+    slot[4..6].copy_from_slice(&temperature.to_le_bytes());
+    slot[6..8].copy_from_slice(&humidity.to_le_bytes());
+    slot[8..12].copy_from_slice(&pressure.to_le_bytes());
+    let crc = crc32(&slot[..12]);
+    slot[12..16].copy_from_slice(&crc.to_le_bytes());
+    slot
+}
+
+/// Decodes a slot written by [`encode`], `None` if it's corrupted.
+pub fn decode(slot: &[u8; SLOT_SIZE]) -> Option<Record> {
+    let [
+        s0,
+        s1,
+        s2,
+        s3,
+        t0,
+        t1,
+        h0,
+        h1,
+        p0,
+        p1,
+        p2,
+        p3,
+        c0,
+        c1,
+        c2,
+        c3,
+    ] = *slot;
+    if crc32(&slot[..12]) != u32::from_le_bytes([c0, c1, c2, c3]) {
+        return None;
+    }
+
+    let temperature = i16::from_le_bytes([t0, t1]);
+    let humidity = u16::from_le_bytes([h0, h1]);
+    let pressure = u32::from_le_bytes([p0, p1, p2, p3]);
+    Some(Record {
+        timestamp_ms: u32::from_le_bytes([s0, s1, s2, s3]) as u64 * 1000,
+        temperature: (temperature != NO_TEMPERATURE).then(|| temperature as f32 / 100.0),
+        humidity: (humidity != NO_HUMIDITY).then(|| humidity as f32 / 100.0),
+        pressure: (pressure != NO_PRESSURE).then(|| pressure as f32 / 1000.0),
+    })
+}
+
+fn fixed_point(value: Option<f32>, scale: f32, min: f32, max: f32) -> Option<f32> {
+    value
+        .filter(|value| value.is_finite())
+        .map(|value| (value * scale).round().clamp(min, max))
+}
+
+/// Wear-levelled, append-only log of [`Record`]s.
+///
+/// Recovers its write head when opened, a record torn by a power loss is
+/// skipped.
+pub struct HistoryLog<F> {
+    flash: F,
+    sectors: u32,
+    /// Sector records are appended to.
+    sector: u32,
+    sequence: u32,
+    /// Next free slot of the current sector, slot 0 is the header.
+    slot: u32,
+}
+
+impl<F: NorFlash> HistoryLog<F> {
+    const SLOTS: u32 = (F::ERASE_SIZE / SLOT_SIZE) as u32;
+
+    /// Opens the log, formatting the flash if it doesn't hold one.
+    pub fn open(flash: F) -> Result<Self, Error<F::Error>> {
+        if !F::ERASE_SIZE.is_multiple_of(SLOT_SIZE)
+            || !SLOT_SIZE.is_multiple_of(F::WRITE_SIZE)
+            || !SLOT_SIZE.is_multiple_of(F::READ_SIZE)
+        {
+            return Err(Error::UnsupportedFlash);
+        }
+        let sectors = (flash.capacity() / F::ERASE_SIZE) as u32;
+        if sectors < 2 {
+            return Err(Error::UnsupportedFlash);
+        }
+
+        let mut log = Self {
+            flash,
+            sectors,
+            sector: 0,
+            sequence: 0,
+            slot: Self::SLOTS,
+        };
+
+        // The sector written last has the highest sequence number
+        let mut current = None;
+        for sector in 0..sectors {
+            if let Some(sequence) = decode_header(MAGIC, &log.read_slot(sector, 0)?)
+                && current.is_none_or(|(_, highest)| sequence > highest)
+            {
+                current = Some((sector, sequence));
+            }
+        }
+
+        let Some((sector, sequence)) = current else {
+            log.start_sector(0, 0)?;
+            return Ok(log);
+        };
+        log.sector = sector;
+        log.sequence = sequence;
+        // Records are appended in order so the first erased slot is the write
+        // head, a torn record isn't erased and stays behind it
+        log.slot = 1;
+        while log.slot < Self::SLOTS && log.read_slot(sector, log.slot)? != ERASED {
+            log.slot += 1;
+        }
+        Ok(log)
+    }
+
+    /// Appends a record, erasing the oldest sector when the current one is full.
+    pub fn append(&mut self, record: &Record) -> Result<(), Error<F::Error>> {
+        if self.slot == Self::SLOTS {
+            self.start_sector((self.sector + 1) % self.sectors, self.sequence + 1)?;
+        }
+        let offset = self.offset(self.sector, self.slot);
+        let written = self.flash.write(offset, &encode(record));
+        // A failed write may have programmed part of the slot, it can't be
+        // written again before the sector is erased. Skipping it matches what
+        // `open` does after a reboot
+        self.slot += 1;
+        written.map_err(Error::Flash)
+    }
+
+    /// Iterates over the records with a timestamp in `range`, oldest first.
+    pub fn records(&mut self, range: impl RangeBounds<u64>) -> Records<'_, F> {
+        Records {
+            start: range.start_bound().cloned(),
+            end: range.end_bound().cloned(),
+            age: Some(self.sectors - 1),
+            slot: 0,
+            log: self,
+        }
+    }
+
+    pub fn destroy(self) -> F {
+        self.flash
+    }
+
+    fn start_sector(&mut self, sector: u32, sequence: u32) -> Result<(), Error<F::Error>> {
+        let offset = self.offset(sector, 0);
+        self.flash
+            .erase(offset, offset + F::ERASE_SIZE as u32)
+            .map_err(Error::Flash)?;
+        self.flash
+            .write(offset, &encode_header(MAGIC, sequence))
+            .map_err(Error::Flash)?;
+        self.sector = sector;
+        self.sequence = sequence;
+        self.slot = 1;
+        Ok(())
+    }
+
+    fn read_slot(&mut self, sector: u32, slot: u32) -> Result<[u8; SLOT_SIZE], Error<F::Error>> {
+        let mut buffer = [0; SLOT_SIZE];
+        let offset = self.offset(sector, slot);
+        self.flash.read(offset, &mut buffer).map_err(Error::Flash)?;
+        Ok(buffer)
+    }
+
+    fn offset(&self, sector: u32, slot: u32) -> u32 {
+        sector * F::ERASE_SIZE as u32 + slot * SLOT_SIZE as u32
+    }
+}
+
+/// Iterator returned by [`HistoryLog::records`].
+pub struct Records<'a, F> {
+    log: &'a mut HistoryLog<F>,
+    start: Bound<u64>,
+    end: Bound<u64>,
+    /// How many sectors the one being read is behind the current one, `None` when done.
+    age: Option<u32>,
+    /// Next slot to read, 0 if the sector header wasn't checked yet.
+    slot: u32,
+}
+
+impl<F: NorFlash> Records<'_, F> {
+    /// The next record of the sector `age` sectors behind the current one,
+    /// `None` when there are no more.
+    fn read_next(&mut self, age: u32) -> Result<Option<Record>, Error<F::Error>> {
+        let log = &mut *self.log;
+        let sector = (log.sector + log.sectors - age) % log.sectors;
+        if self.slot == 0 {
+            // Sectors ahead of the current one were never written or hold
+            // leftovers, their sequence number doesn't match
+            let expected = log.sequence.checked_sub(age);
+            if expected.is_none() || decode_header(MAGIC, &log.read_slot(sector, 0)?) != expected {
+                return Ok(None);
+            }
+            self.slot = 1;
+        }
+
+        let end = if age == 0 {
+            log.slot
+        } else {
+            HistoryLog::<F>::SLOTS
+        };
+        while self.slot < end {
+            let slot = log.read_slot(sector, self.slot)?;
+            self.slot += 1;
+            if slot == ERASED {
+                break;
+            }
+            // A record torn by a power loss is skipped
+            if let Some(record) = decode(&slot) {
+                return Ok(Some(record));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl<F: NorFlash> Iterator for Records<'_, F> {
+    type Item = Result<Record, Error<F::Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let age = self.age?;
+            match self.read_next(age) {
+                Ok(Some(record)) => {
+                    let past_end = match self.end {
+                        Bound::Included(end) => record.timestamp_ms > end,
+                        Bound::Excluded(end) => record.timestamp_ms >= end,
+                        Bound::Unbounded => false,
+                    };
+                    if past_end {
+                        self.age = None;
+                    } else if (self.start, self.end).contains(&record.timestamp_ms) {
+                        return Some(Ok(record));
+                    }
+                }
+                Ok(None) => {
+                    self.age = age.checked_sub(1);
+                    self.slot = 0;
+                }
+                Err(error) => {
+                    self.age = None;
+                    return Some(Err(error));
+                }
+            }
+        }
+    }
+}
+
+/// The log in the `history` flash partition.
+pub type FlashHistoryLog = HistoryLog<Partition>;
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::storage::tests::{MemFlash, MemFlashError};
+
+    /// Three records per sector.
+    type Flash = MemFlash<64, 3>;
+
+    fn record(seconds: u64) -> Record {
+        Record {
+            timestamp_ms: seconds * 1000,
+            temperature: Some(21.25),
+            humidity: Some(45.5),
+            pressure: Some(100.5),
+        }
+    }
+
+    fn timestamps(log: &mut HistoryLog<Flash>) -> Vec<u64> {
+        log.records(..)
+            .map(|record| record.unwrap().timestamp_ms / 1000)
+            .collect()
+    }
+
+    /// Opens the log again, as after a reboot.
+    fn reopen(log: HistoryLog<Flash>) -> HistoryLog<Flash> {
+        let mut flash = log.destroy();
+        flash.writes_before_power_loss = None;
+        HistoryLog::open(flash).unwrap()
+    }
+
+    #[test]
+    fn encodes_records() {
+        let full = Record {
+            timestamp_ms: 12_345,
+            temperature: Some(-12.5),
+            humidity: Some(99.75),
+            pressure: Some(101.25),
+        };
+        assert_eq!(
+            decode(&encode(&full)),
+            Some(Record {
+                timestamp_ms: 12_000,
+                ..full
+            })
+        );
+
+        let missing = Record {
+            timestamp_ms: 0,
+            temperature: None,
+            humidity: Some(f32::NAN),
+            pressure: Some(f32::INFINITY),
+        };
+        assert_eq!(
+            decode(&encode(&missing)),
+            Some(Record {
+                timestamp_ms: 0,
+                temperature: None,
+                humidity: None,
+                pressure: None,
+            })
+        );
+    }
+
+    #[test]
+    fn clamps_values_out_of_range() {
+        let slot = encode(&Record {
+            timestamp_ms: 0,
+            temperature: Some(1000.0),
+            humidity: Some(-5.0),
+            pressure: None,
+        });
+        let record = decode(&slot).unwrap();
+
+        assert_eq!(record.temperature, Some(327.67));
+        assert_eq!(record.humidity, Some(0.0));
+    }
+
+    #[test]
+    fn rejects_corrupted_slots() {
+        let mut slot = encode(&record(1));
+        slot[5] ^= 0x01;
+
+        assert_eq!(decode(&slot), None);
+        assert_eq!(decode(&ERASED), None);
+    }
+
+    #[test]
+    fn keeps_records_across_reboots() {
+        let mut log = HistoryLog::open(Flash::new()).unwrap();
+        for seconds in 1..=4 {
+            log.append(&record(seconds)).unwrap();
+        }
+
+        let mut log = reopen(log);
+        assert_eq!(timestamps(&mut log), [1, 2, 3, 4]);
+        log.append(&record(5)).unwrap();
+        assert_eq!(timestamps(&mut log), [1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn erases_the_oldest_sector_when_full() {
+        let mut log = HistoryLog::open(Flash::new()).unwrap();
+        for seconds in 1..=11 {
+            log.append(&record(seconds)).unwrap();
+        }
+
+        // Records 10 and 11 reused the first sector
+        assert_eq!(timestamps(&mut log), [4, 5, 6, 7, 8, 9, 10, 11]);
+        let mut log = reopen(log);
+        assert_eq!(timestamps(&mut log), [4, 5, 6, 7, 8, 9, 10, 11]);
+        log.append(&record(12)).unwrap();
+        log.append(&record(13)).unwrap();
+        assert_eq!(timestamps(&mut log), [7, 8, 9, 10, 11, 12, 13]);
+    }
+
+    #[test]
+    fn filters_by_timestamp() {
+        let mut log = HistoryLog::open(Flash::new()).unwrap();
+        for seconds in 1..=5 {
+            log.append(&record(seconds)).unwrap();
+        }
+
+        let between: Vec<_> = log
+            .records(2000..4000)
+            .map(|record| record.unwrap().timestamp_ms)
+            .collect();
+        assert_eq!(between, [2000, 3000]);
+    }
+
+    #[test]
+    fn skips_a_torn_record() {
+        let mut log = HistoryLog::open(Flash::new()).unwrap();
+        log.append(&record(1)).unwrap();
+        log.flash.writes_before_power_loss = Some(0);
+        assert!(matches!(
+            log.append(&record(2)),
+            Err(Error::Flash(MemFlashError::PowerLoss))
+        ));
+
+        let mut log = reopen(log);
+        assert_eq!(timestamps(&mut log), [1]);
+        // Appended after the torn slot
+        log.append(&record(3)).unwrap();
+        log.append(&record(4)).unwrap();
+        assert_eq!(timestamps(&mut log), [1, 3, 4]);
+    }
+
+    #[test]
+    fn skips_the_slot_of_a_failed_write() {
+        let mut log = HistoryLog::open(Flash::new()).unwrap();
+        log.append(&record(1)).unwrap();
+        log.flash.writes_before_power_loss = Some(0);
+        assert!(matches!(
+            log.append(&record(2)),
+            Err(Error::Flash(MemFlashError::PowerLoss))
+        ));
+
+        // Without a reboot, the next records don't land on the torn slot
+        log.flash.writes_before_power_loss = None;
+        for seconds in 3..=5 {
+            log.append(&record(seconds)).unwrap();
+        }
+        assert_eq!(timestamps(&mut log), [1, 3, 4, 5]);
+        let mut log = reopen(log);
+        assert_eq!(timestamps(&mut log), [1, 3, 4, 5]);
+    }
+
+    #[test]
+    fn recovers_from_a_torn_sector_header() {
+        let mut log = HistoryLog::open(Flash::new()).unwrap();
+        for seconds in 1..=3 {
+            log.append(&record(seconds)).unwrap();
+        }
+        // Cuts the header of the second sector
+        log.flash.writes_before_power_loss = Some(0);
+        assert!(log.append(&record(4)).is_err());
+
+        let mut log = reopen(log);
+        assert_eq!(timestamps(&mut log), [1, 2, 3]);
+        log.append(&record(5)).unwrap();
+        assert_eq!(timestamps(&mut log), [1, 2, 3, 5]);
+    }
+}
@@ -0,0 +1,436 @@
+// Key-value store in a flash partition
+//
+// Entries are appended to the current sector, the latest entry of a key holds
+// its value. When the sector is full, the latest entries are copied to the
+// next one and its header is written last, so a power loss during the
+// compaction leaves the previous sector in use.
+use embedded_storage::nor_flash::NorFlash;
+use heapless::LinearMap;
+
+use super::crc::crc32;
+use super::header::{HEADER_SIZE, decode_header, encode_header};
+use super::partition::Partition;
+
+/// Longest value that can be stored.
+pub const MAX_VALUE_LEN: usize = 128;
+
+/// How many keys the store can hold.
+pub const MAX_KEYS: usize = 32;
+
+const MAGIC: u32 = u32::from_le_bytes(*b"WSKV");
+
+/// CRC, key and value length.
+const ENTRY_HEADER_SIZE: usize = 8;
+
+const MAX_ENTRY_SIZE: usize = ENTRY_HEADER_SIZE + MAX_VALUE_LEN;
+
+/// Entries start at multiples of this.
+const ALIGN: usize = 4;
+
+/// Key of an erased entry.
+const RESERVED_KEY: u16 = 0xFFFF;
+
+/// Error type for the store.
+#[derive(Debug, defmt::Format)]
+pub enum Error<E> {
+    /// The sector size isn't a multiple of the entry alignment or there are fewer than two sectors.
+    UnsupportedFlash,
+
+    /// The key is reserved.
+    InvalidKey,
+
+    /// The value is longer than [`MAX_VALUE_LEN`].
+    ValueTooLong,
+
+    /// The buffer is shorter than the value.
+    BufferTooSmall,
+
+    /// There are more than [`MAX_KEYS`] keys or their values don't fit in a sector.
+    Full,
+
+    /// Flash error.
+    Flash(E),
+}
+
+/// Size of the entry holding a value of `len` bytes.
+fn entry_size(len: usize) -> usize {
+    ENTRY_HEADER_SIZE + len.next_multiple_of(ALIGN)
+}
+
+/// Encodes an entry into `entry`, returns its size.
+fn encode_entry(key: u16, value: &[u8], entry: &mut [u8; MAX_ENTRY_SIZE]) -> usize {
+    let end = ENTRY_HEADER_SIZE + value.len();
+    entry.fill(0xFF);
+    entry[4..6].copy_from_slice(&key.to_le_bytes());
+    entry[6..8].copy_from_slice(&(value.len() as u16).to_le_bytes());
+    entry[ENTRY_HEADER_SIZE..end].copy_from_slice(value);
+    let crc = crc32(&entry[4..end]);
+    entry[0..4].copy_from_slice(&crc.to_le_bytes());
+    entry_size(value.len())
+}
+
+/// Wear-levelled key-value store of short byte strings.
+pub struct KvStore<F> {
+    flash: F,
+    sectors: u32,
+    /// Sector entries are appended to.
+    sector: u32,
+    sequence: u32,
+    /// Offset of the next entry in the current sector.
+    head: usize,
+}
+
+impl<F: NorFlash> KvStore<F> {
+    /// Opens the store, formatting the flash if it doesn't hold one.
+    pub fn open(flash: F) -> Result<Self, Error<F::Error>> {
+        if !F::ERASE_SIZE.is_multiple_of(ALIGN)
+            || !ALIGN.is_multiple_of(F::WRITE_SIZE)
+            || !ALIGN.is_multiple_of(F::READ_SIZE)
+        {
+            return Err(Error::UnsupportedFlash);
+        }
+        let sectors = (flash.capacity() / F::ERASE_SIZE) as u32;
+        if sectors < 2 {
+            return Err(Error::UnsupportedFlash);
+        }
+
+        let mut store = Self {
+            flash,
+            sectors,
+            sector: 0,
+            sequence: 0,
+            head: HEADER_SIZE,
+        };
+
+        // The sector compacted last has the highest sequence number
+        let mut current = None;
+        for sector in 0..sectors {
+            let mut header = [0; HEADER_SIZE];
+            store.read(sector, 0, &mut header)?;
+            if let Some(sequence) = decode_header(MAGIC, &header)
+                && current.is_none_or(|(_, highest)| sequence > highest)
+            {
+                current = Some((sector, sequence));
+            }
+        }
+
+        match current {
+            Some((sector, sequence)) => {
+                store.sector = sector;
+                store.sequence = sequence;
+                store.head = store.scan(sector, |_, _, _| Ok(()))?;
+            }
+            None => {
+                store.erase(0)?;
+                store.write(0, 0, &encode_header(MAGIC, 0))?;
+            }
+        }
+        Ok(store)
+    }
+
+    /// Reads the value of `key` into `buffer`, `None` if it was never set.
+    pub fn get<'b>(
+        &mut self,
+        key: u16,
+        buffer: &'b mut [u8],
+    ) -> Result<Option<&'b [u8]>, Error<F::Error>> {
+        let mut latest = None;
+        self.scan(self.sector, |entry_key, offset, len| {
+            if entry_key == key {
+                latest = Some((offset, len));
+            }
+            Ok(())
+        })?;
+        let Some((offset, len)) = latest else {
+            return Ok(None);
+        };
+
+        let mut entry = [0; MAX_ENTRY_SIZE];
+        self.read(self.sector, offset, &mut entry[..entry_size(len)])?;
+        let value = buffer.get_mut(..len).ok_or(Error::BufferTooSmall)?;
+        value.copy_from_slice(&entry[ENTRY_HEADER_SIZE..ENTRY_HEADER_SIZE + len]);
+        Ok(Some(value))
+    }
+
+    /// Sets the value of `key`, nothing is written if it doesn't change.
+    pub fn set(&mut self, key: u16, value: &[u8]) -> Result<(), Error<F::Error>> {
+        if key == RESERVED_KEY {
+            return Err(Error::InvalidKey);
+        }
+        if value.len() > MAX_VALUE_LEN {
+            return Err(Error::ValueTooLong);
+        }
+        let mut current = [0; MAX_VALUE_LEN];
+        if self.get(key, &mut current)? == Some(value) {
+            return Ok(());
+        }
+
+        let mut entry = [0; MAX_ENTRY_SIZE];
+        let size = encode_entry(key, value, &mut entry);
+        if self.head + size > F::ERASE_SIZE {
+            return self.compact(key, &entry[..size]);
+        }
+        self.write(self.sector, self.head, &entry[..size])?;
+        self.head += size;
+        Ok(())
+    }
+
+    pub fn destroy(self) -> F {
+        self.flash
+    }
+
+    /// Moves the latest entries to the next sector, `entry` replacing the one of `key`.
+    fn compact(&mut self, key: u16, entry: &[u8]) -> Result<(), Error<F::Error>> {
+        let mut latest = LinearMap::<u16, (usize, usize), MAX_KEYS>::new();
+        self.scan(self.sector, |entry_key, offset, len| {
+            if entry_key != key {
+                latest
+                    .insert(entry_key, (offset, len))
+                    .map_err(|_| Error::Full)?;
+            }
+            Ok(())
+        })?;
+
+        let next = (self.sector + 1) % self.sectors;
+        self.erase(next)?;
+        let mut head = HEADER_SIZE;
+        let mut buffer = [0; MAX_ENTRY_SIZE];
+        for &(offset, len) in latest.values() {
+            let size = entry_size(len);
+            if head + size > F::ERASE_SIZE {
+                return Err(Error::Full);
+            }
+            self.read(self.sector, offset, &mut buffer[..size])?;
+            self.write(next, head, &buffer[..size])?;
+            head += size;
+        }
+        if head + entry.len() > F::ERASE_SIZE {
+            return Err(Error::Full);
+        }
+        self.write(next, head, entry)?;
+        head += entry.len();
+
+        // Writing the header makes the new sector the current one
+        self.write(next, 0, &encode_header(MAGIC, self.sequence + 1))?;
+        self.sequence += 1;
+        self.sector = next;
+        self.head = head;
+        Ok(())
+    }
+
+    /// Calls `f` with the key, offset and value length of every valid entry
+    /// of `sector`, returns the offset after the last entry.
+    fn scan(
+        &mut self,
+        sector: u32,
+        mut f: impl FnMut(u16, usize, usize) -> Result<(), Error<F::Error>>,
+    ) -> Result<usize, Error<F::Error>> {
+        let mut entry = [0; MAX_ENTRY_SIZE];
+        let mut offset = HEADER_SIZE;
+        while offset + ENTRY_HEADER_SIZE <= F::ERASE_SIZE {
+            self.read(sector, offset, &mut entry[..ENTRY_HEADER_SIZE])?;
+            let [c0, c1, c2, c3, k0, k1, l0, l1, ..] = entry;
+            if entry[..ENTRY_HEADER_SIZE] == [0xFF; ENTRY_HEADER_SIZE] {
+                return Ok(offset);
+            }
+
+            let len = u16::from_le_bytes([l0, l1]) as usize;
+            let size = entry_size(len);
+            // A header torn by a power loss, where the entry ends is unknown
+            if len > MAX_VALUE_LEN || offset + size > F::ERASE_SIZE {
+                return Ok(F::ERASE_SIZE);
+            }
+
+            self.read(
+                sector,
+                offset + ENTRY_HEADER_SIZE,
+                &mut entry[ENTRY_HEADER_SIZE..size],
+            )?;
+            // A value torn by a power loss is skipped
+            if crc32(&entry[4..ENTRY_HEADER_SIZE + len]) == u32::from_le_bytes([c0, c1, c2, c3]) {
+                f(u16::from_le_bytes([k0, k1]), offset, len)?;
+            }
+            offset += size;
+        }
+        Ok(offset)
+    }
+
+    fn read(
+        &mut self,
+        sector: u32,
+        offset: usize,
+        bytes: &mut [u8],
+    ) -> Result<(), Error<F::Error>> {
+        self.flash
+            .read(sector * F::ERASE_SIZE as u32 + offset as u32, bytes)
+            .map_err(Error::Flash)
+    }
+
+    fn write(&mut self, sector: u32, offset: usize, bytes: &[u8]) -> Result<(), Error<F::Error>> {
+        self.flash
+            .write(sector * F::ERASE_SIZE as u32 + offset as u32, bytes)
+            .map_err(Error::Flash)
+    }
+
+    fn erase(&mut self, sector: u32) -> Result<(), Error<F::Error>> {
+        let from = sector * F::ERASE_SIZE as u32;
+        self.flash
+            .erase(from, from + F::ERASE_SIZE as u32)
+            .map_err(Error::Flash)
+    }
+}
+
+/// The store in the `config` flash partition.
+pub type FlashKvStore = KvStore<Partition>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::tests::{MemFlash, MemFlashError};
+
+    type Flash = MemFlash<256, 2>;
+
+    fn get(store: &mut KvStore<Flash>, key: u16) -> Option<u32> {
+        let mut buffer = [0; 4];
+        let value = store.get(key, &mut buffer).unwrap()?;
+        Some(u32::from_le_bytes(value.try_into().unwrap()))
+    }
+
+    fn set(store: &mut KvStore<Flash>, key: u16, value: u32) {
+        store.set(key, &value.to_le_bytes()).unwrap();
+    }
+
+    /// Opens the store again, as after a reboot.
+    fn reopen(store: KvStore<Flash>) -> KvStore<Flash> {
+        let mut flash = store.destroy();
+        flash.writes_before_power_loss = None;
+        KvStore::open(flash).unwrap()
+    }
+
+    /// Overwrites `key` until the next entry doesn't fit in the sector.
+    fn fill_sector(store: &mut KvStore<Flash>, key: u16) {
+        let mut value = 1000;
+        while store.head + entry_size(4) <= Flash::ERASE_SIZE {
+            set(store, key, value);
+            value += 1;
+        }
+    }
+
+    #[test]
+    fn stores_values() {
+        let mut store = KvStore::open(Flash::new()).unwrap();
+        set(&mut store, 1, 10);
+        set(&mut store, 2, 20);
+        store.set(3, b"").unwrap();
+
+        assert_eq!(get(&mut store, 1), Some(10));
+        assert_eq!(get(&mut store, 2), Some(20));
+        assert_eq!(store.get(3, &mut [0; 4]).unwrap(), Some(&b""[..]));
+        assert_eq!(get(&mut store, 4), None);
+        assert!(matches!(
+            store.get(1, &mut [0; 3]),
+            Err(Error::BufferTooSmall)
+        ));
+        assert!(matches!(
+            store.set(RESERVED_KEY, b"value"),
+            Err(Error::InvalidKey)
+        ));
+        assert!(matches!(
+            store.set(1, &[0; MAX_VALUE_LEN + 1]),
+            Err(Error::ValueTooLong)
+        ));
+    }
+
+    #[test]
+    fn keeps_the_latest_values_across_reboots() {
+        let mut store = KvStore::open(Flash::new()).unwrap();
+        set(&mut store, 1, 10);
+        set(&mut store, 2, 20);
+        set(&mut store, 1, 11);
+
+        let mut store = reopen(store);
+        assert_eq!(get(&mut store, 1), Some(11));
+        assert_eq!(get(&mut store, 2), Some(20));
+    }
+
+    #[test]
+    fn does_not_rewrite_an_unchanged_value() {
+        let mut store = KvStore::open(Flash::new()).unwrap();
+        set(&mut store, 1, 10);
+        let head = store.head;
+        set(&mut store, 1, 10);
+
+        assert_eq!(store.head, head);
+    }
+
+    #[test]
+    fn compacts_a_full_sector() {
+        let mut store = KvStore::open(Flash::new()).unwrap();
+        set(&mut store, 1, 10);
+        fill_sector(&mut store, 2);
+        set(&mut store, 2, 20);
+
+        assert_eq!(store.sector, 1);
+        assert_eq!(store.head, HEADER_SIZE + 2 * entry_size(4));
+        let mut store = reopen(store);
+        assert_eq!(get(&mut store, 1), Some(10));
+        assert_eq!(get(&mut store, 2), Some(20));
+
+        // And back to the first sector
+        fill_sector(&mut store, 1);
+        set(&mut store, 1, 11);
+        assert_eq!(store.sector, 0);
+        assert_eq!(get(&mut store, 1), Some(11));
+        assert_eq!(get(&mut store, 2), Some(20));
+    }
+
+    #[test]
+    fn rejects_values_that_do_not_fit_in_a_sector() {
+        let mut store = KvStore::open(Flash::new()).unwrap();
+        store.set(0, &[0; MAX_VALUE_LEN]).unwrap();
+
+        // Two of them don't fit
+        assert!(matches!(
+            store.set(1, &[1; MAX_VALUE_LEN]),
+            Err(Error::Full)
+        ));
+        assert_eq!(store.get(1, &mut [0; MAX_VALUE_LEN]).unwrap(), None);
+    }
+
+    #[test]
+    fn skips_a_torn_entry() {
+        let mut store = KvStore::open(Flash::new()).unwrap();
+        set(&mut store, 1, 10);
+        store.flash.writes_before_power_loss = Some(0);
+        assert!(matches!(
+            store.set(1, &11u32.to_le_bytes()),
+            Err(Error::Flash(MemFlashError::PowerLoss))
+        ));
+
+        let mut store = reopen(store);
+        assert_eq!(get(&mut store, 1), Some(10));
+        set(&mut store, 1, 12);
+        let mut store = reopen(store);
+        assert_eq!(get(&mut store, 1), Some(12));
+    }
+
+    #[test]
+    fn keeps_the_previous_sector_after_a_torn_compaction() {
+        let mut store = KvStore::open(Flash::new()).unwrap();
+        set(&mut store, 1, 10);
+        fill_sector(&mut store, 2);
+        let latest = get(&mut store, 2);
+        // Copies the entry of key 1, writes the new one then cuts the header
+        store.flash.writes_before_power_loss = Some(2);
+        assert!(store.set(2, &20u32.to_le_bytes()).is_err());
+
+        let mut store = reopen(store);
+        assert_eq!(store.sector, 0);
+        assert_eq!(get(&mut store, 1), Some(10));
+        assert_eq!(get(&mut store, 2), latest);
+        set(&mut store, 2, 21);
+        assert_eq!(store.sector, 1);
+        assert_eq!(get(&mut store, 1), Some(10));
+        assert_eq!(get(&mut store, 2), Some(21));
+    }
+}
@@ -0,0 +1,75 @@
+// Flash partitions sharing one `FlashStorage`
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::{Mutex, raw::NoopRawMutex};
+use embedded_storage::nor_flash::{ErrorType, NorFlash, ReadNorFlash};
+use esp_bootloader_esp_idf::partitions::{self, PARTITION_TABLE_MAX_LEN, read_partition_table};
+use esp_storage::{FlashStorage, FlashStorageError};
+
+pub type TheFlash = Mutex<NoopRawMutex, RefCell<FlashStorage<'static>>>;
+
+/// A partition of the flash, offsets are relative to its start.
+pub struct Partition {
+    flash: &'static TheFlash,
+    offset: u32,
+    size: u32,
+}
+
+impl Partition {
+    /// Looks up the partition labelled `label` in the partition table.
+    pub fn find(flash: &'static TheFlash, label: &str) -> Result<Option<Self>, partitions::Error> {
+        let mut buffer = [0; PARTITION_TABLE_MAX_LEN];
+        let table =
+            flash.lock(|storage| read_partition_table(&mut *storage.borrow_mut(), &mut buffer))?;
+        Ok(table
+            .iter()
+            .find(|partition| partition.label_as_str() == label)
+            .map(|partition| Self {
+                flash,
+                offset: partition.offset(),
+                size: partition.len(),
+            }))
+    }
+
+    fn address(&self, offset: u32, len: usize) -> Result<u32, FlashStorageError> {
+        if offset as usize + len > self.size as usize {
+            return Err(FlashStorageError::OutOfBounds);
+        }
+        Ok(self.offset + offset)
+    }
+}
+
+impl ErrorType for Partition {
+    type Error = FlashStorageError;
+}
+
+impl ReadNorFlash for Partition {
+    const READ_SIZE: usize = FlashStorage::READ_SIZE;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        let address = self.address(offset, bytes.len())?;
+        self.flash
+            .lock(|storage| storage.borrow_mut().read(address, bytes))
+    }
+
+    fn capacity(&self) -> usize {
+        self.size as usize
+    }
+}
+
+impl NorFlash for Partition {
+    const WRITE_SIZE: usize = FlashStorage::WRITE_SIZE;
+    const ERASE_SIZE: usize = FlashStorage::ERASE_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        let address = self.address(from, to.saturating_sub(from) as usize)?;
+        self.flash
+            .lock(|storage| storage.borrow_mut().erase(address, address + (to - from)))
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        let address = self.address(offset, bytes.len())?;
+        self.flash
+            .lock(|storage| storage.borrow_mut().write(address, bytes))
+    }
+}